@@ -0,0 +1,377 @@
+// Actions planned ahead by something outside, such as a Node-RED flow or a show scheduler, so a
+// whole evening can be programmed in one call. POST /agenda takes one action per line, as
+// "<RFC 3339 time> <command>" with the commands of the POST endpoints, and "for <seconds>s" after
+// the command for one that only lasts a while, e.g.
+//
+//   2024-12-24T17:00:00+01:00 power on
+//   2024-12-24T17:00:00+01:00 effect rainbow
+//   2024-12-24T18:00:00+01:00 color #ff0000 for 600s
+//   2024-12-24T21:30:00+01:00 brightness 0.4
+//   2024-12-24T23:00:00+01:00 power off
+//
+// The agenda is kept in storage with the override running, if any, so neither is lost to a
+// restart, and the render loop sends each action once its time comes. On boot the actions
+// missed while the daemon was down are caught up on: of those from the last CATCH_UP, only the
+// last power, show and brightness actions are sent, as the others would have been replaced by
+// now, and an override that has not run out yet comes back for the rest of its time.
+use crate::{color, effects::EffectKind, metrics::format_time, remote::RemoteCommand, storage};
+use chrono::DateTime;
+use log::{info, warn};
+use std::{
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+// Where storage keeps the agenda
+pub const KEY: &str = "agenda";
+// Plenty for a season of evenings, and keeps a runaway planner from filling the SD card
+pub const MAX_ACTIONS: usize = 1000;
+// Missed actions older than this are stale by the time the daemon is back
+const CATCH_UP: i64 = 12 * 60 * 60;
+
+/// A command to send at a given time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Action {
+    // Unix seconds
+    pub at: i64,
+    pub command: RemoteCommand,
+}
+
+static AGENDA: Mutex<Vec<Action>> = Mutex::new(Vec::new());
+// The commands of the override running, all ending when the latest does
+static OVERRIDE: Mutex<Vec<Action>> = Mutex::new(Vec::new());
+
+/// Reads one action per line, naming the first line that does not parse.
+pub fn parse(text: &str) -> Result<Vec<Action>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            parse_action(line.trim()).ok_or_else(|| {
+                format!(
+                    "line {} is not \"<RFC 3339 time> <command>\": {:?}",
+                    i + 1,
+                    line
+                )
+            })
+        })
+        .collect()
+}
+
+fn parse_action(line: &str) -> Option<Action> {
+    let (at, command) = line.split_once(' ')?;
+    let at = DateTime::parse_from_rfc3339(at).ok()?.timestamp();
+    let mut command = command.trim();
+    let mut ttl = None;
+    if let Some((rest, seconds)) = command.rsplit_once(" for ") {
+        if let Some(seconds) = seconds.strip_suffix('s').and_then(|s| s.parse().ok()) {
+            command = rest;
+            ttl = Some(Duration::from_secs(seconds));
+        }
+    }
+    let (name, argument) = command.split_once(' ')?;
+    let argument = argument.trim();
+    let command = match name {
+        "power" => match argument {
+            "on" => RemoteCommand::On,
+            "off" => RemoteCommand::Off,
+            _ => return None,
+        },
+        "color" => RemoteCommand::Color(color::parse_hex(argument)?),
+        "effect" => RemoteCommand::Effect(EffectKind::from_name(argument)?),
+        "brightness" => RemoteCommand::Brightness(
+            argument
+                .parse()
+                .ok()
+                .filter(|level| (0.0..=1.0).contains(level))?,
+        ),
+        "scene" => RemoteCommand::Scene(argument.to_string()),
+        "palette" => RemoteCommand::Palette(argument.to_string()),
+        _ => return None,
+    };
+    let command = match ttl {
+        Some(ttl) => RemoteCommand::Temporary(Box::new(command), ttl),
+        None => command,
+    };
+    Some(Action { at, command })
+}
+
+/// The actions one per line, as POST /agenda takes them, with UTC times.
+pub fn format(actions: &[Action]) -> String {
+    actions
+        .iter()
+        .map(|action| {
+            format!(
+                "{} {}\n",
+                format_time(action.at),
+                command_text(&action.command)
+            )
+        })
+        .collect()
+}
+
+fn command_text(command: &RemoteCommand) -> String {
+    match command {
+        RemoteCommand::On => "power on".to_string(),
+        RemoteCommand::Off => "power off".to_string(),
+        RemoteCommand::Temporary(command, ttl) => {
+            format!("{} for {}s", command_text(command), ttl.as_secs())
+        }
+        command => command.to_string(),
+    }
+}
+
+/// Adds `actions` to the agenda in time order, unless that would take it past MAX_ACTIONS.
+pub fn add(actions: Vec<Action>) -> Result<(), String> {
+    let mut agenda = AGENDA.lock().unwrap_or_else(PoisonError::into_inner);
+    if agenda.len() + actions.len() > MAX_ACTIONS {
+        return Err(format!(
+            "the agenda holds at most {} actions, and has {} already",
+            MAX_ACTIONS,
+            agenda.len()
+        ));
+    }
+    agenda.extend(actions);
+    // Stable, so actions for the same time keep the order they were given in
+    agenda.sort_by_key(|action| action.at);
+    save(&agenda, &running());
+    Ok(())
+}
+
+/// The actions still to come, soonest first.
+pub fn pending() -> Vec<Action> {
+    AGENDA
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Drops every action still to come.
+pub fn clear() {
+    let mut agenda = AGENDA.lock().unwrap_or_else(PoisonError::into_inner);
+    if !agenda.is_empty() {
+        agenda.clear();
+        save(&agenda, &running());
+    }
+}
+
+/// Takes the commands whose time has come by `now`, in Unix seconds, off the agenda.
+pub fn due(now: i64) -> Vec<RemoteCommand> {
+    let mut agenda = AGENDA.lock().unwrap_or_else(PoisonError::into_inner);
+    let count = agenda.iter().take_while(|action| action.at <= now).count();
+    if count == 0 {
+        return Vec::new();
+    }
+    let due = agenda.drain(..count).map(|action| action.command).collect();
+    save(&agenda, &running());
+    due
+}
+
+/// Keeps the override `command`, sent at `now` in Unix seconds to last `ttl`, for a restart to
+/// bring back. Commands of the override already running now end with it, as the render loop
+/// runs their time from the latest.
+pub fn overriding(now: i64, command: RemoteCommand, ttl: Duration) {
+    let agenda = AGENDA.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut running = OVERRIDE.lock().unwrap_or_else(PoisonError::into_inner);
+    let ends = now + ttl.as_secs() as i64;
+    running.push(Action {
+        at: now,
+        command: RemoteCommand::Temporary(Box::new(command), ttl),
+    });
+    for action in running.iter_mut() {
+        if let RemoteCommand::Temporary(_, ttl) = &mut action.command {
+            *ttl = Duration::from_secs((ends - action.at).max(0) as u64);
+        }
+    }
+    save(&agenda, &running);
+}
+
+/// Forgets the override once it has ended or been replaced.
+pub fn override_ended() {
+    let agenda = AGENDA.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut running = OVERRIDE.lock().unwrap_or_else(PoisonError::into_inner);
+    if !running.is_empty() {
+        running.clear();
+        save(&agenda, &running);
+    }
+}
+
+fn running() -> Vec<Action> {
+    OVERRIDE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// How long until the next action, for waits that should not run past it.
+pub fn until_next(now: i64) -> Option<Duration> {
+    AGENDA
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .first()
+        .map(|action| Duration::from_secs((action.at - now).max(0) as u64))
+}
+
+/// Picks up the agenda and the override from earlier runs, at `now` in Unix seconds, and
+/// returns the commands to catch up on.
+pub fn load(now: i64) -> Vec<RemoteCommand> {
+    let contents = match storage::current().read(KEY) {
+        Ok(contents) => contents,
+        Err(e) => {
+            warn!("Cannot read the agenda: {}", e);
+            return Vec::new();
+        }
+    };
+    let Some(contents) = contents else {
+        return Vec::new();
+    };
+    let mut saved = match parse(&String::from_utf8_lossy(&contents)) {
+        Ok(saved) => saved,
+        Err(e) => {
+            warn!("Cannot read the agenda: {}", e);
+            return Vec::new();
+        }
+    };
+    // The override was saved after the agenda, and goes back in time order with it
+    saved.sort_by_key(|action| action.at);
+    let (missed, upcoming): (Vec<Action>, Vec<Action>) =
+        saved.into_iter().partition(|action| action.at < now);
+    let missed_count = missed.len();
+    let catch_up = catch_up(missed, now);
+    if missed_count > 0 {
+        info!(
+            "Catching up on {} of {} agenda action(s) missed while the daemon was down",
+            catch_up.len(),
+            missed_count
+        );
+    }
+    if !upcoming.is_empty() {
+        info!("{} agenda action(s) to come", upcoming.len());
+    }
+    let mut agenda = AGENDA.lock().unwrap_or_else(PoisonError::into_inner);
+    *agenda = upcoming;
+    if missed_count > 0 {
+        // The override is saved again when the render loop brings it back
+        save(&agenda, &[]);
+    }
+    catch_up
+}
+
+// Of the actions missed by `now`, in time order, the ones that still decide what the lights
+// show: the last of each kind within CATCH_UP, and the overrides with time left
+fn catch_up(missed: Vec<Action>, now: i64) -> Vec<RemoteCommand> {
+    let kind = |command: &RemoteCommand| match command {
+        RemoteCommand::On | RemoteCommand::Off => 0,
+        RemoteCommand::Brightness(_) => 1,
+        _ => 2,
+    };
+    let mut kept: Vec<RemoteCommand> = Vec::new();
+    for action in missed {
+        match action.command {
+            RemoteCommand::Temporary(command, ttl) => {
+                let left = action.at + ttl.as_secs() as i64 - now;
+                if left > 0 {
+                    let left = Duration::from_secs(left as u64);
+                    kept.push(RemoteCommand::Temporary(command, left));
+                }
+            }
+            command if now - action.at <= CATCH_UP => {
+                kept.retain(|earlier| {
+                    matches!(earlier, RemoteCommand::Temporary(..))
+                        || kind(earlier) != kind(&command)
+                });
+                kept.push(command);
+            }
+            _ => {}
+        }
+    }
+    kept
+}
+
+fn save(agenda: &[Action], running: &[Action]) {
+    let contents = format(agenda) + &format(running);
+    if let Err(e) = storage::current().write(KEY, contents.as_bytes()) {
+        warn!("Cannot save the agenda: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_evening_reads_back_in_utc() {
+        let actions = parse(
+            "2024-12-24T17:00:00+01:00 power on\n\
+             2024-12-24T17:00:00+01:00 effect rainbow\n\n\
+             2024-12-24T21:30:00+01:00 brightness 0.4\n\
+             2024-12-24T22:00:00Z scene midnight mass\n",
+        )
+        .unwrap();
+        assert_eq!(
+            actions[0],
+            Action {
+                at: 1_735_056_000,
+                command: RemoteCommand::On
+            }
+        );
+        assert_eq!(
+            actions[3].command,
+            RemoteCommand::Scene("midnight mass".to_string())
+        );
+        assert_eq!(
+            format(&actions[..2]),
+            "2024-12-24T16:00:00Z power on\n2024-12-24T16:00:00Z effect rainbow\n"
+        );
+        assert_eq!(parse(&format(&actions)), Ok(actions));
+
+        for wrong in [
+            "17:00 power on",
+            "2024-12-24T17:00:00+01:00 power",
+            "2024-12-24T17:00:00+01:00 brightness 2",
+            "2024-12-24T17:00:00+01:00 effect disco",
+            "2024-12-24T17:00:00+01:00 undo it",
+        ] {
+            assert!(parse(wrong).is_err(), "{:?} was accepted", wrong);
+        }
+        assert!(parse("\n2024-12-24T17:00:00+01:00 power of")
+            .unwrap_err()
+            .starts_with("line 2 "));
+    }
+
+    #[test]
+    fn a_restart_catches_up_on_what_still_counts() {
+        let missed = parse(
+            "2024-12-24T07:00:00Z power on\n\
+             2024-12-24T16:00:00Z power on\n\
+             2024-12-24T16:00:00Z effect rainbow\n\
+             2024-12-24T16:30:00Z brightness 0.5\n\
+             2024-12-24T17:00:00Z scene midnight mass\n\
+             2024-12-24T17:50:00Z color #ff0000 for 900s\n\
+             2024-12-24T17:55:00Z power off for 60s\n",
+        )
+        .unwrap();
+        assert_eq!(
+            missed[5].command,
+            RemoteCommand::Temporary(
+                Box::new(RemoteCommand::Color((255, 0, 0))),
+                Duration::from_secs(900)
+            )
+        );
+        assert_eq!(parse(&format(&missed)), Ok(missed.clone()));
+
+        let now = parse("2024-12-24T18:00:00Z power on").unwrap()[0].at;
+        assert_eq!(
+            catch_up(missed, now),
+            [
+                RemoteCommand::On,
+                RemoteCommand::Brightness(0.5),
+                RemoteCommand::Scene("midnight mass".to_string()),
+                RemoteCommand::Temporary(
+                    Box::new(RemoteCommand::Color((255, 0, 0))),
+                    Duration::from_secs(300)
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,269 @@
+// Music sync for the audio effect: microphone input is cut into windows, run through an FFT
+// and summed into bass, mid and treble energy. Capturing needs the `audio` build feature; the
+// analysis is plain arithmetic and always built.
+use crate::config::AudioConfig;
+use log::warn;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc,
+};
+
+// Samples per analysis window, a power of two for the FFT; about 23ms at 44.1kHz
+pub const WINDOW: usize = 1024;
+// Upper edges of the bass and mid bands, and where treble stops counting
+const BAND_EDGES_HZ: [f32; 3] = [250.0, 2000.0, 8000.0];
+// How much of its peak a band keeps per window, so the gain follows the music's loudness
+const PEAK_DECAY: f32 = 0.995;
+// Below this a band counts as silence rather than being amplified to full scale
+const NOISE_FLOOR: f32 = 1e-3;
+
+/// Bass, mid and treble levels between 0 and 1, shared between the capture thread and the
+/// renderers.
+#[derive(Clone, Default)]
+pub struct AudioLevels {
+    bands: Arc<[AtomicU32; 3]>,
+    capturing: Arc<AtomicBool>,
+}
+
+impl AudioLevels {
+    pub fn new() -> Self {
+        AudioLevels::default()
+    }
+
+    pub fn set(&self, levels: [f32; 3]) {
+        for (band, level) in self.bands.iter().zip(levels) {
+            band.store(level.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self) -> [f32; 3] {
+        [0, 1, 2].map(|i| f32::from_bits(self.bands[i].load(Ordering::Relaxed)))
+    }
+
+    /// Starts capturing from the configured input, unless already started.
+    pub fn start(&self, config: &AudioConfig) {
+        if self.capturing.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = capture(config, self.clone()) {
+            warn!("Cannot capture audio, the audio effect stays dark: {}", e);
+        }
+    }
+}
+
+/// Turns band energies into levels, scaled against each band's recent peak.
+pub struct Analyzer {
+    sensitivity: f32,
+    peaks: [f32; 3],
+}
+
+impl Analyzer {
+    pub fn new(sensitivity: f32) -> Self {
+        Analyzer {
+            sensitivity,
+            peaks: [NOISE_FLOOR; 3],
+        }
+    }
+
+    pub fn levels(&mut self, samples: &[f32], sample_rate: f32) -> [f32; 3] {
+        let energies = band_energies(samples, sample_rate);
+        let mut levels = [0.0; 3];
+        for (i, energy) in energies.into_iter().enumerate() {
+            self.peaks[i] = (self.peaks[i] * PEAK_DECAY).max(energy).max(NOISE_FLOOR);
+            levels[i] = (energy / self.peaks[i] * self.sensitivity).min(1.0);
+        }
+        levels
+    }
+}
+
+/// Energy in the bass, mid and treble bands of a window of mono samples.
+pub fn band_energies(samples: &[f32], sample_rate: f32) -> [f32; 3] {
+    let len = samples.len().next_power_of_two();
+    // A Hann window keeps a tone in its own band instead of smearing it across the spectrum
+    let mut spectrum: Vec<(f32, f32)> = (0..len)
+        .map(|i| {
+            let sample = samples.get(i).copied().unwrap_or(0.0);
+            let hann = 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / len as f32).cos();
+            (sample * hann, 0.0)
+        })
+        .collect();
+    fft(&mut spectrum);
+
+    let mut energies = [0.0; 3];
+    // Skips the constant offset in bin 0 and the mirrored upper half
+    for (bin, (re, im)) in spectrum.iter().enumerate().take(len / 2).skip(1) {
+        let frequency = bin as f32 * sample_rate / len as f32;
+        if let Some(band) = BAND_EDGES_HZ.iter().position(|edge| frequency < *edge) {
+            energies[band] += (re * re + im * im) / len as f32;
+        }
+    }
+    energies
+}
+
+// In-place radix-2 FFT over (real, imaginary) pairs; the length must be a power of two
+fn fft(values: &mut [(f32, f32)]) {
+    let len = values.len();
+    let bits = len.trailing_zeros();
+    for i in 0..len {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if i < j {
+            values.swap(i, j);
+        }
+    }
+    let mut size = 2;
+    while size <= len {
+        let angle = -2.0 * std::f32::consts::PI / size as f32;
+        for start in (0..len).step_by(size) {
+            for k in 0..size / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (re, im) = values[start + k + size / 2];
+                let twiddled = (re * cos - im * sin, re * sin + im * cos);
+                let even = values[start + k];
+                values[start + k] = (even.0 + twiddled.0, even.1 + twiddled.1);
+                values[start + k + size / 2] = (even.0 - twiddled.0, even.1 - twiddled.1);
+            }
+        }
+        size *= 2;
+    }
+}
+
+#[cfg(feature = "audio")]
+fn capture(config: &AudioConfig, levels: AudioLevels) -> Result<(), String> {
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+    let host = cpal::default_host();
+    let device = match &config.device {
+        Some(name) => host
+            .input_devices()
+            .map_err(|e| e.to_string())?
+            .find(|device| device.name().is_ok_and(|found| found == *name)),
+        None => host.default_input_device(),
+    }
+    .ok_or_else(|| "no such input device".to_string())?;
+    let supported = device.default_input_config().map_err(|e| e.to_string())?;
+    let sample_format = supported.sample_format();
+    let stream_config: cpal::StreamConfig = supported.into();
+    let sensitivity = config.sensitivity;
+
+    // cpal streams cannot move between threads, so one thread owns the stream for good
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let stream = match sample_format {
+            cpal::SampleFormat::I16 => {
+                build_stream::<i16>(&device, &stream_config, sensitivity, levels)
+            }
+            cpal::SampleFormat::U16 => {
+                build_stream::<u16>(&device, &stream_config, sensitivity, levels)
+            }
+            _ => build_stream::<f32>(&device, &stream_config, sensitivity, levels),
+        }
+        .and_then(|stream| stream.play().map(|()| stream).map_err(|e| e.to_string()));
+        match stream {
+            Ok(_stream) => {
+                started_tx.send(Ok(())).ok();
+                loop {
+                    std::thread::park();
+                }
+            }
+            Err(e) => {
+                started_tx.send(Err(e)).ok();
+            }
+        }
+    });
+    started_rx
+        .recv()
+        .map_err(|_| "the capture thread stopped".to_string())?
+}
+
+#[cfg(feature = "audio")]
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    sensitivity: f32,
+    levels: AudioLevels,
+) -> Result<cpal::Stream, String>
+where
+    T: cpal::SizedSample,
+    f32: cpal::FromSample<T>,
+{
+    use cpal::{traits::DeviceTrait, Sample};
+
+    let channels = usize::from(config.channels).max(1);
+    let sample_rate = config.sample_rate.0 as f32;
+    let mut analyzer = Analyzer::new(sensitivity);
+    let mut window = Vec::with_capacity(WINDOW);
+    device
+        .build_input_stream(
+            config,
+            move |data: &[T], _: &cpal::InputCallbackInfo| {
+                // Mixes the channels down to mono
+                for frame in data.chunks(channels) {
+                    let sum: f32 = frame.iter().map(|sample| sample.to_sample::<f32>()).sum();
+                    window.push(sum / channels as f32);
+                    if window.len() == WINDOW {
+                        levels.set(analyzer.levels(&window, sample_rate));
+                        window.clear();
+                    }
+                }
+            },
+            |e| warn!("Audio capture failed: {}", e),
+            None,
+        )
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "audio"))]
+fn capture(_config: &AudioConfig, _levels: AudioLevels) -> Result<(), String> {
+    Err("this build has no audio support, rebuild with --features audio".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+
+    fn tone(hz: f32) -> Vec<f32> {
+        (0..WINDOW)
+            .map(|i| (2.0 * std::f32::consts::PI * hz * i as f32 / SAMPLE_RATE).sin())
+            .collect()
+    }
+
+    fn loudest_band(hz: f32) -> usize {
+        let energies = band_energies(&tone(hz), SAMPLE_RATE);
+        (0..3)
+            .max_by(|&a, &b| energies[a].total_cmp(&energies[b]))
+            .unwrap()
+    }
+
+    #[test]
+    fn fft_finds_a_tone_in_its_bin() {
+        let mut values: Vec<(f32, f32)> = (0..16)
+            .map(|i| {
+                (
+                    (2.0 * std::f32::consts::PI * 3.0 * i as f32 / 16.0).cos(),
+                    0.0,
+                )
+            })
+            .collect();
+        fft(&mut values);
+        let magnitude = |(re, im): (f32, f32)| (re * re + im * im).sqrt();
+        assert!((magnitude(values[3]) - 8.0).abs() < 1e-3);
+        assert!(magnitude(values[5]) < 1e-3);
+    }
+
+    #[test]
+    fn tones_land_in_their_band() {
+        assert_eq!(loudest_band(80.0), 0);
+        assert_eq!(loudest_band(800.0), 1);
+        assert_eq!(loudest_band(4000.0), 2);
+    }
+
+    #[test]
+    fn silence_stays_dark() {
+        let mut analyzer = Analyzer::new(1.0);
+        assert_eq!(analyzer.levels(&[0.0; WINDOW], SAMPLE_RATE), [0.0; 3]);
+        let levels = analyzer.levels(&tone(80.0), SAMPLE_RATE);
+        assert_eq!(levels[0], 1.0);
+    }
+}
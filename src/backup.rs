@@ -0,0 +1,404 @@
+// Bundles the config, the rules file and what the daemon keeps between runs into one tar archive, so a setup
+// can move to a new SD card. Stored state goes in under the file names the file backend uses,
+// whichever backend holds it. Only plain files are written and read, which is all the archive
+// ever holds.
+use crate::{
+    cache,
+    config::{self, StorageConfig},
+    error::Failure,
+    metrics, scenes,
+    storage::{self, Storage},
+    Config,
+};
+use std::{fs, path::Path, time::SystemTime};
+
+const BLOCK: usize = 512;
+const NAME_LENGTH: usize = 100;
+const CONFIG: &str = "config.toml";
+// What storage holds, under the names the file backend gives it
+const STORED: [&str; 3] = [cache::KEY, metrics::KEY, scenes::KEY];
+
+/// Writes the config and everything in `storage` into a tar archive at `to`, returning the
+/// names of the files it holds.
+pub fn create(to: &Path, storage: &dyn Storage) -> Result<Vec<&'static str>, Failure> {
+    let mut archive = Vec::new();
+    let mut included = Vec::new();
+    let mut files = vec![
+        (CONFIG, fs::read(config::path()).ok()),
+        (config::RULES_FILE, fs::read(rules_path()).ok()),
+    ];
+    for key in STORED {
+        files.push((key, read(storage, key)?));
+    }
+    for (name, contents) in files {
+        let Some(contents) = contents else {
+            continue;
+        };
+        append(&mut archive, name, &contents);
+        included.push(name);
+    }
+    if included.is_empty() {
+        return Err(Failure::Backup(
+            "there is nothing to back up yet".to_string(),
+        ));
+    }
+    // Two empty blocks end a tar archive
+    archive.resize(archive.len() + 2 * BLOCK, 0);
+    fs::write(to, archive)
+        .map_err(|e| Failure::Backup(format!("cannot write {}: {}", to.display(), e)))?;
+    Ok(included)
+}
+
+/// Puts the files from an archive made by `create` back in place, keeping what each replaces
+/// next to it with a .bak suffix. The state goes into the storage the archived config picks,
+/// or else `current`. Returns what it restored.
+pub fn restore(from: &Path, current: &StorageConfig) -> Result<Vec<String>, Failure> {
+    let archive = fs::read(from)
+        .map_err(|e| Failure::Backup(format!("cannot read {}: {}", from.display(), e)))?;
+    let files = read_archive(&archive)?;
+
+    // Check everything before touching anything
+    let mut storage_config = current.clone();
+    for (name, contents) in &files {
+        let text = || {
+            String::from_utf8(contents.clone())
+                .map_err(|_| Failure::Backup(format!("the archived {} is not text", name)))
+        };
+        match name.as_str() {
+            CONFIG => storage_config = Config::from_toml(&text()?)?.storage,
+            config::RULES_FILE => {
+                config::rules_from_toml(&text()?)?;
+            }
+            scenes::KEY => {
+                config::scenes_from_toml(&text()?)?;
+            }
+            metrics::KEY | cache::KEY => {
+                text()?;
+            }
+            _ => {
+                return Err(Failure::Backup(format!(
+                    "unexpected file {:?} in the archive",
+                    name
+                )))
+            }
+        }
+    }
+    let storage = storage::open(&storage_config)?;
+
+    let mut restored = Vec::new();
+    for (name, contents) in files {
+        if name == CONFIG || name == config::RULES_FILE {
+            let path = if name == CONFIG {
+                config::path()
+            } else {
+                rules_path()
+            };
+            let mut backup = path.as_os_str().to_owned();
+            backup.push(".bak");
+            let written = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|()| match fs::rename(&path, &backup) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                    _ => Ok(()),
+                })
+                .and_then(|()| fs::write(&path, contents));
+            written
+                .map_err(|e| Failure::Backup(format!("cannot write {}: {}", path.display(), e)))?;
+            restored.push(path.display().to_string());
+            continue;
+        }
+        let replaced = read(storage.as_ref(), &name)?;
+        let mut result = replaced.map_or(Ok(()), |replaced| {
+            write(storage.as_ref(), &format!("{}.bak", name), &replaced)
+        });
+        if result.is_ok() {
+            result = write(storage.as_ref(), &name, &contents);
+        }
+        result.map_err(|e| Failure::Backup(format!("cannot restore {}: {}", name, e)))?;
+        restored.push(format!(
+            "{} into {} storage",
+            name,
+            storage_config.backend.name()
+        ));
+    }
+    Ok(restored)
+}
+
+// The rules file sits next to the config, whatever that is called
+fn rules_path() -> std::path::PathBuf {
+    config::path().with_file_name(config::RULES_FILE)
+}
+
+// Adds a file, behind a pax extended header holding its name when that does not fit the
+// 100 bytes the tar header has for it
+fn append(archive: &mut Vec<u8>, name: &str, contents: &[u8]) {
+    let mut short_name = name;
+    if name.len() >= NAME_LENGTH {
+        let record = pax_record("path", name);
+        archive.extend_from_slice(&header("././@PaxHeader", record.len(), b'x'));
+        archive.extend_from_slice(record.as_bytes());
+        archive.resize(archive.len().next_multiple_of(BLOCK), 0);
+        // Readers without pax support still get a name, cut short
+        let mut end = NAME_LENGTH - 1;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        short_name = &name[..end];
+    }
+    archive.extend_from_slice(&header(short_name, contents.len(), b'0'));
+    archive.extend_from_slice(contents);
+    archive.resize(archive.len().next_multiple_of(BLOCK), 0);
+}
+
+// "<length> <key>=<value>\n", where the length counts its own digits
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = format!(" {}={}\n", key, value);
+    let mut length = rest.len();
+    while length.to_string().len() + rest.len() != length {
+        length = length.to_string().len() + rest.len();
+    }
+    format!("{}{}", length, rest)
+}
+
+// The daily totals are rows rather than a document in some backends, so they go through days
+fn read(storage: &dyn Storage, key: &str) -> Result<Option<Vec<u8>>, Failure> {
+    let read = if key == metrics::KEY {
+        storage
+            .days()
+            .map(|days| (!days.is_empty()).then(|| metrics::format_days(&days).into_bytes()))
+    } else {
+        storage.read(key)
+    };
+    read.map_err(|e| Failure::Backup(format!("cannot read {}: {}", key, e)))
+}
+
+fn write(storage: &dyn Storage, key: &str, contents: &[u8]) -> Result<(), String> {
+    if key == metrics::KEY {
+        storage.save_days(&metrics::parse_days(&String::from_utf8_lossy(contents)))
+    } else {
+        storage.write(key, contents)
+    }
+}
+
+fn header(name: &str, size: usize, kind: u8) -> [u8; BLOCK] {
+    let mtime = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let mut header = [0; BLOCK];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name.as_bytes());
+    field(100, b"0000600\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, format!("{:011o}\0", mtime).as_bytes());
+    field(148, b"        ");
+    field(156, &[kind]);
+    field(257, b"ustar\x0000");
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+// The name and contents of each regular file in a tar archive
+fn read_archive(archive: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Failure> {
+    let corrupt = || Failure::Backup("the archive is damaged or not a backup".to_string());
+    let mut files = Vec::new();
+    let mut offset: usize = 0;
+    // Set by a pax or GNU long name entry for the file after it
+    let mut long_name = None;
+    loop {
+        let header = archive.get(offset..offset + BLOCK).ok_or_else(corrupt)?;
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(files);
+        }
+        let stored_checksum = octal(&header[148..156]).ok_or_else(corrupt)?;
+        let checksum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| if (148..156).contains(&i) { b' ' } else { byte })
+            .map(u64::from)
+            .sum();
+        if checksum != stored_checksum {
+            return Err(corrupt());
+        }
+
+        let size = usize::try_from(octal(&header[124..136]).ok_or_else(corrupt)?)
+            .map_err(|_| corrupt())?;
+        offset += BLOCK;
+        let end = offset.checked_add(size).ok_or_else(corrupt)?;
+        let contents = archive.get(offset..end).ok_or_else(corrupt)?;
+        match header[156] {
+            b'x' => {
+                if let Some(path) = pax_path(contents) {
+                    long_name = Some(path);
+                }
+            }
+            b'L' => {
+                let name = contents.split(|&byte| byte == 0).next().unwrap_or_default();
+                long_name = Some(String::from_utf8(name.to_vec()).map_err(|_| corrupt())?);
+            }
+            // Directories and links have no place in a backup, skip them
+            b'0' | 0 => {
+                let name = match long_name.take() {
+                    Some(name) => name,
+                    None => header_name(header).ok_or_else(corrupt)?,
+                };
+                files.push((name, contents.to_vec()));
+            }
+            _ => long_name = None,
+        }
+        offset = end.checked_next_multiple_of(BLOCK).ok_or_else(corrupt)?;
+    }
+}
+
+// The name field, after the ustar prefix field when that is set
+fn header_name(header: &[u8]) -> Option<String> {
+    let text = |field: &[u8]| {
+        let field = field.split(|&byte| byte == 0).next().unwrap_or_default();
+        String::from_utf8(field.to_vec()).ok()
+    };
+    let name = text(&header[..NAME_LENGTH])?;
+    if &header[257..262] != b"ustar" {
+        return Some(name);
+    }
+    match text(&header[345..500])? {
+        prefix if prefix.is_empty() => Some(name),
+        prefix => Some(format!("{}/{}", prefix, name)),
+    }
+}
+
+// The path from a pax extended header's records, if it has one
+fn pax_path(records: &[u8]) -> Option<String> {
+    let mut records = std::str::from_utf8(records).ok()?;
+    let mut path = None;
+    while !records.is_empty() {
+        let (length, _) = records.split_once(' ')?;
+        let record = records.get(..length.parse().ok()?)?;
+        records = &records[record.len()..];
+        let (key, value) = record[length.len() + 1..]
+            .strip_suffix('\n')?
+            .split_once('=')?;
+        if key == "path" {
+            path = Some(value.to_string());
+        }
+    }
+    path
+}
+
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits = std::str::from_utf8(field).ok()?;
+    u64::from_str_radix(digits.trim_matches(|c: char| c == '\0' || c == ' '), 8).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archived_files_read_back() {
+        let mut archive = header("config.toml", 5, b'0').to_vec();
+        archive.extend_from_slice(b"a = 1");
+        archive.resize(4 * BLOCK, 0);
+        assert_eq!(
+            read_archive(&archive).unwrap(),
+            vec![("config.toml".to_string(), b"a = 1".to_vec())]
+        );
+    }
+
+    #[test]
+    fn damaged_archives_are_rejected() {
+        let mut archive = header("config.toml", 5, b'0').to_vec();
+        archive[0] = b'x';
+        archive.resize(4 * BLOCK, 0);
+        assert!(read_archive(&archive).is_err());
+        // Cut off before the end marker
+        assert!(read_archive(&header("addresses", 0, b'0')).is_err());
+        // A size running past the end of memory
+        let mut archive = header("config.toml", 0, b'0').to_vec();
+        archive[124..136].copy_from_slice(b"77777777777\0");
+        fix_checksum(&mut archive);
+        assert!(read_archive(&archive).is_err());
+    }
+
+    #[test]
+    fn checksums_cover_the_header_with_the_field_as_spaces() {
+        let header = header("config.toml", 5, b'0');
+        let mut blank = header;
+        blank[148..156].copy_from_slice(b"        ");
+        let sum: u64 = blank.iter().map(|&byte| u64::from(byte)).sum();
+        assert_eq!(octal(&header[148..156]), Some(sum));
+        // Six octal digits, a NUL and a space, as tar writes it
+        assert_eq!(&header[154..156], b"\0 ");
+    }
+
+    #[test]
+    fn long_paths_survive_the_round_trip() {
+        for name in [
+            "x".repeat(99),
+            "x".repeat(100),
+            format!("{}/scenes.toml", "nested/".repeat(30)),
+            // Cut short in the plain header on a character boundary
+            format!("{}ünïcode", "x".repeat(98)),
+        ] {
+            let mut archive = Vec::new();
+            append(&mut archive, &name, b"contents");
+            append(&mut archive, "config.toml", b"a = 1");
+            archive.resize(archive.len() + 2 * BLOCK, 0);
+            assert_eq!(
+                read_archive(&archive).unwrap(),
+                vec![
+                    (name.clone(), b"contents".to_vec()),
+                    ("config.toml".to_string(), b"a = 1".to_vec()),
+                ],
+                "{}",
+                name.len()
+            );
+        }
+    }
+
+    #[test]
+    fn pax_records_count_their_own_length() {
+        assert_eq!(pax_record("path", "a"), "9 path=a\n");
+        // 99 bytes and two digits would make 101, which takes three
+        let record = pax_record("path", &"x".repeat(92));
+        assert_eq!(record.len(), 102);
+        assert!(record.starts_with("102 "));
+    }
+
+    #[test]
+    fn long_names_from_other_tars_are_read() {
+        // GNU tar puts the name in a ././@LongLink entry before the file
+        let name = "y".repeat(150);
+        let mut archive = header("././@LongLink", name.len() + 1, b'L').to_vec();
+        archive.extend_from_slice(name.as_bytes());
+        archive.resize(archive.len().next_multiple_of(BLOCK), 0);
+        archive.extend_from_slice(&header(&name[..99], 2, b'0'));
+        archive.extend_from_slice(b"ok");
+        archive.resize(archive.len().next_multiple_of(BLOCK) + 2 * BLOCK, 0);
+        assert_eq!(
+            read_archive(&archive).unwrap(),
+            vec![(name, b"ok".to_vec())]
+        );
+
+        // ustar splits it into a prefix and a name
+        let mut archive = header("scenes.toml", 2, b'0').to_vec();
+        archive[345..351].copy_from_slice(b"nested");
+        fix_checksum(&mut archive);
+        archive.extend_from_slice(b"ok");
+        archive.resize(4 * BLOCK, 0);
+        assert_eq!(
+            read_archive(&archive).unwrap(),
+            vec![("nested/scenes.toml".to_string(), b"ok".to_vec())]
+        );
+    }
+
+    fn fix_checksum(header: &mut [u8]) {
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = header[..BLOCK].iter().map(|&byte| byte as u32).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    }
+}
@@ -0,0 +1,39 @@
+// Remembers the addresses of the lights found last time, so the next start can stop scanning
+// as soon as they show up instead of waiting out the full scan
+use crate::storage;
+use log::warn;
+use std::{env, path::PathBuf};
+
+// Where storage keeps the addresses, also their name in a backup
+pub const KEY: &str = "addresses";
+
+/// Where the daemon keeps files between runs.
+pub fn dir() -> Option<PathBuf> {
+    let cache_home = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(cache_home.join("christmas-lights"))
+}
+
+/// The addresses stored by the last successful discovery, one per line.
+pub fn addresses() -> Vec<String> {
+    storage::current()
+        .read(KEY)
+        .ok()
+        .flatten()
+        .map(|contents| {
+            String::from_utf8_lossy(&contents)
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn store_addresses(addresses: &[String]) {
+    if let Err(e) = storage::current().write(KEY, (addresses.join("\n") + "\n").as_bytes()) {
+        warn!("Cannot cache light addresses: {}", e);
+    }
+}
@@ -0,0 +1,164 @@
+// Fault injection for soak runs before the season: with --chaos every light's transport is
+// wrapped so writes randomly fail, stall or take the whole link down, exercising the
+// reconnect logic, the write queue and the supervisors the way a flaky garden link would.
+use crate::{error::Failure, transport::LightTransport};
+use btleplug::{api::Peripheral as _, platform::Peripheral};
+use futures::future::BoxFuture;
+use log::warn;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+/// How often each fault strikes, as chances per write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Faults {
+    pub write_failure: f32,
+    pub latency_spike: f32,
+    pub max_latency: Duration,
+    pub disconnect: f32,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Faults {
+            write_failure: 0.02,
+            latency_spike: 0.01,
+            max_latency: Duration::from_secs(2),
+            // At 30 writes a second, a drop every minute or so
+            disconnect: 0.0005,
+        }
+    }
+}
+
+static FAULTS: Mutex<Option<Faults>> = Mutex::new(None);
+
+/// Turns chaos on for every transport set up from now on.
+pub fn enable(faults: Faults) {
+    warn!("Chaos mode: injecting write failures, latency spikes and disconnects");
+    if let Ok(mut enabled) = FAULTS.lock() {
+        *enabled = Some(faults);
+    }
+}
+
+/// `transport` as is, or wrapped in a `ChaosTransport` when chaos is on.
+pub fn wrap(
+    transport: Arc<dyn LightTransport>,
+    peripheral: Option<Peripheral>,
+) -> Arc<dyn LightTransport> {
+    match FAULTS.lock().ok().and_then(|faults| *faults) {
+        Some(faults) => Arc::new(ChaosTransport::new(transport, peripheral, faults)),
+        None => transport,
+    }
+}
+
+/// Passes writes through to another transport, injecting faults along the way.
+pub struct ChaosTransport {
+    inner: Arc<dyn LightTransport>,
+    // Dropped for real on a disconnect, so the adapter reports it like any other
+    peripheral: Option<Peripheral>,
+    faults: Faults,
+    rng: Mutex<u64>,
+}
+
+impl ChaosTransport {
+    pub fn new(
+        inner: Arc<dyn LightTransport>,
+        peripheral: Option<Peripheral>,
+        faults: Faults,
+    ) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_nanos() as u64);
+        ChaosTransport {
+            inner,
+            peripheral,
+            faults,
+            rng: Mutex::new(seed | 1),
+        }
+    }
+
+    // xorshift64, as for twinkles
+    fn random(&self) -> f32 {
+        let Ok(mut rng) = self.rng.lock() else {
+            return 1.0;
+        };
+        *rng ^= *rng << 13;
+        *rng ^= *rng >> 7;
+        *rng ^= *rng << 17;
+        (*rng >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+impl LightTransport for ChaosTransport {
+    fn write<'a>(&'a self, chunk: &'a [u8]) -> BoxFuture<'a, Result<(), Failure>> {
+        Box::pin(async move {
+            if self.random() < self.faults.disconnect {
+                warn!("Chaos: dropping the connection");
+                if let Some(peripheral) = &self.peripheral {
+                    peripheral.disconnect().await.ok();
+                }
+                return Err(Failure::DeviceNotFound(
+                    "lights, chaos dropped the connection",
+                ));
+            }
+            if self.random() < self.faults.latency_spike {
+                let delay = self.faults.max_latency.mul_f32(self.random());
+                warn!("Chaos: stalling a write for {}ms", delay.as_millis());
+                time::sleep(delay).await;
+            }
+            if self.random() < self.faults.write_failure {
+                return Err(Failure::DeviceNotFound("lights, chaos failed a write"));
+            }
+            self.inner.write(chunk).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{self, MockTransport};
+
+    fn chaos(faults: Faults) -> (Arc<MockTransport>, ChaosTransport) {
+        let mock = Arc::new(MockTransport::default());
+        let chaos = ChaosTransport::new(mock.clone(), None, faults);
+        (mock, chaos)
+    }
+
+    #[tokio::test]
+    async fn writes_pass_through_without_faults() {
+        let (mock, chaos) = chaos(Faults {
+            write_failure: 0.0,
+            latency_spike: 0.0,
+            max_latency: Duration::ZERO,
+            disconnect: 0.0,
+        });
+        transport::send(&chaos, vec![1, 2, 3]).await.unwrap();
+        assert_eq!(*mock.written.lock().unwrap(), vec![vec![1, 2, 3]]);
+    }
+
+    #[tokio::test]
+    async fn failed_writes_never_reach_the_light() {
+        let (mock, chaos) = chaos(Faults {
+            write_failure: 1.0,
+            latency_spike: 0.0,
+            ..Faults::default()
+        });
+        assert!(transport::send(&chaos, vec![1, 2, 3]).await.is_err());
+        assert!(mock.written.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn disconnects_fail_the_write() {
+        let (mock, chaos) = chaos(Faults {
+            disconnect: 1.0,
+            write_failure: 0.0,
+            latency_spike: 0.0,
+            ..Faults::default()
+        });
+        assert!(transport::send(&chaos, vec![1]).await.is_err());
+        assert!(mock.written.lock().unwrap().is_empty());
+    }
+}
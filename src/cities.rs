@@ -0,0 +1,47 @@
+// Offline fallback for geocode, for when the geocoder cannot be reached
+const CITIES: &[(&str, &str, f64, f64)] = &[
+    ("Amsterdam", "NL", 52.3676, 4.9041),
+    ("Athens", "GR", 37.9838, 23.7275),
+    ("Berlin", "DE", 52.5200, 13.4050),
+    ("Bratislava", "SK", 48.1486, 17.1077),
+    ("Brussels", "BE", 50.8503, 4.3517),
+    ("Bucharest", "RO", 44.4268, 26.1025),
+    ("Budapest", "HU", 47.4979, 19.0402),
+    ("Copenhagen", "DK", 55.6761, 12.5683),
+    ("Debrecen", "HU", 47.5316, 21.6273),
+    ("Dublin", "IE", 53.3498, -6.2603),
+    ("Helsinki", "FI", 60.1699, 24.9384),
+    ("Lisbon", "PT", 38.7223, -9.1393),
+    ("Ljubljana", "SI", 46.0569, 14.5058),
+    ("London", "GB", 51.5072, -0.1276),
+    ("Madrid", "ES", 40.4168, -3.7038),
+    ("Miskolc", "HU", 48.1035, 20.7784),
+    ("New York", "US", 40.7128, -74.0060),
+    ("Oslo", "NO", 59.9139, 10.7522),
+    ("Paris", "FR", 48.8566, 2.3522),
+    ("Pécs", "HU", 46.0727, 18.2323),
+    ("Prague", "CZ", 50.0755, 14.4378),
+    ("Reykjavik", "IS", 64.1466, -21.9426),
+    ("Rome", "IT", 41.9028, 12.4964),
+    ("Stockholm", "SE", 59.3293, 18.0686),
+    ("Szeged", "HU", 46.2530, 20.1414),
+    ("Tromsø", "NO", 69.6492, 18.9553),
+    ("Vienna", "AT", 48.2082, 16.3738),
+    ("Warsaw", "PL", 52.2297, 21.0122),
+    ("Zagreb", "HR", 45.8150, 15.9819),
+    ("Zurich", "CH", 47.3769, 8.5417),
+];
+
+pub fn lookup(location: &str) -> Option<(f64, f64)> {
+    let mut parts = location.split(',').map(str::trim);
+    let city = parts.next()?;
+    let country = parts.next();
+
+    CITIES
+        .iter()
+        .find(|(name, code, _, _)| {
+            name.eq_ignore_ascii_case(city)
+                && country.is_none_or(|country| code.eq_ignore_ascii_case(country))
+        })
+        .map(|&(_, _, latitude, longitude)| (latitude, longitude))
+}
@@ -0,0 +1,813 @@
+use christmas_lights::{color, effects::EffectKind, photo, Failure};
+use chrono::{Duration, NaiveDate, Utc};
+use log::LevelFilter;
+use std::path::PathBuf;
+
+// Longest range `schedule preview` prints, so a typo in the year does not print for minutes
+const MAX_PREVIEW_DAYS: i64 = 366;
+const DEFAULT_SOAK_HOURS: f32 = 24.0;
+const DEFAULT_PHOTO_COLORS: usize = 5;
+
+pub const USAGE: &str = "\
+Usage: christmas-lights [--device ADDRESS|PATTERN] [--takeover] [COMMAND]
+
+Options:
+  --device ADDRESS|PATTERN
+                 Drive only the light with this address, or the lights whose
+                 name matches this regex, instead of the configured ones;
+                 for logs, show only the lines that mention it
+  --takeover     If another instance drives the same lights, ask it to let go
+                 of them and exit instead of giving up
+
+Commands:
+  run [EFFECT] [--brightness LEVEL]
+                 Run the sunset-to-sunrise animation (default), optionally
+                 overriding the configured effect and brightness (0 to 1)
+  scan           List nearby BLE devices, lights first
+  devices        Show each configured light's health as the running daemon last
+                 saved it: last connect and write, firmware and signal strength
+  on             Turn the lights on
+  off            Turn the lights off
+  color <HEX>    Show a solid color, e.g. ff0000 or \"#ff0000\"
+  undo           Have the running daemon go back to the color, effect or
+                 brightness from before the last change; again for the one
+                 before that
+  calibrate      Step through test colors with the [calibration] settings, for
+                 tuning gamma and per-channel gains by eye
+  schedule preview [--from YYYY-MM-DD] [--to YYYY-MM-DD]
+                 Print the on/off times and effect for each day, without
+                 touching the lights; defaults to the next 30 days
+  logs [--level error|warn|info]
+                 Show the running daemon's recent log lines and follow new
+                 ones: connections, commands and schedule decisions, down to
+                 the given level (info by default)
+  guest [--minutes MINUTES]
+                 Print a link and a QR code to the guest page, which offers the
+                 scenes in http.guest_scenes until the pass runs out after
+                 http.guest_minutes or MINUTES
+  history commands
+                 List the commands that changed the lights, when and from where:
+                 the command line, an HTTP client, MQTT or the schedule
+  replay-decisions [--date YYYY-MM-DD]
+                 Work out a day's decisions again from the recorded forecast and
+                 command log, and print them with why; defaults to today
+  scene list     List the configured and saved scenes
+  scene apply <NAME>
+                 Run the animation showing a scene
+  scene save <NAME> [EFFECT] [--color HEX] [--brightness LEVEL] [--speed FACTOR]
+                 Save a scene for later runs, starting from the configured
+                 effect and its color
+  palette from-photo <FILE> [--name NAME] [--colors COUNT]
+                 Print a palette and a gradient of the photo's main colors
+                 (5 by default) for the config; needs ImageMagick
+  soak [--hours HOURS]
+                 Run the daemon for a while (24 hours by default) watching for
+                 stalls, missed switches and growing memory, then report; with
+                 --chaos it also rides out injected faults
+  home-assistant Print Home Assistant YAML for the configured MQTT and HTTP
+                 settings, for setups without MQTT discovery
+  schema         Print the JSON Schema of the state published on
+                 <topic_prefix>/state over MQTT
+  update [--install | --rollback]
+                 Check the release feed for a newer version, optionally
+                 installing it, or put back the binary the last install replaced
+  backup <FILE>  Save the config, cached light addresses, daily totals and saved
+                 scenes to a tar archive
+  restore <FILE> Put the files from a backup back in place, keeping the
+                 replaced ones with a .bak suffix
+  version        Print the version
+  help           Print this message
+
+Effects:
+  rainbow, gradient, hold, breathing, candy_cane, twinkle, solid, strobe,
+  temperature, audio, palette";
+
+pub struct Invocation {
+    pub command: Command,
+    pub device: Option<String>,
+    // Hidden: injects BLE faults for soak runs
+    pub chaos: bool,
+    pub takeover: bool,
+}
+
+pub enum Command {
+    Run {
+        effect: Option<EffectKind>,
+        brightness: Option<f32>,
+    },
+    Scan,
+    On,
+    Off,
+    Color((u8, u8, u8)),
+    Calibrate,
+    SchedulePreview {
+        from: NaiveDate,
+        to: NaiveDate,
+    },
+    HistoryCommands,
+    ReplayDecisions {
+        date: NaiveDate,
+    },
+    Logs {
+        level: LevelFilter,
+    },
+    Guest {
+        minutes: Option<u64>,
+    },
+    Devices,
+    Undo,
+    Scene(SceneAction),
+    PaletteFromPhoto {
+        photo: PathBuf,
+        name: String,
+        colors: usize,
+    },
+    Soak {
+        hours: f32,
+    },
+    HomeAssistant,
+    Schema,
+    Update(UpdateAction),
+    Backup(PathBuf),
+    Restore(PathBuf),
+    Version,
+    Help,
+}
+
+pub enum SceneAction {
+    List,
+    Apply(String),
+    // Anything left out comes from the config
+    Save {
+        name: String,
+        effect: Option<EffectKind>,
+        color: Option<(u8, u8, u8)>,
+        brightness: Option<f32>,
+        speed: Option<f32>,
+    },
+}
+
+pub enum UpdateAction {
+    Check,
+    Install,
+    Rollback,
+}
+
+pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Invocation, Failure> {
+    let mut device = None;
+    let mut chaos = false;
+    let mut takeover = false;
+    let mut rest = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--device" {
+            device = Some(
+                args.next()
+                    .ok_or_else(|| usage("--device needs an address or name pattern"))?,
+            );
+        } else if arg == "--chaos" {
+            chaos = true;
+        } else if arg == "--takeover" {
+            takeover = true;
+        } else {
+            rest.push(arg);
+        }
+    }
+    let command = parse_command(rest.into_iter())?;
+    Ok(Invocation {
+        command,
+        device,
+        chaos,
+        takeover,
+    })
+}
+
+fn parse_command(mut args: impl Iterator<Item = String>) -> Result<Command, Failure> {
+    let command = match args.next().as_deref() {
+        None => Command::Run {
+            effect: None,
+            brightness: None,
+        },
+        Some("run") => parse_run(&mut args)?,
+        Some("scan") => Command::Scan,
+        Some("devices") => Command::Devices,
+        Some("on") => Command::On,
+        Some("off") => Command::Off,
+        Some("undo") => Command::Undo,
+        Some("color") => {
+            let hex = args
+                .next()
+                .ok_or_else(|| usage("color needs a hex value"))?;
+            let rgb = color::parse_hex(&hex)
+                .ok_or_else(|| usage(&format!("{:?} is not a hex color like ff0000", hex)))?;
+            Command::Color(rgb)
+        }
+        Some("calibrate") => Command::Calibrate,
+        Some("schedule") => match args.next().as_deref() {
+            Some("preview") => parse_preview(&mut args)?,
+            _ => return Err(usage("schedule needs a subcommand: preview")),
+        },
+        Some("history") => match args.next().as_deref() {
+            Some("commands") => Command::HistoryCommands,
+            _ => return Err(usage("history needs a subcommand: commands")),
+        },
+        Some("replay-decisions") => {
+            let date = match args.next().as_deref() {
+                None => Utc::now().date_naive(),
+                Some("--date") => {
+                    let date = args.next().ok_or_else(|| usage("--date needs a date"))?;
+                    NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+                        .map_err(|_| usage(&format!("{:?} is not a date like 2024-12-20", date)))?
+                }
+                Some(arg) => return Err(usage(&format!("unexpected argument {:?}", arg))),
+            };
+            Command::ReplayDecisions { date }
+        }
+        Some("logs") => {
+            let level = match args.next().as_deref() {
+                None => LevelFilter::Info,
+                Some("--level") => {
+                    let level = args.next().ok_or_else(|| usage("--level needs a level"))?;
+                    match level.as_str() {
+                        "error" => LevelFilter::Error,
+                        "warn" => LevelFilter::Warn,
+                        "info" => LevelFilter::Info,
+                        _ => {
+                            return Err(usage(&format!(
+                                "{:?} is not one of error, warn and info",
+                                level
+                            )))
+                        }
+                    }
+                }
+                Some(other) => return Err(usage(&format!("unexpected argument {:?}", other))),
+            };
+            Command::Logs { level }
+        }
+        Some("guest") => {
+            let minutes = match args.next().as_deref() {
+                None => None,
+                Some("--minutes") => {
+                    let minutes = args
+                        .next()
+                        .ok_or_else(|| usage("--minutes needs a number"))?;
+                    Some(
+                        minutes
+                            .parse::<u64>()
+                            .ok()
+                            .filter(|minutes| *minutes > 0)
+                            .ok_or_else(|| {
+                                usage(&format!("{:?} is not a number of minutes", minutes))
+                            })?,
+                    )
+                }
+                Some(other) => return Err(usage(&format!("unexpected argument {:?}", other))),
+            };
+            Command::Guest { minutes }
+        }
+        Some("scene") => Command::Scene(match args.next().as_deref() {
+            Some("list") => SceneAction::List,
+            Some("apply") => SceneAction::Apply(
+                args.next()
+                    .ok_or_else(|| usage("scene apply needs a name"))?,
+            ),
+            Some("save") => parse_scene_save(&mut args)?,
+            _ => return Err(usage("scene needs a subcommand: list, apply or save")),
+        }),
+        Some("palette") => match args.next().as_deref() {
+            Some("from-photo") => parse_palette_from_photo(&mut args)?,
+            _ => return Err(usage("palette needs a subcommand: from-photo")),
+        },
+        Some("soak") => {
+            let hours = match args.next().as_deref() {
+                None => DEFAULT_SOAK_HOURS,
+                Some("--hours") => {
+                    let hours = args.next().ok_or_else(|| usage("--hours needs a number"))?;
+                    hours
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|hours| *hours > 0.0)
+                        .ok_or_else(|| usage(&format!("{:?} is not a number of hours", hours)))?
+                }
+                Some(other) => return Err(usage(&format!("unexpected argument {:?}", other))),
+            };
+            Command::Soak { hours }
+        }
+        Some("home-assistant") => Command::HomeAssistant,
+        Some("schema") => Command::Schema,
+        Some("update") => Command::Update(match args.next().as_deref() {
+            None => UpdateAction::Check,
+            Some("--install") => UpdateAction::Install,
+            Some("--rollback") => UpdateAction::Rollback,
+            Some(other) => return Err(usage(&format!("unexpected argument {:?}", other))),
+        }),
+        Some("backup") => Command::Backup(
+            args.next()
+                .ok_or_else(|| usage("backup needs a file to write"))?
+                .into(),
+        ),
+        Some("restore") => Command::Restore(
+            args.next()
+                .ok_or_else(|| usage("restore needs a backup file"))?
+                .into(),
+        ),
+        Some("version" | "--version") => Command::Version,
+        Some("help" | "-h" | "--help") => Command::Help,
+        Some(other) => return Err(usage(&format!("unknown command {:?}", other))),
+    };
+    if let Some(extra) = args.next() {
+        return Err(usage(&format!("unexpected argument {:?}", extra)));
+    }
+    Ok(command)
+}
+
+fn parse_run(args: &mut impl Iterator<Item = String>) -> Result<Command, Failure> {
+    let mut effect = None;
+    let mut brightness = None;
+    while let Some(arg) = args.next() {
+        if arg == "--brightness" {
+            let level = args
+                .next()
+                .ok_or_else(|| usage("--brightness needs a level"))?;
+            brightness = Some(
+                level
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|level| (0.0..=1.0).contains(level))
+                    .ok_or_else(|| {
+                        usage(&format!("{:?} is not a brightness between 0 and 1", level))
+                    })?,
+            );
+        } else if effect.is_none() {
+            effect = Some(
+                EffectKind::from_name(&arg)
+                    .ok_or_else(|| usage(&format!("{:?} is not a known effect", arg)))?,
+            );
+        } else {
+            return Err(usage(&format!("unexpected argument {:?}", arg)));
+        }
+    }
+    Ok(Command::Run { effect, brightness })
+}
+
+fn parse_palette_from_photo(args: &mut impl Iterator<Item = String>) -> Result<Command, Failure> {
+    let photo = args
+        .next()
+        .ok_or_else(|| usage("palette from-photo needs a photo"))?
+        .into();
+    let (mut name, mut colors) = ("photo".to_string(), DEFAULT_PHOTO_COLORS);
+    while let Some(arg) = args.next() {
+        let mut value = |what: &str| {
+            args.next()
+                .ok_or_else(|| usage(&format!("{} needs {}", arg, what)))
+        };
+        match arg.as_str() {
+            "--name" => name = value("a name")?,
+            "--colors" => {
+                let count = value("a number")?;
+                colors = count
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|count| (1..=photo::MAX_COLORS).contains(count))
+                    .ok_or_else(|| {
+                        usage(&format!(
+                            "{:?} is not a number of colors from 1 to {}",
+                            count,
+                            photo::MAX_COLORS
+                        ))
+                    })?;
+            }
+            _ => return Err(usage(&format!("unexpected argument {:?}", arg))),
+        }
+    }
+    Ok(Command::PaletteFromPhoto {
+        photo,
+        name,
+        colors,
+    })
+}
+
+fn parse_scene_save(args: &mut impl Iterator<Item = String>) -> Result<SceneAction, Failure> {
+    let name = args
+        .next()
+        .ok_or_else(|| usage("scene save needs a name"))?;
+    let (mut effect, mut color, mut brightness, mut speed) = (None, None, None, None);
+    while let Some(arg) = args.next() {
+        let mut value = |what: &str| {
+            args.next()
+                .ok_or_else(|| usage(&format!("{} needs {}", arg, what)))
+        };
+        match arg.as_str() {
+            "--color" => {
+                let hex = value("a hex value")?;
+                color =
+                    Some(color::parse_hex(&hex).ok_or_else(|| {
+                        usage(&format!("{:?} is not a hex color like ff0000", hex))
+                    })?);
+            }
+            "--brightness" => {
+                let level = value("a level")?;
+                brightness = Some(
+                    level
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|level| (0.0..=1.0).contains(level))
+                        .ok_or_else(|| {
+                            usage(&format!("{:?} is not a brightness between 0 and 1", level))
+                        })?,
+                );
+            }
+            "--speed" => {
+                let factor = value("a factor")?;
+                speed = Some(
+                    factor
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|factor| *factor > 0.0)
+                        .ok_or_else(|| usage(&format!("{:?} is not a positive speed", factor)))?,
+                );
+            }
+            _ if effect.is_none() => {
+                effect = Some(
+                    EffectKind::from_name(&arg)
+                        .ok_or_else(|| usage(&format!("{:?} is not a known effect", arg)))?,
+                );
+            }
+            _ => return Err(usage(&format!("unexpected argument {:?}", arg))),
+        }
+    }
+    Ok(SceneAction::Save {
+        name,
+        effect,
+        color,
+        brightness,
+        speed,
+    })
+}
+
+fn parse_preview(args: &mut impl Iterator<Item = String>) -> Result<Command, Failure> {
+    let mut from = Utc::now().date_naive();
+    let mut to = None;
+    while let Some(flag) = args.next() {
+        let date = match flag.as_str() {
+            "--from" | "--to" => args
+                .next()
+                .ok_or_else(|| usage(&format!("{} needs a date", flag)))?,
+            _ => return Err(usage(&format!("unexpected argument {:?}", flag))),
+        };
+        let date = NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+            .map_err(|_| usage(&format!("{:?} is not a date like 2024-12-01", date)))?;
+        if flag == "--from" {
+            from = date;
+        } else {
+            to = Some(date);
+        }
+    }
+    let to = to.unwrap_or(from + Duration::days(30));
+    if to < from || (to - from).num_days() >= MAX_PREVIEW_DAYS {
+        return Err(usage(&format!(
+            "the preview must end after it starts and cover at most {} days",
+            MAX_PREVIEW_DAYS
+        )));
+    }
+    Ok(Command::SchedulePreview { from, to })
+}
+
+fn usage(reason: &str) -> Failure {
+    Failure::Usage(format!("{}\n\n{}", reason, USAGE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invocation(args: &[&str]) -> Result<Invocation, Failure> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn parse_args(args: &[&str]) -> Result<Command, Failure> {
+        invocation(args).map(|invocation| invocation.command)
+    }
+
+    #[test]
+    fn run_is_the_default() {
+        for args in [&[][..], &["run"]] {
+            assert!(matches!(
+                parse_args(args),
+                Ok(Command::Run {
+                    effect: None,
+                    brightness: None
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn run_takes_an_effect() {
+        assert!(matches!(
+            parse_args(&["run", "candy_cane"]),
+            Ok(Command::Run {
+                effect: Some(EffectKind::CandyCane),
+                brightness: None
+            })
+        ));
+        assert!(matches!(
+            parse_args(&["run", "disco"]),
+            Err(Failure::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn run_brightness_is_between_0_and_1() {
+        let brightness = |args: &[&str]| match parse_args(args) {
+            Ok(Command::Run { brightness, .. }) => Ok(brightness),
+            Ok(_) => panic!("{:?} is not a run", args),
+            Err(e) => Err(e),
+        };
+        assert_eq!(
+            brightness(&["run", "--brightness", "0"]).unwrap(),
+            Some(0.0)
+        );
+        assert_eq!(
+            brightness(&["run", "candy_cane", "--brightness", "1"]).unwrap(),
+            Some(1.0)
+        );
+        assert_eq!(
+            brightness(&["run", "--brightness", "0.4", "candy_cane"]).unwrap(),
+            Some(0.4)
+        );
+        for level in ["-0.1", "1.01", "half", "NaN"] {
+            assert!(matches!(
+                brightness(&["run", "--brightness", level]),
+                Err(Failure::Usage(_))
+            ));
+        }
+        assert!(matches!(
+            brightness(&["run", "--brightness"]),
+            Err(Failure::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn the_device_flag_goes_anywhere() {
+        let parsed = invocation(&["--device", "A4:C1:38:12:34:56", "on"]).unwrap();
+        assert_eq!(parsed.device.as_deref(), Some("A4:C1:38:12:34:56"));
+        assert!(matches!(parsed.command, Command::On));
+
+        let parsed = invocation(&["run", "candy_cane", "--device", "^Light-0[12]$"]).unwrap();
+        assert_eq!(parsed.device.as_deref(), Some("^Light-0[12]$"));
+        assert!(matches!(
+            parsed.command,
+            Command::Run {
+                effect: Some(EffectKind::CandyCane),
+                ..
+            }
+        ));
+
+        assert_eq!(invocation(&["off"]).unwrap().device, None);
+        assert!(matches!(
+            invocation(&["on", "--device"]),
+            Err(Failure::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn the_takeover_flag_goes_anywhere() {
+        assert!(!invocation(&["run"]).unwrap().takeover);
+        for args in [
+            &["--takeover", "run"][..],
+            &["run", "--takeover"],
+            &["--takeover"],
+        ] {
+            let parsed = invocation(args).unwrap();
+            assert!(parsed.takeover, "{:?}", args);
+            assert!(matches!(parsed.command, Command::Run { effect: None, .. }));
+        }
+        let parsed = invocation(&["on", "--takeover", "--device", "Tree"]).unwrap();
+        assert!(parsed.takeover);
+        assert_eq!(parsed.device.as_deref(), Some("Tree"));
+    }
+
+    #[test]
+    fn commands_without_arguments_parse() {
+        assert!(matches!(parse_args(&["scan"]), Ok(Command::Scan)));
+        assert!(matches!(parse_args(&["on"]), Ok(Command::On)));
+        assert!(matches!(parse_args(&["off"]), Ok(Command::Off)));
+        assert!(matches!(parse_args(&["undo"]), Ok(Command::Undo)));
+        assert!(matches!(parse_args(&["--help"]), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn color_takes_a_hex_value() {
+        assert!(matches!(
+            parse_args(&["color", "#ff8000"]),
+            Ok(Command::Color((255, 128, 0)))
+        ));
+        assert!(matches!(
+            parse_args(&["color", "purple"]),
+            Err(Failure::Usage(_))
+        ));
+        assert!(matches!(parse_args(&["color"]), Err(Failure::Usage(_))));
+    }
+
+    #[test]
+    fn logs_take_a_level() {
+        assert!(matches!(
+            parse_args(&["logs"]),
+            Ok(Command::Logs {
+                level: LevelFilter::Info
+            })
+        ));
+        let parsed = invocation(&["logs", "--level", "warn", "--device", "Tree"]).unwrap();
+        assert_eq!(parsed.device.as_deref(), Some("Tree"));
+        assert!(matches!(
+            parsed.command,
+            Command::Logs {
+                level: LevelFilter::Warn
+            }
+        ));
+        for args in [&["logs", "--level", "debug"][..], &["logs", "--level"]] {
+            assert!(matches!(parse_args(args), Err(Failure::Usage(_))));
+        }
+    }
+
+    #[test]
+    fn guest_takes_minutes() {
+        assert!(matches!(
+            parse_args(&["guest"]),
+            Ok(Command::Guest { minutes: None })
+        ));
+        assert!(matches!(
+            parse_args(&["guest", "--minutes", "90"]),
+            Ok(Command::Guest { minutes: Some(90) })
+        ));
+        for minutes in ["0", "-5", "soon"] {
+            assert!(matches!(
+                parse_args(&["guest", "--minutes", minutes]),
+                Err(Failure::Usage(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn replay_decisions_takes_a_date() {
+        assert!(matches!(
+            parse_args(&["replay-decisions", "--date", "2024-12-20"]),
+            Ok(Command::ReplayDecisions { date: replayed }) if replayed == date(2024, 12, 20)
+        ));
+        for args in [
+            &["replay-decisions", "--date", "20.12.2024"][..],
+            &["replay-decisions", "--date"],
+            &["replay-decisions", "yesterday"],
+        ] {
+            assert!(matches!(parse_args(args), Err(Failure::Usage(_))));
+        }
+    }
+
+    #[test]
+    fn unknown_commands_and_extra_arguments_are_rejected() {
+        assert!(matches!(parse_args(&["blink"]), Err(Failure::Usage(_))));
+        assert!(matches!(parse_args(&["on", "now"]), Err(Failure::Usage(_))));
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn preview(args: &[&str]) -> Result<(NaiveDate, NaiveDate), Failure> {
+        let args: Vec<&str> = ["schedule", "preview"]
+            .iter()
+            .chain(args)
+            .copied()
+            .collect();
+        match parse_args(&args)? {
+            Command::SchedulePreview { from, to } => Ok((from, to)),
+            _ => panic!("{:?} is not a preview", args),
+        }
+    }
+
+    #[test]
+    fn the_preview_covers_the_next_30_days() {
+        let (from, to) = preview(&["--from", "2024-12-01"]).unwrap();
+        assert_eq!((from, to), (date(2024, 12, 1), date(2024, 12, 31)));
+
+        let (from, to) = preview(&[]).unwrap();
+        assert_eq!(from, Utc::now().date_naive());
+        assert_eq!((to - from).num_days(), 30);
+    }
+
+    #[test]
+    fn the_preview_range_is_checked() {
+        assert_eq!(
+            preview(&["--from", "2024-01-01", "--to", "2024-12-31"]).unwrap(),
+            (date(2024, 1, 1), date(2024, 12, 31))
+        );
+        assert_eq!(
+            preview(&["--from", "2024-12-01", "--to", "2024-12-01"]).unwrap(),
+            (date(2024, 12, 1), date(2024, 12, 1))
+        );
+        for args in [
+            &["--from", "2024-12-02", "--to", "2024-12-01"][..],
+            &["--from", "2024-01-01", "--to", "2025-01-01"],
+            &["--from", "2024-13-01"],
+            &["--from"],
+            &["--until", "2024-12-01"],
+        ] {
+            assert!(
+                matches!(preview(args), Err(Failure::Usage(_))),
+                "{:?} was accepted",
+                args
+            );
+        }
+        assert!(matches!(parse_args(&["schedule"]), Err(Failure::Usage(_))));
+    }
+
+    #[test]
+    fn scenes_are_saved_with_their_flags() {
+        let Ok(Command::Scene(SceneAction::Save {
+            name,
+            effect,
+            color,
+            brightness,
+            speed,
+        })) = parse_args(&[
+            "scene",
+            "save",
+            "cosy",
+            "candy_cane",
+            "--color",
+            "#ff8000",
+            "--brightness",
+            "0.5",
+            "--speed",
+            "2",
+        ])
+        else {
+            panic!("not a scene save");
+        };
+        assert_eq!(name, "cosy");
+        assert!(matches!(effect, Some(EffectKind::CandyCane)));
+        assert_eq!(color, Some((255, 128, 0)));
+        assert_eq!(brightness, Some(0.5));
+        assert_eq!(speed, Some(2.0));
+
+        assert!(matches!(
+            parse_args(&["scene", "save", "plain"]),
+            Ok(Command::Scene(SceneAction::Save {
+                effect: None,
+                color: None,
+                brightness: None,
+                speed: None,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn scene_flags_are_checked() {
+        for args in [
+            &["scene", "save"][..],
+            &["scene", "save", "cosy", "--color", "purple"],
+            &["scene", "save", "cosy", "--brightness", "1.5"],
+            &["scene", "save", "cosy", "--speed", "0"],
+            &["scene", "save", "cosy", "--speed", "-1"],
+            &["scene", "save", "cosy", "--speed"],
+            &["scene", "save", "cosy", "disco"],
+            &["scene", "save", "cosy", "candy_cane", "rainbow"],
+            &["scene", "apply"],
+            &["scene"],
+        ] {
+            assert!(
+                matches!(parse_args(args), Err(Failure::Usage(_))),
+                "{:?} was accepted",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn photos_take_a_name_and_a_number_of_colors() {
+        let Ok(Command::PaletteFromPhoto {
+            photo,
+            name,
+            colors,
+        }) = parse_args(&["palette", "from-photo", "card.jpg", "--colors", "3"])
+        else {
+            panic!("not a palette from a photo");
+        };
+        assert_eq!(photo, PathBuf::from("card.jpg"));
+        assert_eq!(name, "photo");
+        assert_eq!(colors, 3);
+        for args in [
+            &["palette", "from-photo"][..],
+            &["palette", "from-photo", "card.jpg", "--colors", "0"],
+            &["palette", "from-photo", "card.jpg", "--colors", "13"],
+            &["palette", "from-photo", "card.jpg", "--name"],
+        ] {
+            assert!(
+                matches!(parse_args(args), Err(Failure::Usage(_))),
+                "{:?} was accepted",
+                args
+            );
+        }
+    }
+}
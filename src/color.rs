@@ -0,0 +1,351 @@
+use prisma::Rgb;
+use std::time::Duration;
+
+pub type GradientStop = (f32, (u8, u8, u8));
+
+pub const NEUTRAL_WHITE_POINT: (f32, f32, f32) = (1.0, 1.0, 1.0);
+
+// What an effect looks like unless overridden: overall brightness and per-channel gains
+#[derive(Clone, Copy, Debug)]
+pub struct EffectDefaults {
+    pub brightness: f32,
+    pub white_point: (f32, f32, f32),
+}
+
+impl EffectDefaults {
+    pub fn is_valid(&self) -> bool {
+        let (r, g, b) = self.white_point;
+        [self.brightness, r, g, b]
+            .iter()
+            .all(|value| (0.0..=1.0).contains(value))
+    }
+}
+
+// How the strip's LEDs respond, applied as each frame becomes bytes: every channel is raised to
+// gamma, as the LEDs' output is linear where frames are perceptual, then scaled by its gain
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calibration {
+    pub gamma: f32,
+    pub gains: (f32, f32, f32),
+}
+
+impl Calibration {
+    pub const NONE: Calibration = Calibration {
+        gamma: 1.0,
+        gains: NEUTRAL_WHITE_POINT,
+    };
+
+    pub fn is_valid(&self) -> bool {
+        let (r, g, b) = self.gains;
+        (0.1..=5.0).contains(&self.gamma) && [r, g, b].iter().all(|gain| (0.0..=1.0).contains(gain))
+    }
+}
+
+// The hue range may wrap around 360, e.g. (330.0, 30.0) for reds only
+pub fn rainbow_hue(phase: f32, (start, end): (f32, f32)) -> f32 {
+    let mut span = (end - start).rem_euclid(360.0);
+    if span == 0.0 {
+        span = 360.0;
+    }
+    (start + phase * span).rem_euclid(360.0)
+}
+
+// When wrapping, the gradient blends from the last stop back into the first
+pub fn gradient_color(stops: &[GradientStop], position: f32, wrap: bool) -> Rgb<f32> {
+    let (first, last) = (stops[0], stops[stops.len() - 1]);
+    let segment = stops
+        .windows(2)
+        .find(|pair| pair[0].0 <= position && position <= pair[1].0);
+    let (from, to, t) = match segment {
+        Some(pair) => {
+            let span = pair[1].0 - pair[0].0;
+            let t = if span > 0.0 {
+                (position - pair[0].0) / span
+            } else {
+                0.0
+            };
+            (pair[0].1, pair[1].1, t)
+        }
+        None if wrap => {
+            let span = 1.0 - last.0 + first.0;
+            let t = if span > 0.0 {
+                (position - last.0).rem_euclid(1.0) / span
+            } else {
+                0.0
+            };
+            (last.1, first.1, t)
+        }
+        None if position < first.0 => (first.1, first.1, 0.0),
+        None => (last.1, last.1, 0.0),
+    };
+
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t) / 255.0;
+    Rgb::new(lerp(from.0, to.0), lerp(from.1, to.1), lerp(from.2, to.2))
+}
+
+// Holds each color for `hold`, then fades to the next one over `fade`
+pub fn hold_color(
+    palette: &[(u8, u8, u8)],
+    elapsed: Duration,
+    hold: Duration,
+    fade: Duration,
+) -> Rgb<f32> {
+    let period = (hold + fade).as_secs_f32();
+    let periods = elapsed.as_secs_f32() / period;
+    let index = periods as usize % palette.len();
+    let faded = (periods.fract() * period - hold.as_secs_f32()).max(0.0);
+    let t = faded / fade.as_secs_f32().max(f32::EPSILON);
+
+    let (from, to) = (palette[index], palette[(index + 1) % palette.len()]);
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t.min(1.0)) / 255.0;
+    Rgb::new(lerp(from.0, to.0), lerp(from.1, to.1), lerp(from.2, to.2))
+}
+
+// The index `roll` (between 0 and 1) lands on when each entry takes up its share of the
+// weights, passing over `current` so a palette never picks the color it already shows
+pub fn weighted_pick(weights: &[f32], roll: f32, current: Option<usize>) -> usize {
+    let skipped = |i: usize| weights.len() > 1 && Some(i) == current;
+    let total: f32 = (0..weights.len())
+        .filter(|&i| !skipped(i))
+        .map(|i| weights[i])
+        .sum();
+    let mut left = roll * total;
+    let mut picked = 0;
+    for (i, weight) in weights.iter().enumerate().filter(|&(i, _)| !skipped(i)) {
+        picked = i;
+        if left < *weight {
+            break;
+        }
+        left -= weight;
+    }
+    picked
+}
+
+pub fn scale_rgb(rgb: Rgb<f32>, value: f32) -> Rgb<f32> {
+    Rgb::new(rgb.red() * value, rgb.green() * value, rgb.blue() * value)
+}
+
+pub fn rgb_f32_to_u8_capped(rgb: Rgb<f32>, calibration: Calibration) -> (u8, u8, u8) {
+    let channel = |value: f32, gain: f32| {
+        (value.clamp(0.0, 1.0).powf(calibration.gamma) * gain * 255.0).round() as u8
+    };
+    let (r, g, b) = calibration.gains;
+    (
+        channel(rgb.red(), r),
+        channel(rgb.green(), g),
+        channel(rgb.blue(), b),
+    )
+}
+
+// Accepts "#rrggbb" or "rrggbb"
+pub fn parse_hex(hex: &str) -> Option<(u8, u8, u8)> {
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+pub fn apply_white_point((r, g, b): (u8, u8, u8), (wr, wg, wb): (f32, f32, f32)) -> (u8, u8, u8) {
+    (
+        (r as f32 * wr) as u8,
+        (g as f32 * wg) as u8,
+        (b as f32 * wb) as u8,
+    )
+}
+
+// Moves the desaturated part of the color onto the white LED
+pub fn rgb_to_rgbw((r, g, b): (u8, u8, u8)) -> (u8, u8, u8, u8) {
+    let w = r.min(g).min(b);
+    (r - w, g - w, b - w, w)
+}
+
+pub fn estimated_watts((r, g, b): (u8, u8, u8), watts_full_white: f32) -> f32 {
+    watts_full_white * (r as f32 + g as f32 + b as f32) / (3.0 * 255.0)
+}
+
+// Caps output right after the lights turn on, ramping linearly from initial_brightness to
+// full over ramp, so cheap controllers do not brown out on a cold start at full white
+pub fn soft_start(
+    (r, g, b): (u8, u8, u8),
+    since_on: Duration,
+    ramp: Duration,
+    initial_brightness: f32,
+) -> (u8, u8, u8) {
+    if since_on >= ramp {
+        return (r, g, b);
+    }
+    let progress = since_on.as_secs_f32() / ramp.as_secs_f32();
+    let scale = initial_brightness + (1.0 - initial_brightness) * progress;
+    (
+        (r as f32 * scale) as u8,
+        (g as f32 * scale) as u8,
+        (b as f32 * scale) as u8,
+    )
+}
+
+pub fn limit_to_power_budget(
+    rgb: (u8, u8, u8),
+    watts_full_white: f32,
+    budget_watts: Option<f32>,
+) -> (u8, u8, u8) {
+    limit_group_to_power_budget(&[rgb], &[watts_full_white], budget_watts)[0]
+}
+
+// Scales every light by the same factor so that together they stay within the budget, rather
+// than splitting it evenly and leaving the share of dim lights unused
+pub fn limit_group_to_power_budget(
+    colors: &[(u8, u8, u8)],
+    watts_full_white: &[f32],
+    budget_watts: Option<f32>,
+) -> Vec<(u8, u8, u8)> {
+    let Some(budget) = budget_watts else {
+        return colors.to_vec();
+    };
+    let requested: f32 = colors
+        .iter()
+        .zip(watts_full_white)
+        .map(|(rgb, watts)| estimated_watts(*rgb, *watts))
+        .sum();
+    if requested <= budget {
+        return colors.to_vec();
+    }
+
+    let scale = budget / requested;
+    colors
+        .iter()
+        .map(|(r, g, b)| {
+            (
+                (*r as f32 * scale) as u8,
+                (*g as f32 * scale) as u8,
+                (*b as f32 * scale) as u8,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use angular_units::Deg;
+    use prisma::{FromColor, Hsv};
+
+    #[test]
+    fn rgb_to_rgbw_moves_the_shared_part_onto_white() {
+        assert_eq!(rgb_to_rgbw((255, 255, 255)), (0, 0, 0, 255));
+        assert_eq!(rgb_to_rgbw((255, 128, 64)), (191, 64, 0, 64));
+        assert_eq!(rgb_to_rgbw((0, 200, 100)), (0, 200, 100, 0));
+        assert_eq!(rgb_to_rgbw((0, 0, 0)), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn estimated_watts_scales_with_the_channel_sum() {
+        assert_eq!(estimated_watts((255, 255, 255), 6.0), 6.0);
+        assert_eq!(estimated_watts((255, 0, 0), 6.0), 2.0);
+        assert_eq!(estimated_watts((0, 0, 0), 6.0), 0.0);
+    }
+
+    #[test]
+    fn colors_within_the_budget_are_left_alone() {
+        assert_eq!(
+            limit_to_power_budget((255, 0, 0), 6.0, Some(2.0)),
+            (255, 0, 0)
+        );
+        assert_eq!(
+            limit_to_power_budget((255, 255, 255), 6.0, None),
+            (255, 255, 255)
+        );
+    }
+
+    #[test]
+    fn colors_over_the_budget_are_scaled_down_to_it() {
+        let limited = limit_to_power_budget((255, 255, 255), 6.0, Some(3.0));
+
+        assert_eq!(limited, (127, 127, 127));
+        assert!(estimated_watts(limited, 6.0) <= 3.0);
+    }
+
+    #[test]
+    fn a_group_over_the_budget_is_scaled_down_together() {
+        // 6 W + 2 W + 0 W against a 4 W budget halves every light
+        let limited = limit_group_to_power_budget(
+            &[(255, 255, 255), (255, 0, 0), (0, 0, 0)],
+            &[6.0, 6.0, 6.0],
+            Some(4.0),
+        );
+
+        assert_eq!(limited, vec![(127, 127, 127), (127, 0, 0), (0, 0, 0)]);
+    }
+
+    #[test]
+    fn each_light_counts_with_its_own_wattage() {
+        let colors = [(255, 255, 255), (255, 255, 255)];
+
+        assert_eq!(
+            limit_group_to_power_budget(&colors, &[6.0, 12.0], Some(18.0)),
+            colors.to_vec()
+        );
+        assert_eq!(
+            limit_group_to_power_budget(&colors, &[6.0, 12.0], Some(9.0)),
+            vec![(127, 127, 127), (127, 127, 127)]
+        );
+    }
+
+    #[test]
+    fn picks_follow_the_weights() {
+        let weights = [1.0, 2.0, 1.0];
+        assert_eq!(weighted_pick(&weights, 0.0, None), 0);
+        assert_eq!(weighted_pick(&weights, 0.3, None), 1);
+        assert_eq!(weighted_pick(&weights, 0.74, None), 1);
+        assert_eq!(weighted_pick(&weights, 0.8, None), 2);
+        assert_eq!(weighted_pick(&weights, 1.0, None), 2);
+    }
+
+    #[test]
+    fn the_current_color_is_not_picked_again() {
+        let weights = [1.0, 2.0, 1.0];
+        assert_eq!(weighted_pick(&weights, 0.0, Some(0)), 1);
+        assert_eq!(weighted_pick(&weights, 0.7, Some(1)), 2);
+        // Unless there is nothing else
+        assert_eq!(weighted_pick(&[1.0], 0.5, Some(0)), 0);
+    }
+
+    #[test]
+    fn channels_are_capped_when_converting_to_bytes() {
+        assert_eq!(
+            rgb_f32_to_u8_capped(Rgb::new(1.5, -0.2, 0.5), Calibration::NONE),
+            (255, 0, 128)
+        );
+        assert_eq!(
+            rgb_f32_to_u8_capped(scale_rgb(Rgb::new(1.0, 0.5, 0.0), 4.0), Calibration::NONE),
+            (255, 255, 0)
+        );
+    }
+
+    #[test]
+    fn calibration_applies_gamma_then_gains() {
+        let calibration = Calibration {
+            gamma: 2.0,
+            gains: (1.0, 0.5, 1.0),
+        };
+        assert_eq!(
+            rgb_f32_to_u8_capped(Rgb::new(0.5, 1.0, 0.0), calibration),
+            (64, 128, 0)
+        );
+        assert!(calibration.is_valid());
+        assert!(!Calibration {
+            gains: (1.0, 1.5, 1.0),
+            ..calibration
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn full_value_hsv_red_is_full_red() {
+        let red: Rgb<f32> = Rgb::from_color(&Hsv::new(Deg(0.0f32), 1.0, 1.0));
+        assert_eq!(rgb_f32_to_u8_capped(red, Calibration::NONE), (255, 0, 0));
+        let dimmed: Rgb<f32> = Rgb::from_color(&Hsv::new(Deg(0.0f32), 1.0, 0.5));
+        assert_eq!(rgb_f32_to_u8_capped(dimmed, Calibration::NONE), (128, 0, 0));
+    }
+}
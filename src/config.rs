@@ -0,0 +1,3085 @@
+mod migrate;
+mod toml;
+
+use crate::{
+    color::{self, Calibration, EffectDefaults, GradientStop, NEUTRAL_WHITE_POINT},
+    effects::EffectKind,
+    error::Failure,
+    geocode, observances,
+    protocol::ProtocolKind,
+    remote::RemoteCommand,
+    scenes,
+    schedule::{DimmingCurve, Trigger},
+    storage::StorageBackend,
+    template::Template,
+    themes,
+    timezone::Zone,
+};
+use btleplug::api::bleuuid::uuid_from_u16;
+use chrono::NaiveDate;
+use log::{info, warn};
+use regex::Regex;
+use std::{
+    env,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use toml::{Table, Value};
+use uuid::Uuid;
+
+type Palette = Vec<(u8, u8, u8)>;
+
+pub const CONFIG_PATH_ENV: &str = "CHRISTMAS_LIGHTS_CONFIG";
+
+// Larger offsets would push the on or off time into the neighbouring night
+const MAX_SUN_OFFSET_MINUTES: i64 = 6 * 60;
+
+const THEME_KEYS: &[&str] = &["from", "to", "effect", "colors"];
+const SCENE_KEYS: &[&str] = &["effect", "color", "brightness", "speed"];
+const PALETTE_KEYS: &[&str] = &["colors"];
+const RULE_KEYS: &[&str] = &[
+    "on",
+    "value",
+    "between",
+    "lights",
+    "effects",
+    "during_override",
+    "debounce_ms",
+    "cooldown_seconds",
+    "power",
+    "color",
+    "effect",
+    "scene",
+    "palette",
+    "brightness",
+    "for_seconds",
+];
+// Where the rules file sits, next to the config file
+pub const RULES_FILE: &str = "rules.toml";
+
+const KNOWN_KEYS: &[(&str, &[&str])] = &[
+    ("location", &["latitude", "longitude", "name", "geocoder"]),
+    (
+        "device",
+        &[
+            "name_pattern",
+            "addresses",
+            "protocol",
+            "characteristic_uuid",
+            "white_channel_opcode",
+            "adapter",
+            "max_writes_per_second",
+            "count",
+            "phase_offset_ms",
+            "best_effort",
+        ],
+    ),
+    (
+        "schedule",
+        &[
+            "fallback_sunrise",
+            "fallback_sunset",
+            "daily_plan_time",
+            "startup_stay_off",
+            "on_offset_minutes",
+            "off_offset_minutes",
+            "on_time",
+            "off_time",
+            "timezone",
+        ],
+    ),
+    ("brightness", &["level", "curve"]),
+    ("calibration", &["gamma", "gains"]),
+    (
+        "transitions",
+        &["fade_in_ms", "fade_out_ms", "color_change_ms"],
+    ),
+    (
+        "animation",
+        &[
+            "effect",
+            "cycle_time_ms",
+            "hue_degrees_per_second",
+            "speed",
+            "reverse",
+            "ping_pong",
+        ],
+    ),
+    (
+        "rainbow",
+        &["saturation", "hue_range", "brightness", "white_point"],
+    ),
+    ("gradient", &["stops", "brightness", "white_point"]),
+    (
+        "hold",
+        &[
+            "palette",
+            "hold_minutes",
+            "fade_seconds",
+            "brightness",
+            "white_point",
+        ],
+    ),
+    (
+        "breathing",
+        &[
+            "color",
+            "period_ms",
+            "min_brightness",
+            "brightness",
+            "white_point",
+        ],
+    ),
+    (
+        "candy_cane",
+        &["colors", "step_ms", "fade_ms", "brightness", "white_point"],
+    ),
+    (
+        "twinkle",
+        &[
+            "color",
+            "twinkles_per_second",
+            "decay_ms",
+            "palette_sparkles",
+            "brightness",
+            "white_point",
+        ],
+    ),
+    ("solid", &["color", "brightness", "white_point"]),
+    (
+        "strobe",
+        &["color", "frequency_hz", "duty", "brightness", "white_point"],
+    ),
+    (
+        "temperature",
+        &[
+            "cold_celsius",
+            "warm_celsius",
+            "cold_hue",
+            "warm_hue",
+            "smoothing_minutes",
+            "brightness",
+            "white_point",
+        ],
+    ),
+    (
+        "audio",
+        &[
+            "device",
+            "sensitivity",
+            "smoothing_ms",
+            "min_brightness",
+            "brightness",
+            "white_point",
+        ],
+    ),
+    (
+        "palette",
+        &[
+            "active",
+            "dwell_seconds",
+            "fade_seconds",
+            "brightness",
+            "white_point",
+        ],
+    ),
+    // Holds one table per palette, checked against PALETTE_KEYS
+    ("palettes", &[]),
+    ("santa", &["enabled", "scene_time", "scene_minutes"]),
+    ("advent", &["enabled", "reveal_seconds"]),
+    ("observances", &["categories"]),
+    // Holds one table per theme, checked against THEME_KEYS
+    ("themes", &[]),
+    // Holds one table per scene, checked against SCENE_KEYS
+    ("scenes", &[]),
+    (
+        "welcome",
+        &["people", "color", "duration_secs", "cooldown_minutes"],
+    ),
+    ("wake", &["sunrise_minutes", "stay_minutes"]),
+    ("idle", &["when"]),
+    (
+        "power",
+        &[
+            "device_watts_full_white",
+            "light_watts_full_white",
+            "budget_watts",
+            "soft_start_ms",
+            "soft_start_brightness",
+        ],
+    ),
+    ("battery", &["enabled", "cycle_slowdown", "brightness"]),
+    ("thermal", &["limit_celsius", "cycle_slowdown"]),
+    ("scan", &["interval_secs", "window_ms"]),
+    (
+        "mqtt",
+        &[
+            "broker",
+            "client_id",
+            "username",
+            "password",
+            "name",
+            "topic_prefix",
+            "discovery_prefix",
+            "keep_alive_secs",
+            "temperature_topic",
+            "alarm_topic",
+        ],
+    ),
+    (
+        "http",
+        &["listen", "token", "tokens", "guest_scenes", "guest_minutes"],
+    ),
+    ("status_page", &["listen", "requests_per_minute"]),
+    ("notifications", &["url", "daily_summary", "weekly_report"]),
+    ("e131", &["universe", "channel", "listen", "multicast"]),
+    ("mpris", &["enabled", "drift_seconds"]),
+    ("gpio", &["pins", "poll_ms"]),
+    ("shutdown", &["turn_off"]),
+    ("resume", &["enabled", "save_interval_minutes"]),
+    ("storage", &["backend", "path"]),
+    (
+        "update",
+        &["enabled", "feed", "check_interval_hours", "install"],
+    ),
+    (
+        "supervisor",
+        &["max_failures", "window_minutes", "cooldown_minutes"],
+    ),
+    ("devices", &["shed_after_failed_writes", "cooldown_minutes"]),
+    (
+        "connection",
+        &[
+            "reconnect_after_failed_writes",
+            "reconnect_backoff_max_secs",
+            "vendor_app_grace_period_secs",
+            "startup_timeout_secs",
+            "startup_concurrency",
+            "startup_stagger_ms",
+        ],
+    ),
+];
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub location: (f64, f64),
+    pub device: DeviceConfig,
+    pub schedule: ScheduleConfig,
+    pub brightness: BrightnessConfig,
+    pub calibration: Calibration,
+    pub transitions: TransitionsConfig,
+    pub effect: EffectKind,
+    pub animation: AnimationConfig,
+    pub rainbow: RainbowConfig,
+    pub gradient: GradientConfig,
+    pub hold: HoldConfig,
+    pub breathing: BreathingConfig,
+    pub candy_cane: CandyCaneConfig,
+    pub twinkle: TwinkleConfig,
+    pub solid: SolidConfig,
+    pub strobe: StrobeConfig,
+    pub temperature: TemperatureConfig,
+    pub audio: AudioConfig,
+    pub palette: PaletteConfig,
+    pub palettes: Vec<NamedPalette>,
+    pub santa: SantaConfig,
+    pub advent: AdventConfig,
+    pub observances: ObservancesConfig,
+    pub themes: Vec<ThemeConfig>,
+    pub scenes: Vec<SceneConfig>,
+    pub welcome: WelcomeConfig,
+    pub wake: WakeConfig,
+    pub idle: IdleConfig,
+    pub power: PowerConfig,
+    pub battery: BatteryConfig,
+    pub thermal: ThermalConfig,
+    pub scan: ScanConfig,
+    pub mqtt: Option<MqttConfig>,
+    pub http: Option<HttpConfig>,
+    pub status_page: Option<StatusPageConfig>,
+    pub notifications: Option<NotificationsConfig>,
+    pub e131: Option<E131Config>,
+    pub mpris: Option<MprisConfig>,
+    pub gpio: Option<GpioConfig>,
+    pub shutdown: ShutdownConfig,
+    pub resume: ResumeConfig,
+    pub storage: StorageConfig,
+    pub supervisor: SupervisorConfig,
+    pub devices: DevicesConfig,
+    pub update: UpdateConfig,
+    pub connection: ConnectionConfig,
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceConfig {
+    // Regex searched for in the advertised name; plain text matches anywhere in it
+    pub name_pattern: Regex,
+    // Pins the lights by address, e.g. "A4:C1:38:12:34:56"; overrides name_pattern when set
+    pub addresses: Vec<String>,
+    pub protocol: ProtocolKind,
+    // Overrides the protocol's command characteristic
+    pub characteristic_uuid: Option<Uuid>,
+    // Opcode of the dedicated warm-white channel on Actuel controllers that have one
+    pub white_channel_opcode: Option<u8>,
+    // Adapter name or address to scan on, e.g. "hci1"; the first adapter when unset
+    pub adapter: Option<String>,
+    // For lights that lag behind when written every frame; frames in between are blended
+    pub max_writes_per_second: Option<f32>,
+    // How many matching lights to drive together
+    pub count: usize,
+    // Each light runs this far ahead of the previous one, zero keeps them in sync
+    pub phase_offset: Duration,
+    // Addresses of decorative lights, shed first when the adapter struggles; the rest are
+    // critical
+    pub best_effort: Vec<String>,
+}
+
+impl DeviceConfig {
+    /// Whether a peripheral with this address and advertised name is one of the lights.
+    pub fn selects(&self, address: &str, name: Option<&str>) -> bool {
+        if !self.addresses.is_empty() {
+            return self
+                .addresses
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(address));
+        }
+        name.is_some_and(|name| self.name_pattern.is_match(name))
+    }
+
+    /// Whether the light at this address is critical rather than best-effort.
+    pub fn is_critical(&self, address: &str) -> bool {
+        !self
+            .best_effort
+            .iter()
+            .any(|decorative| decorative.eq_ignore_ascii_case(address))
+    }
+
+    /// Narrows the selection to a single address, or to a name pattern, as given on the
+    /// command line.
+    pub fn pin(&mut self, device: &str) -> Result<(), Failure> {
+        match parse_address(device) {
+            Ok(address) => self.addresses = vec![address],
+            Err(_) => {
+                self.addresses.clear();
+                self.name_pattern = name_pattern(device)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn name_pattern(pattern: &str) -> Result<Regex, Failure> {
+    Regex::new(pattern).map_err(|e| invalid(format!("device name pattern: {}", e)))
+}
+
+#[derive(Clone, Debug)]
+pub struct ScheduleConfig {
+    // Used on days when the sun never rises or never sets at the location
+    pub fallback_sunrise_utc: (u32, u32),
+    pub fallback_sunset_utc: (u32, u32),
+    // In `timezone`, like the fixed on and off times and the dimming curve
+    pub daily_plan_time: (u32, u32),
+    pub timezone: Zone,
+    // Keeps the lights off when starting during the evening, until the next sunset
+    pub startup_stay_off: bool,
+    pub on: Trigger,
+    pub off: Trigger,
+}
+
+// Applies to every frame, on top of the effect's own brightness
+#[derive(Clone, Debug)]
+pub struct BrightnessConfig {
+    pub level: f32,
+    pub curve: DimmingCurve,
+}
+
+// Fades instead of snapping, zero disables each one
+#[derive(Clone, Debug)]
+pub struct TransitionsConfig {
+    // When the lights switch on, and off again
+    pub fade_in: Duration,
+    pub fade_out: Duration,
+    // When a remote command or the CLI changes the color or effect
+    pub color_change: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct AnimationConfig {
+    pub cycle_time: Duration,
+    pub hue_degrees_per_second: f32,
+    // Runs every effect faster or slower, 1 for as configured
+    pub speed: f32,
+    pub reverse: bool,
+    pub ping_pong: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct RainbowConfig {
+    pub saturation: f32,
+    // Start and end hue in degrees, may wrap around 360 (e.g. [330, 30] for reds only)
+    pub hue_range: (f32, f32),
+    pub defaults: EffectDefaults,
+}
+
+// The default effect when stops are given and no effect is chosen
+#[derive(Clone, Debug)]
+pub struct GradientConfig {
+    pub stops: Option<Vec<GradientStop>>,
+    pub defaults: EffectDefaults,
+}
+
+// The default effect when a palette is given and no effect is chosen
+#[derive(Clone, Debug)]
+pub struct HoldConfig {
+    pub palette: Option<Vec<(u8, u8, u8)>>,
+    pub hold: Duration,
+    pub fade: Duration,
+    pub defaults: EffectDefaults,
+}
+
+#[derive(Clone, Debug)]
+pub struct BreathingConfig {
+    pub color: (u8, u8, u8),
+    // One full dim-bright-dim cycle
+    pub period: Duration,
+    pub min_brightness: f32,
+    pub defaults: EffectDefaults,
+}
+
+// Steps through the colors, so more than red and white also works
+#[derive(Clone, Debug)]
+pub struct CandyCaneConfig {
+    pub colors: Vec<(u8, u8, u8)>,
+    pub step: Duration,
+    pub fade: Duration,
+    pub defaults: EffectDefaults,
+}
+
+#[derive(Clone, Debug)]
+pub struct TwinkleConfig {
+    pub color: (u8, u8, u8),
+    // Average number of sparkles, each fading back to the base color over decay
+    pub twinkles_per_second: f32,
+    pub decay: Duration,
+    // Sparkles take a color from the active palette, by weight, instead of flashing white
+    pub palette_sparkles: bool,
+    pub defaults: EffectDefaults,
+}
+
+#[derive(Clone, Debug)]
+pub struct SolidConfig {
+    pub color: (u8, u8, u8),
+    pub defaults: EffectDefaults,
+}
+
+#[derive(Clone, Debug)]
+pub struct StrobeConfig {
+    pub color: (u8, u8, u8),
+    pub frequency_hz: f32,
+    // Fraction of each flash period the color is on
+    pub duty: f32,
+    pub defaults: EffectDefaults,
+}
+
+// Colors the lights by the outdoor temperature, reported over MQTT or the HTTP API
+#[derive(Clone, Debug)]
+pub struct TemperatureConfig {
+    pub cold_celsius: f32,
+    pub warm_celsius: f32,
+    // Hues in degrees; the color sweeps between them, e.g. from blue through green to amber
+    pub cold_hue: f32,
+    pub warm_hue: f32,
+    pub smoothing: Duration,
+    pub defaults: EffectDefaults,
+}
+
+// Pulses with music picked up by a microphone; capturing needs the audio build feature
+#[derive(Clone, Debug)]
+pub struct AudioConfig {
+    // Input device name; the system default when unset
+    pub device: Option<String>,
+    // Gain on top of the automatic level, above 1 for quiet rooms
+    pub sensitivity: f32,
+    // How long a pulse takes to fall back after a beat
+    pub smoothing: Duration,
+    // Between beats the lights stay at least this bright
+    pub min_brightness: f32,
+    pub defaults: EffectDefaults,
+}
+
+// Draws each light's colors at random from one of [palettes]
+#[derive(Clone, Debug)]
+pub struct PaletteConfig {
+    // The palette to draw from, switched at runtime with POST /palette
+    pub active: Option<String>,
+    // How long a color holds at least, unless it has its own dwell time
+    pub dwell: Duration,
+    pub fade: Duration,
+    pub defaults: EffectDefaults,
+}
+
+// Colors for the palette effect, e.g. [palettes.traditional]
+#[derive(Clone, Debug, PartialEq)]
+pub struct NamedPalette {
+    pub name: String,
+    pub colors: Vec<PaletteColor>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PaletteColor {
+    pub color: (u8, u8, u8),
+    // Relative to the palette's other colors, 1 by default
+    pub weight: f32,
+    // palette.dwell when unset
+    pub dwell: Option<Duration>,
+}
+
+// Tracks Santa on Christmas Eve in place of the configured effect
+#[derive(Clone, Debug)]
+pub struct SantaConfig {
+    pub enabled: bool,
+    // UTC time of the scene that marks Santa's visit
+    pub scene_time: (u32, u32),
+    pub scene_length: Duration,
+}
+
+// Plays a short scene, colored from the date, the first time the lights come on each day from
+// December 1st to 24th
+#[derive(Clone, Debug)]
+pub struct AdventConfig {
+    pub enabled: bool,
+    pub reveal_length: Duration,
+}
+
+// Shows the color of awareness days in these categories instead of the configured effect
+#[derive(Clone, Debug)]
+pub struct ObservancesConfig {
+    pub categories: Vec<String>,
+}
+
+// The effect and colors for a stretch of the calendar, e.g. [themes.halloween]
+#[derive(Clone, Debug)]
+pub struct ThemeConfig {
+    pub name: String,
+    // Month and day, both inclusive; a range that ends before it starts runs over New Year
+    pub from: (u32, u32),
+    pub to: (u32, u32),
+    pub effect: EffectKind,
+    // Stand in for the effect's own colors, as a palette, gradient or single color
+    pub colors: Option<Vec<(u8, u8, u8)>>,
+}
+
+// A named look to switch to by hand, e.g. [scenes.cozy]; more are saved at runtime
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneConfig {
+    pub name: String,
+    pub effect: EffectKind,
+    // Stands in for the effect's own color, where it has one
+    pub color: Option<(u8, u8, u8)>,
+    // 0 to 1, on top of brightness.level
+    pub brightness: f32,
+    // As animation.speed
+    pub speed: f32,
+}
+
+// A short bright scene when someone comes home while the lights are on; arrivals are reported
+// through POST /arrived or the MQTT arrived topic
+#[derive(Clone, Debug)]
+pub struct WelcomeConfig {
+    // Names presence detection reports, anyone else is ignored
+    pub people: Vec<String>,
+    pub color: (u8, u8, u8),
+    pub duration: Duration,
+    // Per person, so coming and going does not replay the scene all evening
+    pub cooldown: Duration,
+}
+
+// A sunrise on the lights ending at the next alarm from mqtt.alarm_topic, see wake.rs
+#[derive(Clone, Debug)]
+pub struct WakeConfig {
+    // How long the lights take from dark to full warm white
+    pub sunrise: Duration,
+    // How long they stay lit after the alarm
+    pub stay: Duration,
+}
+
+// Stops rendering while nobody benefits from the show, see idle.rs
+#[derive(Clone, Debug)]
+pub struct IdleConfig {
+    // <trigger>/<event> and the value that means nobody is around, e.g. "mqtt/lights_wanted"
+    // and "off", sorted by event
+    pub when: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PowerConfig {
+    // Estimated draw of one light string showing full white
+    pub device_watts_full_white: f32,
+    // Per light, in address order, for installations mixing strings of different lengths;
+    // lights past the end of the list use device_watts_full_white
+    pub light_watts_full_white: Vec<f32>,
+    // For all the lights together
+    pub budget_watts: Option<f32>,
+    // Ramp up from soft_start_brightness after each power-on, zero disables it
+    pub soft_start: Duration,
+    pub soft_start_brightness: f32,
+}
+
+// Dims and slows the animation while UPower reports the host running on battery
+#[derive(Clone, Debug)]
+pub struct BatteryConfig {
+    pub enabled: bool,
+    pub cycle_slowdown: u32,
+    pub brightness: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct ThermalConfig {
+    pub limit_celsius: f32,
+    pub cycle_slowdown: u32,
+}
+
+// Short scan windows while connected, for hot-plug discovery; zero disables them
+#[derive(Clone, Debug)]
+pub struct ScanConfig {
+    pub interval: Duration,
+    pub window: Duration,
+}
+
+// Home Assistant integration, enabled by setting a broker
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    // host:port
+    pub broker: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Entity name shown in Home Assistant
+    pub name: String,
+    pub topic_prefix: String,
+    pub discovery_prefix: String,
+    pub keep_alive: Duration,
+    // A sensor topic carrying the outdoor temperature in °C, for the temperature effect
+    pub temperature_topic: Option<String>,
+    // Home Assistant's next_alarm sensor state, an RFC 3339 time, for the wake-up sunrise
+    pub alarm_topic: Option<String>,
+}
+
+// REST API for remote control, enabled by setting an address to listen on
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    // e.g. "0.0.0.0:8080"
+    pub listen: String,
+    // Sent by clients as Authorization: Bearer <token>; without one anyone on the LAN may drive
+    // the lights
+    pub token: Option<String>,
+    // Further tokens by name, e.g. one per phone, so the command log says whose sent a command
+    pub tokens: Vec<(String, String)>,
+    // The scenes the guest page offers; no guest passes without any
+    pub guest_scenes: Vec<String>,
+    // How long a guest pass lasts unless `guest --minutes` says otherwise
+    pub guest_duration: Duration,
+}
+
+// Read-only status page for dashboards, on its own address so control stays off it
+#[derive(Clone, Debug)]
+pub struct StatusPageConfig {
+    pub listen: String,
+    // Per client address
+    pub requests_per_minute: u32,
+}
+
+// Messages POSTed as plain text to a push service or webhook, see notifications.rs
+#[derive(Clone, Debug)]
+pub struct NotificationsConfig {
+    pub url: String,
+    // The evening's plan when the lights first come on each day
+    pub daily_summary: bool,
+    // Reconnects, downtime and signal strength per light, and time on against the schedule
+    pub weekly_report: bool,
+}
+
+// E1.31 (sACN) input from sequencers such as xLights and Vixen, enabled by setting a universe
+#[derive(Clone, Debug)]
+pub struct E131Config {
+    pub universe: u16,
+    // DMX channel of the first light's red, counting from 1
+    pub channel: u16,
+    // Local address to receive on
+    pub listen: String,
+    // Joins the universe's multicast group, as well as taking unicast packets
+    pub multicast: bool,
+}
+
+// A gradient from the album art of the media playing on the desktop, see mpris.rs
+#[derive(Clone, Debug)]
+pub struct MprisConfig {
+    // How long the colors take to go once round the lights
+    pub drift: Duration,
+}
+
+// Buttons and sensors on the host's GPIO pins as rule triggers, enabled by naming a pin
+#[derive(Clone, Debug)]
+pub struct GpioConfig {
+    // Event name and sysfs GPIO number, sorted by name
+    pub pins: Vec<(String, u32)>,
+    pub poll: Duration,
+}
+
+// One rule from the rules file, e.g. [doorbell]: the event it waits for, the conditions it
+// needs and the command it sends
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleConfig {
+    pub name: String,
+    // <trigger>/<event>, e.g. "gpio/doorbell"; a trigger alone matches all its events
+    pub on: String,
+    // Only events carrying this value, e.g. "1" for a pressed button
+    pub value: Option<String>,
+    // Start and end, both inclusive; may run over midnight
+    pub between: Option<(TimeOfDay, TimeOfDay)>,
+    // Only while the lights are on, or only while they are off
+    pub lights: Option<bool>,
+    // Only while one of these effects shows; any effect when empty
+    pub effects: Vec<EffectKind>,
+    // Whether the rule may act while a temporary override runs, e.g. one another rule started
+    pub during_override: bool,
+    // Events coming again within this long of the last one are bounces and ignored
+    pub debounce: Duration,
+    // How long the rule waits after acting before it acts again
+    pub cooldown: Duration,
+    pub action: RuleAction,
+    // How long the action lasts, from for_seconds; for good when None
+    pub duration: Option<Duration>,
+}
+
+// What a rule does: a command as written, or a color or brightness worked out from the event
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuleAction {
+    Command(RemoteCommand),
+    Color(Template),
+    Brightness(Template),
+}
+
+// One end of a rule's time window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeOfDay {
+    // In schedule.timezone
+    At((u32, u32)),
+    // Minutes after the day's sunrise or sunset, negative for before
+    Sunrise(i64),
+    Sunset(i64),
+}
+
+#[derive(Clone, Debug)]
+pub struct ShutdownConfig {
+    // Whether stopping the daemon also turns the lights off, rather than leaving the last color
+    pub turn_off: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct ResumeConfig {
+    // Whether a restart picks the show up where the last run left off
+    pub enabled: bool,
+    // How often the animation's position is saved; changes to the show are saved right away
+    pub save_interval: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    // The directory for files, or the database; in the cache directory when unset
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub struct UpdateConfig {
+    // Checking for releases is opt-in
+    pub enabled: bool,
+    // GitHub-style "latest release" JSON
+    pub feed: String,
+    pub check_interval: Duration,
+    // Also download and swap in new releases, not just report them
+    pub install: bool,
+}
+
+// Failure budget for optional subsystems such as MQTT and the HTTP API
+#[derive(Clone, Debug)]
+pub struct SupervisorConfig {
+    // Failures within window that disable the subsystem for cooldown
+    pub max_failures: usize,
+    pub window: Duration,
+    pub cooldown: Duration,
+}
+
+// When best-effort lights are shed to leave the adapter to the critical ones
+#[derive(Clone, Debug)]
+pub struct DevicesConfig {
+    // Consecutive failed writes to one light before it, or for a critical light every
+    // best-effort one, is shed
+    pub shed_after_failed_writes: u32,
+    pub cooldown: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectionConfig {
+    pub reconnect_after_failed_writes: u32,
+    // Reconnect attempts after a drop back off exponentially up to this
+    pub reconnect_backoff_max: Duration,
+    // How long to back off when the light drops us, e.g. because the vendor app connected
+    pub vendor_app_grace_period: Duration,
+    // How long the daemon keeps looking for the lights at startup before giving up
+    pub startup_timeout: Duration,
+    // Lights connected at once, and the pause between starting each, so BlueZ is not asked to
+    // discover the services of every light together
+    pub startup_concurrency: usize,
+    pub startup_stagger: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            location: (47.552922, 19.254477),
+            device: DeviceConfig {
+                name_pattern: Regex::new("Light").expect("the default pattern is valid"),
+                addresses: Vec::new(),
+                protocol: ProtocolKind::Auto,
+                characteristic_uuid: None,
+                white_channel_opcode: None,
+                adapter: None,
+                max_writes_per_second: None,
+                count: 1,
+                phase_offset: Duration::ZERO,
+                best_effort: Vec::new(),
+            },
+            schedule: ScheduleConfig {
+                fallback_sunrise_utc: (7, 0),
+                fallback_sunset_utc: (15, 0),
+                daily_plan_time: (5, 0),
+                timezone: Zone::Utc,
+                startup_stay_off: false,
+                on: Trigger::Sun { offset_minutes: 0 },
+                off: Trigger::Sun { offset_minutes: 0 },
+            },
+            brightness: BrightnessConfig {
+                level: 1.0,
+                curve: DimmingCurve::default(),
+            },
+            calibration: Calibration::NONE,
+            transitions: TransitionsConfig {
+                fade_in: Duration::from_secs(5),
+                fade_out: Duration::from_secs(5),
+                color_change: Duration::from_secs(1),
+            },
+            effect: EffectKind::Rainbow,
+            animation: AnimationConfig {
+                cycle_time: Duration::from_millis(10),
+                hue_degrees_per_second: 30.0,
+                speed: 1.0,
+                reverse: false,
+                ping_pong: false,
+            },
+            rainbow: RainbowConfig {
+                saturation: 1.0,
+                hue_range: (0.0, 360.0),
+                defaults: EffectDefaults {
+                    brightness: 1.0,
+                    white_point: NEUTRAL_WHITE_POINT,
+                },
+            },
+            gradient: GradientConfig {
+                stops: None,
+                defaults: EffectDefaults {
+                    brightness: 1.0,
+                    white_point: NEUTRAL_WHITE_POINT,
+                },
+            },
+            // Warmer by default, as holding one color reads more like ambient lighting
+            hold: HoldConfig {
+                palette: None,
+                hold: Duration::from_secs(15 * 60),
+                fade: Duration::from_secs(60),
+                defaults: EffectDefaults {
+                    brightness: 0.8,
+                    white_point: (1.0, 0.9, 0.75),
+                },
+            },
+            breathing: BreathingConfig {
+                color: (255, 140, 40),
+                period: Duration::from_secs(6),
+                min_brightness: 0.1,
+                defaults: EffectDefaults {
+                    brightness: 1.0,
+                    white_point: NEUTRAL_WHITE_POINT,
+                },
+            },
+            candy_cane: CandyCaneConfig {
+                colors: vec![(255, 0, 0), (255, 255, 255)],
+                step: Duration::from_secs(1),
+                fade: Duration::from_millis(250),
+                defaults: EffectDefaults {
+                    brightness: 1.0,
+                    white_point: NEUTRAL_WHITE_POINT,
+                },
+            },
+            twinkle: TwinkleConfig {
+                color: (255, 140, 40),
+                twinkles_per_second: 1.5,
+                decay: Duration::from_millis(400),
+                palette_sparkles: false,
+                defaults: EffectDefaults {
+                    brightness: 0.8,
+                    white_point: NEUTRAL_WHITE_POINT,
+                },
+            },
+            solid: SolidConfig {
+                color: (255, 140, 40),
+                defaults: EffectDefaults {
+                    brightness: 0.8,
+                    white_point: NEUTRAL_WHITE_POINT,
+                },
+            },
+            strobe: StrobeConfig {
+                color: (255, 255, 255),
+                frequency_hz: 2.0,
+                duty: 0.1,
+                defaults: EffectDefaults {
+                    brightness: 1.0,
+                    white_point: NEUTRAL_WHITE_POINT,
+                },
+            },
+            temperature: TemperatureConfig {
+                cold_celsius: -5.0,
+                warm_celsius: 15.0,
+                cold_hue: 220.0,
+                warm_hue: 35.0,
+                smoothing: Duration::from_secs(30 * 60),
+                defaults: EffectDefaults {
+                    brightness: 1.0,
+                    white_point: NEUTRAL_WHITE_POINT,
+                },
+            },
+            audio: AudioConfig {
+                device: None,
+                sensitivity: 1.0,
+                smoothing: Duration::from_millis(150),
+                min_brightness: 0.1,
+                defaults: EffectDefaults {
+                    brightness: 1.0,
+                    white_point: NEUTRAL_WHITE_POINT,
+                },
+            },
+            palette: PaletteConfig {
+                active: None,
+                dwell: Duration::from_secs(30),
+                fade: Duration::from_secs(2),
+                defaults: EffectDefaults {
+                    brightness: 1.0,
+                    white_point: NEUTRAL_WHITE_POINT,
+                },
+            },
+            palettes: Vec::new(),
+            santa: SantaConfig {
+                enabled: false,
+                scene_time: (18, 0),
+                scene_length: Duration::from_secs(15 * 60),
+            },
+            advent: AdventConfig {
+                enabled: false,
+                reveal_length: Duration::from_secs(20),
+            },
+            themes: Vec::new(),
+            scenes: Vec::new(),
+            observances: ObservancesConfig {
+                categories: Vec::new(),
+            },
+            welcome: WelcomeConfig {
+                people: Vec::new(),
+                color: (255, 244, 229),
+                duration: Duration::from_secs(30),
+                cooldown: Duration::from_secs(60 * 60),
+            },
+            wake: WakeConfig {
+                sunrise: Duration::from_secs(30 * 60),
+                stay: Duration::from_secs(15 * 60),
+            },
+            idle: IdleConfig { when: Vec::new() },
+            power: PowerConfig {
+                device_watts_full_white: 6.0,
+                light_watts_full_white: Vec::new(),
+                budget_watts: None,
+                soft_start: Duration::ZERO,
+                soft_start_brightness: 0.25,
+            },
+            battery: BatteryConfig {
+                enabled: true,
+                cycle_slowdown: 5,
+                brightness: 0.4,
+            },
+            thermal: ThermalConfig {
+                limit_celsius: 75.0,
+                cycle_slowdown: 4,
+            },
+            scan: ScanConfig {
+                interval: Duration::from_secs(60),
+                window: Duration::from_secs(1),
+            },
+            mqtt: None,
+            http: None,
+            status_page: None,
+            notifications: None,
+            e131: None,
+            mpris: None,
+            gpio: None,
+            shutdown: ShutdownConfig { turn_off: true },
+            resume: ResumeConfig {
+                enabled: true,
+                save_interval: Duration::from_secs(5 * 60),
+            },
+            storage: StorageConfig {
+                backend: StorageBackend::File,
+                path: None,
+            },
+            supervisor: SupervisorConfig {
+                max_failures: 5,
+                window: Duration::from_secs(10 * 60),
+                cooldown: Duration::from_secs(30 * 60),
+            },
+            devices: DevicesConfig {
+                shed_after_failed_writes: 300,
+                cooldown: Duration::from_secs(10 * 60),
+            },
+            update: UpdateConfig {
+                enabled: false,
+                feed: "https://api.github.com/repos/Zoltan-Balazs/christmas-lights/releases/latest"
+                    .to_string(),
+                check_interval: Duration::from_secs(24 * 60 * 60),
+                install: false,
+            },
+            connection: ConnectionConfig {
+                reconnect_after_failed_writes: 100,
+                reconnect_backoff_max: Duration::from_secs(60),
+                vendor_app_grace_period: Duration::from_secs(5 * 60),
+                startup_timeout: Duration::from_secs(10 * 60),
+                startup_concurrency: 2,
+                startup_stagger: Duration::from_millis(500),
+            },
+        }
+    }
+}
+
+impl PowerConfig {
+    /// Estimated draw of the `light`th light, in address order, showing full white.
+    pub fn watts_full_white(&self, light: usize) -> f32 {
+        self.light_watts_full_white
+            .get(light)
+            .copied()
+            .unwrap_or(self.device_watts_full_white)
+    }
+}
+
+impl Config {
+    // Reads $CHRISTMAS_LIGHTS_CONFIG, or ~/.config/christmas-lights/config.toml if present
+    pub fn load() -> Result<Config, Failure> {
+        let path = path();
+        let explicit = env::var_os(CONFIG_PATH_ENV).is_some();
+
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                info!("Loading config from {}", path.display());
+                let contents = match migrate::upgrade(&contents) {
+                    Ok(Some(upgraded)) => {
+                        save_upgrade(&path, &contents, &upgraded);
+                        upgraded
+                    }
+                    Ok(None) => contents,
+                    Err(e) => return Err(prefix_path(e, &path.display().to_string())),
+                };
+                Config::from_toml(&contents)
+                    .map_err(|e| prefix_path(e, &path.display().to_string()))
+            }
+            Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => {
+                info!("No config at {}, using defaults", path.display());
+                let config = Config::default();
+                config.validate()?;
+                Ok(config)
+            }
+            Err(e) => Err(Failure::ConfigInvalid(format!(
+                "cannot read {}: {}",
+                path.display(),
+                e
+            ))),
+        }
+    }
+
+    // Older config versions are upgraded in memory first
+    pub fn from_toml(contents: &str) -> Result<Config, Failure> {
+        let upgraded = migrate::upgrade(contents)?;
+        let contents = upgraded.as_deref().unwrap_or(contents);
+        let document = toml::parse(contents).map_err(Failure::ConfigInvalid)?;
+        warn_unknown_keys(&document);
+
+        let defaults = Config::default();
+        let section = |name| Section::new(&document, name);
+
+        let location = section("location");
+        // An empty geocoder keeps to the bundled cities
+        let geocoder = location
+            .string("geocoder")?
+            .unwrap_or(geocode::DEFAULT_GEOCODER);
+        let location = match location.string("name")? {
+            Some(name) => geocode::locate(name, Some(geocoder).filter(|url| !url.is_empty()))
+                .ok_or_else(|| {
+                    Failure::ConfigInvalid(format!("location {:?} could not be found", name))
+                })?,
+            None => (
+                location.float("latitude", defaults.location.0)?,
+                location.float("longitude", defaults.location.1)?,
+            ),
+        };
+
+        let device = section("device");
+        let device = DeviceConfig {
+            name_pattern: match device.string("name_pattern")? {
+                Some(pattern) => name_pattern(pattern)?,
+                None => defaults.device.name_pattern,
+            },
+            addresses: device
+                .strings("addresses")?
+                .unwrap_or_default()
+                .iter()
+                .map(|address| parse_address(address))
+                .collect::<Result<_, _>>()?,
+            protocol: match device.string("protocol")? {
+                Some(name) => ProtocolKind::from_name(name).ok_or_else(|| {
+                    invalid(format!(
+                        "device.protocol must be auto, actuel, triones or magic_home, found {:?}",
+                        name
+                    ))
+                })?,
+                None => defaults.device.protocol,
+            },
+            characteristic_uuid: device
+                .string("characteristic_uuid")?
+                .map(parse_uuid)
+                .transpose()?,
+            white_channel_opcode: device
+                .optional_unsigned("white_channel_opcode")?
+                .map(|opcode| {
+                    u8::try_from(opcode).map_err(|_| {
+                        invalid("device white_channel_opcode must be between 0 and 255")
+                    })
+                })
+                .transpose()?,
+            adapter: device.string("adapter")?.map(str::to_string),
+            max_writes_per_second: device
+                .optional_float("max_writes_per_second")?
+                .map(|rate| rate as f32),
+            count: device.unsigned("count", defaults.device.count as u64)? as usize,
+            phase_offset: Duration::from_millis(device.unsigned(
+                "phase_offset_ms",
+                defaults.device.phase_offset.as_millis() as u64,
+            )?),
+            best_effort: device
+                .strings("best_effort")?
+                .unwrap_or_default()
+                .iter()
+                .map(|address| parse_address(address))
+                .collect::<Result<_, _>>()?,
+        };
+
+        let schedule = section("schedule");
+        let schedule = ScheduleConfig {
+            fallback_sunrise_utc: schedule
+                .time("fallback_sunrise", defaults.schedule.fallback_sunrise_utc)?,
+            fallback_sunset_utc: schedule
+                .time("fallback_sunset", defaults.schedule.fallback_sunset_utc)?,
+            daily_plan_time: schedule.time("daily_plan_time", defaults.schedule.daily_plan_time)?,
+            timezone: match schedule.string("timezone")? {
+                Some(name) => Zone::from_name(name)
+                    .map_err(|e| invalid(format!("schedule.timezone: {}", e)))?,
+                None => defaults.schedule.timezone,
+            },
+            startup_stay_off: schedule
+                .boolean("startup_stay_off", defaults.schedule.startup_stay_off)?,
+            on: schedule.trigger("on", defaults.schedule.on)?,
+            off: schedule.trigger("off", defaults.schedule.off)?,
+        };
+
+        let brightness = section("brightness");
+        let brightness = BrightnessConfig {
+            level: brightness.float("level", defaults.brightness.level as f64)? as f32,
+            curve: match brightness.dimming_curve("curve")? {
+                Some(curve) => curve,
+                None => defaults.brightness.curve,
+            },
+        };
+
+        let calibration = section("calibration");
+        let calibration = Calibration {
+            gamma: calibration.float("gamma", defaults.calibration.gamma as f64)? as f32,
+            gains: match calibration.floats("gains", 3)? {
+                Some(gains) => (gains[0], gains[1], gains[2]),
+                None => defaults.calibration.gains,
+            },
+        };
+
+        let transitions = section("transitions");
+        let transitions = TransitionsConfig {
+            fade_in: Duration::from_millis(transitions.unsigned(
+                "fade_in_ms",
+                defaults.transitions.fade_in.as_millis() as u64,
+            )?),
+            fade_out: Duration::from_millis(transitions.unsigned(
+                "fade_out_ms",
+                defaults.transitions.fade_out.as_millis() as u64,
+            )?),
+            color_change: Duration::from_millis(transitions.unsigned(
+                "color_change_ms",
+                defaults.transitions.color_change.as_millis() as u64,
+            )?),
+        };
+
+        let animation = section("animation");
+        let effect = match animation.string("effect")? {
+            Some(name) => EffectKind::from_name(name).ok_or_else(|| {
+                invalid(format!(
+                    "animation.effect {:?} is not one of {}",
+                    name,
+                    EffectKind::ALL.map(EffectKind::name).join(", ")
+                ))
+            })?,
+            None => defaults.effect,
+        };
+        let animation = AnimationConfig {
+            cycle_time: Duration::from_millis(animation.unsigned(
+                "cycle_time_ms",
+                defaults.animation.cycle_time.as_millis() as u64,
+            )?),
+            hue_degrees_per_second: animation.float(
+                "hue_degrees_per_second",
+                defaults.animation.hue_degrees_per_second as f64,
+            )? as f32,
+            speed: animation.float("speed", defaults.animation.speed as f64)? as f32,
+            reverse: animation.boolean("reverse", defaults.animation.reverse)?,
+            ping_pong: animation.boolean("ping_pong", defaults.animation.ping_pong)?,
+        };
+
+        let rainbow = section("rainbow");
+        let rainbow = RainbowConfig {
+            saturation: rainbow.float("saturation", defaults.rainbow.saturation as f64)? as f32,
+            hue_range: match rainbow.floats("hue_range", 2)? {
+                Some(range) => (range[0], range[1]),
+                None => defaults.rainbow.hue_range,
+            },
+            defaults: rainbow.effect_defaults(defaults.rainbow.defaults)?,
+        };
+
+        let gradient = section("gradient");
+        let gradient = GradientConfig {
+            stops: gradient.gradient_stops("stops")?,
+            defaults: gradient.effect_defaults(defaults.gradient.defaults)?,
+        };
+
+        let hold = section("hold");
+        let hold = HoldConfig {
+            palette: hold.colors("palette")?,
+            hold: Duration::from_secs(
+                60 * hold.unsigned("hold_minutes", defaults.hold.hold.as_secs() / 60)?,
+            ),
+            fade: Duration::from_secs(hold.unsigned("fade_seconds", defaults.hold.fade.as_secs())?),
+            defaults: hold.effect_defaults(defaults.hold.defaults)?,
+        };
+
+        let breathing = section("breathing");
+        let breathing = BreathingConfig {
+            color: breathing.color("color", defaults.breathing.color)?,
+            period: Duration::from_millis(
+                breathing.unsigned("period_ms", defaults.breathing.period.as_millis() as u64)?,
+            ),
+            min_brightness: breathing
+                .float("min_brightness", defaults.breathing.min_brightness as f64)?
+                as f32,
+            defaults: breathing.effect_defaults(defaults.breathing.defaults)?,
+        };
+
+        let candy_cane = section("candy_cane");
+        let candy_cane = CandyCaneConfig {
+            colors: candy_cane
+                .colors("colors")?
+                .unwrap_or(defaults.candy_cane.colors),
+            step: Duration::from_millis(
+                candy_cane.unsigned("step_ms", defaults.candy_cane.step.as_millis() as u64)?,
+            ),
+            fade: Duration::from_millis(
+                candy_cane.unsigned("fade_ms", defaults.candy_cane.fade.as_millis() as u64)?,
+            ),
+            defaults: candy_cane.effect_defaults(defaults.candy_cane.defaults)?,
+        };
+
+        let twinkle = section("twinkle");
+        let twinkle = TwinkleConfig {
+            color: twinkle.color("color", defaults.twinkle.color)?,
+            twinkles_per_second: twinkle.float(
+                "twinkles_per_second",
+                defaults.twinkle.twinkles_per_second as f64,
+            )? as f32,
+            decay: Duration::from_millis(
+                twinkle.unsigned("decay_ms", defaults.twinkle.decay.as_millis() as u64)?,
+            ),
+            palette_sparkles: twinkle
+                .boolean("palette_sparkles", defaults.twinkle.palette_sparkles)?,
+            defaults: twinkle.effect_defaults(defaults.twinkle.defaults)?,
+        };
+
+        let solid = section("solid");
+        let solid = SolidConfig {
+            color: solid.color("color", defaults.solid.color)?,
+            defaults: solid.effect_defaults(defaults.solid.defaults)?,
+        };
+
+        let strobe = section("strobe");
+        let strobe = StrobeConfig {
+            color: strobe.color("color", defaults.strobe.color)?,
+            frequency_hz: strobe.float("frequency_hz", defaults.strobe.frequency_hz as f64)? as f32,
+            duty: strobe.float("duty", defaults.strobe.duty as f64)? as f32,
+            defaults: strobe.effect_defaults(defaults.strobe.defaults)?,
+        };
+
+        let temperature = section("temperature");
+        let temperature = TemperatureConfig {
+            cold_celsius: temperature
+                .float("cold_celsius", defaults.temperature.cold_celsius as f64)?
+                as f32,
+            warm_celsius: temperature
+                .float("warm_celsius", defaults.temperature.warm_celsius as f64)?
+                as f32,
+            cold_hue: temperature.float("cold_hue", defaults.temperature.cold_hue as f64)? as f32,
+            warm_hue: temperature.float("warm_hue", defaults.temperature.warm_hue as f64)? as f32,
+            smoothing: Duration::from_secs(
+                60 * temperature.unsigned(
+                    "smoothing_minutes",
+                    defaults.temperature.smoothing.as_secs() / 60,
+                )?,
+            ),
+            defaults: temperature.effect_defaults(defaults.temperature.defaults)?,
+        };
+
+        let audio = section("audio");
+        let audio = AudioConfig {
+            device: audio.string("device")?.map(str::to_string),
+            sensitivity: audio.float("sensitivity", defaults.audio.sensitivity as f64)? as f32,
+            smoothing: Duration::from_millis(
+                audio.unsigned("smoothing_ms", defaults.audio.smoothing.as_millis() as u64)?,
+            ),
+            min_brightness: audio.float("min_brightness", defaults.audio.min_brightness as f64)?
+                as f32,
+            defaults: audio.effect_defaults(defaults.audio.defaults)?,
+        };
+
+        let palette = section("palette");
+        let palette = PaletteConfig {
+            active: palette.string("active")?.map(str::to_string),
+            dwell: Duration::from_secs(
+                palette.unsigned("dwell_seconds", defaults.palette.dwell.as_secs())?,
+            ),
+            fade: Duration::from_secs(
+                palette.unsigned("fade_seconds", defaults.palette.fade.as_secs())?,
+            ),
+            defaults: palette.effect_defaults(defaults.palette.defaults)?,
+        };
+        let palettes = match document.get("palettes") {
+            None => Vec::new(),
+            Some(Value::Table(palettes)) => palettes
+                .iter()
+                .map(|(name, palette)| parse_palette(name, palette))
+                .collect::<Result<_, _>>()?,
+            Some(value) => {
+                return Err(invalid(format!(
+                    "palettes must be a table of palettes, found {}",
+                    value.type_name()
+                )))
+            }
+        };
+
+        let santa = section("santa");
+        let santa = SantaConfig {
+            enabled: santa.boolean("enabled", defaults.santa.enabled)?,
+            scene_time: santa.time("scene_time", defaults.santa.scene_time)?,
+            scene_length: Duration::from_secs(
+                60 * santa.unsigned("scene_minutes", defaults.santa.scene_length.as_secs() / 60)?,
+            ),
+        };
+
+        let advent = section("advent");
+        let advent = AdventConfig {
+            enabled: advent.boolean("enabled", defaults.advent.enabled)?,
+            reveal_length: Duration::from_secs(
+                advent.unsigned("reveal_seconds", defaults.advent.reveal_length.as_secs())?,
+            ),
+        };
+
+        let observances = section("observances");
+        let observances = ObservancesConfig {
+            categories: observances
+                .strings("categories")?
+                .unwrap_or(defaults.observances.categories),
+        };
+
+        let themes = match document.get("themes") {
+            None => Vec::new(),
+            Some(Value::Table(themes)) => themes
+                .iter()
+                .map(|(name, theme)| parse_theme(name, theme))
+                .collect::<Result<_, _>>()?,
+            Some(value) => {
+                return Err(invalid(format!(
+                    "themes must be a table of themes, found {}",
+                    value.type_name()
+                )))
+            }
+        };
+
+        let scenes = parse_scenes(&document)?;
+
+        let welcome = section("welcome");
+        let welcome = WelcomeConfig {
+            people: welcome
+                .strings("people")?
+                .unwrap_or(defaults.welcome.people),
+            color: welcome.color("color", defaults.welcome.color)?,
+            duration: Duration::from_secs(
+                welcome.unsigned("duration_secs", defaults.welcome.duration.as_secs())?,
+            ),
+            cooldown: Duration::from_secs(
+                60 * welcome
+                    .unsigned("cooldown_minutes", defaults.welcome.cooldown.as_secs() / 60)?,
+            ),
+        };
+
+        let wake = section("wake");
+        let wake = WakeConfig {
+            sunrise: Duration::from_secs(
+                60 * wake.unsigned("sunrise_minutes", defaults.wake.sunrise.as_secs() / 60)?,
+            ),
+            stay: Duration::from_secs(
+                60 * wake.unsigned("stay_minutes", defaults.wake.stay.as_secs() / 60)?,
+            ),
+        };
+
+        let idle = IdleConfig {
+            when: section("idle").string_table("when")?,
+        };
+
+        let power = section("power");
+        let power = PowerConfig {
+            device_watts_full_white: power.float(
+                "device_watts_full_white",
+                defaults.power.device_watts_full_white as f64,
+            )? as f32,
+            light_watts_full_white: power
+                .float_list("light_watts_full_white")?
+                .unwrap_or_default(),
+            budget_watts: power
+                .optional_float("budget_watts")?
+                .map(|watts| watts as f32),
+            soft_start: Duration::from_millis(power.unsigned(
+                "soft_start_ms",
+                defaults.power.soft_start.as_millis() as u64,
+            )?),
+            soft_start_brightness: power.float(
+                "soft_start_brightness",
+                defaults.power.soft_start_brightness as f64,
+            )? as f32,
+        };
+
+        let battery = section("battery");
+        let battery = BatteryConfig {
+            enabled: battery.boolean("enabled", defaults.battery.enabled)?,
+            cycle_slowdown: battery
+                .unsigned("cycle_slowdown", defaults.battery.cycle_slowdown as u64)?
+                as u32,
+            brightness: battery.float("brightness", defaults.battery.brightness as f64)? as f32,
+        };
+
+        let thermal = section("thermal");
+        let thermal = ThermalConfig {
+            limit_celsius: thermal.float("limit_celsius", defaults.thermal.limit_celsius as f64)?
+                as f32,
+            cycle_slowdown: thermal
+                .unsigned("cycle_slowdown", defaults.thermal.cycle_slowdown as u64)?
+                as u32,
+        };
+
+        let scan = section("scan");
+        let scan = ScanConfig {
+            interval: Duration::from_secs(
+                scan.unsigned("interval_secs", defaults.scan.interval.as_secs())?,
+            ),
+            window: Duration::from_millis(
+                scan.unsigned("window_ms", defaults.scan.window.as_millis() as u64)?,
+            ),
+        };
+
+        let mqtt = section("mqtt");
+        let mqtt = match mqtt.string("broker")? {
+            Some(broker) => Some(MqttConfig {
+                broker: broker.to_string(),
+                client_id: mqtt
+                    .string("client_id")?
+                    .unwrap_or("christmas-lights")
+                    .to_string(),
+                username: mqtt.string("username")?.map(str::to_string),
+                password: mqtt.string("password")?.map(str::to_string),
+                name: mqtt
+                    .string("name")?
+                    .unwrap_or("Christmas lights")
+                    .to_string(),
+                topic_prefix: mqtt
+                    .string("topic_prefix")?
+                    .unwrap_or("christmas-lights")
+                    .to_string(),
+                discovery_prefix: mqtt
+                    .string("discovery_prefix")?
+                    .unwrap_or("homeassistant")
+                    .to_string(),
+                keep_alive: Duration::from_secs(mqtt.unsigned("keep_alive_secs", 30)?),
+                temperature_topic: mqtt.string("temperature_topic")?.map(str::to_string),
+                alarm_topic: mqtt.string("alarm_topic")?.map(str::to_string),
+            }),
+            None => None,
+        };
+
+        let http = section("http");
+        let http = match http.string("listen")? {
+            Some(listen) => Some(HttpConfig {
+                listen: listen.to_string(),
+                token: http.string("token")?.map(str::to_string),
+                tokens: http.string_table("tokens")?,
+                guest_scenes: http.strings("guest_scenes")?.unwrap_or_default(),
+                guest_duration: Duration::from_secs(http.unsigned("guest_minutes", 180)? * 60),
+            }),
+            None => None,
+        };
+
+        let status_page = section("status_page");
+        let status_page = match status_page.string("listen")? {
+            Some(listen) => Some(StatusPageConfig {
+                listen: listen.to_string(),
+                requests_per_minute: status_page.unsigned("requests_per_minute", 30)? as u32,
+            }),
+            None => None,
+        };
+
+        let notifications = section("notifications");
+        let notifications = match notifications.string("url")? {
+            Some(url) => Some(NotificationsConfig {
+                url: url.to_string(),
+                daily_summary: notifications.boolean("daily_summary", false)?,
+                weekly_report: notifications.boolean("weekly_report", false)?,
+            }),
+            None => None,
+        };
+
+        let e131 = section("e131");
+        let e131 = match e131.unsigned("universe", 0)? {
+            0 => None,
+            universe => Some(E131Config {
+                universe: universe.min(u64::from(u16::MAX)) as u16,
+                channel: e131.unsigned("channel", 1)?.min(u64::from(u16::MAX)) as u16,
+                listen: e131.string("listen")?.unwrap_or("0.0.0.0").to_string(),
+                multicast: e131.boolean("multicast", true)?,
+            }),
+        };
+
+        let mpris = section("mpris");
+        let mpris = match mpris.boolean("enabled", false)? {
+            false => None,
+            true => Some(MprisConfig {
+                drift: Duration::from_secs(mpris.unsigned("drift_seconds", 60)?),
+            }),
+        };
+
+        let gpio = section("gpio");
+        let pins = match gpio.get("pins") {
+            None => Vec::new(),
+            Some(Value::Table(pins)) => pins
+                .iter()
+                .map(|(name, pin)| match pin {
+                    Value::Integer(pin) if (0..=i64::from(u16::MAX)).contains(pin) => {
+                        Ok((name.clone(), *pin as u32))
+                    }
+                    _ => Err(invalid(format!("gpio.pins.{} must be a GPIO number", name))),
+                })
+                .collect::<Result<_, _>>()?,
+            Some(value) => return Err(gpio.type_error("pins", "a table of GPIO numbers", value)),
+        };
+        let gpio = (!pins.is_empty())
+            .then(|| {
+                Ok::<_, Failure>(GpioConfig {
+                    pins,
+                    poll: Duration::from_millis(gpio.unsigned("poll_ms", 50)?),
+                })
+            })
+            .transpose()?;
+
+        let shutdown = ShutdownConfig {
+            turn_off: section("shutdown").boolean("turn_off", defaults.shutdown.turn_off)?,
+        };
+
+        let resume = section("resume");
+        let resume = ResumeConfig {
+            enabled: resume.boolean("enabled", defaults.resume.enabled)?,
+            save_interval: Duration::from_secs(
+                resume.unsigned(
+                    "save_interval_minutes",
+                    defaults.resume.save_interval.as_secs() / 60,
+                )? * 60,
+            ),
+        };
+
+        let storage = section("storage");
+        let storage = StorageConfig {
+            backend: match storage.string("backend")? {
+                Some(name) => StorageBackend::from_name(name).ok_or_else(|| {
+                    invalid(format!(
+                        "storage.backend must be file, sled or sqlite, found {:?}",
+                        name
+                    ))
+                })?,
+                None => defaults.storage.backend,
+            },
+            path: storage.string("path")?.map(PathBuf::from),
+        };
+
+        let supervisor = section("supervisor");
+        let supervisor = SupervisorConfig {
+            max_failures: supervisor
+                .unsigned("max_failures", defaults.supervisor.max_failures as u64)?
+                as usize,
+            window: Duration::from_secs(
+                supervisor.unsigned("window_minutes", defaults.supervisor.window.as_secs() / 60)?
+                    * 60,
+            ),
+            cooldown: Duration::from_secs(
+                supervisor.unsigned(
+                    "cooldown_minutes",
+                    defaults.supervisor.cooldown.as_secs() / 60,
+                )? * 60,
+            ),
+        };
+
+        let devices = section("devices");
+        let devices = DevicesConfig {
+            shed_after_failed_writes: devices.unsigned(
+                "shed_after_failed_writes",
+                defaults.devices.shed_after_failed_writes as u64,
+            )? as u32,
+            cooldown: Duration::from_secs(
+                devices.unsigned("cooldown_minutes", defaults.devices.cooldown.as_secs() / 60)?
+                    * 60,
+            ),
+        };
+
+        let update = section("update");
+        let update = UpdateConfig {
+            enabled: update.boolean("enabled", defaults.update.enabled)?,
+            feed: update
+                .string("feed")?
+                .map(str::to_string)
+                .unwrap_or(defaults.update.feed),
+            check_interval: Duration::from_secs(
+                update.unsigned(
+                    "check_interval_hours",
+                    defaults.update.check_interval.as_secs() / 3600,
+                )? * 3600,
+            ),
+            install: update.boolean("install", defaults.update.install)?,
+        };
+
+        let connection = section("connection");
+        let connection = ConnectionConfig {
+            reconnect_after_failed_writes: connection.unsigned(
+                "reconnect_after_failed_writes",
+                defaults.connection.reconnect_after_failed_writes as u64,
+            )? as u32,
+            reconnect_backoff_max: Duration::from_secs(connection.unsigned(
+                "reconnect_backoff_max_secs",
+                defaults.connection.reconnect_backoff_max.as_secs(),
+            )?),
+            vendor_app_grace_period: Duration::from_secs(connection.unsigned(
+                "vendor_app_grace_period_secs",
+                defaults.connection.vendor_app_grace_period.as_secs(),
+            )?),
+            startup_timeout: Duration::from_secs(connection.unsigned(
+                "startup_timeout_secs",
+                defaults.connection.startup_timeout.as_secs(),
+            )?),
+            startup_concurrency: connection.unsigned(
+                "startup_concurrency",
+                defaults.connection.startup_concurrency as u64,
+            )? as usize,
+            startup_stagger: Duration::from_millis(connection.unsigned(
+                "startup_stagger_ms",
+                defaults.connection.startup_stagger.as_millis() as u64,
+            )?),
+        };
+
+        let config = Config {
+            location,
+            device,
+            schedule,
+            brightness,
+            calibration,
+            transitions,
+            effect,
+            animation,
+            rainbow,
+            gradient,
+            hold,
+            breathing,
+            candy_cane,
+            twinkle,
+            solid,
+            strobe,
+            temperature,
+            audio,
+            palette,
+            palettes,
+            santa,
+            advent,
+            observances,
+            themes,
+            scenes,
+            welcome,
+            wake,
+            idle,
+            power,
+            battery,
+            thermal,
+            scan,
+            mqtt,
+            http,
+            status_page,
+            notifications,
+            e131,
+            mpris,
+            gpio,
+            shutdown,
+            resume,
+            storage,
+            supervisor,
+            devices,
+            update,
+            connection,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The colors of the palette named by palette.active.
+    pub fn active_palette(&self) -> Option<Vec<PaletteColor>> {
+        let active = self.palette.active.as_deref()?;
+        self.palettes
+            .iter()
+            .find(|palette| palette.name == active)
+            .map(|palette| palette.colors.clone())
+    }
+
+    pub fn validate(&self) -> Result<(), Failure> {
+        let (latitude, longitude) = self.location;
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(invalid(format!(
+                "location {:?} is not a valid coordinate",
+                self.location
+            )));
+        }
+        if self.device.name_pattern.as_str().is_empty() {
+            return Err(invalid("device name pattern cannot be empty"));
+        }
+        if self.device.count == 0 {
+            return Err(invalid("device count must be at least 1"));
+        }
+        if self
+            .device
+            .max_writes_per_second
+            .is_some_and(|rate| rate <= 0.0)
+        {
+            return Err(invalid("device max writes per second must be positive"));
+        }
+        if self.update.check_interval.is_zero() {
+            return Err(invalid("update check interval must be at least an hour"));
+        }
+        if self.connection.startup_concurrency == 0 {
+            return Err(invalid("connection startup concurrency must be at least 1"));
+        }
+        if self.supervisor.max_failures == 0 {
+            return Err(invalid("supervisor max failures must be at least 1"));
+        }
+        if self.devices.shed_after_failed_writes == 0 {
+            return Err(invalid(
+                "devices shed after failed writes must be at least 1",
+            ));
+        }
+        let mut times = vec![
+            self.schedule.fallback_sunrise_utc,
+            self.schedule.fallback_sunset_utc,
+            self.schedule.daily_plan_time,
+            self.santa.scene_time,
+        ];
+        for trigger in [self.schedule.on, self.schedule.off] {
+            match trigger {
+                Trigger::At(time) => times.push(time),
+                Trigger::Sun { offset_minutes }
+                    if offset_minutes.abs() > MAX_SUN_OFFSET_MINUTES =>
+                {
+                    return Err(invalid(format!(
+                        "schedule offsets must be within {} minutes of sunrise or sunset",
+                        MAX_SUN_OFFSET_MINUTES
+                    )));
+                }
+                Trigger::Sun { .. } => {}
+            }
+        }
+        if let (Trigger::At(on), Trigger::At(off)) = (self.schedule.on, self.schedule.off) {
+            if on == off {
+                return Err(invalid("schedule on_time and off_time cannot be the same"));
+            }
+        }
+        times.extend(self.brightness.curve.steps.iter().map(|(time, _)| *time));
+        if !(0.0..=1.0).contains(&self.brightness.level)
+            || !self
+                .brightness
+                .curve
+                .steps
+                .iter()
+                .all(|(_, level)| (0.0..=1.0).contains(level))
+        {
+            return Err(invalid("brightness levels must be between 0 and 1"));
+        }
+        if !self.calibration.is_valid() {
+            return Err(invalid(
+                "calibration.gamma must be between 0.1 and 5 and its gains between 0 and 1",
+            ));
+        }
+        for (hour, minute) in times {
+            if hour > 23 || minute > 59 {
+                return Err(invalid(format!(
+                    "{:02}:{:02} is not a valid time of day",
+                    hour, minute
+                )));
+            }
+        }
+        if self.animation.cycle_time.is_zero() {
+            return Err(invalid("animation cycle time must be positive"));
+        }
+        if self.animation.speed <= 0.0 {
+            return Err(invalid("animation speed must be positive"));
+        }
+        if !(0.0..=1.0).contains(&self.rainbow.saturation) {
+            return Err(invalid("rainbow saturation must be between 0 and 1"));
+        }
+        for (effect, defaults) in [
+            ("rainbow", self.rainbow.defaults),
+            ("gradient", self.gradient.defaults),
+            ("hold", self.hold.defaults),
+            ("breathing", self.breathing.defaults),
+            ("candy_cane", self.candy_cane.defaults),
+            ("twinkle", self.twinkle.defaults),
+            ("solid", self.solid.defaults),
+            ("strobe", self.strobe.defaults),
+            ("temperature", self.temperature.defaults),
+            ("audio", self.audio.defaults),
+            ("palette", self.palette.defaults),
+        ] {
+            if !defaults.is_valid() {
+                return Err(invalid(format!(
+                    "{} brightness and white point must be between 0 and 1",
+                    effect
+                )));
+            }
+        }
+        if let Some(stops) = &self.gradient.stops {
+            let sorted = stops.windows(2).all(|pair| pair[0].0 <= pair[1].0);
+            let in_range = stops
+                .iter()
+                .all(|(position, _)| (0.0..=1.0).contains(position));
+            if stops.is_empty() || !sorted || !in_range {
+                return Err(invalid(
+                    "gradient stops must be sorted positions between 0 and 1",
+                ));
+            }
+        }
+        if let Some(palette) = &self.hold.palette {
+            if palette.is_empty() || self.gradient.stops.is_some() {
+                return Err(invalid(
+                    "hold palette must be non-empty and cannot be combined with a gradient",
+                ));
+            }
+        }
+        if self.hold.hold.is_zero() && self.hold.fade.is_zero() {
+            return Err(invalid("hold and fade durations cannot both be zero"));
+        }
+        if self.palette.dwell.is_zero() && self.palette.fade.is_zero() {
+            return Err(invalid(
+                "palette dwell and fade durations cannot both be zero",
+            ));
+        }
+        match self.effect {
+            EffectKind::Gradient if self.gradient.stops.is_none() => {
+                return Err(invalid("the gradient effect needs gradient.stops"));
+            }
+            EffectKind::Hold if self.hold.palette.is_none() => {
+                return Err(invalid("the hold effect needs hold.palette"));
+            }
+            EffectKind::Palette if self.palette.active.is_none() => {
+                return Err(invalid("the palette effect needs palette.active"));
+            }
+            EffectKind::Twinkle
+                if self.twinkle.palette_sparkles && self.palette.active.is_none() =>
+            {
+                return Err(invalid("twinkle palette_sparkles needs palette.active"));
+            }
+            _ => {}
+        }
+        for palette in &self.palettes {
+            if palette.colors.is_empty() {
+                return Err(invalid(format!("palette {:?} has no colors", palette.name)));
+            }
+            if palette
+                .colors
+                .iter()
+                .any(|color| !(color.weight > 0.0 && color.weight.is_finite()))
+            {
+                return Err(invalid(format!(
+                    "palette {:?} weights must be positive",
+                    palette.name
+                )));
+            }
+        }
+        if let Some(active) = &self.palette.active {
+            if !self.palettes.iter().any(|palette| palette.name == *active) {
+                return Err(invalid(format!(
+                    "palette.active {:?} is not one of the [palettes]",
+                    active
+                )));
+            }
+        }
+        if self.breathing.period.is_zero() {
+            return Err(invalid("breathing period must be positive"));
+        }
+        if !(0.0..=1.0).contains(&self.breathing.min_brightness) {
+            return Err(invalid("breathing min brightness must be between 0 and 1"));
+        }
+        if self.candy_cane.colors.is_empty() {
+            return Err(invalid("candy cane colors must be non-empty"));
+        }
+        if self.candy_cane.step.is_zero() && self.candy_cane.fade.is_zero() {
+            return Err(invalid("candy cane step and fade cannot both be zero"));
+        }
+        if self.twinkle.twinkles_per_second < 0.0 {
+            return Err(invalid("twinkles per second cannot be negative"));
+        }
+        if self.strobe.frequency_hz <= 0.0 || !(0.0..=1.0).contains(&self.strobe.duty) {
+            return Err(invalid(
+                "strobe frequency must be positive and duty between 0 and 1",
+            ));
+        }
+        if self.power.device_watts_full_white <= 0.0
+            || self
+                .power
+                .light_watts_full_white
+                .iter()
+                .any(|watts| *watts <= 0.0)
+        {
+            return Err(invalid("light wattages must be positive"));
+        }
+        if let Some(unknown) = self
+            .observances
+            .categories
+            .iter()
+            .find(|category| !observances::CATEGORIES.contains(&category.as_str()))
+        {
+            return Err(invalid(format!(
+                "unknown observance category {:?}, expected one of {}",
+                unknown,
+                observances::CATEGORIES.join(", ")
+            )));
+        }
+        for theme in &self.themes {
+            let mut themed = themes::apply(theme, self);
+            themed.themes.clear();
+            themed
+                .validate()
+                .map_err(|e| prefix_path(e, &format!("theme {:?}", theme.name)))?;
+        }
+        for scene in &self.scenes {
+            scenes::check(scene, self)
+                .map_err(|e| prefix_path(e, &format!("scene {:?}", scene.name)))?;
+        }
+        if self.temperature.cold_celsius >= self.temperature.warm_celsius {
+            return Err(invalid(
+                "temperature cold_celsius must be below warm_celsius",
+            ));
+        }
+        if ![self.temperature.cold_hue, self.temperature.warm_hue]
+            .iter()
+            .all(|hue| (0.0..=360.0).contains(hue))
+        {
+            return Err(invalid("temperature hues must be between 0 and 360"));
+        }
+        if self.audio.sensitivity <= 0.0 {
+            return Err(invalid("audio sensitivity must be positive"));
+        }
+        if !(0.0..=1.0).contains(&self.audio.min_brightness) {
+            return Err(invalid("audio min_brightness must be between 0 and 1"));
+        }
+        if let Some((event, _)) = self
+            .idle
+            .when
+            .iter()
+            .find(|(event, _)| !event.contains('/'))
+        {
+            return Err(invalid(format!(
+                "idle.when {:?} must be <trigger>/<event>, e.g. mqtt/lights_wanted",
+                event
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.power.soft_start_brightness) {
+            return Err(invalid("soft start brightness must be between 0 and 1"));
+        }
+        if !(0.0..=1.0).contains(&self.battery.brightness) {
+            return Err(invalid("battery brightness must be between 0 and 1"));
+        }
+        if let Some(mqtt) = &self.mqtt {
+            if !mqtt.broker.contains(':') {
+                return Err(invalid("mqtt broker must be a host:port address"));
+            }
+            if mqtt.client_id.is_empty() || mqtt.topic_prefix.is_empty() {
+                return Err(invalid("mqtt client id and topic prefix cannot be empty"));
+            }
+            if !(1..=u16::MAX as u64).contains(&mqtt.keep_alive.as_secs()) {
+                return Err(invalid(
+                    "mqtt keep alive must be between 1 and 65535 seconds",
+                ));
+            }
+        }
+        if let Some(http) = &self.http {
+            if !http.listen.contains(':') {
+                return Err(invalid("http listen must be a host:port address"));
+            }
+            if http
+                .token
+                .as_deref()
+                .is_some_and(|token| token.trim().is_empty())
+            {
+                return Err(invalid("http token cannot be empty"));
+            }
+            if http.guest_duration.is_zero() {
+                return Err(invalid("http guest minutes must be at least 1"));
+            }
+        }
+        if let Some(status_page) = &self.status_page {
+            if !status_page.listen.contains(':') {
+                return Err(invalid("status page listen must be a host:port address"));
+            }
+            if status_page.requests_per_minute == 0 {
+                return Err(invalid(
+                    "status page requests per minute must be at least 1",
+                ));
+            }
+        }
+        if let Some(notifications) = &self.notifications {
+            if !notifications.url.starts_with("http://")
+                && !notifications.url.starts_with("https://")
+            {
+                return Err(invalid(
+                    "notifications url must be an http:// or https:// URL",
+                ));
+            }
+        }
+        if let Some(e131) = &self.e131 {
+            if e131.universe > 63999 {
+                return Err(invalid("E1.31 universe must be between 1 and 63999"));
+            }
+            // The last light's blue must still fit in the universe's 512 channels
+            let last = usize::from(e131.channel) + 3 * self.device.count - 1;
+            if e131.channel == 0 || last > 512 {
+                return Err(invalid(format!(
+                    "E1.31 channels {}..={} do not fit in a universe of 512",
+                    e131.channel, last
+                )));
+            }
+            if e131.listen.parse::<std::net::IpAddr>().is_err() {
+                return Err(invalid("E1.31 listen must be an IP address"));
+            }
+        }
+        if self.wake.sunrise.is_zero() {
+            return Err(invalid("wake sunrise must be at least a minute"));
+        }
+        if self
+            .mpris
+            .as_ref()
+            .is_some_and(|mpris| mpris.drift.is_zero())
+        {
+            return Err(invalid("MPRIS drift must be at least a second"));
+        }
+        if self.gpio.as_ref().is_some_and(|gpio| gpio.poll.is_zero()) {
+            return Err(invalid("GPIO poll interval must be at least a millisecond"));
+        }
+        if self.resume.save_interval.is_zero() {
+            return Err(invalid("resume save interval must be at least a minute"));
+        }
+        if self.battery.cycle_slowdown == 0 || self.thermal.cycle_slowdown == 0 {
+            return Err(invalid("cycle slowdown factors must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+/// Where the config is read from: $CHRISTMAS_LIGHTS_CONFIG, or the default location.
+pub fn path() -> PathBuf {
+    env::var_os(CONFIG_PATH_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(default_path)
+}
+
+fn default_path() -> PathBuf {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_default();
+    config_home.join("christmas-lights").join("config.toml")
+}
+
+fn invalid(reason: impl Into<String>) -> Failure {
+    Failure::ConfigInvalid(reason.into())
+}
+
+fn prefix_path(failure: Failure, path: &str) -> Failure {
+    match failure {
+        Failure::ConfigInvalid(reason) => Failure::ConfigInvalid(format!("{}: {}", path, reason)),
+        failure => failure,
+    }
+}
+
+// Writes the upgraded config back, keeping the original next to it. A read-only config
+// still works, it is just upgraded again on every start.
+fn save_upgrade(path: &Path, original: &str, upgraded: &str) {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    let backup = PathBuf::from(backup);
+    let saved = std::fs::write(&backup, original).and_then(|()| std::fs::write(path, upgraded));
+    match saved {
+        Ok(()) => warn!(
+            "Upgraded {} to config version {}, the old file is at {}",
+            path.display(),
+            migrate::CURRENT_VERSION,
+            backup.display()
+        ),
+        Err(e) => warn!(
+            "Cannot save the upgraded config to {} ({}), upgrading in memory instead",
+            path.display(),
+            e
+        ),
+    }
+}
+
+fn warn_unknown_keys(document: &Table) {
+    for (name, value) in document.iter().filter(|(name, _)| *name != "version") {
+        let Some((_, keys)) = KNOWN_KEYS.iter().find(|(section, _)| section == name) else {
+            warn!("Ignoring unknown config section [{}]", name);
+            continue;
+        };
+        match value {
+            Value::Table(tables) if ["themes", "scenes", "palettes"].contains(&name.as_str()) => {
+                let keys = match name.as_str() {
+                    "themes" => THEME_KEYS,
+                    "scenes" => SCENE_KEYS,
+                    _ => PALETTE_KEYS,
+                };
+                for (table_name, value) in tables {
+                    let Value::Table(table) = value else {
+                        continue;
+                    };
+                    for key in table.keys().filter(|key| !keys.contains(&key.as_str())) {
+                        warn!(
+                            "Ignoring unknown config key {}.{}.{}",
+                            name, table_name, key
+                        );
+                    }
+                }
+            }
+            Value::Table(table) => {
+                for key in table.keys().filter(|key| !keys.contains(&key.as_str())) {
+                    warn!("Ignoring unknown config key {}.{}", name, key);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The rules file next to the config, or no rules when there is none.
+pub fn load_rules() -> Result<Vec<RuleConfig>, Failure> {
+    let path = path().with_file_name(RULES_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => {
+            info!("Loading rules from {}", path.display());
+            rules_from_toml(&contents).map_err(|e| prefix_path(e, &path.display().to_string()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(Failure::ConfigInvalid(format!(
+            "cannot read {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+/// Rules as kept in the rules file, one table per rule.
+pub fn rules_from_toml(contents: &str) -> Result<Vec<RuleConfig>, Failure> {
+    let document = toml::parse(contents).map_err(Failure::ConfigInvalid)?;
+    document
+        .iter()
+        .map(|(name, rule)| parse_rule(name, rule))
+        .collect()
+}
+
+fn parse_rule(name: &str, value: &Value) -> Result<RuleConfig, Failure> {
+    let Value::Table(table) = value else {
+        return Err(invalid(format!(
+            "rule {} must be a table, found {}",
+            name,
+            value.type_name()
+        )));
+    };
+    if let Some(key) = table.keys().find(|key| !RULE_KEYS.contains(&key.as_str())) {
+        return Err(invalid(format!("{}.{} is not a rule setting", name, key)));
+    }
+    let rule = Section {
+        name,
+        table: Some(table),
+    };
+    let on = rule
+        .string("on")?
+        .filter(|on| !on.is_empty())
+        .ok_or_else(|| invalid(format!("{}.on is required", name)))?;
+    let window = || {
+        invalid(format!(
+            "{}.between must be two times like \"22:00\", \"sunset\" or \"sunrise-30\"",
+            name
+        ))
+    };
+    let between = match rule.array("between")? {
+        None => None,
+        Some([Value::String(from), Value::String(to)]) => Some(
+            parse_time_of_day(from)
+                .zip(parse_time_of_day(to))
+                .ok_or_else(window)?,
+        ),
+        Some(_) => return Err(window()),
+    };
+    let effects = rule
+        .strings("effects")?
+        .unwrap_or_default()
+        .iter()
+        .map(|effect| {
+            EffectKind::from_name(effect).ok_or_else(|| {
+                invalid(format!(
+                    "{}.effects: {:?} is not one of {}",
+                    name,
+                    effect,
+                    EffectKind::ALL.map(EffectKind::name).join(", ")
+                ))
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    let lights = match rule.string("lights")? {
+        None => None,
+        Some("on") => Some(true),
+        Some("off") => Some(false),
+        Some(other) => {
+            return Err(invalid(format!(
+                "{}.lights must be on or off, found {:?}",
+                name, other
+            )))
+        }
+    };
+    let template = |key: &str, color: bool| -> Result<Option<Template>, Failure> {
+        let Some(Value::String(text)) = table.get(key) else {
+            return Ok(None);
+        };
+        if !Template::is_template(text) {
+            return Ok(None);
+        }
+        let template =
+            Template::parse(text).map_err(|e| invalid(format!("{}.{}: {}", name, key, e)))?;
+        if template.is_color() != color {
+            let kind = if color { "color" } else { "number" };
+            return Err(invalid(format!(
+                "{}.{} must work out a {}",
+                name, key, kind
+            )));
+        }
+        Ok(Some(template))
+    };
+    let mut commands = Vec::new();
+    match rule.string("power")? {
+        None => {}
+        Some("on") => commands.push(RemoteCommand::On),
+        Some("off") => commands.push(RemoteCommand::Off),
+        Some(other) => {
+            return Err(invalid(format!(
+                "{}.power must be on or off, found {:?}",
+                name, other
+            )))
+        }
+    }
+    let color = template("color", true)?;
+    if color.is_none() {
+        if let Some(color) = rule.optional_color("color")? {
+            commands.push(RemoteCommand::Color(color));
+        }
+    }
+    if let Some(effect) = rule.string("effect")? {
+        commands.push(RemoteCommand::Effect(
+            EffectKind::from_name(effect).ok_or_else(|| {
+                invalid(format!(
+                    "{}.effect {:?} is not one of {}",
+                    name,
+                    effect,
+                    EffectKind::ALL.map(EffectKind::name).join(", ")
+                ))
+            })?,
+        ));
+    }
+    if let Some(scene) = rule.string("scene")? {
+        commands.push(RemoteCommand::Scene(scene.to_string()));
+    }
+    if let Some(palette) = rule.string("palette")? {
+        commands.push(RemoteCommand::Palette(palette.to_string()));
+    }
+    let brightness = template("brightness", false)?;
+    if brightness.is_none() {
+        if let Some(brightness) = rule.optional_float("brightness")? {
+            if !(0.0..=1.0).contains(&brightness) {
+                return Err(invalid(format!(
+                    "{}.brightness must be between 0 and 1",
+                    name
+                )));
+            }
+            commands.push(RemoteCommand::Brightness(brightness as f32));
+        }
+    }
+    let actions: Vec<RuleAction> = color
+        .map(RuleAction::Color)
+        .into_iter()
+        .chain(brightness.map(RuleAction::Brightness))
+        .chain(commands.into_iter().map(RuleAction::Command))
+        .collect();
+    let [action] = <[RuleAction; 1]>::try_from(actions).map_err(|_| {
+        invalid(format!(
+            "{} needs exactly one of power, color, effect, scene, palette and brightness",
+            name
+        ))
+    })?;
+    let duration = match rule.optional_unsigned("for_seconds")? {
+        Some(0) => return Err(invalid(format!("{}.for_seconds must be at least 1", name))),
+        seconds => seconds.map(Duration::from_secs),
+    };
+    Ok(RuleConfig {
+        name: name.to_string(),
+        on: on.to_string(),
+        value: rule.string("value")?.map(str::to_string),
+        between,
+        lights,
+        effects,
+        during_override: rule.boolean("during_override", true)?,
+        debounce: Duration::from_millis(rule.unsigned("debounce_ms", 0)?),
+        cooldown: Duration::from_secs(rule.unsigned("cooldown_seconds", 0)?),
+        action,
+        duration,
+    })
+}
+
+// HH:MM, or sunrise or sunset with an optional offset in minutes, e.g. "sunset+30"
+fn parse_time_of_day(time: &str) -> Option<TimeOfDay> {
+    let sun = |rest: &str| match rest {
+        "" => Some(0),
+        offset if offset.starts_with(['+', '-']) => offset.parse().ok(),
+        _ => None,
+    };
+    if let Some(rest) = time.strip_prefix("sunrise") {
+        return sun(rest).map(TimeOfDay::Sunrise);
+    }
+    if let Some(rest) = time.strip_prefix("sunset") {
+        return sun(rest).map(TimeOfDay::Sunset);
+    }
+    parse_time(time)
+        .filter(|&(hour, minute)| hour < 24 && minute < 60)
+        .map(TimeOfDay::At)
+}
+
+/// Scenes saved at runtime, kept as [scenes.<name>] tables like the config's own.
+pub fn scenes_from_toml(contents: &str) -> Result<Vec<SceneConfig>, Failure> {
+    let document = toml::parse(contents).map_err(Failure::ConfigInvalid)?;
+    parse_scenes(&document)
+}
+
+fn parse_scenes(document: &Table) -> Result<Vec<SceneConfig>, Failure> {
+    match document.get("scenes") {
+        None => Ok(Vec::new()),
+        Some(Value::Table(scenes)) => scenes
+            .iter()
+            .map(|(name, scene)| parse_scene(name, scene))
+            .collect(),
+        Some(value) => Err(invalid(format!(
+            "scenes must be a table of scenes, found {}",
+            value.type_name()
+        ))),
+    }
+}
+
+fn parse_scene(name: &str, value: &Value) -> Result<SceneConfig, Failure> {
+    let label = format!("scenes.{}", name);
+    let Value::Table(table) = value else {
+        return Err(invalid(format!(
+            "{} must be a table, found {}",
+            label,
+            value.type_name()
+        )));
+    };
+    let scene = Section {
+        name: &label,
+        table: Some(table),
+    };
+    let effect = scene
+        .string("effect")?
+        .ok_or_else(|| invalid(format!("{}.effect is required", label)))?;
+    Ok(SceneConfig {
+        name: name.to_string(),
+        effect: EffectKind::from_name(effect).ok_or_else(|| {
+            invalid(format!(
+                "{}.effect {:?} is not one of {}",
+                label,
+                effect,
+                EffectKind::ALL.map(EffectKind::name).join(", ")
+            ))
+        })?,
+        color: scene.optional_color("color")?,
+        brightness: scene.float("brightness", 1.0)? as f32,
+        speed: scene.float("speed", 1.0)? as f32,
+    })
+}
+
+fn parse_theme(name: &str, value: &Value) -> Result<ThemeConfig, Failure> {
+    let label = format!("themes.{}", name);
+    let Value::Table(table) = value else {
+        return Err(invalid(format!(
+            "{} must be a table, found {}",
+            label,
+            value.type_name()
+        )));
+    };
+    let theme = Section {
+        name: &label,
+        table: Some(table),
+    };
+    let required = |key: &str| invalid(format!("{}.{} is required", label, key));
+    let effect = theme.string("effect")?.ok_or_else(|| required("effect"))?;
+    Ok(ThemeConfig {
+        name: name.to_string(),
+        from: theme.month_day("from")?.ok_or_else(|| required("from"))?,
+        to: theme.month_day("to")?.ok_or_else(|| required("to"))?,
+        effect: EffectKind::from_name(effect).ok_or_else(|| {
+            invalid(format!(
+                "{}.effect {:?} is not one of {}",
+                label,
+                effect,
+                EffectKind::ALL.map(EffectKind::name).join(", ")
+            ))
+        })?,
+        colors: theme.colors("colors")?,
+    })
+}
+
+fn parse_palette(name: &str, value: &Value) -> Result<NamedPalette, Failure> {
+    let label = format!("palettes.{}", name);
+    let Value::Table(table) = value else {
+        return Err(invalid(format!(
+            "{} must be a table, found {}",
+            label,
+            value.type_name()
+        )));
+    };
+    let malformed = || {
+        invalid(format!(
+            "{}.colors must be an array of hex colors like \"#ff0000\" or tables like \
+             {{ color = \"#ff0000\", weight = 2, dwell_seconds = 60 }}",
+            label
+        ))
+    };
+    let colors = match table.get("colors") {
+        None => return Err(invalid(format!("{}.colors is required", label))),
+        Some(Value::Array(colors)) => colors,
+        Some(_) => return Err(malformed()),
+    };
+    let colors = colors
+        .iter()
+        .map(|value| match value {
+            Value::String(hex) => Some(PaletteColor {
+                color: color::parse_hex(hex)?,
+                weight: 1.0,
+                dwell: None,
+            }),
+            Value::Table(entry) => {
+                let Some(Value::String(hex)) = entry.get("color") else {
+                    return None;
+                };
+                if entry
+                    .keys()
+                    .any(|key| !["color", "weight", "dwell_seconds"].contains(&key.as_str()))
+                {
+                    return None;
+                }
+                Some(PaletteColor {
+                    color: color::parse_hex(hex)?,
+                    weight: entry.get("weight").map_or(Some(1.0), as_float)?,
+                    dwell: match entry.get("dwell_seconds") {
+                        None => None,
+                        Some(Value::Integer(seconds)) if *seconds > 0 => {
+                            Some(Duration::from_secs(*seconds as u64))
+                        }
+                        Some(_) => return None,
+                    },
+                })
+            }
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(malformed)?;
+    Ok(NamedPalette {
+        name: name.to_string(),
+        colors,
+    })
+}
+
+fn parse_address(address: &str) -> Result<String, Failure> {
+    let octets: Vec<&str> = address.split([':', '-']).collect();
+    if octets.len() == 6
+        && octets
+            .iter()
+            .all(|octet| octet.len() == 2 && u8::from_str_radix(octet, 16).is_ok())
+    {
+        Ok(octets.join(":").to_ascii_uppercase())
+    } else {
+        Err(invalid(format!(
+            "{:?} is not a Bluetooth address like A4:C1:38:12:34:56",
+            address
+        )))
+    }
+}
+
+fn parse_uuid(uuid: &str) -> Result<Uuid, Failure> {
+    let short = uuid.trim_start_matches("0x");
+    if short.len() == 4 {
+        if let Ok(short) = u16::from_str_radix(short, 16) {
+            return Ok(uuid_from_u16(short));
+        }
+    }
+    Uuid::parse_str(uuid)
+        .map_err(|_| invalid(format!("{:?} is not a valid characteristic UUID", uuid)))
+}
+
+struct Section<'a> {
+    name: &'a str,
+    table: Option<&'a Table>,
+}
+
+impl<'a> Section<'a> {
+    fn new(document: &'a Table, name: &'a str) -> Self {
+        let table = match document.get(name) {
+            Some(Value::Table(table)) => Some(table),
+            _ => None,
+        };
+        Section { name, table }
+    }
+
+    fn get(&self, key: &str) -> Option<&'a Value> {
+        self.table.and_then(|table| table.get(key))
+    }
+
+    fn type_error(&self, key: &str, expected: &str, found: &Value) -> Failure {
+        invalid(format!(
+            "{}.{} must be {}, found {}",
+            self.name,
+            key,
+            expected,
+            found.type_name()
+        ))
+    }
+
+    fn optional_float(&self, key: &str) -> Result<Option<f64>, Failure> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Float(value)) => Ok(Some(*value)),
+            Some(Value::Integer(value)) => Ok(Some(*value as f64)),
+            Some(value) => Err(self.type_error(key, "a number", value)),
+        }
+    }
+
+    fn float(&self, key: &str, default: f64) -> Result<f64, Failure> {
+        Ok(self.optional_float(key)?.unwrap_or(default))
+    }
+
+    fn optional_unsigned(&self, key: &str) -> Result<Option<u64>, Failure> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(value)) if *value >= 0 => Ok(Some(*value as u64)),
+            Some(value) => Err(self.type_error(key, "a non-negative integer", value)),
+        }
+    }
+
+    fn unsigned(&self, key: &str, default: u64) -> Result<u64, Failure> {
+        Ok(self.optional_unsigned(key)?.unwrap_or(default))
+    }
+
+    fn boolean(&self, key: &str, default: bool) -> Result<bool, Failure> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(Value::Boolean(value)) => Ok(*value),
+            Some(value) => Err(self.type_error(key, "a boolean", value)),
+        }
+    }
+
+    fn string(&self, key: &str) -> Result<Option<&'a str>, Failure> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(value) => Err(self.type_error(key, "a string", value)),
+        }
+    }
+
+    fn integer(&self, key: &str, default: i64) -> Result<i64, Failure> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(Value::Integer(value)) => Ok(*value),
+            Some(value) => Err(self.type_error(key, "an integer", value)),
+        }
+    }
+
+    fn optional_time(&self, key: &str) -> Result<Option<(u32, u32)>, Failure> {
+        let Some(time) = self.string(key)? else {
+            return Ok(None);
+        };
+        parse_time(time).map(Some).ok_or_else(|| {
+            invalid(format!(
+                "{}.{} must be a HH:MM time, found {:?}",
+                self.name, key, time
+            ))
+        })
+    }
+
+    // A day of the year as MM-DD, e.g. "12-24"
+    fn month_day(&self, key: &str) -> Result<Option<(u32, u32)>, Failure> {
+        let Some(day) = self.string(key)? else {
+            return Ok(None);
+        };
+        day.split_once('-')
+            .and_then(|(month, day)| Some((month.parse().ok()?, day.parse().ok()?)))
+            // Checked against a leap year so 02-29 is allowed
+            .filter(|&(month, day)| NaiveDate::from_ymd_opt(2000, month, day).is_some())
+            .map(Some)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "{}.{} must be a MM-DD day of the year, found {:?}",
+                    self.name, key, day
+                ))
+            })
+    }
+
+    fn time(&self, key: &str, default: (u32, u32)) -> Result<(u32, u32), Failure> {
+        Ok(self.optional_time(key)?.unwrap_or(default))
+    }
+
+    // Reads <end>_time, or else <end>_offset_minutes relative to the sun
+    fn trigger(&self, end: &str, default: Trigger) -> Result<Trigger, Failure> {
+        let time_key = format!("{}_time", end);
+        let offset_key = format!("{}_offset_minutes", end);
+        match (self.optional_time(&time_key)?, self.get(&offset_key)) {
+            (Some(_), Some(_)) => Err(invalid(format!(
+                "{}.{} and {}.{} cannot both be set",
+                self.name, time_key, self.name, offset_key
+            ))),
+            (Some(time), None) => Ok(Trigger::At(time)),
+            (None, Some(_)) => Ok(Trigger::Sun {
+                offset_minutes: self.integer(&offset_key, 0)?,
+            }),
+            (None, None) => Ok(default),
+        }
+    }
+
+    fn array(&self, key: &str) -> Result<Option<&'a [Value]>, Failure> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Array(values)) => Ok(Some(values)),
+            Some(value) => Err(self.type_error(key, "an array", value)),
+        }
+    }
+
+    fn floats(&self, key: &str, len: usize) -> Result<Option<Vec<f32>>, Failure> {
+        let Some(values) = self.array(key)? else {
+            return Ok(None);
+        };
+        let floats: Option<Vec<f32>> = values.iter().map(as_float).collect();
+        match floats {
+            Some(floats) if floats.len() == len => Ok(Some(floats)),
+            _ => Err(invalid(format!(
+                "{}.{} must be an array of {} numbers",
+                self.name, key, len
+            ))),
+        }
+    }
+
+    fn float_list(&self, key: &str) -> Result<Option<Vec<f32>>, Failure> {
+        let Some(values) = self.array(key)? else {
+            return Ok(None);
+        };
+        values
+            .iter()
+            .map(as_float)
+            .collect::<Option<Vec<_>>>()
+            .map(Some)
+            .ok_or_else(|| invalid(format!("{}.{} must be an array of numbers", self.name, key)))
+    }
+
+    fn optional_color(&self, key: &str) -> Result<Option<(u8, u8, u8)>, Failure> {
+        let Some(hex) = self.string(key)? else {
+            return Ok(None);
+        };
+        color::parse_hex(hex).map(Some).ok_or_else(|| {
+            invalid(format!(
+                "{}.{} must be a hex color like \"#ff0000\", found {:?}",
+                self.name, key, hex
+            ))
+        })
+    }
+
+    fn color(&self, key: &str, default: (u8, u8, u8)) -> Result<(u8, u8, u8), Failure> {
+        Ok(self.optional_color(key)?.unwrap_or(default))
+    }
+
+    fn strings(&self, key: &str) -> Result<Option<Vec<String>>, Failure> {
+        let Some(values) = self.array(key)? else {
+            return Ok(None);
+        };
+        values
+            .iter()
+            .map(|value| match value {
+                Value::String(value) => Some(value.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(Some)
+            .ok_or_else(|| invalid(format!("{}.{} must be an array of strings", self.name, key)))
+    }
+
+    // A table of strings by name, e.g. [http.tokens], sorted by name
+    fn string_table(&self, key: &str) -> Result<Vec<(String, String)>, Failure> {
+        match self.get(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Table(table)) => table
+                .iter()
+                .map(|(name, value)| match value {
+                    Value::String(value) => Ok((name.clone(), value.clone())),
+                    _ => Err(invalid(format!(
+                        "{}.{}.{} must be a string",
+                        self.name, key, name
+                    ))),
+                })
+                .collect(),
+            Some(value) => Err(self.type_error(key, "a table of strings", value)),
+        }
+    }
+
+    fn colors(&self, key: &str) -> Result<Option<Palette>, Failure> {
+        let Some(values) = self.array(key)? else {
+            return Ok(None);
+        };
+        values
+            .iter()
+            .map(|value| match value {
+                Value::String(hex) => color::parse_hex(hex),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(Some)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "{}.{} must be an array of hex colors like \"#ff0000\"",
+                    self.name, key
+                ))
+            })
+    }
+
+    fn gradient_stops(&self, key: &str) -> Result<Option<Vec<GradientStop>>, Failure> {
+        let Some(values) = self.array(key)? else {
+            return Ok(None);
+        };
+        values
+            .iter()
+            .map(|value| match value {
+                Value::Array(stop) => match stop.as_slice() {
+                    [position, Value::String(hex)] => {
+                        Some((as_float(position)?, color::parse_hex(hex)?))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(Some)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "{}.{} must be an array of [position, \"#rrggbb\"] pairs",
+                    self.name, key
+                ))
+            })
+    }
+
+    fn dimming_curve(&self, key: &str) -> Result<Option<DimmingCurve>, Failure> {
+        let Some(values) = self.array(key)? else {
+            return Ok(None);
+        };
+        values
+            .iter()
+            .map(|value| match value {
+                Value::Array(step) => match step.as_slice() {
+                    [Value::String(time), level] => Some((parse_time(time)?, as_float(level)?)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|steps| Some(DimmingCurve { steps }))
+            .ok_or_else(|| {
+                invalid(format!(
+                    "{}.{} must be an array of [\"HH:MM\", brightness] pairs",
+                    self.name, key
+                ))
+            })
+    }
+
+    fn effect_defaults(&self, defaults: EffectDefaults) -> Result<EffectDefaults, Failure> {
+        Ok(EffectDefaults {
+            brightness: self.float("brightness", defaults.brightness as f64)? as f32,
+            white_point: match self.floats("white_point", 3)? {
+                Some(gains) => (gains[0], gains[1], gains[2]),
+                None => defaults.white_point,
+            },
+        })
+    }
+}
+
+fn as_float(value: &Value) -> Option<f32> {
+    match value {
+        Value::Float(value) => Some(*value as f32),
+        Value::Integer(value) => Some(*value as f32),
+        _ => None,
+    }
+}
+
+fn parse_time(time: &str) -> Option<(u32, u32)> {
+    let (hour, minute) = time.split_once(':')?;
+    Some((hour.parse().ok()?, minute.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_keys_take_the_defaults() {
+        let config = Config::from_toml("").unwrap();
+        let defaults = Config::default();
+
+        assert_eq!(config.location, defaults.location);
+        assert_eq!(config.device.name_pattern.as_str(), "Light");
+        assert_eq!(config.device.protocol, ProtocolKind::Auto);
+        assert_eq!(config.device.characteristic_uuid, None);
+        assert_eq!(config.device.white_channel_opcode, None);
+        assert_eq!(config.schedule.daily_plan_time, (5, 0));
+        assert_eq!(config.animation.cycle_time, Duration::from_millis(10));
+        assert_eq!(config.power.budget_watts, None);
+        assert!(defaults.validate().is_ok());
+    }
+
+    #[test]
+    fn the_example_config_is_the_defaults() {
+        let config = Config::from_toml(include_str!("../config.example.toml")).unwrap();
+        let defaults = Config::default();
+
+        assert_eq!(config.location, defaults.location);
+        assert_eq!(config.schedule.fallback_sunset_utc, (15, 0));
+        assert_eq!(
+            config.hold.defaults.white_point,
+            defaults.hold.defaults.white_point
+        );
+        assert_eq!(config.battery.brightness, defaults.battery.brightness);
+    }
+
+    #[test]
+    fn values_are_read_from_their_sections() {
+        let config = Config::from_toml(
+            r#"
+            [location]
+            latitude = 60.17
+            longitude = 24.94
+
+            [device]
+            name_pattern = "Tree"
+            white_channel_opcode = 5
+
+            [schedule]
+            daily_plan_time = "06:30"
+            startup_stay_off = true
+
+            [power]
+            light_watts_full_white = [12, 4.5]
+            budget_watts = 10
+
+            [idle.when]
+            "mqtt/lights_wanted" = "off"
+
+            [http]
+            listen = "0.0.0.0:8080"
+            guest_scenes = ["party", "calm"]
+
+            [http.tokens]
+            tablet = "0ther"
+            phone = "s3cret"
+
+            [notifications]
+            url = "https://ntfy.sh/lights"
+            daily_summary = true
+            weekly_report = true
+
+            [wake]
+            sunrise_minutes = 20
+
+            [mpris]
+            enabled = true
+            drift_seconds = 90
+
+            [gpio]
+            pins = { gate = 27, doorbell = 17 }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.location, (60.17, 24.94));
+        assert_eq!(config.device.name_pattern.as_str(), "Tree");
+        assert_eq!(config.device.white_channel_opcode, Some(5));
+        assert_eq!(config.schedule.daily_plan_time, (6, 30));
+        assert!(config.schedule.startup_stay_off);
+        assert_eq!(config.power.budget_watts, Some(10.0));
+        assert_eq!(config.power.watts_full_white(1), 4.5);
+        assert_eq!(config.power.watts_full_white(2), 6.0);
+        assert_eq!(
+            config.idle.when,
+            [("mqtt/lights_wanted".to_string(), "off".to_string())]
+        );
+        let notifications = config.notifications.unwrap();
+        assert_eq!(notifications.url, "https://ntfy.sh/lights");
+        assert!(notifications.daily_summary);
+        assert!(notifications.weekly_report);
+        assert_eq!(config.wake.sunrise, Duration::from_secs(20 * 60));
+        assert_eq!(config.mpris.unwrap().drift, Duration::from_secs(90));
+        let http = config.http.unwrap();
+        assert_eq!(http.token, None);
+        assert_eq!(
+            http.tokens,
+            [
+                ("phone".to_string(), "s3cret".to_string()),
+                ("tablet".to_string(), "0ther".to_string()),
+            ]
+        );
+        assert_eq!(http.guest_scenes, ["party", "calm"]);
+        assert_eq!(http.guest_duration, Duration::from_secs(3 * 60 * 60));
+        let gpio = config.gpio.unwrap();
+        assert_eq!(
+            gpio.pins,
+            [("doorbell".to_string(), 17), ("gate".to_string(), 27)]
+        );
+        assert_eq!(gpio.poll, Duration::from_millis(50));
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_rejected() {
+        for contents in [
+            "[power]\nbudget_watts = \"lots\"",
+            "[schedule]\nstartup_stay_off = 1",
+            "[device]\nwhite_channel_opcode = -1",
+            "[schedule]\ndaily_plan_time = \"noon\"",
+            "[http]\nlisten = \"0.0.0.0:8080\"\ntokens = \"s3cret\"",
+            "[http]\nlisten = \"0.0.0.0:8080\"\n[http.tokens]\nphone = 1",
+            "[gpio]\npins = { doorbell = \"17\" }",
+        ] {
+            assert!(
+                matches!(Config::from_toml(contents), Err(Failure::ConfigInvalid(_))),
+                "{:?} was accepted",
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn values_out_of_range_are_rejected() {
+        for contents in [
+            "[location]\nlatitude = 91",
+            "[device]\nname_pattern = \"\"",
+            "[device]\nwhite_channel_opcode = 256",
+            "[schedule]\nfallback_sunrise = \"24:00\"",
+            "[rainbow]\nsaturation = 1.5",
+            "[gradient]\nstops = [[0.5, \"#ff0000\"], [0.2, \"#00ff00\"]]",
+            "[power]\nlight_watts_full_white = [6.0, 0]",
+            "[battery]\ncycle_slowdown = 0",
+            "[idle.when]\nlights_wanted = \"off\"",
+            "[notifications]\nurl = \"ntfy.sh/lights\"",
+            "[wake]\nsunrise_minutes = 0",
+            "[mpris]\nenabled = true\ndrift_seconds = 0",
+        ] {
+            assert!(
+                matches!(Config::from_toml(contents), Err(Failure::ConfigInvalid(_))),
+                "{:?} was accepted",
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn syntax_errors_are_rejected() {
+        assert!(matches!(
+            Config::from_toml("[location"),
+            Err(Failure::ConfigInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn lights_are_selected_by_address_or_name() {
+        let mut device = Config::from_toml("[device]\nname_pattern = \"^Light-[0-9]{2}$\"")
+            .unwrap()
+            .device;
+        assert!(device.selects("A4:C1:38:00:00:01", Some("Light-07")));
+        assert!(!device.selects("A4:C1:38:00:00:01", Some("Light-107")));
+        assert!(!device.selects("A4:C1:38:00:00:01", None));
+
+        device.pin("a4:c1:38:12:34:56").unwrap();
+        assert!(device.selects("A4:C1:38:12:34:56", Some("Neighbor")));
+        assert!(!device.selects("A4:C1:38:00:00:01", Some("Light-07")));
+
+        device.pin("Tree").unwrap();
+        assert!(device.selects("A4:C1:38:00:00:01", Some("Big Tree")));
+        assert!(device.pin("Light-(").is_err());
+    }
+}
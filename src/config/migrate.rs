@@ -0,0 +1,190 @@
+// Upgrades config files written for older releases. Migrations edit the text rather than
+// re-serializing the parsed document, so comments and layout survive the upgrade.
+use super::toml::{self, Table, Value};
+use crate::error::Failure;
+use log::warn;
+
+pub const CURRENT_VERSION: i64 = 2;
+
+// Files without a version key predate versioning
+const UNVERSIONED: i64 = 1;
+
+type Migration = fn(&Table, &mut String);
+
+// MIGRATIONS[i] upgrades version i + 1 to version i + 2
+const MIGRATIONS: [Migration; (CURRENT_VERSION - UNVERSIONED) as usize] = [explicit_effect];
+
+pub fn version(document: &Table) -> Result<i64, Failure> {
+    match document.get("version") {
+        None => Ok(UNVERSIONED),
+        Some(Value::Integer(version)) if (UNVERSIONED..=CURRENT_VERSION).contains(version) => {
+            Ok(*version)
+        }
+        Some(Value::Integer(version)) if *version > CURRENT_VERSION => {
+            Err(Failure::ConfigInvalid(format!(
+                "config version {} is newer than the supported version {}",
+                version, CURRENT_VERSION
+            )))
+        }
+        Some(value) => Err(Failure::ConfigInvalid(format!(
+            "version must be an integer between {} and {}, found {:?}",
+            UNVERSIONED, CURRENT_VERSION, value
+        ))),
+    }
+}
+
+// Returns the upgraded file, or None if it is already current
+pub fn upgrade(contents: &str) -> Result<Option<String>, Failure> {
+    let document = toml::parse(contents).map_err(Failure::ConfigInvalid)?;
+    let from = version(&document)?;
+    if from == CURRENT_VERSION {
+        return Ok(None);
+    }
+
+    let mut upgraded = contents.to_string();
+    for migration in &MIGRATIONS[(from - UNVERSIONED) as usize..] {
+        let document = toml::parse(&upgraded).map_err(Failure::ConfigInvalid)?;
+        migration(&document, &mut upgraded);
+    }
+    set_version(&mut upgraded);
+    Ok(Some(upgraded))
+}
+
+// Version 1 picked the gradient or hold effect when its stops or palette were set; version 2
+// only shows the effect named in animation.effect
+fn explicit_effect(document: &Table, contents: &mut String) {
+    let section = |name| match document.get(name) {
+        Some(Value::Table(table)) => Some(table),
+        _ => None,
+    };
+    if section("animation").is_some_and(|animation| animation.contains_key("effect")) {
+        return;
+    }
+    let effect = if section("gradient").is_some_and(|gradient| gradient.contains_key("stops")) {
+        "gradient"
+    } else if section("hold").is_some_and(|hold| hold.contains_key("palette")) {
+        "hold"
+    } else {
+        return;
+    };
+    warn!(
+        "{}.{} no longer selects the {} effect on its own, adding animation.effect = {:?}",
+        effect,
+        if effect == "gradient" {
+            "stops"
+        } else {
+            "palette"
+        },
+        effect,
+        effect
+    );
+    set_key(contents, "animation", &format!("effect = {:?}", effect));
+}
+
+// Adds `line` right below the [section] header, appending the section if it is missing
+fn set_key(contents: &mut String, section: &str, line: &str) {
+    let header = format!("[{}]", section);
+    let mut offset = 0;
+    for existing in contents.split_inclusive('\n') {
+        offset += existing.len();
+        if existing.trim() == header {
+            if !existing.ends_with('\n') {
+                contents.push('\n');
+                offset += 1;
+            }
+            contents.insert_str(offset, &format!("{}\n", line));
+            return;
+        }
+    }
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(&format!("\n{}\n{}\n", header, line));
+}
+
+// Replaces the version line, or adds one below the leading comments; top-level keys have to
+// come before the first table
+fn set_version(contents: &mut String) {
+    let line = format!("version = {}\n", CURRENT_VERSION);
+    let mut offset = 0;
+    let mut first_key = None;
+    for existing in contents.split_inclusive('\n') {
+        let trimmed = existing.trim();
+        if trimmed.starts_with('[') {
+            break;
+        }
+        if trimmed
+            .strip_prefix("version")
+            .is_some_and(|rest| rest.trim_start().starts_with('='))
+        {
+            contents.replace_range(offset..offset + existing.len(), &line);
+            return;
+        }
+        if first_key.is_none() && !trimmed.is_empty() && !trimmed.starts_with('#') {
+            first_key = Some(offset);
+        }
+        offset += existing.len();
+    }
+    let offset = first_key.unwrap_or(offset);
+    let separator = if offset > 0 && !contents[..offset].ends_with("\n\n") {
+        "\n"
+    } else {
+        ""
+    };
+    contents.insert_str(offset, &format!("{}{}\n", separator, line));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_files_are_left_alone() {
+        assert_eq!(upgrade("version = 2\n[device]\ncount = 2\n").unwrap(), None);
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        assert!(matches!(
+            upgrade("version = 3\n"),
+            Err(Failure::ConfigInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn implicit_gradients_are_named_and_comments_kept() {
+        let upgraded = upgrade(
+            "# My lights\n\n[animation]\n# Slow\ncycle_ms = 20\n\n[gradient]\nstops = [[0.0, \"#ff0000\"], [1.0, \"#00ff00\"]]\n",
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            upgraded,
+            "# My lights\n\nversion = 2\n\n[animation]\neffect = \"gradient\"\n# Slow\ncycle_ms = 20\n\n[gradient]\nstops = [[0.0, \"#ff0000\"], [1.0, \"#00ff00\"]]\n"
+        );
+    }
+
+    #[test]
+    fn a_missing_animation_section_is_added() {
+        let upgraded = upgrade("[hold]\npalette = [\"#ff0000\"]").unwrap().unwrap();
+
+        assert_eq!(
+            upgraded,
+            "version = 2\n\n[hold]\npalette = [\"#ff0000\"]\n\n[animation]\neffect = \"hold\"\n"
+        );
+    }
+
+    #[test]
+    fn an_explicit_effect_only_gains_the_version() {
+        let upgraded =
+            upgrade("[animation]\neffect = \"rainbow\"\n\n[hold]\npalette = [\"#ff0000\"]\n")
+                .unwrap()
+                .unwrap();
+
+        assert_eq!(
+            upgraded,
+            "version = 2\n\n[animation]\neffect = \"rainbow\"\n\n[hold]\npalette = [\"#ff0000\"]\n"
+        );
+    }
+}
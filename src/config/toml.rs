@@ -0,0 +1,130 @@
+// The config file as plain values. toml_edit does the parsing; the document is copied into
+// these types so the rest of the config code does not depend on its representation. Dates
+// and times are not used by any setting and are rejected.
+use std::collections::BTreeMap;
+use toml_edit::{Document, Item};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+pub type Table = BTreeMap<String, Value>;
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Table, String> {
+    let document = Document::parse(input).map_err(|e| {
+        // The first line of toml_edit's message is enough for a log line
+        let line = e.span().map_or(1, |span| {
+            input[..span.start.min(input.len())].matches('\n').count() + 1
+        });
+        format!("line {}: {}", line, e.message().trim())
+    })?;
+    table(document.as_table().iter())
+}
+
+fn table<'a>(entries: impl Iterator<Item = (&'a str, &'a Item)>) -> Result<Table, String> {
+    entries
+        .map(|(key, item)| Ok((key.to_string(), item_value(key, item)?)))
+        .collect()
+}
+
+fn item_value(key: &str, item: &Item) -> Result<Value, String> {
+    match item {
+        Item::Value(value) => value_of(key, value),
+        Item::Table(entries) => table(entries.iter()).map(Value::Table),
+        Item::ArrayOfTables(tables) => tables
+            .iter()
+            .map(|entries| table(entries.iter()).map(Value::Table))
+            .collect::<Result<_, _>>()
+            .map(Value::Array),
+        Item::None => Err(format!("'{}' has no value", key)),
+    }
+}
+
+fn value_of(key: &str, value: &toml_edit::Value) -> Result<Value, String> {
+    Ok(match value {
+        toml_edit::Value::String(string) => Value::String(string.value().clone()),
+        toml_edit::Value::Integer(integer) => Value::Integer(*integer.value()),
+        toml_edit::Value::Float(float) => Value::Float(*float.value()),
+        toml_edit::Value::Boolean(boolean) => Value::Boolean(*boolean.value()),
+        toml_edit::Value::Datetime(_) => {
+            return Err(format!("'{}': dates and times are not supported", key))
+        }
+        toml_edit::Value::Array(array) => Value::Array(
+            array
+                .iter()
+                .map(|value| value_of(key, value))
+                .collect::<Result<_, _>>()?,
+        ),
+        toml_edit::Value::InlineTable(entries) => Value::Table(
+            entries
+                .iter()
+                .map(|(key, value)| Ok((key.to_string(), value_of(key, value)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_become_plain_values() {
+        let document = parse(
+            "version = 2\n\
+             [animation]\n\
+             effect = 'gradient'  # comment\n\
+             gradient.stops = [\"#ff0000\", { color = \"#00ff00\", at = 0.5 }]\n\
+             [[themes]]\n\
+             name = \"halloween\"\n",
+        )
+        .unwrap();
+        assert_eq!(document["version"], Value::Integer(2));
+        let Value::Table(animation) = &document["animation"] else {
+            panic!("animation is not a table");
+        };
+        assert_eq!(animation["effect"], Value::String("gradient".to_string()));
+        let Value::Table(gradient) = &animation["gradient"] else {
+            panic!("gradient is not a table");
+        };
+        let Value::Array(stops) = &gradient["stops"] else {
+            panic!("stops is not an array");
+        };
+        assert_eq!(stops[0], Value::String("#ff0000".to_string()));
+        assert_eq!(
+            stops[1],
+            Value::Table(Table::from([
+                ("at".to_string(), Value::Float(0.5)),
+                ("color".to_string(), Value::String("#00ff00".to_string())),
+            ]))
+        );
+        assert!(matches!(&document["themes"], Value::Array(themes) if themes.len() == 1));
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = parse("[animation]\neffect = \"rainbow\"\neffect = \"solid\"\n").unwrap_err();
+        assert!(error.starts_with("line 3: "), "{}", error);
+        let error = parse("[schedule]\non = 1979-05-27\n").unwrap_err();
+        assert!(error.contains("dates"), "{}", error);
+    }
+}
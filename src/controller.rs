@@ -0,0 +1,465 @@
+use crate::{
+    cache, chaos,
+    config::DeviceConfig,
+    devices,
+    error::Failure,
+    protocol::{LightProtocol, ProtocolKind},
+    report,
+    transport::{self, BleTransport, LightTransport},
+};
+use btleplug::{
+    api::{
+        bleuuid::uuid_from_u16, Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter,
+    },
+    platform::{Adapter, Manager, Peripheral},
+};
+use futures::StreamExt;
+use log::{info, warn};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use tokio::time;
+use uuid::Uuid;
+
+const FIRMWARE_REVISION: u16 = 0x2A26;
+const DEVICE_INFORMATION_CHARACTERISTICS: [(&str, u16); 3] = [
+    ("Manufacturer", 0x2A29),
+    ("Model", 0x2A24),
+    ("Firmware revision", FIRMWARE_REVISION),
+];
+const SCAN_DURATION: Duration = Duration::from_secs(2);
+const SCAN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A peripheral seen during a scan, as printed by `christmas-lights scan`.
+pub struct DiscoveredDevice {
+    pub address: String,
+    pub name: Option<String>,
+    pub rssi: Option<i16>,
+    pub is_light: bool,
+}
+
+/// What a light reports in its Device Information service, read on every connect.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceInformation {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub firmware: Option<String>,
+}
+
+/// A single light string reached over BLE.
+pub struct LightController {
+    adapter: Adapter,
+    peripheral: Peripheral,
+    address: String,
+    // Either may change on connect while detecting
+    protocol: Mutex<Arc<dyn LightProtocol>>,
+    cmd_char_uuid: Mutex<Uuid>,
+    // Whether the light's characteristics pick the protocol on connect, as with device.protocol
+    // auto and no characteristic_uuid, and the opcode an Actuel controller's white channel takes
+    detecting: bool,
+    white_channel_opcode: Option<u8>,
+    // Set once connected
+    transport: Mutex<Option<Arc<dyn LightTransport>>>,
+    last_color: Mutex<(u8, u8, u8)>,
+    device_information: Mutex<DeviceInformation>,
+}
+
+impl LightController {
+    pub fn new(
+        adapter: Adapter,
+        peripheral: Peripheral,
+        protocol: Box<dyn LightProtocol>,
+        cmd_char_uuid: Uuid,
+    ) -> Self {
+        LightController {
+            adapter,
+            address: peripheral.address().to_string(),
+            peripheral,
+            protocol: Mutex::new(Arc::from(protocol)),
+            cmd_char_uuid: Mutex::new(cmd_char_uuid),
+            detecting: false,
+            white_channel_opcode: None,
+            transport: Mutex::new(None),
+            last_color: Mutex::new((255, 255, 255)),
+            device_information: Mutex::new(DeviceInformation::default()),
+        }
+    }
+
+    /// Scans on the configured adapter and picks the first peripheral that looks like a light.
+    pub async fn find(device: &DeviceConfig) -> Result<Self, Failure> {
+        let mut lights = LightController::find_all(device, 1).await?;
+        Ok(lights.remove(0))
+    }
+
+    /// Scans on the configured adapter and picks up to `count` peripherals that look like
+    /// lights, ordered by address so the order is the same on every start. The scan ends early
+    /// once the lights found last time are all back.
+    pub async fn find_all(device: &DeviceConfig, count: usize) -> Result<Vec<Self>, Failure> {
+        let central = scan_adapter(device.adapter.as_deref()).await?;
+        let cached = cache::addresses();
+        let mut lights = Vec::new();
+        for _ in 0..SCAN_DURATION.as_millis() / SCAN_POLL_INTERVAL.as_millis() {
+            time::sleep(SCAN_POLL_INTERVAL).await;
+            lights = find_lights(&central, device).await?;
+            let addresses: Vec<String> = lights.iter().map(|p| p.address().to_string()).collect();
+            if !cached.is_empty()
+                && addresses.len() >= count
+                && cached.iter().all(|address| addresses.contains(address))
+            {
+                info!("Found the cached lights, ending the scan early");
+                break;
+            }
+        }
+        if lights.is_empty() {
+            return Err(Failure::DeviceNotFound("lights"));
+        }
+        lights.sort_by_key(|p| p.address());
+        if lights.len() < count {
+            warn!("Found only {} of {} lights", lights.len(), count);
+        }
+        lights.truncate(count);
+        let addresses: Vec<String> = lights.iter().map(|p| p.address().to_string()).collect();
+        if addresses != cached {
+            cache::store_addresses(&addresses);
+        }
+
+        let mut controllers = Vec::with_capacity(lights.len());
+        for light in lights {
+            let services = light
+                .properties()
+                .await?
+                .map(|properties| properties.services)
+                .unwrap_or_default();
+            let protocol = device
+                .protocol
+                .resolve(&services, device.white_channel_opcode);
+            info!("Found {} lights: {:?}", protocol.name(), light);
+            let cmd_char_uuid = device
+                .characteristic_uuid
+                .unwrap_or_else(|| protocol.characteristic_uuid());
+            let mut controller =
+                LightController::new(central.clone(), light, protocol, cmd_char_uuid);
+            // Advertisements can be incomplete, so the guess is checked once connected
+            controller.detecting =
+                device.protocol == ProtocolKind::Auto && device.characteristic_uuid.is_none();
+            controller.white_channel_opcode = device.white_channel_opcode;
+            controllers.push(controller);
+        }
+        Ok(controllers)
+    }
+
+    /// Lists every peripheral the configured adapter can see, flagging the ones that look like
+    /// lights.
+    pub async fn scan(device: &DeviceConfig) -> Result<Vec<DiscoveredDevice>, Failure> {
+        let central = scan_adapter(device.adapter.as_deref()).await?;
+        time::sleep(SCAN_DURATION).await;
+        let mut devices = Vec::new();
+        for p in central.peripherals().await? {
+            let Some(properties) = p.properties().await? else {
+                continue;
+            };
+            let address = properties.address.to_string();
+            let is_light = device.selects(&address, properties.local_name.as_deref());
+            devices.push(DiscoveredDevice {
+                address,
+                name: properties.local_name,
+                rssi: properties.rssi,
+                is_light,
+            });
+        }
+        devices.sort_by_key(|d| (!d.is_light, -(d.rssi.unwrap_or(i16::MIN) as i32)));
+        Ok(devices)
+    }
+
+    pub fn peripheral(&self) -> &Peripheral {
+        &self.peripheral
+    }
+
+    /// The light's Bluetooth address, e.g. "A4:C1:38:12:34:56".
+    pub fn address(&self) -> String {
+        self.address.clone()
+    }
+
+    pub async fn connect(&self) -> Result<(), Failure> {
+        self.peripheral.connect().await?;
+        info!("Connected to {}", self.address());
+        devices::connected(&self.address);
+        self.peripheral.discover_services().await?;
+        info!("Discovering light services");
+        self.read_device_information().await;
+        if self.detecting {
+            self.detect_protocol();
+        }
+
+        let cmd_char_uuid = *self
+            .cmd_char_uuid
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let cmd_char = self
+            .peripheral
+            .characteristics()
+            .into_iter()
+            .find(|c| c.uuid == cmd_char_uuid)
+            .ok_or(Failure::DeviceNotFound("command characteristic"))?;
+        info!("Found characterics: {}", cmd_char_uuid);
+        *self
+            .transport
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(chaos::wrap(
+            Arc::new(BleTransport::new(self.peripheral.clone(), cmd_char)),
+            Some(self.peripheral.clone()),
+        ));
+        Ok(())
+    }
+
+    // Switches to the protocol whose command characteristic the light turns out to have
+    fn detect_protocol(&self) {
+        let characteristics: Vec<Uuid> = self
+            .peripheral
+            .characteristics()
+            .iter()
+            .map(|c| c.uuid)
+            .collect();
+        let Some(kind) = ProtocolKind::detect(&characteristics) else {
+            warn!(
+                "{} has none of the known command characteristics, trying {}",
+                self.address,
+                self.protocol().name()
+            );
+            return;
+        };
+        let protocol: Arc<dyn LightProtocol> =
+            Arc::from(kind.resolve(&[], self.white_channel_opcode));
+        info!(
+            "{} has the {} command characteristic, so it speaks {}",
+            self.address,
+            protocol.characteristic_uuid(),
+            protocol.name()
+        );
+        *self
+            .cmd_char_uuid
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = protocol.characteristic_uuid();
+        *self.protocol.lock().unwrap_or_else(PoisonError::into_inner) = protocol;
+    }
+
+    pub async fn disconnect(&self) -> Result<(), Failure> {
+        self.peripheral.disconnect().await?;
+        info!("Disconnected from {}", self.address());
+        Ok(())
+    }
+
+    pub async fn reconnect(&self) -> Result<(), Failure> {
+        self.peripheral.disconnect().await.ok();
+        report::reconnected(&self.address);
+        self.connect().await
+    }
+
+    /// Sets `disconnected` whenever the adapter reports that this light dropped the connection.
+    /// Runs until the adapter's event stream ends.
+    pub async fn watch_disconnects(&self, disconnected: &AtomicBool) -> Result<(), Failure> {
+        let mut events = self.adapter.events().await?;
+        let id = self.peripheral.id();
+        while let Some(event) = events.next().await {
+            if matches!(event, CentralEvent::DeviceDisconnected(ref peer) if *peer == id) {
+                warn!("{} reported a disconnect", self.address());
+                disconnected.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn set_scanning(&self, scanning: bool) -> Result<(), Failure> {
+        if scanning {
+            self.adapter.start_scan(ScanFilter::default()).await?;
+        } else {
+            self.adapter.stop_scan().await?;
+        }
+        Ok(())
+    }
+
+    /// Records the signal strength the adapter last saw from the light, if it reports one.
+    pub async fn sample_rssi(&self) {
+        if let Ok(Some(rssi)) = self
+            .peripheral
+            .properties()
+            .await
+            .map(|properties| properties.and_then(|properties| properties.rssi))
+        {
+            devices::rssi(&self.address, rssi);
+        }
+    }
+
+    pub async fn is_connected(&self) -> bool {
+        self.peripheral.is_connected().await.unwrap_or(false)
+    }
+
+    /// The manufacturer, model and firmware read on the last connect.
+    pub fn device_information(&self) -> DeviceInformation {
+        self.device_information
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn protocol(&self) -> Arc<dyn LightProtocol> {
+        self.protocol
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn has_white_channel(&self) -> bool {
+        self.protocol().white(0).is_some()
+    }
+
+    pub async fn set_color(&self, rgb: (u8, u8, u8)) -> Result<(), Failure> {
+        *self
+            .last_color
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = rgb;
+        self.write_command(self.protocol().color(rgb)).await
+    }
+
+    // Falls back to mixing the white back into RGB on controllers without a white channel
+    pub async fn set_color_rgbw(&self, (r, g, b, w): (u8, u8, u8, u8)) -> Result<(), Failure> {
+        let Some(white_cmd) = self.protocol().white(w) else {
+            return self
+                .set_color((
+                    r.saturating_add(w),
+                    g.saturating_add(w),
+                    b.saturating_add(w),
+                ))
+                .await;
+        };
+        self.set_color((r, g, b)).await?;
+        *self
+            .last_color
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = (
+            r.saturating_add(w),
+            g.saturating_add(w),
+            b.saturating_add(w),
+        );
+        self.write_command(white_cmd).await
+    }
+
+    // Scales the last color on controllers without a brightness command
+    pub async fn set_brightness(&self, brightness: f32) -> Result<(), Failure> {
+        let level = (brightness.clamp(0.0, 1.0) * 255.0).round() as u8;
+        if let Some(brightness_cmd) = self.protocol().brightness(level) {
+            return self.write_command(brightness_cmd).await;
+        }
+        let (r, g, b) = *self
+            .last_color
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let scale = |channel: u8| (channel as f32 * brightness.clamp(0.0, 1.0)).round() as u8;
+        self.write_command(self.protocol().color((scale(r), scale(g), scale(b))))
+            .await
+    }
+
+    pub async fn turn_off(&self) -> Result<(), Failure> {
+        match self.protocol().power(false) {
+            Some(shut_off_cmd) => self.write_command(shut_off_cmd).await,
+            None => self.write_command(self.protocol().color((0, 0, 0))).await,
+        }
+    }
+
+    // Without a separate power-on command, any color turns the lights back on
+    pub async fn turn_on(&self) -> Result<(), Failure> {
+        let last_color = *self
+            .last_color
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(power_on_cmd) = self.protocol().power(true) {
+            self.write_command(power_on_cmd).await?;
+        }
+        self.set_color(last_color).await
+    }
+
+    async fn write_command(&self, command: Vec<u8>) -> Result<(), Failure> {
+        let transport = self
+            .transport
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+            .ok_or(Failure::DeviceNotFound("command characteristic"))?;
+        transport::send(transport.as_ref(), command).await?;
+        devices::wrote(&self.address);
+        Ok(())
+    }
+
+    async fn read_device_information(&self) {
+        let chars = self.peripheral.characteristics();
+        let mut values = [None, None, None];
+        for ((name, uuid), value) in DEVICE_INFORMATION_CHARACTERISTICS.iter().zip(&mut values) {
+            let Some(characteristic) = chars.iter().find(|c| c.uuid == uuid_from_u16(*uuid)) else {
+                continue;
+            };
+            match self.peripheral.read(characteristic).await {
+                Ok(bytes) => {
+                    let text = String::from_utf8_lossy(&bytes)
+                        .trim_end_matches('\0')
+                        .to_string();
+                    info!("{}: {}", name, text);
+                    *value = Some(text);
+                }
+                Err(e) => warn!("Failed to read {}: {}", name.to_lowercase(), e),
+            }
+        }
+        let [manufacturer, model, firmware] = values;
+        if let Some(firmware) = firmware.as_deref().filter(|firmware| !firmware.is_empty()) {
+            devices::firmware(&self.address, firmware);
+        }
+        *self
+            .device_information
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = DeviceInformation {
+            manufacturer,
+            model,
+            firmware,
+        };
+    }
+}
+
+// Picks the first adapter whose info (e.g. "hci1 (00:1A:7D:DA:71:13)") contains `wanted`,
+// or the first adapter at all
+async fn scan_adapter(wanted: Option<&str>) -> Result<Adapter, Failure> {
+    let manager = Manager::new().await?;
+    let mut central = None;
+    for adapter in manager.adapters().await? {
+        let adapter_info = adapter.adapter_info().await.unwrap_or_default();
+        if central.is_none() && wanted.is_none_or(|wanted| adapter_info.contains(wanted)) {
+            central = Some(adapter);
+        } else {
+            info!("Skipping adapter {}", adapter_info);
+        }
+    }
+    let central = central.ok_or(Failure::NoAdapter)?;
+    info!("Found adapter: {:?}", central);
+
+    central.start_scan(ScanFilter::default()).await.ok();
+    info!("Starting scan for BLE devices");
+    Ok(central)
+}
+
+async fn find_lights(
+    central: &Adapter,
+    device: &DeviceConfig,
+) -> btleplug::Result<Vec<Peripheral>> {
+    let mut lights = Vec::new();
+    for p in central.peripherals().await? {
+        if p.properties().await?.is_some_and(|properties| {
+            device.selects(
+                &properties.address.to_string(),
+                properties.local_name.as_deref(),
+            )
+        }) {
+            lights.push(p);
+        }
+    }
+    Ok(lights)
+}
@@ -0,0 +1,304 @@
+// What the daemon last knew about each light, for `christmas-lights devices`: when it last
+// connected and last took a write, its firmware revision when the light exposes one, and its
+// signal strength once a minute for the last hour. Kept in memory while running and saved to
+// storage every minute, so the command can read it from outside the daemon.
+use crate::storage;
+use chrono::Utc;
+use log::warn;
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_RSSI_READINGS: usize = 60;
+// A light that took no write for this long before the save is reported as stalled
+const STALLED_AFTER: i64 = 5 * 60;
+// Where storage keeps the devices
+pub const KEY: &str = "devices";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Device {
+    pub address: String,
+    // Unix timestamps
+    pub last_connected: Option<i64>,
+    pub last_write_ok: Option<i64>,
+    pub firmware: Option<String>,
+    // Oldest first, in dBm
+    pub rssi: VecDeque<i16>,
+}
+
+impl Device {
+    /// How the light was doing when the daemon saved at `saved_at`.
+    pub fn health(&self, saved_at: i64) -> &'static str {
+        match (self.last_connected, self.last_write_ok) {
+            (None, _) => "never connected",
+            (Some(_), Some(wrote)) if saved_at - wrote < STALLED_AFTER => "ok",
+            (Some(_), _) => "stalled",
+        }
+    }
+
+    /// The average of the readings kept, in dBm.
+    pub fn average_rssi(&self) -> Option<i16> {
+        let total: i32 = self.rssi.iter().map(|&rssi| rssi as i32).sum();
+        (!self.rssi.is_empty()).then(|| (total / self.rssi.len() as i32) as i16)
+    }
+}
+
+/// The devices as saved by the daemon, and when it saved them.
+pub struct Saved {
+    pub at: i64,
+    pub devices: Vec<Device>,
+}
+
+/// The configured addresses in order, then any other light the daemon saved, e.g. ones found
+/// by name.
+pub fn listed(configured: &[String], saved: &[Device]) -> Vec<Device> {
+    let mut listed: Vec<Device> = configured
+        .iter()
+        .map(|address| {
+            saved
+                .iter()
+                .find(|device| device.address.eq_ignore_ascii_case(address))
+                .cloned()
+                .unwrap_or_else(|| Device {
+                    address: address.clone(),
+                    ..Device::default()
+                })
+        })
+        .collect();
+    for device in saved {
+        if !listed
+            .iter()
+            .any(|listed| listed.address.eq_ignore_ascii_case(&device.address))
+        {
+            listed.push(device.clone());
+        }
+    }
+    listed
+}
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+
+// Runs `update` on the device with `address`, adding it when it is new
+fn update(address: &str, update: impl FnOnce(&mut Device)) {
+    let Ok(mut devices) = DEVICES.lock() else {
+        return;
+    };
+    let index = match devices.iter().position(|device| device.address == address) {
+        Some(index) => index,
+        None => {
+            devices.push(Device {
+                address: address.to_string(),
+                ..Device::default()
+            });
+            devices.len() - 1
+        }
+    };
+    update(&mut devices[index]);
+}
+
+pub fn connected(address: &str) {
+    update(address, |device| {
+        device.last_connected = Some(Utc::now().timestamp())
+    });
+}
+
+/// Counts one write to the light that went through.
+pub fn wrote(address: &str) {
+    update(address, |device| {
+        device.last_write_ok = Some(Utc::now().timestamp())
+    });
+}
+
+pub fn firmware(address: &str, firmware: &str) {
+    update(address, |device| {
+        device.firmware = Some(firmware.to_string())
+    });
+}
+
+pub fn rssi(address: &str, rssi: i16) {
+    update(address, |device| {
+        if device.rssi.len() == MAX_RSSI_READINGS {
+            device.rssi.pop_front();
+        }
+        device.rssi.push_back(rssi);
+    });
+}
+
+/// What the daemon knows about each light now.
+pub fn current() -> Vec<Device> {
+    DEVICES
+        .lock()
+        .map(|devices| devices.clone())
+        .unwrap_or_default()
+}
+
+/// Picks up what the last run knew, so lights not seen yet keep their history.
+pub fn load() {
+    if let Some(saved) = saved() {
+        if let Ok(mut devices) = DEVICES.lock() {
+            *devices = saved.devices;
+        }
+    }
+}
+
+pub fn save() {
+    let devices = match DEVICES.lock() {
+        Ok(devices) => devices.clone(),
+        Err(_) => return,
+    };
+    let formatted = format(Utc::now().timestamp(), &devices);
+    if let Err(e) = storage::current().write(KEY, formatted.as_bytes()) {
+        warn!("Cannot save the device states: {}", e);
+    }
+}
+
+/// What the daemon saved last, if it ever ran.
+pub fn saved() -> Option<Saved> {
+    let contents = match storage::current().read(KEY) {
+        Ok(contents) => contents?,
+        Err(e) => {
+            warn!("Cannot read the device states: {}", e);
+            return None;
+        }
+    };
+    parse(&String::from_utf8_lossy(&contents))
+}
+
+// A "saved_at <timestamp>" line, then one "address last_connected last_write_ok rssi,..
+// firmware" line per device, with "-" for what is not known. The firmware goes last as it may
+// contain spaces.
+fn format(at: i64, devices: &[Device]) -> String {
+    let known = |value: Option<i64>| value.map_or("-".to_string(), |value| value.to_string());
+    let mut formatted = format!("saved_at {}\n", at);
+    for device in devices {
+        let rssi: Vec<String> = device.rssi.iter().map(i16::to_string).collect();
+        formatted.push_str(&format!(
+            "{} {} {} {} {}\n",
+            device.address,
+            known(device.last_connected),
+            known(device.last_write_ok),
+            if rssi.is_empty() {
+                "-".to_string()
+            } else {
+                rssi.join(",")
+            },
+            device.firmware.as_deref().unwrap_or("-")
+        ));
+    }
+    formatted
+}
+
+fn parse(contents: &str) -> Option<Saved> {
+    let mut lines = contents.lines();
+    let at = lines
+        .next()?
+        .strip_prefix("saved_at ")?
+        .trim()
+        .parse()
+        .ok()?;
+    let known = |field: &str| match field {
+        "-" => Some(None),
+        field => field.parse().ok().map(Some),
+    };
+    let devices = lines
+        .filter_map(|line| {
+            let mut fields = line.splitn(5, ' ');
+            Some(Device {
+                address: fields.next()?.to_string(),
+                last_connected: known(fields.next()?)?,
+                last_write_ok: known(fields.next()?)?,
+                rssi: match fields.next()? {
+                    "-" => VecDeque::new(),
+                    rssi => rssi
+                        .split(',')
+                        .map(|rssi| rssi.parse().ok())
+                        .collect::<Option<_>>()?,
+                },
+                firmware: match fields.next()?.trim() {
+                    "-" => None,
+                    firmware => Some(firmware.to_string()),
+                },
+            })
+        })
+        .collect();
+    Some(Saved { at, devices })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_devices_read_back() {
+        let devices = vec![
+            Device {
+                address: "A4:C1:38:12:34:56".to_string(),
+                last_connected: Some(1_733_000_000),
+                last_write_ok: Some(1_733_000_500),
+                firmware: Some("V1.2 build 7".to_string()),
+                rssi: VecDeque::from([-70, -64, -61]),
+            },
+            Device {
+                address: "A4:C1:38:65:43:21".to_string(),
+                ..Device::default()
+            },
+        ];
+        let saved = parse(&format(1_733_000_600, &devices)).unwrap();
+        assert_eq!(saved.at, 1_733_000_600);
+        assert_eq!(saved.devices, devices);
+        assert!(parse("").is_none());
+    }
+
+    #[test]
+    fn configured_lights_are_listed_first() {
+        let saved = vec![
+            Device {
+                address: "A4:C1:38:00:00:02".to_string(),
+                last_connected: Some(1),
+                ..Device::default()
+            },
+            Device {
+                address: "A4:C1:38:00:00:01".to_string(),
+                ..Device::default()
+            },
+        ];
+        let listed = listed(
+            &[
+                "a4:c1:38:00:00:02".to_string(),
+                "A4:C1:38:00:00:03".to_string(),
+            ],
+            &saved,
+        );
+        let addresses: Vec<&str> = listed
+            .iter()
+            .map(|device| device.address.as_str())
+            .collect();
+        assert_eq!(
+            addresses,
+            [
+                "A4:C1:38:00:00:02",
+                "A4:C1:38:00:00:03",
+                "A4:C1:38:00:00:01"
+            ]
+        );
+        assert_eq!(listed[0].last_connected, Some(1));
+    }
+
+    #[test]
+    fn lights_without_recent_writes_are_stalled() {
+        let mut device = Device::default();
+        assert_eq!(device.health(1000), "never connected");
+        device.last_connected = Some(100);
+        assert_eq!(device.health(1000), "stalled");
+        device.last_write_ok = Some(990);
+        assert_eq!(device.health(1000), "ok");
+        assert_eq!(device.health(990 + STALLED_AFTER), "stalled");
+    }
+
+    #[test]
+    fn rssi_is_averaged_over_the_readings() {
+        let mut device = Device::default();
+        assert_eq!(device.average_rssi(), None);
+        device.rssi = VecDeque::from([-70, -64, -61]);
+        assert_eq!(device.average_rssi(), Some(-65));
+    }
+}
@@ -0,0 +1,208 @@
+// E1.31 (streaming ACN) input, so xLights, Vixen or a lighting desk can sequence the lights
+// alongside other props. Each light takes one RGB triplet from the configured universe: the
+// first light the three channels from `channel` on, the next light the three after those.
+// While data keeps arriving it stands in for the effect; when the sender goes quiet or ends
+// its stream, the effect comes back.
+use crate::{
+    config::{Config, E131Config},
+    integrations::{self, Context, Integration},
+    remote::LiveColors,
+    supervisor::FailureBudget,
+};
+use futures::future::BoxFuture;
+use log::{debug, info};
+use std::{io, net::Ipv4Addr};
+use tokio::net::UdpSocket;
+
+pub const PORT: u16 = 5568;
+const ACN_PACKET_IDENTIFIER: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const VECTOR_ROOT_E131_DATA: u32 = 0x0000_0004;
+const VECTOR_E131_DATA_PACKET: u32 = 0x0000_0002;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+const OPTION_PREVIEW_DATA: u8 = 0x80;
+const OPTION_STREAM_TERMINATED: u8 = 0x40;
+// Where the DMX start code sits; the slots follow it
+const START_CODE_OFFSET: usize = 125;
+
+/// One E1.31 data packet for a universe.
+#[derive(Debug, PartialEq)]
+pub struct Packet<'a> {
+    pub universe: u16,
+    pub sequence: u8,
+    pub terminated: bool,
+    // DMX slot values, channel 1 first
+    pub slots: &'a [u8],
+}
+
+struct E131Input(E131Config);
+
+impl Integration for E131Input {
+    fn name(&self) -> &'static str {
+        "E1.31 input"
+    }
+
+    fn start(self: Box<Self>, context: Context, budget: FailureBudget) -> BoxFuture<'static, ()> {
+        let commands = context.commands.clone();
+        Box::pin(integrations::restarting(commands, budget, move || {
+            listen(self.0.clone(), context.lights, context.live.clone())
+        }))
+    }
+}
+
+pub fn integration(config: &Config) -> Option<Box<dyn Integration>> {
+    Some(Box::new(E131Input(config.e131.clone()?)))
+}
+
+/// Receives the configured universe until the socket fails.
+pub async fn listen(config: E131Config, lights: usize, input: LiveColors) -> io::Result<()> {
+    let socket = UdpSocket::bind((config.listen.as_str(), PORT)).await?;
+    if config.multicast {
+        socket.join_multicast_v4(multicast_group(config.universe), Ipv4Addr::UNSPECIFIED)?;
+    }
+    info!(
+        "Listening for E1.31 universe {} on port {}",
+        config.universe, PORT
+    );
+    let mut buffer = [0; 1144];
+    let mut last_sequence = None;
+    loop {
+        let (len, sender) = socket.recv_from(&mut buffer).await?;
+        let Some(packet) = parse(&buffer[..len]).filter(|p| p.universe == config.universe) else {
+            continue;
+        };
+        if packet.terminated {
+            info!("E1.31 sender {} ended its stream", sender);
+            input.set(None);
+            last_sequence = None;
+            continue;
+        }
+        if last_sequence.is_some_and(|last| is_stale(last, packet.sequence)) {
+            debug!("Dropping out of order E1.31 packet from {}", sender);
+            continue;
+        }
+        last_sequence = Some(packet.sequence);
+        input.set(Some(colors(packet.slots, config.channel, lights)));
+    }
+}
+
+/// Parses an E1.31 data packet, or None for anything else on the port, including preview data
+/// meant for a visualiser rather than the lights.
+pub fn parse(packet: &[u8]) -> Option<Packet<'_>> {
+    let u16_at = |offset: usize| {
+        Some(u16::from_be_bytes([
+            *packet.get(offset)?,
+            *packet.get(offset + 1)?,
+        ]))
+    };
+    let u32_at = |offset: usize| {
+        Some(u32::from_be_bytes(
+            packet.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    if packet.get(4..16)? != ACN_PACKET_IDENTIFIER
+        || u32_at(18)? != VECTOR_ROOT_E131_DATA
+        || u32_at(40)? != VECTOR_E131_DATA_PACKET
+        || *packet.get(117)? != VECTOR_DMP_SET_PROPERTY
+        // Only the null start code carries dimmer levels
+        || *packet.get(START_CODE_OFFSET)? != 0
+    {
+        return None;
+    }
+    let options = *packet.get(112)?;
+    if options & OPTION_PREVIEW_DATA != 0 {
+        return None;
+    }
+    // The property count includes the start code
+    let count = usize::from(u16_at(123)?).checked_sub(1)?;
+    Some(Packet {
+        universe: u16_at(113)?,
+        sequence: *packet.get(111)?,
+        terminated: options & OPTION_STREAM_TERMINATED != 0,
+        slots: packet.get(START_CODE_OFFSET + 1..START_CODE_OFFSET + 1 + count)?,
+    })
+}
+
+// The universe's multicast address, 239.255.<high byte>.<low byte>
+fn multicast_group(universe: u16) -> Ipv4Addr {
+    let [high, low] = universe.to_be_bytes();
+    Ipv4Addr::new(239, 255, high, low)
+}
+
+// E1.31 drops packets up to 20 behind the last one, allowing for the counter wrapping around
+fn is_stale(last: u8, sequence: u8) -> bool {
+    let behind = sequence.wrapping_sub(last) as i8;
+    behind <= 0 && behind > -20
+}
+
+// One triplet per light from `channel` on, black where the universe runs out
+fn colors(slots: &[u8], channel: u16, lights: usize) -> Vec<(u8, u8, u8)> {
+    let slot = |i: usize| slots.get(i).copied().unwrap_or(0);
+    (0..lights)
+        .map(|light| {
+            let first = usize::from(channel) - 1 + 3 * light;
+            (slot(first), slot(first + 1), slot(first + 2))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(universe: u16, sequence: u8, options: u8, slots: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; START_CODE_OFFSET + 1];
+        packet[0..2].copy_from_slice(&0x0010u16.to_be_bytes());
+        packet[4..16].copy_from_slice(ACN_PACKET_IDENTIFIER);
+        packet[18..22].copy_from_slice(&VECTOR_ROOT_E131_DATA.to_be_bytes());
+        packet[40..44].copy_from_slice(&VECTOR_E131_DATA_PACKET.to_be_bytes());
+        packet[108] = 100;
+        packet[111] = sequence;
+        packet[112] = options;
+        packet[113..115].copy_from_slice(&universe.to_be_bytes());
+        packet[117] = VECTOR_DMP_SET_PROPERTY;
+        packet[123..125].copy_from_slice(&(slots.len() as u16 + 1).to_be_bytes());
+        packet.extend_from_slice(slots);
+        packet
+    }
+
+    #[test]
+    fn data_packets_parse() {
+        let bytes = packet(7, 42, 0, &[255, 128, 0]);
+        assert_eq!(
+            parse(&bytes),
+            Some(Packet {
+                universe: 7,
+                sequence: 42,
+                terminated: false,
+                slots: &[255, 128, 0],
+            })
+        );
+        assert!(parse(&packet(7, 42, OPTION_STREAM_TERMINATED, &[])).is_some_and(|p| p.terminated));
+    }
+
+    #[test]
+    fn preview_and_foreign_packets_are_ignored() {
+        assert!(parse(&packet(7, 1, OPTION_PREVIEW_DATA, &[255, 0, 0])).is_none());
+        let mut bytes = packet(7, 1, 0, &[255, 0, 0]);
+        bytes[4] = b'X';
+        assert!(parse(&bytes).is_none());
+        assert!(parse(&bytes[..60]).is_none());
+    }
+
+    #[test]
+    fn each_light_takes_the_next_triplet() {
+        let slots = [0, 1, 2, 3, 4, 5, 6, 7];
+        assert_eq!(colors(&slots, 2, 2), vec![(1, 2, 3), (4, 5, 6)]);
+        assert_eq!(colors(&slots, 7, 1), vec![(6, 7, 0)]);
+    }
+
+    #[test]
+    fn old_sequence_numbers_are_dropped() {
+        assert!(is_stale(10, 10));
+        assert!(is_stale(10, 5));
+        assert!(!is_stale(10, 11));
+        assert!(!is_stale(255, 0));
+        // Far behind means the sender restarted
+        assert!(!is_stale(100, 10));
+    }
+}
@@ -0,0 +1,933 @@
+use crate::{
+    audio::AudioLevels,
+    color::{self, EffectDefaults, GradientStop},
+    config::{Config, PaletteColor},
+    observances, santa,
+    timezone::Zone,
+};
+use angular_units::Deg;
+use chrono::{Datelike, NaiveDate, Utc};
+use log::{error, warn};
+use prisma::{FromColor, Hsv, Rgb};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    f64::consts::TAU,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Close enough to the sRGB curve for blending frames
+const GAMMA: f32 = 2.2;
+
+const SANTA_RED: (u8, u8, u8) = (255, 0, 0);
+// The colors the Santa scene flashes through
+const SANTA_SCENE: [(u8, u8, u8); 3] = [(255, 0, 0), (255, 255, 255), (0, 255, 0)];
+const SANTA_SCENE_STEP: Duration = Duration::from_millis(300);
+const ADVENT_COLORS: usize = 3;
+// Where bass, mid and treble pull the audio effect's hue: red, green and blue
+const AUDIO_BAND_HUES: [f32; 3] = [0.0, 120.0, 240.0];
+
+// What an effect that keeps failing is replaced with: steady warm white
+const SAFE_MODE_COLOR: (u8, u8, u8) = (255, 190, 120);
+// This many failures within the window switch a light's effect to safe mode
+const SAFE_MODE_FAILURES: usize = 3;
+const SAFE_MODE_WINDOW: Duration = Duration::from_secs(60);
+
+// Why the lights fell back to safe mode, for the status endpoint; cleared when the effect changes
+static SAFE_MODE: Mutex<Option<String>> = Mutex::new(None);
+
+thread_local! {
+    // Set while a guarded effect renders, so the panic hook lets the panic unwind to the guard
+    static GUARDED: Cell<bool> = const { Cell::new(false) };
+}
+
+// The day the advent scene was last revealed and when it started, shared by every light and
+// kept across effect changes so each day's scene plays once
+static ADVENT_REVEAL: Mutex<Option<(NaiveDate, Instant)>> = Mutex::new(None);
+
+// One color for the whole string, each channel between 0 and 1
+pub type Frame = Rgb<f32>;
+
+pub trait Effect: Send {
+    // `elapsed` is the time since the effect started
+    fn next_frame(&mut self, elapsed: Duration) -> Frame;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EffectKind {
+    Rainbow,
+    Gradient,
+    Hold,
+    Breathing,
+    CandyCane,
+    Twinkle,
+    Solid,
+    Strobe,
+    Temperature,
+    Audio,
+    Palette,
+}
+
+impl EffectKind {
+    pub const ALL: [EffectKind; 11] = [
+        EffectKind::Rainbow,
+        EffectKind::Gradient,
+        EffectKind::Hold,
+        EffectKind::Breathing,
+        EffectKind::CandyCane,
+        EffectKind::Twinkle,
+        EffectKind::Solid,
+        EffectKind::Strobe,
+        EffectKind::Temperature,
+        EffectKind::Audio,
+        EffectKind::Palette,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EffectKind::Rainbow => "rainbow",
+            EffectKind::Gradient => "gradient",
+            EffectKind::Hold => "hold",
+            EffectKind::Breathing => "breathing",
+            EffectKind::CandyCane => "candy_cane",
+            EffectKind::Twinkle => "twinkle",
+            EffectKind::Solid => "solid",
+            EffectKind::Strobe => "strobe",
+            EffectKind::Temperature => "temperature",
+            EffectKind::Audio => "audio",
+            EffectKind::Palette => "palette",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<EffectKind> {
+        EffectKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == name.replace('-', "_"))
+    }
+}
+
+/// The latest outdoor temperature reported by a sensor, shared with the effects that show it.
+#[derive(Clone)]
+pub struct OutdoorTemperature(Arc<AtomicU32>);
+
+impl OutdoorTemperature {
+    pub fn new() -> Self {
+        OutdoorTemperature(Arc::new(AtomicU32::new(f32::NAN.to_bits())))
+    }
+
+    pub fn set(&self, celsius: f32) {
+        self.0.store(celsius.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<f32> {
+        Some(f32::from_bits(self.0.load(Ordering::Relaxed))).filter(|celsius| !celsius.is_nan())
+    }
+}
+
+impl Default for OutdoorTemperature {
+    fn default() -> Self {
+        OutdoorTemperature::new()
+    }
+}
+
+// Builds the configured effect along with the brightness and white point it is shown at
+/// The configured effect for one light, falling back to safe mode should it keep failing.
+pub fn from_config(
+    config: &Config,
+    outdoor: &OutdoorTemperature,
+    audio: &AudioLevels,
+) -> (Box<dyn Effect>, EffectDefaults) {
+    *SAFE_MODE.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    let (effect, defaults) = build(config, outdoor, audio);
+    (Box::new(Guarded::new(effect, config.effect)), defaults)
+}
+
+/// Why the lights show steady warm white instead of their effect, if they do.
+pub fn safe_mode() -> Option<String> {
+    SAFE_MODE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Whether the current thread is rendering a guarded effect, whose panics are caught.
+pub fn is_guarded() -> bool {
+    GUARDED.get()
+}
+
+fn build(
+    config: &Config,
+    outdoor: &OutdoorTemperature,
+    audio: &AudioLevels,
+) -> (Box<dyn Effect>, EffectDefaults) {
+    let motion = Motion {
+        degrees_per_second: config.animation.hue_degrees_per_second as f64,
+        reverse: config.animation.reverse,
+        ping_pong: config.animation.ping_pong,
+    };
+    let (effect, defaults): (Box<dyn Effect>, EffectDefaults) = match config.effect {
+        EffectKind::Rainbow => (
+            Box::new(Rainbow {
+                motion,
+                hue_range: config.rainbow.hue_range,
+                saturation: config.rainbow.saturation,
+            }),
+            config.rainbow.defaults,
+        ),
+        EffectKind::Gradient => (
+            Box::new(Gradient {
+                motion,
+                stops: config.gradient.stops.clone().unwrap_or_default(),
+            }),
+            config.gradient.defaults,
+        ),
+        EffectKind::Hold => (
+            Box::new(Hold {
+                palette: config.hold.palette.clone().unwrap_or_default(),
+                hold: config.hold.hold,
+                fade: config.hold.fade,
+            }),
+            config.hold.defaults,
+        ),
+        EffectKind::Breathing => (
+            Box::new(Breathing {
+                color: config.breathing.color,
+                period: config.breathing.period,
+                min_brightness: config.breathing.min_brightness,
+            }),
+            config.breathing.defaults,
+        ),
+        EffectKind::CandyCane => (
+            Box::new(Hold {
+                palette: config.candy_cane.colors.clone(),
+                hold: config.candy_cane.step,
+                fade: config.candy_cane.fade,
+            }),
+            config.candy_cane.defaults,
+        ),
+        EffectKind::Twinkle => (
+            Box::new(Twinkle::new(
+                config.twinkle.color,
+                if config.twinkle.palette_sparkles {
+                    config.active_palette().unwrap_or_default()
+                } else {
+                    Vec::new()
+                },
+                config.twinkle.twinkles_per_second,
+                config.twinkle.decay,
+            )),
+            config.twinkle.defaults,
+        ),
+        EffectKind::Solid => (
+            Box::new(Solid {
+                color: config.solid.color,
+            }),
+            config.solid.defaults,
+        ),
+        EffectKind::Strobe => (
+            Box::new(Strobe {
+                color: config.strobe.color,
+                frequency_hz: config.strobe.frequency_hz,
+                duty: config.strobe.duty,
+            }),
+            config.strobe.defaults,
+        ),
+        EffectKind::Temperature => (
+            Box::new(Temperature {
+                outdoor: outdoor.clone(),
+                cold_celsius: config.temperature.cold_celsius,
+                warm_celsius: config.temperature.warm_celsius,
+                cold_hue: config.temperature.cold_hue,
+                warm_hue: config.temperature.warm_hue,
+                smoothing: config.temperature.smoothing,
+                shown_celsius: None,
+                last_frame: Duration::ZERO,
+            }),
+            config.temperature.defaults,
+        ),
+        EffectKind::Audio => (
+            Box::new(Audio {
+                levels: audio.clone(),
+                smoothing: config.audio.smoothing,
+                min_brightness: config.audio.min_brightness,
+                shown: [0.0; 3],
+                last_frame: Duration::ZERO,
+            }),
+            config.audio.defaults,
+        ),
+        EffectKind::Palette => (
+            Box::new(Palette::new(
+                config.active_palette().unwrap_or_default(),
+                config.palette.dwell,
+                config.palette.fade,
+            )),
+            config.palette.defaults,
+        ),
+    };
+    let effect: Box<dyn Effect> = if config.observances.categories.is_empty() {
+        effect
+    } else {
+        Box::new(ObservanceDay {
+            inner: effect,
+            categories: config.observances.categories.clone(),
+            zone: config.schedule.timezone,
+        })
+    };
+    let effect: Box<dyn Effect> = if config.advent.enabled {
+        Box::new(AdventReveal {
+            inner: effect,
+            length: config.advent.reveal_length,
+            zone: config.schedule.timezone,
+        })
+    } else {
+        effect
+    };
+    if !config.santa.enabled {
+        return (effect, defaults);
+    }
+    let santa = SantaTracker {
+        inner: effect,
+        home: config.location,
+        scene_time: config.santa.scene_time,
+        scene_length: config.santa.scene_length,
+        phase: 0.0,
+        last_frame: Duration::ZERO,
+    };
+    (Box::new(santa), defaults)
+}
+
+// Renders at full rate but only lets a frame through every `interval`, for lights that
+// cannot keep up with every frame. The frame sent is the average of everything rendered
+// since the last one, blended in linear light so fades and flashes keep their brightness
+pub struct Keyframes {
+    interval: Duration,
+    last_sent: Option<Instant>,
+    sum: (f32, f32, f32),
+    count: u32,
+}
+
+impl Keyframes {
+    pub fn new(max_writes_per_second: Option<f32>) -> Self {
+        Keyframes {
+            interval: max_writes_per_second
+                .filter(|rate| *rate > 0.0)
+                .map_or(Duration::ZERO, |rate| Duration::from_secs_f32(1.0 / rate)),
+            last_sent: None,
+            sum: (0.0, 0.0, 0.0),
+            count: 0,
+        }
+    }
+
+    // Returns the frame to send, if one is due
+    pub fn push(&mut self, frame: Frame, now: Instant) -> Option<Frame> {
+        if self.interval.is_zero() {
+            return Some(frame);
+        }
+
+        let linear = |channel: f32| channel.max(0.0).powf(GAMMA);
+        self.sum.0 += linear(frame.red());
+        self.sum.1 += linear(frame.green());
+        self.sum.2 += linear(frame.blue());
+        self.count += 1;
+
+        if self
+            .last_sent
+            .is_some_and(|sent| now.duration_since(sent) < self.interval)
+        {
+            return None;
+        }
+        let average = |sum: f32| (sum / self.count as f32).powf(1.0 / GAMMA);
+        let keyframe = Rgb::new(
+            average(self.sum.0),
+            average(self.sum.1),
+            average(self.sum.2),
+        );
+        self.reset();
+        self.last_sent = Some(now);
+        Some(keyframe)
+    }
+
+    // Drops frames rendered before the lights went off
+    pub fn reset(&mut self) {
+        self.sum = (0.0, 0.0, 0.0);
+        self.count = 0;
+    }
+}
+
+// Shared by the effects that sweep a 0..1 phase, e.g. around the color wheel
+struct Motion {
+    degrees_per_second: f64,
+    reverse: bool,
+    ping_pong: bool,
+}
+
+impl Motion {
+    fn phase(&self, elapsed: Duration) -> f32 {
+        let mut phase = (self.degrees_per_second * elapsed.as_secs_f64() / 360.0).fract() as f32;
+        if self.ping_pong {
+            phase = 1.0 - (2.0 * phase - 1.0).abs();
+        }
+        if self.reverse {
+            phase = 1.0 - phase;
+        }
+        phase
+    }
+}
+
+struct Rainbow {
+    motion: Motion,
+    hue_range: (f32, f32),
+    saturation: f32,
+}
+
+impl Effect for Rainbow {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        let hue = color::rainbow_hue(self.motion.phase(elapsed), self.hue_range);
+        Rgb::from_color(&Hsv::new(Deg(hue), self.saturation, 1.0))
+    }
+}
+
+struct Gradient {
+    motion: Motion,
+    stops: Vec<GradientStop>,
+}
+
+impl Effect for Gradient {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        color::gradient_color(
+            &self.stops,
+            self.motion.phase(elapsed),
+            !self.motion.ping_pong,
+        )
+    }
+}
+
+// Also drives the candy cane, which is a hold with a short fade between red and white
+struct Hold {
+    palette: Vec<(u8, u8, u8)>,
+    hold: Duration,
+    fade: Duration,
+}
+
+impl Effect for Hold {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        color::hold_color(&self.palette, elapsed, self.hold, self.fade)
+    }
+}
+
+struct Breathing {
+    color: (u8, u8, u8),
+    period: Duration,
+    min_brightness: f32,
+}
+
+impl Effect for Breathing {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        let cycle = (elapsed.as_secs_f64() / self.period.as_secs_f64()).fract();
+        let level = (0.5 - 0.5 * (cycle * TAU).cos()) as f32;
+        let level = self.min_brightness + (1.0 - self.min_brightness) * level;
+        color::scale_rgb(to_frame(self.color), level)
+    }
+}
+
+// Random short flashes over a steady base color, towards white or a color drawn from a palette
+struct Twinkle {
+    color: (u8, u8, u8),
+    // White when empty
+    sparkles: Vec<PaletteColor>,
+    twinkles_per_second: f32,
+    decay: Duration,
+    sparkle: f32,
+    sparkle_color: (u8, u8, u8),
+    last_frame: Duration,
+    random: Random,
+}
+
+impl Twinkle {
+    fn new(
+        color: (u8, u8, u8),
+        sparkles: Vec<PaletteColor>,
+        twinkles_per_second: f32,
+        decay: Duration,
+    ) -> Self {
+        Twinkle {
+            color,
+            sparkles,
+            twinkles_per_second,
+            decay,
+            sparkle: 0.0,
+            sparkle_color: (255, 255, 255),
+            last_frame: Duration::ZERO,
+            random: Random::new(),
+        }
+    }
+}
+
+impl Effect for Twinkle {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        let dt = elapsed.saturating_sub(self.last_frame).as_secs_f32();
+        self.last_frame = elapsed;
+
+        if !self.decay.is_zero() {
+            self.sparkle *= (-dt / self.decay.as_secs_f32()).exp();
+        }
+        if self.random.roll() < self.twinkles_per_second * dt {
+            self.sparkle = 1.0;
+            if !self.sparkles.is_empty() {
+                let weights: Vec<f32> = self.sparkles.iter().map(|color| color.weight).collect();
+                let picked = color::weighted_pick(&weights, self.random.roll(), None);
+                self.sparkle_color = self.sparkles[picked].color;
+            }
+        }
+
+        let (base, towards) = (to_frame(self.color), to_frame(self.sparkle_color));
+        let mix = |channel: f32, to: f32| channel + (to - channel) * self.sparkle;
+        Rgb::new(
+            mix(base.red(), towards.red()),
+            mix(base.green(), towards.green()),
+            mix(base.blue(), towards.blue()),
+        )
+    }
+}
+
+// Colors drawn at random from a palette by weight, each held for between its dwell time and
+// twice that before fading to another
+struct Palette {
+    colors: Vec<PaletteColor>,
+    dwell: Duration,
+    fade: Duration,
+    from: (u8, u8, u8),
+    current: usize,
+    // When the fade to the current color started, and how long it holds after
+    changed_at: Duration,
+    hold: Duration,
+    random: Random,
+}
+
+impl Palette {
+    fn new(colors: Vec<PaletteColor>, dwell: Duration, fade: Duration) -> Self {
+        let mut palette = Palette {
+            colors,
+            dwell,
+            fade,
+            from: (0, 0, 0),
+            current: 0,
+            changed_at: Duration::ZERO,
+            hold: Duration::ZERO,
+            random: Random::new(),
+        };
+        // The first color shows straight away
+        palette.pick(Duration::ZERO, None);
+        palette.from = palette
+            .colors
+            .get(palette.current)
+            .map_or((0, 0, 0), |c| c.color);
+        palette
+    }
+
+    fn pick(&mut self, now: Duration, current: Option<usize>) {
+        let weights: Vec<f32> = self.colors.iter().map(|color| color.weight).collect();
+        self.current = color::weighted_pick(&weights, self.random.roll(), current);
+        let dwell = self
+            .colors
+            .get(self.current)
+            .and_then(|color| color.dwell)
+            .unwrap_or(self.dwell);
+        self.changed_at = now;
+        self.hold = dwell.mul_f32(1.0 + self.random.roll());
+    }
+}
+
+impl Effect for Palette {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        let Some(shown) = self.colors.get(self.current).map(|color| color.color) else {
+            return Rgb::new(0.0, 0.0, 0.0);
+        };
+        if elapsed >= self.changed_at + self.fade + self.hold {
+            self.from = shown;
+            self.pick(elapsed, Some(self.current));
+        }
+        let to = self.colors[self.current].color;
+        let t = (elapsed.saturating_sub(self.changed_at).as_secs_f32()
+            / self.fade.as_secs_f32().max(f32::EPSILON))
+        .min(1.0);
+        let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t) / 255.0;
+        Rgb::new(
+            lerp(self.from.0, to.0),
+            lerp(self.from.1, to.1),
+            lerp(self.from.2, to.2),
+        )
+    }
+}
+
+// xorshift64, plenty for picking when to sparkle or which color comes next
+struct Random(u64);
+
+impl Random {
+    fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_nanos() as u64);
+        Random(seed | 1)
+    }
+
+    // Between 0 and 1
+    fn roll(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+struct Solid {
+    color: (u8, u8, u8),
+}
+
+impl Effect for Solid {
+    fn next_frame(&mut self, _elapsed: Duration) -> Frame {
+        to_frame(self.color)
+    }
+}
+
+struct Strobe {
+    color: (u8, u8, u8),
+    frequency_hz: f32,
+    duty: f32,
+}
+
+impl Effect for Strobe {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        let cycle = (elapsed.as_secs_f64() * self.frequency_hz as f64).fract() as f32;
+        if cycle < self.duty {
+            to_frame(self.color)
+        } else {
+            Rgb::new(0.0, 0.0, 0.0)
+        }
+    }
+}
+
+// Sweeps from the cold hue at or below cold_celsius to the warm hue at or above warm_celsius,
+// easing towards new readings over `smoothing` so the color drifts over the evening
+struct Temperature {
+    outdoor: OutdoorTemperature,
+    cold_celsius: f32,
+    warm_celsius: f32,
+    cold_hue: f32,
+    warm_hue: f32,
+    smoothing: Duration,
+    shown_celsius: Option<f32>,
+    last_frame: Duration,
+}
+
+impl Effect for Temperature {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        let dt = elapsed.saturating_sub(self.last_frame).as_secs_f32();
+        self.last_frame = elapsed;
+
+        // Until the sensor reports, show the middle of the range
+        let target = self
+            .outdoor
+            .get()
+            .unwrap_or((self.cold_celsius + self.warm_celsius) / 2.0);
+        let shown = match self.shown_celsius {
+            Some(shown) if !self.smoothing.is_zero() => {
+                shown + (target - shown) * (1.0 - (-dt / self.smoothing.as_secs_f32()).exp())
+            }
+            _ => target,
+        };
+        self.shown_celsius = Some(shown);
+
+        let t =
+            ((shown - self.cold_celsius) / (self.warm_celsius - self.cold_celsius)).clamp(0.0, 1.0);
+        let hue = self.cold_hue + (self.warm_hue - self.cold_hue) * t;
+        Rgb::from_color(&Hsv::new(Deg(hue.rem_euclid(360.0)), 1.0, 1.0))
+    }
+}
+
+// Pulses with the music: each band jumps up on a beat and falls back over `smoothing`, the
+// hue leans towards the loudest bands and the brightness follows the loudest one
+struct Audio {
+    levels: AudioLevels,
+    smoothing: Duration,
+    min_brightness: f32,
+    shown: [f32; 3],
+    last_frame: Duration,
+}
+
+impl Effect for Audio {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        let dt = elapsed.saturating_sub(self.last_frame).as_secs_f32();
+        self.last_frame = elapsed;
+
+        for (shown, level) in self.shown.iter_mut().zip(self.levels.get()) {
+            *shown = if level >= *shown || self.smoothing.is_zero() {
+                level
+            } else {
+                *shown + (level - *shown) * (1.0 - (-dt / self.smoothing.as_secs_f32()).exp())
+            };
+        }
+        // Averages the band hues as angles, so red and blue meet at purple rather than green
+        let (x, y) =
+            self.shown
+                .iter()
+                .zip(AUDIO_BAND_HUES)
+                .fold((0.0, 0.0), |(x, y), (weight, hue)| {
+                    let (sin, cos) = hue.to_radians().sin_cos();
+                    (x + weight * cos, y + weight * sin)
+                });
+        let hue = y.atan2(x).to_degrees().rem_euclid(360.0);
+        let loudest = self.shown.iter().copied().fold(0.0, f32::max);
+        let value = self.min_brightness + (1.0 - self.min_brightness) * loudest;
+        Rgb::from_color(&Hsv::new(Deg(hue), 1.0, value))
+    }
+}
+
+// Runs the configured effect, except on Christmas Eve: the lights pulse red, faster and brighter
+// as Santa gets closer, and flash through the scene at scene_time
+struct SantaTracker {
+    inner: Box<dyn Effect>,
+    home: (f64, f64),
+    scene_time: (u32, u32),
+    scene_length: Duration,
+    phase: f64,
+    last_frame: Duration,
+}
+
+impl SantaTracker {
+    fn is_scene_time(&self, now: chrono::DateTime<Utc>) -> bool {
+        let (hour, minute) = self.scene_time;
+        if (now.month(), now.day()) != (12, 24) {
+            return false;
+        }
+        let Some(start) = now.date_naive().and_hms_opt(hour, minute, 0) else {
+            return false;
+        };
+        let since = now.timestamp() - start.timestamp();
+        (0..self.scene_length.as_secs() as i64).contains(&since)
+    }
+}
+
+impl Effect for SantaTracker {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        let dt = elapsed.saturating_sub(self.last_frame).as_secs_f64();
+        self.last_frame = elapsed;
+
+        let now = Utc::now();
+        if self.is_scene_time(now) {
+            let step = (elapsed.as_millis() / SANTA_SCENE_STEP.as_millis()) as usize;
+            return to_frame(SANTA_SCENE[step % SANTA_SCENE.len()]);
+        }
+        let Some(distance) = santa::distance_km(self.home, now) else {
+            return self.inner.next_frame(elapsed);
+        };
+
+        // From a slow glow half a world away to a quick, bright pulse overhead
+        let closeness = (1.0 - distance / santa::MAX_DISTANCE_KM).clamp(0.0, 1.0);
+        let period = 4.0 - 3.5 * closeness;
+        self.phase = (self.phase + dt / period).fract();
+        let pulse = 0.5 - 0.5 * (self.phase * TAU).cos();
+        let peak = 0.2 + 0.8 * closeness;
+        color::scale_rgb(to_frame(SANTA_RED), (peak * pulse) as f32)
+    }
+}
+
+// Shows the color of the day's observance, if there is one in an enabled category, and the
+// configured effect on other days
+struct ObservanceDay {
+    inner: Box<dyn Effect>,
+    categories: Vec<String>,
+    // The day turns over at midnight in the schedule's timezone
+    zone: Zone,
+}
+
+impl Effect for ObservanceDay {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        let today = self.zone.date(Utc::now().timestamp());
+        match observances::on(today, &self.categories) {
+            Some(observance) => to_frame(observance.color),
+            None => self.inner.next_frame(elapsed),
+        }
+    }
+}
+
+// Runs the configured effect, after first playing the day's advent scene on each of the first 24
+// days of December
+struct AdventReveal {
+    inner: Box<dyn Effect>,
+    length: Duration,
+    zone: Zone,
+}
+
+impl Effect for AdventReveal {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        let today = self.zone.date(Utc::now().timestamp());
+        if today.month() != 12 || today.day() > 24 || self.length.is_zero() {
+            return self.inner.next_frame(elapsed);
+        }
+        let started = {
+            let mut reveal = ADVENT_REVEAL.lock().unwrap_or_else(PoisonError::into_inner);
+            let last = *reveal;
+            match last {
+                Some((day, started)) if day == today => started,
+                _ => reveal.insert((today, Instant::now())).1,
+            }
+        };
+        let progress = started.elapsed().as_secs_f64() / self.length.as_secs_f64();
+        if progress >= 1.0 {
+            return self.inner.next_frame(elapsed);
+        }
+
+        // Each color swells up and fades out in turn
+        let palette = advent_palette(today);
+        let position = progress * ADVENT_COLORS as f64;
+        let level = (position.fract() * TAU / 2.0).sin() as f32;
+        color::scale_rgb(palette[position as usize], level)
+    }
+}
+
+// Picks the day's colors from the date alone, so a given day always reveals the same scene
+fn advent_palette(date: NaiveDate) -> [Frame; ADVENT_COLORS] {
+    // splitmix64 over the date
+    let mut state = (date.year() as u64) << 16 | date.ordinal() as u64;
+    let mut next = || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+    let base_hue = (next() % 360) as f32;
+    // Spread around the wheel with a little jitter so neighbouring days differ
+    std::array::from_fn(|i| {
+        let jitter = (next() % 60) as f32 - 30.0;
+        let hue = (base_hue + i as f32 * 360.0 / ADVENT_COLORS as f32 + jitter).rem_euclid(360.0);
+        Rgb::from_color(&Hsv::new(Deg(hue), 1.0, 1.0))
+    })
+}
+
+// Catches an effect that panics or renders something other than a color, holding the last good
+// frame, and switches to safe mode once it does so repeatedly, rather than taking the render
+// loop and the daemon down with it
+struct Guarded {
+    inner: Box<dyn Effect>,
+    kind: EffectKind,
+    failed_at: VecDeque<Instant>,
+    safe_mode: bool,
+    last_good: Frame,
+}
+
+impl Guarded {
+    fn new(inner: Box<dyn Effect>, kind: EffectKind) -> Self {
+        Guarded {
+            inner,
+            kind,
+            failed_at: VecDeque::new(),
+            safe_mode: false,
+            last_good: to_frame(SAFE_MODE_COLOR),
+        }
+    }
+
+    fn failed(&mut self, reason: &str) {
+        let now = Instant::now();
+        self.failed_at
+            .retain(|at| now.duration_since(*at) < SAFE_MODE_WINDOW);
+        self.failed_at.push_back(now);
+        warn!("The {} effect failed: {}", self.kind.name(), reason);
+        if self.failed_at.len() >= SAFE_MODE_FAILURES {
+            let reason = format!(
+                "the {} effect failed {} times within {}s, last with: {}",
+                self.kind.name(),
+                self.failed_at.len(),
+                SAFE_MODE_WINDOW.as_secs(),
+                reason
+            );
+            error!("Falling back to steady warm white: {}", reason);
+            *SAFE_MODE.lock().unwrap_or_else(PoisonError::into_inner) = Some(reason);
+            self.safe_mode = true;
+        }
+    }
+}
+
+impl Effect for Guarded {
+    fn next_frame(&mut self, elapsed: Duration) -> Frame {
+        if self.safe_mode {
+            return to_frame(SAFE_MODE_COLOR);
+        }
+        GUARDED.set(true);
+        let rendered = panic::catch_unwind(AssertUnwindSafe(|| self.inner.next_frame(elapsed)));
+        GUARDED.set(false);
+        match rendered {
+            Ok(frame)
+                if [frame.red(), frame.green(), frame.blue()]
+                    .iter()
+                    .all(|c| c.is_finite()) =>
+            {
+                self.last_good = frame;
+                return frame;
+            }
+            Ok(_) => self.failed("it rendered a color that is not a number"),
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "it panicked".to_string());
+                self.failed(&message);
+            }
+        }
+        if self.safe_mode {
+            to_frame(SAFE_MODE_COLOR)
+        } else {
+            self.last_good
+        }
+    }
+}
+
+fn to_frame((r, g, b): (u8, u8, u8)) -> Frame {
+    Rgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Broken;
+
+    impl Effect for Broken {
+        fn next_frame(&mut self, elapsed: Duration) -> Frame {
+            if elapsed.is_zero() {
+                return Rgb::new(0.0, 1.0, 0.0);
+            }
+            if elapsed == Duration::from_secs(1) {
+                return Rgb::new(f32::NAN, 0.0, 0.0);
+            }
+            panic!("out of tinsel");
+        }
+    }
+
+    #[test]
+    fn effects_that_keep_failing_fall_back_to_safe_mode() {
+        let mut guarded = Guarded::new(Box::new(Broken), EffectKind::Rainbow);
+        let green = Rgb::new(0.0, 1.0, 0.0);
+        assert_eq!(guarded.next_frame(Duration::ZERO), green);
+        // The first failures hold the last good frame
+        assert_eq!(guarded.next_frame(Duration::from_secs(1)), green);
+        assert_eq!(guarded.next_frame(Duration::from_secs(2)), green);
+        assert!(!guarded.safe_mode);
+        assert_eq!(
+            guarded.next_frame(Duration::from_secs(3)),
+            to_frame(SAFE_MODE_COLOR)
+        );
+        assert!(safe_mode().is_some_and(|reason| reason.contains("out of tinsel")));
+        assert_eq!(
+            guarded.next_frame(Duration::ZERO),
+            to_frame(SAFE_MODE_COLOR)
+        );
+    }
+}
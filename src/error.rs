@@ -0,0 +1,57 @@
+use thiserror::Error;
+
+// Exit codes follow sysexits(3) so the systemd unit can tell misconfiguration apart from
+// transient Bluetooth trouble
+#[derive(Debug, Error)]
+pub enum Failure {
+    #[error("{0}")]
+    Usage(String),
+    #[error("Unable to find Bluetooth adapters")]
+    NoAdapter,
+    #[error("Unable to find {0}")]
+    DeviceNotFound(&'static str),
+    #[error("Invalid configuration: {0}")]
+    ConfigInvalid(String),
+    #[error("Bluetooth stack failure: {0}")]
+    BleStack(#[from] btleplug::Error),
+    #[error("Update failed: {0}")]
+    Update(String),
+    #[error("Backup failed: {0}")]
+    Backup(String),
+    #[error("Cannot save scene: {0}")]
+    Scene(String),
+    #[error("Soak test failed: {0}")]
+    Soak(String),
+    #[error("Storage failed: {0}")]
+    Storage(String),
+    // Another instance drives the lights
+    #[error("Lights in use: {0}")]
+    InUse(String),
+}
+
+impl Failure {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Failure::Usage(_) => 64,
+            Failure::NoAdapter => 69,
+            Failure::DeviceNotFound(_) => 68,
+            Failure::ConfigInvalid(_) => 78,
+            Failure::BleStack(_) => 76,
+            Failure::Update(_) => 75,
+            Failure::Backup(_) => 74,
+            Failure::Scene(_) => 73,
+            Failure::Soak(_) => 70,
+            Failure::Storage(_) => 74,
+            Failure::InUse(_) => 75,
+        }
+    }
+
+    // Bluetooth trouble that may clear up by itself, e.g. a light that is not advertising yet
+    // or an adapter still coming up at boot
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Failure::NoAdapter | Failure::DeviceNotFound(_) | Failure::BleStack(_)
+        )
+    }
+}
@@ -0,0 +1,150 @@
+// Turns a location name, e.g. "Budapest, HU", into coordinates. The geocoder is asked once and
+// the answer kept in the cache directory, so later starts work offline; without a geocoder,
+// or when it cannot be reached, the bundled city table answers instead. Any service speaking
+// Open-Meteo's search API works, queried with curl.
+use crate::{
+    cache, cities,
+    remote::{number_field, string_field},
+};
+use log::{info, warn};
+use std::{fs, path::PathBuf, process::Command};
+
+pub const DEFAULT_GEOCODER: &str = "https://geocoding-api.open-meteo.com/v1/search";
+const CURL_TIMEOUT_SECS: &str = "10";
+const CACHE_FILE: &str = "locations";
+
+/// The coordinates of `name`, "City" or "City, CC" with a two-letter country code.
+pub fn locate(name: &str, geocoder: Option<&str>) -> Option<(f64, f64)> {
+    let name = name.trim();
+    if let Some(cached) = read_cache().and_then(|cache| cached(&cache, name)) {
+        return Some(cached);
+    }
+    if let Some(found) = geocoder.and_then(|geocoder| ask(geocoder, name)) {
+        info!("Located {:?} at {}, {}", name, found.0, found.1);
+        remember(name, found);
+        return Some(found);
+    }
+    cities::lookup(name)
+}
+
+fn ask(geocoder: &str, name: &str) -> Option<(f64, f64)> {
+    let (city, country) = split(name);
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", "--get"])
+        .args(["--max-time", CURL_TIMEOUT_SECS])
+        .arg("--data-urlencode")
+        .arg(format!("name={}", city))
+        .args(["--data", "count=10", geocoder])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            best_result(&String::from_utf8_lossy(&output.stdout), country)
+        }
+        Ok(output) => {
+            warn!(
+                "Cannot look up {:?}: {}",
+                name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            warn!("Cannot run curl to look up {:?}: {}", name, e);
+            None
+        }
+    }
+}
+
+fn split(name: &str) -> (&str, Option<&str>) {
+    match name.split_once(',') {
+        Some((city, country)) => (city.trim(), Some(country.trim())),
+        None => (name, None),
+    }
+}
+
+// The first result in `country`, or the first at all without one. The results are flat
+// objects, so splitting at each } is enough to tell them apart.
+fn best_result(json: &str, country: Option<&str>) -> Option<(f64, f64)> {
+    let (_, results) = json.split_once("\"results\"")?;
+    results.split('}').find_map(|result| {
+        let in_country = country.is_none_or(|country| {
+            string_field(result, "country_code")
+                .is_some_and(|code| code.eq_ignore_ascii_case(country))
+        });
+        if !in_country {
+            return None;
+        }
+        Some((
+            number_field(result, "latitude")?,
+            number_field(result, "longitude")?,
+        ))
+    })
+}
+
+fn cache_path() -> Option<PathBuf> {
+    cache::dir().map(|dir| dir.join(CACHE_FILE))
+}
+
+fn read_cache() -> Option<String> {
+    fs::read_to_string(cache_path()?).ok()
+}
+
+// One "name<TAB>latitude<TAB>longitude" line per location
+fn cached(cache: &str, name: &str) -> Option<(f64, f64)> {
+    cache.lines().find_map(|line| {
+        let mut fields = line.split('\t');
+        if !fields.next()?.eq_ignore_ascii_case(name) {
+            return None;
+        }
+        Some((fields.next()?.parse().ok()?, fields.next()?.parse().ok()?))
+    })
+}
+
+fn remember(name: &str, (latitude, longitude): (f64, f64)) {
+    let Some(path) = cache_path() else {
+        return;
+    };
+    let mut cache = read_cache().unwrap_or_default();
+    cache.push_str(&format!("{}\t{}\t{}\n", name, latitude, longitude));
+    let written = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(&path, cache));
+    if let Err(e) = written {
+        warn!("Cannot cache the location of {:?}: {}", name, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Trimmed from what Open-Meteo answers for name=Szeged
+    const RESULTS: &str = r#"{"results":[{"id":715429,"name":"Szeged","latitude":46.253,
+        "longitude":20.14824,"country_code":"HU","postcodes":["6700"]},{"id":1,"name":"Szeged",
+        "latitude":-1.5e1,"longitude":30.5,"country_code":"XX"}],"generationtime_ms":0.6}"#;
+
+    #[test]
+    fn the_result_in_the_country_is_picked() {
+        assert_eq!(best_result(RESULTS, None), Some((46.253, 20.14824)));
+        assert_eq!(best_result(RESULTS, Some("hu")), Some((46.253, 20.14824)));
+        assert_eq!(best_result(RESULTS, Some("XX")), Some((-15.0, 30.5)));
+        assert_eq!(best_result(RESULTS, Some("DE")), None);
+        // Open-Meteo leaves out results when nothing matches
+        assert_eq!(best_result(r#"{"generationtime_ms":0.3}"#, None), None);
+    }
+
+    #[test]
+    fn cached_locations_are_found_by_name() {
+        let cache = "Budapest, HU\t47.4979\t19.0402\nSzeged\t46.253\t20.14824\n";
+        assert_eq!(cached(cache, "budapest, hu"), Some((47.4979, 19.0402)));
+        assert_eq!(cached(cache, "Szeged"), Some((46.253, 20.14824)));
+        assert_eq!(cached(cache, "Budapest"), None);
+    }
+
+    #[test]
+    fn names_split_into_city_and_country() {
+        assert_eq!(split("Budapest, HU"), ("Budapest", Some("HU")));
+        assert_eq!(split("Budapest"), ("Budapest", None));
+    }
+}
@@ -0,0 +1,302 @@
+use crate::{
+    color,
+    config::{ConnectionConfig, DeviceConfig},
+    controller::{DeviceInformation, LightController},
+    error::Failure,
+    notify,
+    transition::interpolate_rgb,
+};
+use futures::{future::join_all, stream, StreamExt};
+use log::info;
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time;
+
+/// Several light strings driven together. Commands go to every light, and a failure on one
+/// light does not stop the others from being written. Only critical lights fail a command;
+/// best-effort ones are left to the device supervisor.
+pub struct LightGroup {
+    lights: Vec<LightController>,
+    critical: Vec<bool>,
+    concurrency: usize,
+    stagger: Duration,
+    progress: ConnectProgress,
+}
+
+/// How far the last connect or reconnect of a group got, for the status endpoint.
+#[derive(Clone, Default)]
+pub struct ConnectProgress(Arc<(AtomicUsize, AtomicUsize)>);
+
+impl ConnectProgress {
+    /// The lights connected so far, and how many there are.
+    pub fn get(&self) -> (usize, usize) {
+        let (connected, total) = &*self.0;
+        (
+            connected.load(Ordering::Relaxed),
+            total.load(Ordering::Relaxed),
+        )
+    }
+
+    fn start(&self, total: usize) {
+        self.0 .0.store(0, Ordering::Relaxed);
+        self.0 .1.store(total, Ordering::Relaxed);
+    }
+
+    // Returns how many are connected now
+    fn connected(&self) -> usize {
+        self.0 .0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+impl LightGroup {
+    pub fn new(
+        lights: Vec<LightController>,
+        device: &DeviceConfig,
+        connection: &ConnectionConfig,
+    ) -> Self {
+        LightGroup {
+            critical: lights
+                .iter()
+                .map(|light| device.is_critical(&light.address()))
+                .collect(),
+            lights,
+            concurrency: connection.startup_concurrency.max(1),
+            stagger: connection.startup_stagger,
+            progress: ConnectProgress::default(),
+        }
+    }
+
+    /// Finds up to `device.count` lights on the configured adapter.
+    pub async fn find(
+        device: &DeviceConfig,
+        connection: &ConnectionConfig,
+    ) -> Result<Self, Failure> {
+        Ok(LightGroup::new(
+            LightController::find_all(device, device.count).await?,
+            device,
+            connection,
+        ))
+    }
+
+    pub fn lights(&self) -> &[LightController] {
+        &self.lights
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    /// The device information of the first light; a group is normally one model.
+    pub fn device_information(&self) -> DeviceInformation {
+        self.lights
+            .first()
+            .map(LightController::device_information)
+            .unwrap_or_default()
+    }
+
+    pub fn is_critical(&self, i: usize) -> bool {
+        self.critical.get(i).copied().unwrap_or(true)
+    }
+
+    pub fn progress(&self) -> ConnectProgress {
+        self.progress.clone()
+    }
+
+    pub async fn connect(&self) -> Result<(), Failure> {
+        self.paced(|_| false, |light| light.connect()).await
+    }
+
+    pub async fn disconnect(&self) -> Result<(), Failure> {
+        self.all(self.lights.iter().map(|light| light.disconnect()))
+            .await
+    }
+
+    /// Reconnects every light but those `skip` picks by index, e.g. shed ones.
+    pub async fn reconnect(&self, skip: impl Fn(usize) -> bool) -> Result<(), Failure> {
+        self.paced(skip, |light| light.reconnect()).await
+    }
+
+    // Connects at most `concurrency` lights at a time, each starting `stagger` after the one
+    // before, and reports every light that comes up
+    async fn paced<'a, F>(
+        &'a self,
+        skip: impl Fn(usize) -> bool,
+        connect: impl Fn(&'a LightController) -> F,
+    ) -> Result<(), Failure>
+    where
+        F: Future<Output = Result<(), Failure>>,
+    {
+        let lights: Vec<_> = self
+            .lights
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !skip(*i))
+            .collect();
+        let total = lights.len();
+        self.progress.start(total);
+        let connect = &connect;
+        let results: Vec<_> = stream::iter(lights.into_iter().enumerate())
+            .then(|(n, (i, light))| async move {
+                if n > 0 {
+                    time::sleep(self.stagger).await;
+                }
+                (i, light)
+            })
+            .map(|(i, light)| async move {
+                let result = connect(light).await;
+                match &result {
+                    Ok(()) if total > 1 => {
+                        let connected = self.progress.connected();
+                        let progress = format!("Connected {} of {} lights", connected, total);
+                        info!("{}", progress);
+                        notify::status(&progress);
+                    }
+                    Ok(()) => {
+                        self.progress.connected();
+                    }
+                    Err(e) if !self.is_critical(i) => {
+                        info!(
+                            "Best-effort light {} did not connect: {}",
+                            light.address(),
+                            e
+                        )
+                    }
+                    Err(_) => {}
+                }
+                (i, result)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await;
+        results
+            .into_iter()
+            .filter(|(i, _)| self.is_critical(*i))
+            .try_for_each(|(_, result)| result)
+    }
+
+    /// Sets `disconnected` whenever any light in the group drops the connection.
+    pub async fn watch_disconnects(&self, disconnected: &AtomicBool) -> Result<(), Failure> {
+        self.all(
+            self.lights
+                .iter()
+                .map(|light| light.watch_disconnects(disconnected)),
+        )
+        .await
+    }
+
+    // The lights share one adapter, so scanning is toggled once for all of them
+    pub async fn set_scanning(&self, scanning: bool) -> Result<(), Failure> {
+        match self.lights.first() {
+            Some(light) => light.set_scanning(scanning).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Records each light's signal strength for `christmas-lights devices`.
+    pub async fn sample_rssi(&self) {
+        join_all(self.lights.iter().map(|light| light.sample_rssi())).await;
+    }
+
+    /// Whether every critical light is connected.
+    pub async fn is_connected(&self) -> bool {
+        join_all(self.lights.iter().map(|light| light.is_connected()))
+            .await
+            .into_iter()
+            .enumerate()
+            .all(|(i, connected)| connected || !self.is_critical(i))
+    }
+
+    pub async fn set_color(&self, rgb: (u8, u8, u8)) -> Result<(), Failure> {
+        self.all(self.lights.iter().map(|light| light.set_color(rgb)))
+            .await
+    }
+
+    /// Fades each light from its color in `from` to `to`, writing every `step`. Lights without
+    /// a color in `from` start from black.
+    pub async fn fade(
+        &self,
+        from: &[(u8, u8, u8)],
+        to: (u8, u8, u8),
+        duration: Duration,
+        step: Duration,
+    ) -> Result<(), Failure> {
+        let started = Instant::now();
+        loop {
+            let t = started.elapsed().as_secs_f32() / duration.as_secs_f32().max(f32::EPSILON);
+            self.all(self.lights.iter().enumerate().map(|(i, light)| {
+                let from = from.get(i).copied().unwrap_or((0, 0, 0));
+                light.set_color(interpolate_rgb(from, to, t))
+            }))
+            .await?;
+            if t >= 1.0 {
+                return Ok(());
+            }
+            time::sleep(step).await;
+        }
+    }
+
+    /// Writes every light its color in `frame` at once, on the white channel where it has one;
+    /// lights without a color are left alone. A light that fails is tried again on its own, up
+    /// to `retries` times. The outcome for each light, by index.
+    pub async fn write_frame(
+        &self,
+        frame: &[Option<(u8, u8, u8)>],
+        retries: usize,
+    ) -> Vec<Option<Result<(), Failure>>> {
+        join_all(
+            self.lights
+                .iter()
+                .zip(frame)
+                .map(|(light, rgb)| async move {
+                    let rgb = (*rgb)?;
+                    let write = || async {
+                        if light.has_white_channel() {
+                            light.set_color_rgbw(color::rgb_to_rgbw(rgb)).await
+                        } else {
+                            light.set_color(rgb).await
+                        }
+                    };
+                    let mut written = write().await;
+                    for _ in 0..retries {
+                        if written.is_ok() {
+                            break;
+                        }
+                        written = write().await;
+                    }
+                    Some(written)
+                }),
+        )
+        .await
+    }
+
+    pub async fn turn_on(&self) -> Result<(), Failure> {
+        self.all(self.lights.iter().map(|light| light.turn_on()))
+            .await
+    }
+
+    pub async fn turn_off(&self) -> Result<(), Failure> {
+        self.all(self.lights.iter().map(|light| light.turn_off()))
+            .await
+    }
+
+    // Runs every command to completion and reports the first failure of a critical light
+    async fn all(
+        &self,
+        commands: impl Iterator<Item = impl Future<Output = Result<(), Failure>>>,
+    ) -> Result<(), Failure> {
+        join_all(commands)
+            .await
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| self.is_critical(*i))
+            .try_for_each(|(_, result)| result)
+    }
+}
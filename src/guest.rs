@@ -0,0 +1,66 @@
+// Guest passes: tokens for the guest page of the HTTP API, which offers only the scenes in
+// http.guest_scenes, for a party or visitors. `christmas-lights guest` asks the running daemon
+// for one over the control socket and prints its URL and a QR code. A pass runs out after its
+// time, and so does whatever a guest picked, so the schedule takes over again.
+use crate::remote::same_token;
+use std::{
+    fs::File,
+    io::{self, Read},
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+const TOKEN_BYTES: usize = 16;
+
+struct Pass {
+    token: String,
+    expires: Instant,
+}
+
+static PASSES: Mutex<Vec<Pass>> = Mutex::new(Vec::new());
+
+/// A new pass lasting `valid_for`, by its token.
+pub fn issue(valid_for: Duration) -> io::Result<String> {
+    let token = random_token()?;
+    PASSES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Pass {
+            token: token.clone(),
+            expires: Instant::now() + valid_for,
+        });
+    Ok(token)
+}
+
+/// How long the pass with this token has left, or None when there is no such pass any more.
+pub fn remaining(token: &str) -> Option<Duration> {
+    let now = Instant::now();
+    let mut passes = PASSES.lock().unwrap_or_else(PoisonError::into_inner);
+    passes.retain(|pass| pass.expires > now);
+    passes
+        .iter()
+        .find(|pass| same_token(token, &pass.token))
+        .map(|pass| pass.expires - now)
+}
+
+fn random_token() -> io::Result<String> {
+    let mut bytes = [0; TOKEN_BYTES];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_run_out() {
+        let token = issue(Duration::from_secs(60)).unwrap();
+        assert_eq!(token.len(), 2 * TOKEN_BYTES);
+        assert!(remaining(&token).is_some_and(|left| left <= Duration::from_secs(60)));
+        assert_eq!(remaining("0123456789abcdef0123456789abcdef"), None);
+        let short = issue(Duration::ZERO).unwrap();
+        assert_ne!(short, token);
+        assert_eq!(remaining(&short), None);
+    }
+}
@@ -0,0 +1,355 @@
+// Two histories: the last few seconds of BLE writes and rendered frames, kept in memory for
+// post-mortems, and the log of commands that changed the lights with who asked for them, kept in
+// storage so `history commands` can tell who turned the tree purple.
+use crate::{
+    color,
+    effects::EffectKind,
+    remote::{RemoteCommand, Source},
+    storage,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use log::{info, warn};
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+const COMMAND_HISTORY_DURATION: Duration = Duration::from_secs(10);
+// Where storage keeps the command log
+pub const KEY: &str = "commands";
+// Enough for the whole season at a few dozen commands a day
+const MAX_LOGGED_COMMANDS: usize = 5000;
+
+struct RecordedCommand {
+    sent_at: DateTime<Utc>,
+    recorded_at: Instant,
+    command: Vec<u8>,
+    succeeded: bool,
+}
+
+struct RecordedFrame {
+    rendered_at: DateTime<Utc>,
+    recorded_at: Instant,
+    light: usize,
+    color: (u8, u8, u8),
+}
+
+// The last few seconds of commands and of the frames rendered for each light, kept for
+// post-mortem debugging
+static COMMAND_HISTORY: Mutex<VecDeque<RecordedCommand>> = Mutex::new(VecDeque::new());
+static FRAME_HISTORY: Mutex<VecDeque<RecordedFrame>> = Mutex::new(VecDeque::new());
+// Commands logged since the last flush to storage, oldest first
+static UNSAVED_COMMANDS: Mutex<VecDeque<LoggedCommand>> = Mutex::new(VecDeque::new());
+
+pub fn record(command: Vec<u8>, succeeded: bool) {
+    let Ok(mut history) = COMMAND_HISTORY.lock() else {
+        return;
+    };
+    let now = Instant::now();
+    while history
+        .front()
+        .is_some_and(|oldest| now - oldest.recorded_at > COMMAND_HISTORY_DURATION)
+    {
+        history.pop_front();
+    }
+    history.push_back(RecordedCommand {
+        sent_at: chrono::Utc::now(),
+        recorded_at: now,
+        command,
+        succeeded,
+    });
+}
+
+/// Records the color rendered for the `light`th light.
+pub fn record_frame(light: usize, color: (u8, u8, u8)) {
+    let Ok(mut history) = FRAME_HISTORY.lock() else {
+        return;
+    };
+    let now = Instant::now();
+    while history
+        .front()
+        .is_some_and(|oldest| now - oldest.recorded_at > COMMAND_HISTORY_DURATION)
+    {
+        history.pop_front();
+    }
+    history.push_back(RecordedFrame {
+        rendered_at: chrono::Utc::now(),
+        recorded_at: now,
+        light,
+        color,
+    });
+}
+
+// Uses try_lock as this also runs from the panic hook
+pub fn dump() {
+    match FRAME_HISTORY.try_lock() {
+        Ok(history) => {
+            info!("Last {} frames:", history.len());
+            for recorded in history.iter() {
+                let (r, g, b) = recorded.color;
+                info!(
+                    "{} light {} #{:02x}{:02x}{:02x}",
+                    recorded.rendered_at.format("%H:%M:%S%.3f"),
+                    recorded.light,
+                    r,
+                    g,
+                    b
+                );
+            }
+        }
+        Err(_) => warn!("Frame history is unavailable"),
+    }
+    let Ok(history) = COMMAND_HISTORY.try_lock() else {
+        warn!("Command history is unavailable");
+        return;
+    };
+    info!("Last {} commands:", history.len());
+    for recorded in history.iter() {
+        let bytes: Vec<String> = recorded
+            .command
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        info!(
+            "{} {} {}",
+            recorded.sent_at.format("%H:%M:%S%.3f"),
+            bytes.join(" "),
+            if recorded.succeeded { "ok" } else { "failed" }
+        );
+    }
+}
+
+/// A command as kept in the command log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoggedCommand {
+    pub at: DateTime<Utc>,
+    // As Source and RemoteCommand print, e.g. "http 192.168.1.20" and "color #800080"
+    pub source: String,
+    pub command: String,
+}
+
+/// Adds a command to the command log. It stays in memory until the next flush_commands, so
+/// logging never waits on storage.
+pub fn log_command(source: Source, command: &RemoteCommand) {
+    let mut unsaved = UNSAVED_COMMANDS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if unsaved.len() == MAX_LOGGED_COMMANDS {
+        unsaved.pop_front();
+    }
+    unsaved.push_back(LoggedCommand {
+        at: Utc::now(),
+        source: source.to_string(),
+        command: command.to_string(),
+    });
+}
+
+/// Adds the commands logged since the last flush to the log in storage, dropping the oldest
+/// past the limit. The CLI and the daemon both write the log, so one may rarely lose the other's
+/// entries when they flush at once.
+pub fn flush_commands() {
+    let unsaved: Vec<_> = UNSAVED_COMMANDS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .drain(..)
+        .collect();
+    if unsaved.is_empty() {
+        return;
+    }
+    let count = unsaved.len();
+    let mut logged = saved_commands();
+    logged.extend(unsaved);
+    let excess = logged.len().saturating_sub(MAX_LOGGED_COMMANDS);
+    if let Err(e) = storage::current().write(KEY, format_commands(&logged[excess..]).as_bytes()) {
+        warn!("Cannot save {} logged commands: {}", count, e);
+    }
+}
+
+/// The command log, oldest first, with the commands not flushed yet.
+pub fn commands() -> Vec<LoggedCommand> {
+    let mut logged = saved_commands();
+    logged.extend(
+        UNSAVED_COMMANDS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .cloned(),
+    );
+    logged
+}
+
+/// What the latest undo in the command log goes back to: the show or the brightness from before
+/// the change it takes back, `shown` or full brightness when the log has none, or None when
+/// there was nothing left to undo. Each undo takes back one more change, and changes that run
+/// out by themselves are left alone.
+pub fn undo(logged: &[LoggedCommand], shown: RemoteCommand) -> Option<RemoteCommand> {
+    let mut shows = Vec::new();
+    let mut levels = Vec::new();
+    // Which of the two each change went to, in order
+    let mut changes = Vec::new();
+    let mut undone = None;
+    for entry in logged {
+        if entry.command == "undo" {
+            undone = changes.pop().map(|is_show| {
+                if is_show {
+                    shows.pop();
+                    shows.last().cloned().unwrap_or_else(|| shown.clone())
+                } else {
+                    levels.pop();
+                    levels
+                        .last()
+                        .cloned()
+                        .unwrap_or(RemoteCommand::Brightness(1.0))
+                }
+            });
+            continue;
+        }
+        match parse_change(&entry.command) {
+            Some(level @ RemoteCommand::Brightness(_)) => {
+                levels.push(level);
+                changes.push(false);
+            }
+            Some(show) => {
+                shows.push(show);
+                changes.push(true);
+            }
+            None => {}
+        }
+    }
+    undone
+}
+
+// A logged command that changed what the lights show or how brightly, back from its text
+fn parse_change(command: &str) -> Option<RemoteCommand> {
+    let (name, argument) = command.split_once(' ')?;
+    match name {
+        "color" => color::parse_hex(argument).map(RemoteCommand::Color),
+        "effect" => EffectKind::from_name(argument).map(RemoteCommand::Effect),
+        "brightness" => argument.parse().ok().map(RemoteCommand::Brightness),
+        "palette" => Some(RemoteCommand::Palette(argument.to_string())),
+        "scene" if !argument.starts_with("save ") => {
+            Some(RemoteCommand::Scene(argument.to_string()))
+        }
+        _ => None,
+    }
+}
+
+fn saved_commands() -> Vec<LoggedCommand> {
+    match storage::current().read(KEY) {
+        Ok(contents) => contents
+            .map(|contents| parse_commands(&String::from_utf8_lossy(&contents)))
+            .unwrap_or_default(),
+        Err(e) => {
+            warn!("Cannot read the command log: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+// One tab-separated "timestamp source command" line each
+fn format_commands(logged: &[LoggedCommand]) -> String {
+    logged
+        .iter()
+        .map(|entry| {
+            format!(
+                "{}\t{}\t{}\n",
+                entry.at.timestamp(),
+                entry.source,
+                // Names come from outside and must not break the line apart
+                entry.command.replace(['\t', '\n', '\r'], " ")
+            )
+        })
+        .collect()
+}
+
+// Lines that do not parse are skipped, like damaged daily totals
+fn parse_commands(contents: &str) -> Vec<LoggedCommand> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            Some(LoggedCommand {
+                at: Utc
+                    .timestamp_opt(fields.next()?.parse().ok()?, 0)
+                    .single()?,
+                source: fields.next()?.to_string(),
+                command: fields.next()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged(source: &str, command: &str) -> LoggedCommand {
+        LoggedCommand {
+            at: Utc.timestamp_opt(1_733_076_000, 0).unwrap(),
+            source: source.to_string(),
+            command: command.to_string(),
+        }
+    }
+
+    #[test]
+    fn commands_are_logged_in_memory_up_to_the_limit() {
+        for _ in 0..=MAX_LOGGED_COMMANDS {
+            log_command(Source::HttpToken("phone".to_string()), &RemoteCommand::On);
+        }
+        let unsaved = UNSAVED_COMMANDS.lock().unwrap();
+        assert_eq!(unsaved.len(), MAX_LOGGED_COMMANDS);
+        assert_eq!(unsaved.back().unwrap().source, "http token phone");
+        assert_eq!(unsaved.back().unwrap().command, "on");
+    }
+
+    #[test]
+    fn the_command_log_reads_back() {
+        let log = vec![
+            logged("http 192.168.1.20", "color #800080"),
+            logged("schedule", "off"),
+        ];
+        assert_eq!(parse_commands(&format_commands(&log)), log);
+    }
+
+    #[test]
+    fn names_cannot_break_a_line_apart() {
+        let log = [logged("mqtt", "arrived Anna\n1\tcli\toff")];
+        assert_eq!(
+            parse_commands(&format_commands(&log)),
+            vec![logged("mqtt", "arrived Anna 1 cli off")]
+        );
+    }
+
+    #[test]
+    fn undo_goes_back_one_change_at_a_time() {
+        let log: Vec<LoggedCommand> = [
+            "effect twinkle",
+            "brightness 0.5",
+            "color #ff00ff",
+            "color #00ff00 for 60s",
+            "scene save ugly",
+            "undo",
+            "undo",
+            "undo",
+        ]
+        .iter()
+        .map(|command| logged("http guest", command))
+        .collect();
+        let shown = RemoteCommand::Effect(EffectKind::Rainbow);
+        let after = |undos: usize| undo(&log[..5 + undos], shown.clone());
+
+        assert_eq!(after(0), None);
+        assert_eq!(after(1), Some(RemoteCommand::Effect(EffectKind::Twinkle)));
+        assert_eq!(after(2), Some(RemoteCommand::Brightness(1.0)));
+        assert_eq!(after(3), Some(shown.clone()));
+        let nothing_left = [log.clone(), vec![logged("cli", "undo")]].concat();
+        assert_eq!(undo(&nothing_left, shown), None);
+    }
+
+    #[test]
+    fn damaged_lines_are_skipped() {
+        let contents = "yesterday\tcli\ton\n1733076000\tcli\n1733076000\tcli\ton\n";
+        assert_eq!(parse_commands(contents), vec![logged("cli", "on")]);
+    }
+}
@@ -0,0 +1,33 @@
+use dbus::blocking::{stdintf::org_freedesktop_dbus::Properties, Connection};
+use std::time::Duration;
+
+const CPU_TEMPERATURE_PATH: &str = "/sys/class/thermal/thermal_zone0/temp";
+const HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
+
+pub fn is_on_battery() -> bool {
+    let Ok(conn) = Connection::new_system() else {
+        return false;
+    };
+    let upower = conn.with_proxy(
+        "org.freedesktop.UPower",
+        "/org/freedesktop/UPower",
+        Duration::from_secs(1),
+    );
+    upower
+        .get("org.freedesktop.UPower", "OnBattery")
+        .unwrap_or(false)
+}
+
+pub fn cpu_temperature() -> Option<f32> {
+    let millidegrees: f32 = std::fs::read_to_string(CPU_TEMPERATURE_PATH)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(millidegrees / 1000.0)
+}
+
+pub fn hostname() -> Option<String> {
+    let hostname = std::fs::read_to_string(HOSTNAME_PATH).ok()?;
+    Some(hostname.trim().to_string()).filter(|hostname| !hostname.is_empty())
+}
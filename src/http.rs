@@ -0,0 +1,1220 @@
+// Small HTTP/1.1 API for driving the lights from the LAN. Each connection carries one request
+// with a plain-text body:
+//
+//   GET  /                      a dashboard page for phones, built into the binary, that
+//                               drives the lights through the endpoints below
+//   GET  /openapi.json          an OpenAPI 3 description of all of them, for generating clients
+//   GET  /tree                  a virtual tree mirroring the lights' colors as they change
+//   GET  /frames                the WebSocket the tree page follows, see mirror.rs; takes the
+//                               token as ?token=, since browsers cannot set the header there
+//   GET  /state                 {"on":true,"effect":"rainbow","color":"#ff8c28","brightness":1.0,
+//                                "connected":3,"lights":4,"version":"1.0.0","model":"AL-100",
+//                                "firmware":"1.4.2","plan":{...}} plus "update":"1.1.0" when one
+//                                is available
+//   POST /power       on|off
+//   POST /color       #rrggbb   switches to the solid effect
+//   POST /effect      <name>
+//   POST /brightness  0.0-1.0
+//   POST /temperature <°C>      outdoor reading for the temperature effect
+//   POST /arrived     <name>    plays the welcome scene for someone coming home
+//   POST /scene       <name>    shows a configured or saved scene
+//   POST /scene/save  <name>    saves what the lights show now as a scene
+//   POST /palette     <name>    draws colors from one of the configured palettes
+//   POST /undo                  goes back to the color, effect or brightness before the last
+//                               change, by the command log
+//   GET  /agenda                the actions planned ahead, see agenda.rs
+//   POST /agenda      <actions> adds one "<RFC 3339 time> <command>" action per line, e.g.
+//                               "2024-12-24T17:00:00+01:00 power on", kept across restarts
+//   POST /agenda/clear          drops the actions still to come
+//   POST /trigger?event=<name>  a webhook: reports the event webhook/<name> to the rules, with
+//                               the body as its value
+//   GET  /guest?token=<pass>    the guest page, offering the scenes in http.guest_scenes
+//   POST /guest/scene?token=<pass> <name>
+//                               shows one of them until the guest pass runs out
+//   GET  /schedule              {"on":"2024-12-01T15:53:00Z","off":"2024-12-02T06:12:00Z"}, the
+//                               current or next on window, or nulls when there is none
+//   GET  /metrics/samples       [{"time":"2024-12-01T17:00:00Z","on":true,"brightness":0.8}]
+//   GET  /metrics/daily         [{"date":"2024-12-01","on_hours":14.5,"reconnects":2}]
+//   GET  /metrics               BLE link counters and gauges for Prometheus to scrape
+//
+// The metrics take ?from=&to= in Unix milliseconds, as Grafana's Infinity and JSON API data
+// sources send ${__from} and ${__to}. Builds without the `metrics` feature answer them with 404.
+//
+// /state leaves out the model and firmware the light did not report, and has "plan": null until
+// the first daily plan is made. "connected" counts the lights up so far while they (re)connect.
+// "safe_mode" says why the lights show steady warm white, when an effect kept failing.
+//
+// With http.token or http.tokens set every request but the dashboard page needs
+// Authorization: Bearer <token>, or gets 401; the page asks for the token. Commands sent with a
+// named token are logged under its name. The guest endpoints go by their pass instead, see
+// guest.rs, and answer 403 once it has run out.
+//
+// Commands are handed to the render loop and answered with 202 Accepted. Those that change the
+// lights take ?ttl=<seconds> to last only that long, after which the lights go back to what
+// they showed before, e.g. POST /color?ttl=600 for ten minutes of a color.
+#[cfg(feature = "metrics")]
+use crate::metrics::{self, Day, Sample};
+use crate::{
+    agenda, color,
+    config::{Config, HttpConfig},
+    controller::DeviceInformation,
+    effects::{self, EffectKind},
+    group::ConnectProgress,
+    guest,
+    integrations::{self, Context, Integration},
+    metrics::format_time,
+    mirror, plan,
+    remote::{json_string, same_token, LightStatus, RemoteCommand, Source},
+    schedule::Schedule,
+    supervisor::FailureBudget,
+    triggers::Event,
+    update,
+};
+use futures::future::BoxFuture;
+use log::{info, warn};
+use std::{io, net::IpAddr, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+    time,
+};
+
+// A REST sensor polls /state and a template light calls the POST endpoints through
+// rest_command; $URL, $EFFECTS and the $AUTH lines are filled in by home_assistant_yaml
+const HOME_ASSISTANT_YAML: &str = r##"rest_command:
+  christmas_lights_power:
+    url: "$URL/power"
+    method: post
+$AUTH
+    payload: "{{ state }}"
+  christmas_lights_color:
+    url: "$URL/color"
+    method: post
+$AUTH
+    payload: "#{{ '%02x%02x%02x' | format(r | int, g | int, b | int) }}"
+  christmas_lights_effect:
+    url: "$URL/effect"
+    method: post
+$AUTH
+    payload: "{{ effect }}"
+  christmas_lights_brightness:
+    url: "$URL/brightness"
+    method: post
+$AUTH
+    payload: "{{ ((brightness | int) / 255) | round(3) }}"
+
+sensor:
+  - platform: rest
+    name: christmas_lights_state
+    resource: "$URL/state"
+$AUTH
+    value_template: "{{ value_json.on }}"
+    json_attributes: [effect, color, brightness]
+    scan_interval: 30
+
+light:
+  - platform: template
+    lights:
+      christmas_lights:
+        friendly_name: "Christmas lights"
+        value_template: "{{ is_state('sensor.christmas_lights_state', 'True') }}"
+        level_template: "{{ ((state_attr('sensor.christmas_lights_state', 'brightness') | float(1)) * 255) | int }}"
+        effect_list_template: "{{ $EFFECTS }}"
+        effect_template: "{{ state_attr('sensor.christmas_lights_state', 'effect') }}"
+        turn_on:
+          service: rest_command.christmas_lights_power
+          data:
+            state: "on"
+        turn_off:
+          service: rest_command.christmas_lights_power
+          data:
+            state: "off"
+        set_level:
+          service: rest_command.christmas_lights_brightness
+          data:
+            brightness: "{{ brightness }}"
+        set_effect:
+          service: rest_command.christmas_lights_effect
+          data:
+            effect: "{{ effect }}"
+        set_rgb:
+          service: rest_command.christmas_lights_color
+          data:
+            r: "{{ r }}"
+            g: "{{ g }}"
+            b: "{{ b }}"
+"##;
+const DASHBOARD: &str = include_str!("dashboard.html");
+const GUEST_PAGE: &str = include_str!("guest.html");
+const TREE_PAGE: &str = include_str!("tree.html");
+const OPENAPI: &str = include_str!("openapi.json");
+// Every path route() answers, with the method it takes; others get 405. openapi.json documents
+// exactly these, which a test checks both ways.
+const ROUTES: &[(&str, &str)] = &[
+    ("GET", "/"),
+    ("GET", "/openapi.json"),
+    ("GET", "/tree"),
+    ("GET", "/frames"),
+    ("GET", "/state"),
+    ("GET", "/schedule"),
+    ("POST", "/power"),
+    ("POST", "/color"),
+    ("POST", "/effect"),
+    ("POST", "/brightness"),
+    ("POST", "/temperature"),
+    ("POST", "/arrived"),
+    ("POST", "/scene"),
+    ("POST", "/scene/save"),
+    ("POST", "/palette"),
+    ("POST", "/undo"),
+    ("GET", "/agenda"),
+    ("POST", "/agenda"),
+    ("POST", "/agenda/clear"),
+    ("POST", "/trigger"),
+    ("GET", "/guest"),
+    ("POST", "/guest/scene"),
+    ("GET", "/metrics"),
+    ("GET", "/metrics/samples"),
+    ("GET", "/metrics/daily"),
+];
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LENGTH: usize = 8 * 1024;
+
+pub(crate) struct Response {
+    status: &'static str,
+    body: String,
+}
+
+impl Response {
+    pub(crate) fn new(status: &'static str, body: impl Into<String>) -> Self {
+        Response {
+            status,
+            body: body.into(),
+        }
+    }
+}
+
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    // Whatever follows the ? in the target, if anything
+    pub(crate) query: String,
+    // Names as sent, values trimmed
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: String,
+}
+
+impl Request {
+    /// The value of a query parameter, e.g. `from` in ?from=1700000000000.
+    pub(crate) fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// The value of a header, matched by name regardless of case.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Where other machines reach the API, e.g. http://tree.local:8080. `host` replaces an
+/// unspecified listen address such as 0.0.0.0, which they cannot connect to.
+pub fn base_url(config: &HttpConfig, host: &str) -> String {
+    let (address, port) = config
+        .listen
+        .rsplit_once(':')
+        .unwrap_or((config.listen.as_str(), "80"));
+    let address = match address {
+        "0.0.0.0" | "[::]" | "" => host,
+        address => address,
+    };
+    format!("http://{}:{}", address, port)
+}
+
+/// The link to the guest page for a guest pass, see guest.rs.
+pub fn guest_url(config: &HttpConfig, host: &str, token: &str) -> String {
+    format!("{}/guest?token={}", base_url(config, host), token)
+}
+
+/// Home Assistant YAML for a template light driven through this API, reaching it at `host`
+/// as base_url does.
+pub fn home_assistant_yaml(config: &HttpConfig, host: &str) -> String {
+    let effects = EffectKind::ALL
+        .map(|effect| format!("'{}'", effect.name()))
+        .join(", ");
+    let auth = match &config.token {
+        Some(token) => format!(
+            "    headers:\n      Authorization: {}\n",
+            json_string(&format!("Bearer {}", token))
+        ),
+        None => String::new(),
+    };
+    HOME_ASSISTANT_YAML
+        .replace("$URL", &base_url(config, host))
+        .replace("$EFFECTS", &format!("[{}]", effects))
+        .replace("$AUTH\n", &auth)
+}
+
+struct HttpApi(HttpConfig);
+
+impl Integration for HttpApi {
+    fn name(&self) -> &'static str {
+        "HTTP API"
+    }
+
+    fn start(self: Box<Self>, context: Context, budget: FailureBudget) -> BoxFuture<'static, ()> {
+        let commands = context.commands.clone();
+        Box::pin(integrations::restarting(commands, budget, move || {
+            serve(
+                self.0.clone(),
+                context.device.clone(),
+                context.commands.clone(),
+                context.status.clone(),
+                context.schedule,
+                context.connections.clone(),
+            )
+        }))
+    }
+}
+
+pub fn integration(config: &Config) -> Option<Box<dyn Integration>> {
+    Some(Box::new(HttpApi(config.http.clone()?)))
+}
+
+/// Accepts requests until the listener fails.
+pub async fn serve(
+    config: HttpConfig,
+    device: DeviceInformation,
+    commands: mpsc::Sender<(Source, RemoteCommand)>,
+    status: watch::Receiver<LightStatus>,
+    schedule: Schedule,
+    connections: ConnectProgress,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(&config.listen).await?;
+    info!("HTTP API listening on {}", config.listen);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let commands = commands.clone();
+        let status = status.clone();
+        let config = config.clone();
+        let device = device.clone();
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            let served = handle(
+                stream,
+                &config,
+                &device,
+                &commands,
+                &status,
+                schedule,
+                &connections,
+            );
+            if let Err(e) = served.await {
+                warn!("HTTP request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    config: &HttpConfig,
+    device: &DeviceInformation,
+    commands: &mpsc::Sender<(Source, RemoteCommand)>,
+    status: &watch::Receiver<LightStatus>,
+    schedule: Schedule,
+    connections: &ConnectProgress,
+) -> io::Result<()> {
+    let client = stream.peer_addr()?.ip();
+    let response = match time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        // A guest pass stands in for the token there
+        Ok(Ok(Ok(request))) if request.path.starts_with("/guest") => {
+            guest(&request, config, commands).await
+        }
+        Ok(Ok(Ok(request))) if request.path == "/frames" && is_websocket(&request) => {
+            match (
+                caller(&request, config, client),
+                request.header("sec-websocket-key"),
+            ) {
+                (Some(_), Some(key)) => return mirror::stream(stream, key).await,
+                (Some(_), None) => Response::new("400 Bad Request", "missing Sec-WebSocket-Key"),
+                (None, _) => Response::new("401 Unauthorized", "missing or wrong bearer token"),
+            }
+        }
+        Ok(Ok(Ok(request))) => match caller(&request, config, client) {
+            Some(source) => {
+                route(
+                    &request,
+                    source,
+                    device,
+                    commands,
+                    status,
+                    schedule,
+                    connections,
+                )
+                .await
+            }
+            // The pages hold nothing secret and ask for the token themselves
+            None if request.path == "/" => Response::new("200 OK", dashboard()),
+            None if request.path == "/tree" => Response::new("200 OK", TREE_PAGE),
+            None => Response::new("401 Unauthorized", "missing or wrong bearer token"),
+        },
+        Ok(Ok(Err(rejected))) => rejected,
+        Ok(Err(e)) => return Err(e),
+        Err(_) => Response::new("408 Request Timeout", "request timed out"),
+    };
+    write_response(&mut stream, response).await
+}
+
+pub(crate) async fn write_response(stream: &mut TcpStream, response: Response) -> io::Result<()> {
+    let content_type = if response.body.starts_with(['{', '[']) {
+        "application/json"
+    } else if response.body.starts_with('<') {
+        "text/html; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn is_websocket(request: &Request) -> bool {
+    request
+        .header("upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+}
+
+// Who sent the request, for the command log: the name of the token it carries, or the client's
+// address when the token has no name or none is needed. None when it lacks a configured token.
+fn caller(request: &Request, config: &HttpConfig, client: IpAddr) -> Option<Source> {
+    if config.token.is_none() && config.tokens.is_empty() {
+        return Some(Source::Http(client));
+    }
+    let sent = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| request.param("token").filter(|_| request.path == "/frames"))?;
+    if config
+        .token
+        .as_deref()
+        .is_some_and(|token| same_token(sent, token))
+    {
+        return Some(Source::Http(client));
+    }
+    config
+        .tokens
+        .iter()
+        .find(|(_, token)| same_token(sent, token))
+        .map(|(name, _)| Source::HttpToken(name.clone()))
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+async fn route(
+    request: &Request,
+    source: Source,
+    device: &DeviceInformation,
+    commands: &mpsc::Sender<(Source, RemoteCommand)>,
+    status: &watch::Receiver<LightStatus>,
+    schedule: Schedule,
+    connections: &ConnectProgress,
+) -> Response {
+    let body = request.body.as_str();
+    let command = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => return Response::new("200 OK", dashboard()),
+        ("GET", "/openapi.json") => return Response::new("200 OK", openapi()),
+        ("GET", "/tree") => return Response::new("200 OK", TREE_PAGE),
+        // The WebSocket upgrade is taken care of before routing
+        ("GET", "/frames") => {
+            return Response::new("426 Upgrade Required", "/frames is a WebSocket")
+        }
+        ("GET", "/schedule") => {
+            return Response::new("200 OK", schedule_json(&schedule, chrono::Utc::now()))
+        }
+        ("GET", "/state") => {
+            let current = *status.borrow();
+            let state = state_json(
+                current,
+                device,
+                plan::current(),
+                effects::safe_mode(),
+                connections.get(),
+            );
+            return Response::new("200 OK", state);
+        }
+        #[cfg(feature = "metrics")]
+        ("GET", "/metrics") => {
+            return Response::new("200 OK", prometheus_text(*status.borrow(), schedule))
+        }
+        #[cfg(feature = "metrics")]
+        ("GET", "/metrics/samples") => {
+            return Response::new(
+                "200 OK",
+                samples_json(&metrics::samples(
+                    time_param(request, "from"),
+                    time_param(request, "to"),
+                )),
+            )
+        }
+        #[cfg(feature = "metrics")]
+        ("GET", "/metrics/daily") => {
+            return Response::new(
+                "200 OK",
+                daily_json(&metrics::days(
+                    time_param(request, "from"),
+                    time_param(request, "to"),
+                )),
+            )
+        }
+        ("POST", "/power") => match body.to_ascii_lowercase().as_str() {
+            "on" => Ok(RemoteCommand::On),
+            "off" => Ok(RemoteCommand::Off),
+            _ => Err("power must be on or off"),
+        },
+        ("POST", "/color") => color::parse_hex(body)
+            .map(RemoteCommand::Color)
+            .ok_or("color must be a hex color like #ff0000"),
+        ("POST", "/effect") => EffectKind::from_name(body)
+            .map(RemoteCommand::Effect)
+            .ok_or("unknown effect"),
+        ("POST", "/brightness") => body
+            .parse::<f32>()
+            .ok()
+            .filter(|brightness| (0.0..=1.0).contains(brightness))
+            .map(RemoteCommand::Brightness)
+            .ok_or("brightness must be a number between 0 and 1"),
+        ("POST", "/temperature") => body
+            .parse::<f32>()
+            .ok()
+            .filter(|celsius| celsius.is_finite())
+            .map(RemoteCommand::OutdoorTemperature)
+            .ok_or("temperature must be a number in degrees Celsius"),
+        ("POST", "/arrived") if !body.is_empty() => Ok(RemoteCommand::Arrived(body.to_string())),
+        ("POST", "/arrived") => Err("arrived needs the name of who came home"),
+        ("POST", "/scene") if !body.is_empty() => Ok(RemoteCommand::Scene(body.to_string())),
+        ("POST", "/scene/save") if !body.is_empty() => {
+            Ok(RemoteCommand::SaveScene(body.to_string()))
+        }
+        ("POST", "/scene" | "/scene/save") => Err("scene needs a name"),
+        ("POST", "/palette") if !body.is_empty() => Ok(RemoteCommand::Palette(body.to_string())),
+        ("POST", "/palette") => Err("palette needs a name"),
+        ("POST", "/undo") => Ok(RemoteCommand::Undo),
+        ("GET", "/agenda") => return Response::new("200 OK", agenda::format(&agenda::pending())),
+        ("POST", "/agenda") if !body.trim().is_empty() => {
+            let added = agenda::parse(body).and_then(|actions| {
+                let count = actions.len();
+                agenda::add(actions).map(|()| count)
+            });
+            return match added {
+                Ok(count) => {
+                    info!("{} added {} action(s) to the agenda", source, count);
+                    Response::new("200 OK", agenda::format(&agenda::pending()))
+                }
+                Err(reason) => Response::new("400 Bad Request", reason),
+            };
+        }
+        ("POST", "/agenda") => Err("agenda needs an action per line"),
+        ("POST", "/agenda/clear") => {
+            info!("{} cleared the agenda", source);
+            agenda::clear();
+            return Response::new("200 OK", "");
+        }
+        ("POST", "/trigger") => match request.param("event").filter(|name| !name.is_empty()) {
+            Some(name) => Ok(RemoteCommand::Event(Event::new("webhook", name, body))),
+            None => Err("trigger needs ?event=<name>"),
+        },
+        (_, path) if is_route(path) => {
+            return Response::new("405 Method Not Allowed", "method not allowed")
+        }
+        _ => return Response::new("404 Not Found", "not found"),
+    };
+
+    let command = match (command, request.param("ttl")) {
+        (Ok(command), None) => command,
+        (Ok(command), Some(ttl)) => {
+            let Some(ttl) = ttl.parse::<u64>().ok().filter(|seconds| *seconds > 0) else {
+                return Response::new("400 Bad Request", "ttl must be a number of seconds");
+            };
+            match command.expiring_after(Duration::from_secs(ttl)) {
+                Some(command) => command,
+                None => {
+                    return Response::new(
+                        "400 Bad Request",
+                        "ttl only applies to commands that change the lights",
+                    )
+                }
+            }
+        }
+        (Err(reason), _) => return Response::new("400 Bad Request", reason),
+    };
+    match commands.send((source, command)).await {
+        Ok(()) => Response::new("202 Accepted", "accepted"),
+        Err(_) => Response::new("503 Service Unavailable", "the daemon is shutting down"),
+    }
+}
+
+// The guest page and the one command it sends, for whoever holds a guest pass
+async fn guest(
+    request: &Request,
+    config: &HttpConfig,
+    commands: &mpsc::Sender<(Source, RemoteCommand)>,
+) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/guest") | ("POST", "/guest/scene") => {}
+        (_, path) if is_route(path) => {
+            return Response::new("405 Method Not Allowed", "method not allowed")
+        }
+        _ => return Response::new("404 Not Found", "not found"),
+    }
+    let Some(left) = request.param("token").and_then(guest::remaining) else {
+        return Response::new("403 Forbidden", "the guest pass has run out");
+    };
+    if request.method == "GET" {
+        return Response::new("200 OK", guest_page(config));
+    }
+    let scene = request.body.as_str();
+    if !config
+        .guest_scenes
+        .iter()
+        .any(|guest_scene| guest_scene == scene)
+    {
+        return Response::new("400 Bad Request", "not one of the guest scenes");
+    }
+    // What a guest picks ends with the pass, rather than with ?ttl=
+    let command = RemoteCommand::Scene(scene.to_string())
+        .expiring_after(left)
+        .expect("scenes can expire");
+    match commands.send((Source::Guest, command)).await {
+        Ok(()) => Response::new("202 Accepted", "accepted"),
+        Err(_) => Response::new("503 Service Unavailable", "the daemon is shutting down"),
+    }
+}
+
+fn dashboard() -> String {
+    let effects: String = EffectKind::ALL
+        .iter()
+        .map(|effect| format!("<option>{}</option>\n", effect.name()))
+        .collect();
+    DASHBOARD.replace("$EFFECTS", &effects)
+}
+
+fn guest_page(config: &HttpConfig) -> String {
+    let scenes: Vec<String> = config
+        .guest_scenes
+        .iter()
+        .map(|scene| json_string(scene))
+        .collect();
+    GUEST_PAGE.replace("$SCENES", &format!("[{}]", scenes.join(",")))
+}
+
+// Builds without the `metrics` feature leave out its endpoints
+fn is_route(path: &str) -> bool {
+    ROUTES.iter().any(|(_, route)| *route == path)
+        && (cfg!(feature = "metrics") || !path.starts_with("/metrics"))
+}
+
+// Kept by hand next to ROUTES; a test checks the two against each other
+fn openapi() -> String {
+    let effects: Vec<String> = EffectKind::ALL
+        .iter()
+        .map(|effect| json_string(effect.name()))
+        .collect();
+    OPENAPI
+        .replace("$VERSION", update::CURRENT_VERSION)
+        .replace("$EFFECTS", &format!("[{}]", effects.join(", ")))
+}
+
+fn schedule_json(schedule: &Schedule, now: chrono::DateTime<chrono::Utc>) -> String {
+    let (on, off) =
+        schedule
+            .window(now)
+            .map_or(("null".to_string(), "null".to_string()), |(on, off)| {
+                (
+                    json_string(&format_time(on)),
+                    json_string(&format_time(off)),
+                )
+            });
+    format!("{{\"on\":{},\"off\":{}}}", on, off)
+}
+
+fn state_json(
+    status: LightStatus,
+    device: &DeviceInformation,
+    plan: Option<plan::DailyPlan>,
+    safe_mode: Option<String>,
+    (connected, lights): (usize, usize),
+) -> String {
+    let (r, g, b) = status.color;
+    let mut fields = vec![
+        ("on", status.on.to_string()),
+        ("effect", json_string(status.effect.name())),
+        ("color", format!("\"#{:02x}{:02x}{:02x}\"", r, g, b)),
+        ("brightness", status.brightness.to_string()),
+        ("connected", connected.to_string()),
+        ("lights", lights.to_string()),
+        ("version", json_string(update::CURRENT_VERSION)),
+    ];
+    if let Some(version) = update::available() {
+        fields.push(("update", json_string(&version)));
+    }
+    if let Some(model) = &device.model {
+        fields.push(("model", json_string(model)));
+    }
+    if let Some(firmware) = &device.firmware {
+        fields.push(("firmware", json_string(firmware)));
+    }
+    if let Some(reason) = safe_mode {
+        fields.push(("safe_mode", json_string(&reason)));
+    }
+    fields.push((
+        "plan",
+        plan.map_or("null".to_string(), |plan| plan.to_json()),
+    ));
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| format!("\"{}\":{}", key, value))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+// Prometheus text exposition format
+#[cfg(feature = "metrics")]
+fn prometheus_text(status: LightStatus, schedule: Schedule) -> String {
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        text.push_str(&format!(
+            "# HELP christmas_lights_{} {}\n# TYPE christmas_lights_{} {}\n",
+            name, help, name, kind
+        ));
+        for (labels, value) in samples {
+            text.push_str(&format!("christmas_lights_{}{} {}\n", name, labels, value));
+        }
+    };
+    let value = |value: &dyn std::fmt::Display| vec![(String::new(), value.to_string())];
+
+    if let Some(link) = metrics::link() {
+        metric(
+            "ble_writes_total",
+            "counter",
+            "BLE writes attempted",
+            value(&link.writes),
+        );
+        metric(
+            "ble_write_failures_total",
+            "counter",
+            "BLE writes that failed",
+            value(&link.failed_writes),
+        );
+        metric(
+            "reconnects_total",
+            "counter",
+            "Reconnects to the lights",
+            value(&link.reconnects),
+        );
+        metric(
+            "frames_per_second",
+            "gauge",
+            "Frames sent to the lights per second",
+            value(&link.frames_per_second),
+        );
+        metric(
+            "frame_time_seconds",
+            "gauge",
+            "Average time to work out a frame and write it to every light",
+            value(&link.frame_time.as_secs_f64()),
+        );
+        metric(
+            "connection_uptime_seconds",
+            "gauge",
+            "Time since the lights were last connected, 0 while disconnected",
+            value(&link.uptime.map_or(0, |uptime| uptime.as_secs())),
+        );
+    }
+    metric(
+        "on",
+        "gauge",
+        "Whether the lights are on",
+        value(&u8::from(status.on)),
+    );
+    metric(
+        "effect",
+        "gauge",
+        "The current effect, 1 for the one running",
+        EffectKind::ALL
+            .iter()
+            .map(|&effect| {
+                (
+                    format!("{{effect=\"{}\"}}", effect.name()),
+                    u8::from(effect == status.effect).to_string(),
+                )
+            })
+            .collect(),
+    );
+    let now = chrono::Utc::now();
+    metric(
+        "seconds_until_sunset",
+        "gauge",
+        "Time until the next sunset",
+        value(&(schedule.sun().next_sunset(now) - now.timestamp())),
+    );
+    text
+}
+
+// Grafana passes its time range as Unix milliseconds
+#[cfg(feature = "metrics")]
+fn time_param(request: &Request, name: &str) -> Option<i64> {
+    request.param(name)?.parse().ok()
+}
+
+#[cfg(feature = "metrics")]
+fn samples_json(samples: &[Sample]) -> String {
+    let rows: Vec<String> = samples
+        .iter()
+        .map(|sample| {
+            format!(
+                "{{\"time\":\"{}\",\"on\":{},\"brightness\":{}}}",
+                metrics::format_time(sample.at),
+                sample.on,
+                sample.brightness
+            )
+        })
+        .collect();
+    format!("[{}]", rows.join(","))
+}
+
+#[cfg(feature = "metrics")]
+fn daily_json(days: &[Day]) -> String {
+    let rows: Vec<String> = days
+        .iter()
+        .map(|day| {
+            format!(
+                "{{\"date\":\"{}\",\"on_hours\":{:.2},\"reconnects\":{}}}",
+                day.date,
+                day.on_seconds as f64 / 3600.0,
+                day.reconnects
+            )
+        })
+        .collect();
+    format!("[{}]", rows.join(","))
+}
+
+// The request, or the response refusing it when it is malformed or too large
+pub(crate) async fn read_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> io::Result<Result<Request, Response>> {
+    let malformed = || Err(Response::new("400 Bad Request", "malformed request"));
+    let too_large = || Err(Response::new("413 Payload Too Large", "request too large"));
+    let mut request = Vec::new();
+    let mut chunk = [0; 1024];
+    let head_end = loop {
+        if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(malformed());
+        }
+        if request.len() + read > MAX_REQUEST_LENGTH {
+            return Ok(too_large());
+        }
+        request.extend_from_slice(&chunk[..read]);
+    };
+
+    let Ok(head) = std::str::from_utf8(&request[..head_end]) else {
+        return Ok(malformed());
+    };
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Ok(malformed());
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (method, path, query) = (method.to_string(), path.to_string(), query.to_string());
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map_or(Some(0), |(_, value)| value.parse::<usize>().ok());
+    let Some(content_length) = content_length else {
+        return Ok(malformed());
+    };
+    // Checked, as a length near usize::MAX would otherwise wrap past the limit
+    let Some(end) = head_end
+        .checked_add(content_length)
+        .filter(|end| *end <= MAX_REQUEST_LENGTH)
+    else {
+        return Ok(too_large());
+    };
+
+    while request.len() < end {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(malformed());
+        }
+        request.extend_from_slice(&chunk[..read]);
+    }
+    let Ok(body) = String::from_utf8(request[head_end..end].to_vec()) else {
+        return Ok(malformed());
+    };
+    Ok(Ok(Request {
+        method,
+        path,
+        query,
+        headers,
+        body: body.trim().to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::DailyPlan;
+    use chrono::NaiveDate;
+
+    async fn read(bytes: &[u8]) -> Result<Request, Response> {
+        read_request(&mut &bytes[..]).await.unwrap()
+    }
+
+    fn status(result: Result<Request, Response>) -> &'static str {
+        match result {
+            Ok(_) => "ok",
+            Err(response) => response.status,
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_are_parsed() {
+        let request = read(
+            b"POST /color?x=1 HTTP/1.1\r\nHost: lights\r\ncontent-length: 9\r\n\r\n#ff0000\r\n",
+        )
+        .await
+        .unwrap_or_else(|_| panic!("request refused"));
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/color");
+        assert_eq!(request.param("x"), Some("1"));
+        assert_eq!(request.header("HOST"), Some("lights"));
+        assert_eq!(request.body, "#ff0000");
+    }
+
+    #[tokio::test]
+    async fn a_huge_content_length_is_refused_rather_than_wrapping() {
+        let request = format!(
+            "POST /power HTTP/1.1\r\nContent-Length: {}\r\n\r\non",
+            usize::MAX
+        );
+        assert_eq!(
+            status(read(request.as_bytes()).await),
+            "413 Payload Too Large"
+        );
+        let request = "POST /power HTTP/1.1\r\nContent-Length: 9000\r\n\r\non";
+        assert_eq!(
+            status(read(request.as_bytes()).await),
+            "413 Payload Too Large"
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_heads_are_refused() {
+        let request = format!(
+            "GET /state HTTP/1.1\r\nX-Filler: {}\r\n\r\n",
+            "a".repeat(9000)
+        );
+        assert_eq!(
+            status(read(request.as_bytes()).await),
+            "413 Payload Too Large"
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_are_refused() {
+        for request in [
+            &b""[..],
+            b"GET /state HTTP/1.1\r\n",
+            b"GET\r\n\r\n",
+            b"POST /power HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            b"POST /power HTTP/1.1\r\nContent-Length: ten\r\n\r\n",
+            b"POST /power HTTP/1.1\r\nContent-Length: 10\r\n\r\non",
+            b"POST /power HTTP/1.1\r\nContent-Length: 2\r\n\r\n\xff\xfe",
+            b"GET /\xff HTTP/1.1\r\n\r\n",
+        ] {
+            assert_eq!(status(read(request).await), "400 Bad Request");
+        }
+    }
+
+    #[tokio::test]
+    async fn the_token_is_required_once_configured() {
+        let client = IpAddr::from([192, 168, 1, 20]);
+        let request = read(b"GET /state HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n")
+            .await
+            .unwrap_or_else(|_| panic!("request refused"));
+        let authorize = |token| caller(&request, &with_token(token), client);
+        assert_eq!(authorize(None), Some(Source::Http(client)));
+        assert_eq!(authorize(Some("s3cret")), Some(Source::Http(client)));
+        assert_eq!(authorize(Some("s3cre")), None);
+        assert_eq!(authorize(Some("s3cret!")), None);
+        let anonymous = read(b"GET /state HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap_or_else(|_| panic!("request refused"));
+        assert_eq!(
+            caller(&anonymous, &with_token(None), client),
+            Some(Source::Http(client))
+        );
+        assert_eq!(
+            caller(&anonymous, &with_token(Some("s3cret")), client),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn named_tokens_name_the_caller() {
+        let client = IpAddr::from([192, 168, 1, 20]);
+        let config = HttpConfig {
+            tokens: vec![
+                ("phone".to_string(), "s3cret".to_string()),
+                ("tablet".to_string(), "0ther".to_string()),
+            ],
+            ..with_token(None)
+        };
+        let request = read(b"GET /state HTTP/1.1\r\nAuthorization: Bearer 0ther\r\n\r\n")
+            .await
+            .unwrap_or_else(|_| panic!("request refused"));
+        assert_eq!(
+            caller(&request, &config, client),
+            Some(Source::HttpToken("tablet".to_string()))
+        );
+        let anonymous = read(b"GET /state HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap_or_else(|_| panic!("request refused"));
+        assert_eq!(caller(&anonymous, &config, client), None);
+    }
+
+    #[test]
+    fn the_state_carries_the_device_and_the_plan() {
+        let status = LightStatus {
+            on: true,
+            effect: EffectKind::Rainbow,
+            color: (255, 140, 40),
+            brightness: 1.0,
+            override_until: None,
+        };
+        let device = DeviceInformation {
+            manufacturer: None,
+            model: Some("AL-100".to_string()),
+            firmware: Some("1.4.2".to_string()),
+        };
+        let date = NaiveDate::from_ymd_opt(2023, 12, 21).unwrap();
+        let plan = DailyPlan::new(date, (1_703_174_400, 1_703_228_400, 30_000), None);
+
+        assert_eq!(
+            state_json(status, &DeviceInformation::default(), None, None, (1, 1)),
+            format!(
+                r##"{{"on":true,"effect":"rainbow","color":"#ff8c28","brightness":1,"connected":1,"lights":1,"version":"{}","plan":null}}"##,
+                update::CURRENT_VERSION
+            )
+        );
+        assert_eq!(
+            state_json(
+                status,
+                &device,
+                Some(plan.clone()),
+                Some("the rainbow effect failed".to_string()),
+                (3, 4)
+            ),
+            format!(
+                r##"{{"on":true,"effect":"rainbow","color":"#ff8c28","brightness":1,"connected":3,"lights":4,"version":"{}","model":"AL-100","firmware":"1.4.2","safe_mode":"the rainbow effect failed","plan":{}}}"##,
+                update::CURRENT_VERSION,
+                plan.to_json()
+            )
+        );
+    }
+
+    fn with_token(token: Option<&str>) -> HttpConfig {
+        HttpConfig {
+            listen: "0.0.0.0:8080".to_string(),
+            token: token.map(str::to_string),
+            tokens: Vec::new(),
+            guest_scenes: Vec::new(),
+            guest_duration: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn the_dashboard_offers_every_effect() {
+        let page = dashboard();
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(!page.contains("$EFFECTS"));
+        for effect in EffectKind::ALL {
+            assert!(page.contains(&format!("<option>{}</option>", effect.name())));
+        }
+    }
+
+    #[test]
+    fn the_openapi_document_describes_every_route() {
+        let document = openapi();
+        assert!(!document.contains("$VERSION") && !document.contains("$EFFECTS"));
+        assert!(document.contains("\"enum\": [\"rainbow\", \"gradient\","));
+        // Paths are the keys four spaces in, their methods the keys six spaces in
+        let mut documented = Vec::new();
+        let mut path = None;
+        for line in document.lines() {
+            let Some(key) = line.trim_start().strip_prefix('"') else {
+                continue;
+            };
+            let key = key.split('"').next().unwrap_or_default();
+            match line.len() - line.trim_start().len() {
+                4 => path = key.starts_with('/').then(|| key.to_string()),
+                6 => {
+                    if let Some(path) = &path {
+                        documented.push((key.to_ascii_uppercase(), path.clone()));
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut routes: Vec<(String, String)> = ROUTES
+            .iter()
+            .map(|(method, path)| (method.to_string(), path.to_string()))
+            .collect();
+        documented.sort();
+        routes.sort();
+        assert_eq!(documented, routes);
+    }
+
+    #[tokio::test]
+    async fn every_route_is_answered() {
+        let (commands, _received) = mpsc::channel(ROUTES.len());
+        let (_, status) = watch::channel(LightStatus {
+            on: true,
+            effect: EffectKind::Rainbow,
+            color: (255, 0, 0),
+            brightness: 1.0,
+            override_until: None,
+        });
+        let schedule = Schedule::from_config(&Config::default());
+        let source = Source::Http(IpAddr::from([127, 0, 0, 1]));
+        let device = DeviceInformation::default();
+        let connections = ConnectProgress::default();
+        for (method, path) in ROUTES {
+            let other = if *method == "GET" { "POST" } else { "GET" };
+            let refused = !ROUTES.contains(&(other, *path));
+            for (method, refused) in [(*method, false), (other, refused)] {
+                let request = format!("{} {} HTTP/1.1\r\n\r\n", method, path);
+                let request = read(request.as_bytes())
+                    .await
+                    .unwrap_or_else(|_| panic!("request refused"));
+                let answer = if path.starts_with("/guest") {
+                    guest(&request, &with_token(None), &commands).await
+                } else {
+                    route(
+                        &request,
+                        source.clone(),
+                        &device,
+                        &commands,
+                        &status,
+                        schedule,
+                        &connections,
+                    )
+                    .await
+                }
+                .status;
+                if path.starts_with("/metrics") && !cfg!(feature = "metrics") {
+                    assert_eq!(answer, "404 Not Found", "{} {}", method, path);
+                } else if refused {
+                    assert_eq!(answer, "405 Method Not Allowed", "{} {}", method, path);
+                } else {
+                    assert!(!answer.starts_with("404") && !answer.starts_with("405"));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn guest_passes_offer_only_the_guest_scenes() {
+        let (commands, mut received) = mpsc::channel(1);
+        let config = HttpConfig {
+            guest_scenes: vec!["party".to_string()],
+            ..with_token(Some("s3cret"))
+        };
+        let token = guest::issue(Duration::from_secs(600)).unwrap();
+        let answer = |request: String| {
+            let (commands, config) = (commands.clone(), config.clone());
+            async move {
+                let request = read(request.as_bytes())
+                    .await
+                    .unwrap_or_else(|_| panic!("request refused"));
+                let response = guest(&request, &config, &commands).await;
+                (response.status, response.body)
+            }
+        };
+        let post = |token: &str, scene: &str| {
+            format!(
+                "POST /guest/scene?token={} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                token,
+                scene.len(),
+                scene
+            )
+        };
+
+        let (status, page) = answer(format!("GET /guest?token={} HTTP/1.1\r\n\r\n", token)).await;
+        assert_eq!(status, "200 OK");
+        assert!(page.contains(r#"for (const scene of ["party"])"#));
+        assert_eq!(answer(post(&token, "party")).await.0, "202 Accepted");
+        let Some((Source::Guest, RemoteCommand::Temporary(scene, left))) = received.recv().await
+        else {
+            panic!("no guest command");
+        };
+        assert_eq!(*scene, RemoteCommand::Scene("party".to_string()));
+        assert!(left <= Duration::from_secs(600) && left > Duration::from_secs(590));
+        assert_eq!(answer(post(&token, "strobe")).await.0, "400 Bad Request");
+        assert_eq!(answer(post("s3cret", "party")).await.0, "403 Forbidden");
+        assert_eq!(
+            answer("GET /guest HTTP/1.1\r\n\r\n".to_string()).await.0,
+            "403 Forbidden"
+        );
+    }
+
+    #[test]
+    fn the_schedule_is_the_coming_window() {
+        use crate::{schedule::Trigger, sun::SunSchedule, timezone::Zone};
+        use chrono::TimeZone;
+        let sun = SunSchedule {
+            location: (47.4979, 19.0402),
+            fallback_sunrise_utc: (7, 0),
+            fallback_sunset_utc: (15, 0),
+        };
+        let schedule = Schedule::new(sun, Trigger::At((20, 0)), Trigger::At((2, 0)), Zone::Utc);
+        let noon = chrono::Utc.with_ymd_and_hms(2024, 12, 1, 12, 0, 0).unwrap();
+        assert_eq!(
+            schedule_json(&schedule, noon),
+            r#"{"on":"2024-12-01T20:00:00Z","off":"2024-12-02T02:00:00Z"}"#
+        );
+    }
+
+    #[test]
+    fn home_assistant_sends_the_token() {
+        let yaml = home_assistant_yaml(&with_token(Some("s3cret")), "lights.local");
+        assert_eq!(yaml.matches("Authorization: \"Bearer s3cret\"").count(), 5);
+        assert!(!yaml.contains("$AUTH"));
+        let yaml = home_assistant_yaml(&with_token(None), "lights.local");
+        assert!(!yaml.contains("headers") && !yaml.contains("$AUTH"));
+        assert!(
+            yaml.contains("url: \"http://lights.local:8080/power\"\n    method: post\n    payload")
+        );
+    }
+}
@@ -0,0 +1,78 @@
+// Whether anybody benefits from the show: [idle.when] names events, by <trigger>/<event>, and
+// the values that say nobody does, e.g. a Home Assistant input_boolean forwarded to the MQTT
+// trigger topic, or presence detection posting the house's state to /trigger. While the latest
+// value of any of them says so, the render loop stops writing frames, so the lights hold their
+// last color, and the connection stays up for picking the show up again.
+use crate::triggers::Event;
+use std::collections::HashMap;
+
+pub struct Idle {
+    when: Vec<(String, String)>,
+    // The latest value of each watched event
+    latest: HashMap<String, String>,
+}
+
+impl Idle {
+    pub fn new(when: Vec<(String, String)>) -> Self {
+        Idle {
+            when,
+            latest: HashMap::new(),
+        }
+    }
+
+    /// Whether nobody benefits from the show, going by the latest events; never before the
+    /// first of them arrives.
+    pub fn is_idle(&self) -> bool {
+        self.when.iter().any(|(event, value)| {
+            self.latest
+                .get(event)
+                .is_some_and(|latest| latest.eq_ignore_ascii_case(value))
+        })
+    }
+
+    /// Takes note of an event, returning whether the show should now pause, or None when
+    /// that did not change.
+    pub fn observe(&mut self, event: &Event) -> Option<bool> {
+        let name = format!("{}/{}", event.trigger, event.name);
+        if !self.when.iter().any(|(watched, _)| *watched == name) {
+            return None;
+        }
+        let was_idle = self.is_idle();
+        self.latest.insert(name, event.value.trim().to_string());
+        let idle = self.is_idle();
+        (idle != was_idle).then_some(idle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_latest_value_of_any_condition_pauses_the_show() {
+        let mut idle = Idle::new(vec![
+            ("mqtt/lights_wanted".to_string(), "off".to_string()),
+            ("webhook/house".to_string(), "empty".to_string()),
+        ]);
+        assert!(!idle.is_idle());
+        assert_eq!(
+            idle.observe(&Event::new("mqtt", "lights_wanted", "on")),
+            None
+        );
+        assert_eq!(
+            idle.observe(&Event::new("mqtt", "lights_wanted", "OFF\n")),
+            Some(true)
+        );
+        assert_eq!(idle.observe(&Event::new("webhook", "house", "empty")), None);
+        assert_eq!(idle.observe(&Event::new("gpio", "doorbell", "1")), None);
+        assert_eq!(
+            idle.observe(&Event::new("mqtt", "lights_wanted", "on")),
+            None
+        );
+        assert!(idle.is_idle());
+        assert_eq!(
+            idle.observe(&Event::new("webhook", "house", "occupied")),
+            Some(false)
+        );
+    }
+}
@@ -0,0 +1,387 @@
+// Keeps two instances from fighting over the same lights. Each one holds a control socket per
+// light it drives, named after the adapter and the light's address or name pattern. A second
+// instance finds the socket answering and stops with a clear message, or with --takeover asks
+// the first to let go: it disconnects cleanly, leaving the lights as they are, and exits. A
+// socket left behind by a crash no longer answers and is replaced. `logs` asks over the same
+// socket for the daemon's log lines, `guest` for a guest pass and `undo` to take back the last
+// change to the lights.
+use crate::{
+    cache,
+    config::DeviceConfig,
+    error::Failure,
+    guest,
+    logs::{self, Filter},
+    remote::{RemoteCommand, Source},
+};
+use futures::future::select_all;
+use log::{info, warn};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{UnixListener, UnixStream},
+    sync::mpsc,
+    time,
+};
+
+const RELEASE: &str = "release";
+const RELEASED: &str = "released";
+// Followed by the filter, e.g. "logs warn"
+const LOGS: &str = "logs";
+// Followed by how many seconds the pass lasts; answered with its token
+const GUEST: &str = "guest";
+// Answered with ACCEPTED once the render loop has it
+const UNDO: &str = "undo";
+const ACCEPTED: &str = "accepted";
+// How long the running instance gets to disconnect before a takeover gives up
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(30);
+// Socket paths are limited to about a hundred bytes, so long name patterns are cut short
+const MAX_NAME_LENGTH: usize = 48;
+
+/// The lights this instance drives, held until dropped.
+pub struct InstanceLock {
+    sockets: Vec<(PathBuf, UnixListener)>,
+}
+
+/// Takes the lights `device` selects, or fails if another instance has them. With `takeover`
+/// the other instance is asked to release them first.
+pub async fn acquire(device: &DeviceConfig, takeover: bool) -> Result<InstanceLock, Failure> {
+    let dir = dir().ok_or_else(|| {
+        Failure::Storage("no place for the control socket, set XDG_RUNTIME_DIR".to_string())
+    })?;
+    acquire_in(&dir, &keys(device), takeover).await
+}
+
+impl InstanceLock {
+    /// Resolves once another instance asks for the lights, with the connection to answer on
+    /// after letting go of them. Undos asked for meanwhile go to `commands`.
+    pub async fn takeover_requested(
+        &self,
+        commands: &mpsc::Sender<(Source, RemoteCommand)>,
+    ) -> UnixStream {
+        loop {
+            let accepts = self
+                .sockets
+                .iter()
+                .map(|(_, listener)| Box::pin(listener.accept()));
+            let (accepted, _, _) = select_all(accepts).await;
+            let Ok((stream, _)) = accepted else {
+                continue;
+            };
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.is_err() {
+                continue;
+            }
+            if line.trim() == RELEASE {
+                return stream.into_inner();
+            }
+            let line = line.trim();
+            if let Some(filter) = line.strip_prefix(LOGS).and_then(Filter::from_request) {
+                tokio::spawn(logs::follow(stream.into_inner(), filter));
+            } else if let Some(seconds) = line
+                .strip_prefix(GUEST)
+                .and_then(|seconds| seconds.trim().parse().ok())
+            {
+                tokio::spawn(issue_guest_pass(stream.into_inner(), seconds));
+            } else if line == UNDO {
+                tokio::spawn(forward_undo(stream.into_inner(), commands.clone()));
+            }
+            // Anything else is a second instance checking whether this one is alive
+        }
+    }
+
+    /// Gives up the lights and tells the instance taking over, once they are disconnected.
+    pub async fn release(self, mut requester: UnixStream) {
+        // The sockets go first, so the new instance finds them free once answered
+        drop(self);
+        let reply = format!("{}\n", RELEASED);
+        if let Err(e) = requester.write_all(reply.as_bytes()).await {
+            warn!("Cannot answer the instance taking over: {}", e);
+        }
+    }
+}
+
+async fn issue_guest_pass(mut requester: UnixStream, seconds: u64) {
+    match guest::issue(Duration::from_secs(seconds)) {
+        Ok(token) => {
+            info!("Issued a guest pass for {} minutes", seconds / 60);
+            requester
+                .write_all(format!("{}\n", token).as_bytes())
+                .await
+                .ok();
+        }
+        // The requester finds the connection closed without an answer
+        Err(e) => warn!("Cannot issue a guest pass: {}", e),
+    }
+}
+
+async fn forward_undo(mut requester: UnixStream, commands: mpsc::Sender<(Source, RemoteCommand)>) {
+    if commands
+        .send((Source::Cli, RemoteCommand::Undo))
+        .await
+        .is_ok()
+    {
+        requester
+            .write_all(format!("{}\n", ACCEPTED).as_bytes())
+            .await
+            .ok();
+    }
+}
+
+/// The running daemon's log lines that pass `filter`, recent ones first, then new ones as
+/// they come.
+pub async fn follow_logs(
+    device: &DeviceConfig,
+    filter: &Filter,
+) -> Result<Lines<BufReader<UnixStream>>, Failure> {
+    ask(device, &format!("{} {}", LOGS, filter.to_request())).await
+}
+
+/// A new guest pass from the running daemon, lasting `valid_for`, by its token.
+pub async fn guest_pass(device: &DeviceConfig, valid_for: Duration) -> Result<String, Failure> {
+    let request = format!("{} {}", GUEST, valid_for.as_secs());
+    let mut answer = ask(device, &request).await?;
+    match answer.next_line().await {
+        Ok(Some(token)) => Ok(token),
+        _ => Err(Failure::Storage(
+            "the daemon could not issue a guest pass, see its log".to_string(),
+        )),
+    }
+}
+
+/// Has the running daemon take back the last change to the lights.
+pub async fn undo(device: &DeviceConfig) -> Result<(), Failure> {
+    let mut answer = ask(device, UNDO).await?;
+    match answer.next_line().await {
+        Ok(Some(answer)) if answer == ACCEPTED => Ok(()),
+        _ => Err(Failure::Usage(
+            "the daemon is shutting down and did not undo".to_string(),
+        )),
+    }
+}
+
+async fn ask(
+    device: &DeviceConfig,
+    request: &str,
+) -> Result<Lines<BufReader<UnixStream>>, Failure> {
+    let dir = dir().ok_or_else(not_running)?;
+    ask_in(&dir, &keys(device), request).await
+}
+
+// Any of the sockets reaches the daemon, as one holds them all
+async fn ask_in(
+    dir: &Path,
+    keys: &[String],
+    request: &str,
+) -> Result<Lines<BufReader<UnixStream>>, Failure> {
+    for key in keys {
+        let Ok(mut stream) = UnixStream::connect(dir.join(key)).await else {
+            continue;
+        };
+        if stream
+            .write_all(format!("{}\n", request).as_bytes())
+            .await
+            .is_ok()
+        {
+            return Ok(BufReader::new(stream).lines());
+        }
+    }
+    Err(not_running())
+}
+
+fn not_running() -> Failure {
+    Failure::Usage("no running daemon drives these lights".to_string())
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        for (path, _) in &self.sockets {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+// The runtime directory, where sockets belong, or the cache directory without one
+fn dir() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(|runtime| PathBuf::from(runtime).join("christmas-lights"))
+        .or_else(cache::dir)
+}
+
+// One per pinned address, or the name pattern when the lights are found by name
+fn keys(device: &DeviceConfig) -> Vec<String> {
+    let adapter = device.adapter.as_deref().unwrap_or("default");
+    let lights = if device.addresses.is_empty() {
+        vec![device.name_pattern.as_str().to_string()]
+    } else {
+        device.addresses.clone()
+    };
+    lights
+        .iter()
+        .map(|light| socket_name(adapter, light))
+        .collect()
+}
+
+fn socket_name(adapter: &str, light: &str) -> String {
+    let name: String = format!("{}-{}", adapter, light)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_NAME_LENGTH)
+        .collect();
+    format!("{}.sock", name)
+}
+
+async fn acquire_in(dir: &Path, keys: &[String], takeover: bool) -> Result<InstanceLock, Failure> {
+    fs::create_dir_all(dir)
+        .map_err(|e| Failure::Storage(format!("cannot create {}: {}", dir.display(), e)))?;
+    let mut lock = InstanceLock {
+        sockets: Vec::new(),
+    };
+    for key in keys {
+        let path = dir.join(key);
+        let listener = match UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                take(&path, takeover).await?;
+                UnixListener::bind(&path).map_err(|e| bind_failed(&path, e))?
+            }
+            Err(e) => return Err(bind_failed(&path, e)),
+        };
+        lock.sockets.push((path, listener));
+    }
+    Ok(lock)
+}
+
+// Frees a socket that is already there: a stale one is removed, a live one only on takeover
+async fn take(path: &Path, takeover: bool) -> Result<(), Failure> {
+    let mut stream = match UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(_) => {
+            fs::remove_file(path).map_err(|e| bind_failed(path, e))?;
+            return Ok(());
+        }
+    };
+    if !takeover {
+        return Err(Failure::InUse(
+            "another instance is driving these lights; stop it first, or pass --takeover to \
+             have it let go"
+                .to_string(),
+        ));
+    }
+    info!("Asking the running instance to release the lights");
+    let released = time::timeout(TAKEOVER_TIMEOUT, async {
+        stream
+            .write_all(format!("{}\n", RELEASE).as_bytes())
+            .await?;
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).await?;
+        Ok::<_, io::Error>(line.trim() == RELEASED)
+    })
+    .await;
+    match released {
+        Ok(Ok(true)) => Ok(()),
+        _ => Err(Failure::InUse(
+            "the running instance did not release the lights".to_string(),
+        )),
+    }
+}
+
+fn bind_failed(path: &Path, e: io::Error) -> Failure {
+    Failure::Storage(format!("cannot take {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch_dir;
+
+    #[test]
+    fn sockets_are_named_after_adapter_and_light() {
+        assert_eq!(
+            socket_name("hci0", "A4:C1:38:12:34:56"),
+            "hci0-a4_c1_38_12_34_56.sock"
+        );
+        assert!(socket_name("default", &"x".repeat(200)).len() <= MAX_NAME_LENGTH + 5);
+    }
+
+    #[tokio::test]
+    async fn a_second_instance_is_turned_away() {
+        let dir = scratch_dir("instance-second");
+        let keys = ["default-lights.sock".to_string()];
+        let first = acquire_in(&dir, &keys, false).await.unwrap();
+        assert!(matches!(
+            acquire_in(&dir, &keys, false).await,
+            Err(Failure::InUse(_))
+        ));
+        drop(first);
+        assert!(acquire_in(&dir, &keys, false).await.is_ok());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn stale_sockets_are_replaced() {
+        let dir = scratch_dir("instance-stale");
+        let keys = ["default-lights.sock".to_string()];
+        fs::create_dir_all(&dir).unwrap();
+        // A crash leaves the file without anyone listening
+        drop(std::os::unix::net::UnixListener::bind(dir.join(&keys[0])).unwrap());
+        assert!(acquire_in(&dir, &keys, false).await.is_ok());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn logs_and_guest_passes_are_asked_for_over_the_socket() {
+        let dir = scratch_dir("instance-logs");
+        let keys = ["default-lights.sock".to_string()];
+        assert!(matches!(
+            ask_in(&dir, &keys, "logs warn").await,
+            Err(Failure::Usage(_))
+        ));
+        let first = acquire_in(&dir, &keys, false).await.unwrap();
+        let (commands, mut undos) = mpsc::channel(1);
+        let running = tokio::spawn(async move {
+            let requester = first.takeover_requested(&commands).await;
+            first.release(requester).await;
+        });
+        // Following the logs, asking for a guest pass or undoing is no takeover, so the daemon
+        // keeps its lights
+        assert!(ask_in(&dir, &keys, "logs warn").await.is_ok());
+        let mut pass = ask_in(&dir, &keys, "guest 60").await.unwrap();
+        let token = pass.next_line().await.unwrap().unwrap();
+        assert!(guest::remaining(&token).is_some());
+        let mut undo = ask_in(&dir, &keys, UNDO).await.unwrap();
+        assert_eq!(undo.next_line().await.unwrap().unwrap(), ACCEPTED);
+        assert_eq!(undos.recv().await, Some((Source::Cli, RemoteCommand::Undo)));
+        assert!(matches!(
+            acquire_in(&dir, &keys, false).await,
+            Err(Failure::InUse(_))
+        ));
+        assert!(acquire_in(&dir, &keys, true).await.is_ok());
+        running.await.unwrap();
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn a_takeover_waits_for_the_release() {
+        let dir = scratch_dir("instance-takeover");
+        let keys = ["default-lights.sock".to_string()];
+        let first = acquire_in(&dir, &keys, false).await.unwrap();
+        let running = tokio::spawn(async move {
+            let requester = first.takeover_requested(&mpsc::channel(1).0).await;
+            first.release(requester).await;
+        });
+        assert!(acquire_in(&dir, &keys, true).await.is_ok());
+        running.await.unwrap();
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,122 @@
+// The ways into the daemon besides the config file: MQTT, the HTTP API, the status page and
+// E1.31 input. Each one is its own module behind a cargo feature and registers here, so the
+// daemon starts whatever the build has and the config enables without knowing them by name.
+// A build without one only loses that module; its config section is then reported as ignored.
+use crate::{
+    config::Config,
+    controller::DeviceInformation,
+    group::ConnectProgress,
+    remote::{LightStatus, LiveColors, PublicStatus, RemoteCommand, Source},
+    schedule::Schedule,
+    supervisor::FailureBudget,
+};
+use futures::future::BoxFuture;
+use std::{fmt::Display, future::Future, time::Duration};
+use tokio::{
+    sync::{mpsc, watch},
+    time,
+};
+
+// Pause before restarting an integration that stopped, unless its budget asks for longer
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// An integration enabled in the config, ready to start.
+pub trait Integration: Send {
+    /// For the log, and the name its failure budget goes by.
+    fn name(&self) -> &'static str;
+
+    /// Runs until the daemon shuts down, restarting itself when it fails.
+    fn start(self: Box<Self>, context: Context, budget: FailureBudget) -> BoxFuture<'static, ()>;
+}
+
+/// What the daemon shares with integrations.
+#[derive(Clone)]
+pub struct Context {
+    // Commands for the render loop, closed when the daemon shuts down
+    pub commands: mpsc::Sender<(Source, RemoteCommand)>,
+    pub status: watch::Receiver<LightStatus>,
+    pub public: watch::Receiver<PublicStatus>,
+    // Colors to show instead of the effect
+    pub live: LiveColors,
+    pub schedule: Schedule,
+    pub lights: usize,
+    // Manufacturer, model and firmware of the first light
+    pub device: DeviceInformation,
+    // How many of the lights are connected, while they connect at startup or reconnect
+    pub connections: ConnectProgress,
+}
+
+type Register = fn(&Config) -> Option<Box<dyn Integration>>;
+
+// Config section and the feature it needs, for integrations left out of the build
+const ALL: &[(&str, &str)] = &[
+    ("mqtt", "mqtt"),
+    ("http", "http"),
+    ("status_page", "http"),
+    ("e131", "sacn"),
+    ("mpris", "mpris"),
+];
+
+fn built() -> Vec<Register> {
+    vec![
+        #[cfg(feature = "mqtt")]
+        crate::mqtt::integration,
+        #[cfg(feature = "http")]
+        crate::http::integration,
+        #[cfg(feature = "http")]
+        crate::status_page::integration,
+        #[cfg(feature = "sacn")]
+        crate::e131::integration,
+        #[cfg(feature = "mpris")]
+        crate::mpris::integration,
+    ]
+}
+
+/// The integrations in this build that the config enables.
+pub fn enabled(config: &Config) -> Vec<Box<dyn Integration>> {
+    built()
+        .into_iter()
+        .filter_map(|register| register(config))
+        .collect()
+}
+
+/// Config sections for integrations this build leaves out, with the feature each needs.
+pub fn unavailable(config: &Config) -> Vec<(&'static str, &'static str)> {
+    let configured = |section: &str| match section {
+        "mqtt" => config.mqtt.is_some(),
+        "http" => config.http.is_some(),
+        "status_page" => config.status_page.is_some(),
+        "e131" => config.e131.is_some(),
+        "mpris" => config.mpris.is_some(),
+        _ => false,
+    };
+    let built = |feature: &str| {
+        matches!(feature, "mqtt" if cfg!(feature = "mqtt"))
+            || matches!(feature, "http" if cfg!(feature = "http"))
+            || matches!(feature, "sacn" if cfg!(feature = "sacn"))
+            || matches!(feature, "mpris" if cfg!(feature = "mpris"))
+    };
+    ALL.iter()
+        .copied()
+        .filter(|(section, feature)| configured(section) && !built(feature))
+        .collect()
+}
+
+/// Runs `serve` again whenever it fails, pausing as the budget says, until the daemon shuts
+/// down. For integrations that have no backoff of their own.
+pub async fn restarting<F, E>(
+    commands: mpsc::Sender<(Source, RemoteCommand)>,
+    mut budget: FailureBudget,
+    mut serve: impl FnMut() -> F,
+) where
+    F: Future<Output = Result<(), E>>,
+    E: Display,
+{
+    while !commands.is_closed() {
+        budget.resume();
+        if let Err(e) = serve().await {
+            let pause = budget.failed(e).unwrap_or(RESTART_DELAY);
+            time::sleep(pause).await;
+        }
+    }
+}
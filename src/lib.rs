@@ -0,0 +1,69 @@
+pub mod agenda;
+pub mod audio;
+pub mod backup;
+pub mod cache;
+pub mod chaos;
+pub mod cities;
+pub mod color;
+pub mod config;
+pub mod controller;
+pub mod devices;
+#[cfg(feature = "sacn")]
+pub mod e131;
+pub mod effects;
+pub mod error;
+pub mod geocode;
+pub mod group;
+pub mod guest;
+pub mod history;
+pub mod host;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod idle;
+pub mod instance;
+pub mod integrations;
+pub mod logs;
+pub mod metrics;
+pub mod mirror;
+#[cfg(feature = "mpris")]
+pub mod mpris;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod notifications;
+pub mod notify;
+pub mod observances;
+pub mod overrides;
+pub mod photo;
+pub mod plan;
+pub mod protocol;
+pub mod qr;
+pub mod remote;
+pub mod replay;
+pub mod report;
+pub mod resume;
+pub mod rules;
+pub mod santa;
+pub mod scan;
+pub mod scenes;
+pub mod schedule;
+pub mod soak;
+#[cfg(feature = "http")]
+pub mod status_page;
+pub mod storage;
+pub mod sun;
+pub mod supervisor;
+pub mod template;
+#[cfg(test)]
+mod testing;
+pub mod themes;
+pub mod timezone;
+pub mod transition;
+pub mod transport;
+pub mod triggers;
+pub mod update;
+pub mod wake;
+
+pub use config::Config;
+pub use controller::LightController;
+pub use error::Failure;
+pub use group::LightGroup;
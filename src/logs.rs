@@ -0,0 +1,226 @@
+// The daemon's log, also kept in memory for `logs`: the last few hundred lines, and every new
+// line for whoever is following along over the control socket (see instance.rs). Lines go to
+// the journal as before, so this is only for looking in on a running daemon without
+// journalctl.
+use chrono::{DateTime, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Mutex, OnceLock, PoisonError},
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::broadcast::{self, error::RecvError},
+};
+
+const MAX_RECENT_LINES: usize = 500;
+// Lines a slow follower may fall behind by before it misses some
+const FOLLOW_BUFFER: usize = 256;
+
+static RECENT: Mutex<VecDeque<Line>> = Mutex::new(VecDeque::new());
+static LIVE: OnceLock<broadcast::Sender<Line>> = OnceLock::new();
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub at: DateTime<Utc>,
+    pub level: Level,
+    // The module that logged it, e.g. christmas_lights::controller
+    pub target: String,
+    pub message: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let module = self.target.rsplit("::").next().unwrap_or(&self.target);
+        write!(
+            f,
+            "{}  {:<5} {}: {}",
+            self.at
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            self.level,
+            module,
+            self.message
+        )
+    }
+}
+
+/// Which lines to show: those at `level` or more severe, mentioning `device` if given.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    pub level: LevelFilter,
+    // An address or light name, matched without regard to case
+    pub device: Option<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, line: &Line) -> bool {
+        line.level <= self.level
+            && self
+                .device
+                .as_ref()
+                .is_none_or(|device| line.message.to_lowercase().contains(&device.to_lowercase()))
+    }
+
+    /// As sent over the control socket, e.g. "warn A4:C1:38:12:34:56".
+    pub fn to_request(&self) -> String {
+        match &self.device {
+            Some(device) => format!("{} {}", self.level, device),
+            None => self.level.to_string(),
+        }
+    }
+
+    pub fn from_request(request: &str) -> Option<Filter> {
+        let (level, device) = match request.trim().split_once(' ') {
+            Some((level, device)) => (level, Some(device.trim().to_string())),
+            None => (request.trim(), None),
+        };
+        Some(Filter {
+            level: level.parse().ok()?,
+            device: device.filter(|device| !device.is_empty()),
+        })
+    }
+}
+
+// Passes each line on to the journal after keeping it
+struct Recorder {
+    inner: &'static dyn Log,
+}
+
+impl Log for Recorder {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        record_line(Line {
+            at: Utc::now(),
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        });
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Logs to `inner`, keeping the lines for `logs` as well.
+pub fn init(inner: &'static dyn Log) -> Result<(), log::SetLoggerError> {
+    log::set_boxed_logger(Box::new(Recorder { inner }))
+}
+
+fn record_line(line: Line) {
+    {
+        let mut recent = RECENT.lock().unwrap_or_else(PoisonError::into_inner);
+        if recent.len() == MAX_RECENT_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line.clone());
+    }
+    // Fails only while nobody follows
+    live().send(line).ok();
+}
+
+fn live() -> &'static broadcast::Sender<Line> {
+    LIVE.get_or_init(|| broadcast::channel(FOLLOW_BUFFER).0)
+}
+
+/// Writes the recent lines that pass `filter` to `out`, then new ones as they are logged,
+/// until `out` goes away.
+pub async fn follow(mut out: impl AsyncWrite + Unpin, filter: Filter) {
+    // Subscribing first, so no line falls between the recent ones and the live ones
+    let mut lines = live().subscribe();
+    let recent: Vec<Line> = RECENT
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .filter(|line| filter.matches(line))
+        .cloned()
+        .collect();
+    let last_recent = recent.last().map(|line| line.at);
+    for line in recent {
+        if out
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+    loop {
+        let text = match lines.recv().await {
+            // Lines logged while the recent ones were copied arrive twice
+            Ok(line) if last_recent.is_some_and(|at| line.at <= at) => continue,
+            Ok(line) if filter.matches(&line) => format!("{}\n", line),
+            Ok(_) => continue,
+            Err(RecvError::Lagged(missed)) => format!("... {} lines missed\n", missed),
+            Err(RecvError::Closed) => return,
+        };
+        if out.write_all(text.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(level: Level, message: &str) -> Line {
+        Line {
+            at: Utc::now(),
+            level,
+            target: "christmas_lights::controller".to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn filters_go_by_level_and_device() {
+        let filter = Filter::from_request("warn a4:c1:38:12:34:56").unwrap();
+        assert_eq!(filter.level, LevelFilter::Warn);
+        assert!(filter.matches(&line(Level::Error, "Lost A4:C1:38:12:34:56")));
+        assert!(!filter.matches(&line(Level::Info, "Connected to A4:C1:38:12:34:56")));
+        assert!(!filter.matches(&line(Level::Warn, "Lost A4:C1:38:65:43:21")));
+        let everything = Filter {
+            level: LevelFilter::Info,
+            device: None,
+        };
+        assert_eq!(
+            Filter::from_request(&everything.to_request()),
+            Some(everything)
+        );
+        assert_eq!(Filter::from_request("loud"), None);
+    }
+
+    #[tokio::test]
+    async fn followers_get_the_recent_lines_then_new_ones() {
+        let filter = Filter {
+            level: LevelFilter::Info,
+            device: Some("follow-test".to_string()),
+        };
+        record_line(line(Level::Info, "follow-test before"));
+        let (out, mut received) = tokio::io::duplex(4096);
+        let following = tokio::spawn(follow(out, filter));
+        record_line(line(Level::Info, "follow-test after"));
+        record_line(line(Level::Info, "someone else's line"));
+        let mut text = String::new();
+        while text.matches('\n').count() < 2 {
+            let mut buffer = [0; 1024];
+            let read = tokio::io::AsyncReadExt::read(&mut received, &mut buffer)
+                .await
+                .unwrap();
+            text.push_str(std::str::from_utf8(&buffer[..read]).unwrap());
+        }
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].ends_with("INFO  controller: follow-test before"));
+        assert!(lines[1].ends_with("INFO  controller: follow-test after"));
+        drop(received);
+        record_line(line(Level::Info, "follow-test gone"));
+        following.await.unwrap();
+    }
+}
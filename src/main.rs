@@ -1,193 +1,1848 @@
-use angular_units::Deg;
-use async_mutex::Mutex;
-use btleplug::{
-    api::{
-        bleuuid::uuid_from_u16, Central, Characteristic, Manager as _, Peripheral as _, ScanFilter,
-        WriteType,
-    },
-    platform::{Adapter, Manager, Peripheral},
+mod cli;
+
+#[cfg(feature = "mqtt")]
+use christmas_lights::mqtt;
+use christmas_lights::{
+    agenda,
+    audio::AudioLevels,
+    backup,
+    chaos::{self, Faults},
+    color::{self, Calibration, EffectDefaults},
+    config::{self, StorageConfig},
+    devices,
+    effects::{self, Effect, EffectKind, Frame, Keyframes, OutdoorTemperature},
+    history, host,
+    idle::Idle,
+    instance::{self, InstanceLock},
+    integrations::{self, Context},
+    logs::{self, Filter},
+    metrics, mirror, notifications, notify, observances,
+    overrides::{Overrides, Show},
+    photo,
+    plan::{self, DailyPlan},
+    remote::{LightStatus, LiveColors, PublicStatus, RemoteCommand, Source},
+    replay, report,
+    resume::{self, Resume},
+    rules::Rules,
+    scan::{LinkQuality, ScanCoordinator},
+    scenes,
+    schedule::Schedule,
+    soak, storage,
+    supervisor::{DeviceSupervisor, FailureBudget},
+    themes,
+    timezone::Zone,
+    transition::Transition,
+    triggers::{self, Event, Events},
+    update, wake, Config, Failure, LightController, LightGroup,
 };
-use chrono::{DateTime, Datelike, Utc};
+#[cfg(feature = "http")]
+use christmas_lights::{http, qr::QrCode};
+use chrono::{Datelike, TimeZone};
+use cli::{Command, SceneAction, UpdateAction};
 use clokwerk::{AsyncScheduler, TimeUnits};
-use log::{info, LevelFilter};
-use prisma::{FromColor, Hsv, Rgb};
+use log::{debug, error, info, warn, LevelFilter};
+use prisma::Rgb;
 use std::{
-    borrow::Borrow, error::Error, sync::atomic::AtomicBool, sync::atomic::Ordering, sync::Arc,
-    time::Duration,
+    collections::{HashMap, VecDeque},
+    process::ExitCode,
+    sync::atomic::Ordering,
+    sync::atomic::{AtomicBool, AtomicI32, AtomicU32},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch},
+    time::{self, MissedTickBehavior},
 };
-use tokio::time;
-use uuid::Uuid;
 
-const LIGHT_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x1001);
-const CYCLE_TIME_MILLISECOND: u64 = 10;
-const MAGIC_NUMBER: u8 = 0x3C;
-const CURRENT_LOCATION: (f64, f64) = (47.552922, 19.254477);
+// How long the lights stay off before the schedule is looked at again
+const OFF_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// What `calibrate` shows, and what to look for in each
+const CALIBRATION_STEPS: [(&str, (u8, u8, u8), &str); 7] = [
+    (
+        "white",
+        (255, 255, 255),
+        "should look neutral; lower the gain of any channel it leans towards",
+    ),
+    (
+        "red",
+        (255, 0, 0),
+        "compare its brightness with green and blue",
+    ),
+    ("green", (0, 255, 0), "should not outshine red and blue"),
+    (
+        "blue",
+        (0, 0, 255),
+        "should not look dim next to red and green",
+    ),
+    (
+        "yellow",
+        (255, 255, 0),
+        "should be yellow, not lime; lower the green gain if it leans green",
+    ),
+    (
+        "half grey",
+        (128, 128, 128),
+        "should look about half as bright as white; raise gamma if brighter",
+    ),
+    (
+        "dim grey",
+        (32, 32, 32),
+        "should still be lit and neutral; lower gamma if it goes dark",
+    ),
+];
+const PANIC_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+// How often a light that missed the first frame of a scene is tried again before the group goes
+// back to the earlier show
+const SCENE_SWITCH_RETRIES: usize = 2;
+// How often to warn while frames take longer than the frame period
+const SLOW_FRAME_WARNING_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// Time for one discovery attempt on top of the backoff before it
+const STARTUP_ATTEMPT_ALLOWANCE: Duration = Duration::from_secs(30);
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    systemd_journal_logger::init().expect("Failed to initialize JournalCTL logger");
+async fn main() -> ExitCode {
+    if let Err(e) = logs::init(&systemd_journal_logger::LOG) {
+        eprintln!("Cannot log to the journal: {}", e);
+    }
     log::set_max_level(LevelFilter::Info);
 
+    match run(std::env::args().skip(1)).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            error!("{}", failure);
+            log::logger().flush();
+            eprintln!("{}", failure);
+            ExitCode::from(failure.exit_code())
+        }
+    }
+}
+
+async fn run(args: impl Iterator<Item = String>) -> Result<(), Failure> {
+    let invocation = cli::parse(args)?;
+    let command = invocation.command;
+    if invocation.chaos {
+        chaos::enable(Faults::default());
+    }
+    match &command {
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+        // Also how an install checks that a download runs, so it must not need a config
+        Command::Version => {
+            println!("{}", update::CURRENT_VERSION);
+            return Ok(());
+        }
+        // The schema is the same for every config
+        Command::Schema => {
+            #[cfg(feature = "mqtt")]
+            {
+                println!("{}", mqtt::state_schema());
+                return Ok(());
+            }
+            #[cfg(not(feature = "mqtt"))]
+            return Err(Failure::Usage(
+                "this build has no MQTT support, so it publishes no state".to_string(),
+            ));
+        }
+        // Prints config to paste, so it does not need one to load
+        Command::PaletteFromPhoto {
+            photo,
+            name,
+            colors,
+        } => {
+            let colors = photo::dominant_colors(photo, *colors)?;
+            print!("{}", photo::to_toml(name, &colors));
+            return Ok(());
+        }
+        // Restoring is how a broken config gets replaced, so neither needs it to load
+        Command::Backup(to) => {
+            let storage = storage::open(&stored_state_config())?;
+            let included = backup::create(to, storage.as_ref())?;
+            println!("Saved {} to {}", included.join(", "), to.display());
+            return Ok(());
+        }
+        Command::Restore(from) => {
+            for restored in backup::restore(from, &stored_state_config())? {
+                println!("Restored {}", restored);
+            }
+            println!("Restart the daemon to pick up the restored files");
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // A freshly installed binary has to get as far as connecting within a few starts, or the
+    // one it replaced comes back
+    if matches!(command, Command::Run { .. }) {
+        update::started()?;
+    }
+    let mut config = Config::load()?;
+    // Here --device picks the lines to show rather than the lights to drive
+    if let Command::Logs { level } = command {
+        let filter = Filter {
+            level,
+            device: invocation.device,
+        };
+        let mut lines = instance::follow_logs(&config.device, &filter).await?;
+        // Ends when the daemon stops
+        while let Ok(Some(line)) = lines.next_line().await {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    if let Some(device) = invocation.device {
+        config.device.pin(&device)?;
+        config.validate()?;
+    }
+    storage::select(&config.storage)?;
+    let takeover = invocation.takeover;
+    match command {
+        Command::Run { effect, brightness } => {
+            // Anything picked on the command line starts the show afresh
+            let resumed = (config.resume.enabled && effect.is_none() && brightness.is_none())
+                .then(resume::load)
+                .flatten();
+            // An effect picked on the command line also wins over calendar themes
+            if let Some(effect) = effect {
+                config.effect = effect;
+                config.themes.clear();
+                config.validate()?;
+            }
+            if let Some(brightness) = brightness {
+                config.brightness.level = brightness;
+            }
+            let lock = instance::acquire(&config.device, takeover).await?;
+            run_daemon(config, None, resumed, lock).await
+        }
+        Command::Scan => {
+            for device in LightController::scan(&config.device).await? {
+                println!(
+                    "{}  {:>4}  {}{}",
+                    device.address,
+                    device.rssi.map_or("?".to_string(), |rssi| rssi.to_string()),
+                    device.name.as_deref().unwrap_or("(unnamed)"),
+                    if device.is_light { "  [light]" } else { "" }
+                );
+            }
+            Ok(())
+        }
+        Command::On | Command::Off | Command::Color(_) => {
+            let _lock = instance::acquire(&config.device, takeover).await?;
+            let lights = LightGroup::find(&config.device, &config.connection).await?;
+            lights.connect().await?;
+            let result = match command {
+                Command::On => lights.turn_on().await,
+                Command::Color(rgb) => {
+                    lights
+                        .fade(
+                            &[],
+                            calibrated(rgb, config.calibration),
+                            config.transitions.color_change,
+                            fade_step(&config),
+                        )
+                        .await
+                }
+                _ => lights.turn_off().await,
+            };
+            lights.disconnect().await.ok();
+            if result.is_ok() {
+                history::log_command(
+                    Source::Cli,
+                    &match command {
+                        Command::On => RemoteCommand::On,
+                        Command::Color(rgb) => RemoteCommand::Color(rgb),
+                        _ => RemoteCommand::Off,
+                    },
+                );
+                history::flush_commands();
+            }
+            result
+        }
+        Command::Calibrate => {
+            let _lock = instance::acquire(&config.device, takeover).await?;
+            let lights = LightGroup::find(&config.device, &config.connection).await?;
+            lights.connect().await?;
+            let result = calibrate(&lights, config.calibration).await;
+            lights.disconnect().await.ok();
+            result
+        }
+        Command::SchedulePreview { from, to } => {
+            print_schedule(&config, from, to);
+            Ok(())
+        }
+        // Reads what the daemon saved rather than the lights, which it may be driving right now
+        Command::Devices => {
+            let saved = devices::saved();
+            match &saved {
+                Some(saved) => println!("As saved by the daemon at {}\n", local_time(saved.at)),
+                None => println!("The daemon has not saved any device states yet\n"),
+            }
+            let listed = devices::listed(
+                &config.device.addresses,
+                saved.as_ref().map_or(&[], |saved| &saved.devices),
+            );
+            println!(
+                "{:<17}  {:<11}  {:<15}  {:<16}  {:<16}  {:>9}  FIRMWARE",
+                "ADDRESS", "ROLE", "HEALTH", "LAST CONNECTED", "LAST WRITE OK", "RSSI/AVG"
+            );
+            let known = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+            for device in listed {
+                println!(
+                    "{:<17}  {:<11}  {:<15}  {:<16}  {:<16}  {:>9}  {}",
+                    device.address,
+                    if config.device.is_critical(&device.address) {
+                        "critical"
+                    } else {
+                        "best-effort"
+                    },
+                    device.health(saved.as_ref().map_or(0, |saved| saved.at)),
+                    known(device.last_connected.map(local_time)),
+                    known(device.last_write_ok.map(local_time)),
+                    known(device.rssi.back().map(|rssi| format!(
+                        "{}/{}",
+                        rssi,
+                        device.average_rssi().unwrap_or(*rssi)
+                    ))),
+                    device.firmware.as_deref().unwrap_or("-")
+                );
+            }
+            Ok(())
+        }
+        Command::ReplayDecisions { date } => {
+            let schedule = Schedule::from_config(&config);
+            let forecast = match plan::recorded_forecast(date) {
+                Some(forecast) => forecast,
+                None => {
+                    println!(
+                        "No forecast was recorded for {}, so the plan is made without one\n",
+                        date
+                    );
+                    None
+                }
+            };
+            let decisions = replay::replay(&schedule, date, forecast, &history::commands());
+            print!("{}", replay::format(&decisions, schedule.zone()));
+            Ok(())
+        }
+        Command::HistoryCommands => {
+            for logged in history::commands() {
+                println!(
+                    "{}  {:<20} {}",
+                    logged
+                        .at
+                        .with_timezone(&chrono::Local)
+                        .format("%Y-%m-%d %H:%M:%S"),
+                    logged.source,
+                    logged.command
+                );
+            }
+            Ok(())
+        }
+        Command::Scene(SceneAction::List) => {
+            for scene in scenes::all(&config) {
+                println!(
+                    "{:<16} {:<12} {:<8} brightness {} speed {}",
+                    scene.name,
+                    scene.effect.name(),
+                    scene.color.map_or("-".to_string(), |(r, g, b)| format!(
+                        "#{:02x}{:02x}{:02x}",
+                        r, g, b
+                    )),
+                    scene.brightness,
+                    scene.speed
+                );
+            }
+            Ok(())
+        }
+        Command::Scene(SceneAction::Apply(name)) => {
+            let scene = scenes::find(&name, &config)
+                .ok_or_else(|| Failure::Usage(format!("there is no scene called {:?}", name)))?;
+            // Like an effect picked with `run`, a scene wins over calendar themes
+            config = scenes::apply(&scene, &config);
+            config.themes.clear();
+            config.brightness.level *= scene.brightness;
+            let lock = instance::acquire(&config.device, takeover).await?;
+            history::log_command(Source::Cli, &RemoteCommand::Scene(name));
+            run_daemon(config, None, None, lock).await
+        }
+        Command::Scene(SceneAction::Save {
+            name,
+            effect,
+            color,
+            brightness,
+            speed,
+        }) => {
+            let mut base = config.clone();
+            if let Some(effect) = effect {
+                base.effect = effect;
+            }
+            let mut scene = scenes::capture(&name, &base, brightness.unwrap_or(1.0));
+            scene.color = color.or(scene.color);
+            scene.speed = speed.unwrap_or(scene.speed);
+            scenes::save(scene, &config)?;
+            history::log_command(Source::Cli, &RemoteCommand::SaveScene(name.clone()));
+            history::flush_commands();
+            println!("Saved scene {}", name);
+            Ok(())
+        }
+        Command::HomeAssistant => {
+            if config.mqtt.is_none() && config.http.is_none() {
+                return Err(Failure::ConfigInvalid(
+                    "set mqtt.broker or http.listen to generate Home Assistant YAML".to_string(),
+                ));
+            }
+            #[cfg(feature = "mqtt")]
+            if let Some(mqtt) = &config.mqtt {
+                println!("# MQTT light\n{}", mqtt::home_assistant_yaml(mqtt));
+            }
+            #[cfg(feature = "http")]
+            if let Some(api) = &config.http {
+                let hostname = host::hostname().unwrap_or_else(|| "localhost".to_string());
+                println!(
+                    "# Template light using the HTTP API\n{}",
+                    http::home_assistant_yaml(api, &hostname)
+                );
+            }
+            Ok(())
+        }
+        Command::Guest { minutes } => print_guest_pass(&config, minutes).await,
+        Command::Undo => {
+            instance::undo(&config.device).await?;
+            println!("Asked the daemon to undo the last change, see its log for what it did");
+            Ok(())
+        }
+        Command::Soak { hours } => {
+            info!("Soak testing for {} hours", hours);
+            let lock = instance::acquire(&config.device, takeover).await?;
+            let soak_for = Duration::from_secs_f32(hours * 3600.0);
+            run_daemon(config, Some(soak_for), None, lock).await
+        }
+        Command::Update(action) => {
+            if let UpdateAction::Rollback = action {
+                update::rollback()?;
+                println!("Rolled back, restart the daemon to run the previous version");
+                return Ok(());
+            }
+            match update::check(&config.update)? {
+                None => println!("christmas-lights {} is up to date", update::CURRENT_VERSION),
+                Some(release) => {
+                    println!(
+                        "christmas-lights {} is available, running {}",
+                        release.version,
+                        update::CURRENT_VERSION
+                    );
+                    if let UpdateAction::Install = action {
+                        update::install(&release)?;
+                        println!("Installed, restart the daemon to run it");
+                    }
+                }
+            }
+            Ok(())
+        }
+        Command::Help
+        | Command::Version
+        | Command::Logs { .. }
+        | Command::Schema
+        | Command::PaletteFromPhoto { .. }
+        | Command::Backup(_)
+        | Command::Restore(_) => Ok(()),
+    }
+}
+
+// With `soak_for` set the daemon stops after that long and reports what a soak::Monitor found.
+// `resumed` is the state the last run left off at, to carry on from. `lock` keeps other instances
+// off the lights until the daemon stops or hands them over.
+async fn run_daemon(
+    mut config: Config,
+    soak_for: Option<Duration>,
+    resumed: Option<Resume>,
+    lock: InstanceLock,
+) -> Result<(), Failure> {
+    let schedule = Schedule::from_config(&config);
+    // What undo goes back to once the command log has no older show
+    let configured_effect = config.effect;
+    let mut rules = Rules::new(config::load_rules()?, schedule);
+    if !rules.is_empty() {
+        info!("Loaded {} rule(s)", rules.len());
+    }
+    let mut idle = Idle::new(config.idle.when.clone());
+
     let mut scheduler = AsyncScheduler::with_tz(chrono::Utc);
 
-    let light = get_light().await;
-    light.connect().await?;
-    info!("Connected to lights");
-    light.discover_services().await?;
-    info!("Discovering light services");
+    metrics::load();
+    devices::load();
+    report::load();
+    let caught_up = agenda::load(chrono::Utc::now().timestamp());
+    let lights = Arc::new(find_with_retry(&config).await?);
+    metrics::connected();
 
-    let light = Arc::new(Mutex::new(light));
-    let light_clone = Arc::clone(&light);
+    install_panic_guard(Arc::clone(&lights));
+    let mut devices = DeviceSupervisor::new(
+        lights
+            .lights()
+            .iter()
+            .enumerate()
+            .map(|(i, light)| (light.address(), lights.is_critical(i))),
+        &config.devices,
+    );
 
-    let cmd_char = Arc::new(Mutex::new(
-        get_command_characteristics((*light.lock().await).borrow()).await,
-    ));
-    let cmd_char_clone = Arc::clone(&cmd_char);
+    let disconnected = Arc::new(AtomicBool::new(false));
+    {
+        let lights = Arc::clone(&lights);
+        let disconnected = Arc::clone(&disconnected);
+        tokio::spawn(async move {
+            if let Err(e) = lights.watch_disconnects(&disconnected).await {
+                warn!("Cannot watch for disconnects: {}", e);
+            }
+        });
+    }
+
+    // Checked every minute rather than run at a fixed UTC time, so the plan is made at
+    // daily_plan_time on the schedule's clocks in summer and winter alike
+    let zone = schedule.zone();
+    let today = zone.date(chrono::Utc::now().timestamp());
+    make_daily_plan(&schedule, today).await;
+    let plan_time = config.schedule.daily_plan_time;
+    let planned_day = Arc::new(AtomicI32::new(today.num_days_from_ce()));
+    scheduler.every(1.minutes()).run(move || {
+        let now = chrono::Utc::now().timestamp();
+        let today = zone.date(now);
+        let is_due = zone.at(today, plan_time) <= now
+            && planned_day.swap(today.num_days_from_ce(), Ordering::Relaxed)
+                != today.num_days_from_ce();
+        async move {
+            if is_due {
+                make_daily_plan(&schedule, today).await;
+            }
+        }
+    });
 
-    let is_off = Arc::new(AtomicBool::new(false));
+    let is_off_hours = is_planned_off(&schedule);
+    let was_held_off = resumed
+        .as_ref()
+        .is_some_and(|last| last.is_held_off(&schedule, chrono::Utc::now()));
+    let stay_off = (config.schedule.startup_stay_off || was_held_off) && !is_off_hours;
+    if is_off_hours || stay_off {
+        info!("Starting with lights off");
+        if let Err(e) = lights.turn_off().await {
+            warn!("Failed to turn off lights: {}", e);
+        }
+    }
+
+    let is_off = Arc::new(AtomicBool::new(is_off_hours || stay_off));
     let is_off_clone = Arc::clone(&is_off);
+    let is_held_off = Arc::new(AtomicBool::new(stay_off));
+    let is_held_off_clone = Arc::clone(&is_held_off);
     scheduler.every(2.minutes()).run(move || {
         let is_off_clone = is_off_clone.clone();
-        let light_clone = light_clone.clone();
-        let cmd_char_clone = cmd_char_clone.clone();
+        let is_held_off = is_held_off_clone.clone();
         async move {
-            if is_after_sunrise() && is_before_sunset() {
-                if !is_off_clone.load(Ordering::Relaxed) {
-                    is_off_clone.store(true, Ordering::Relaxed);
-                    info!("Turning off lights");
-                    turn_off_lights(
-                        cmd_char_clone.lock().await.borrow(),
-                        (*light_clone.lock().await).borrow(),
-                    )
-                    .await;
-                    info!("Turned off lights");
+            // The render loop fades the lights out and turns them off
+            if is_planned_off(&schedule) {
+                is_held_off.store(false, Ordering::Relaxed);
+                if !is_off_clone.swap(true, Ordering::Relaxed) {
+                    history::log_command(Source::Schedule, &RemoteCommand::Off);
                 }
-            } else if is_off_clone.load(Ordering::Relaxed) {
+            } else if is_off_clone.load(Ordering::Relaxed) && !is_held_off.load(Ordering::Relaxed) {
                 is_off_clone.store(false, Ordering::Relaxed);
+                history::log_command(Source::Schedule, &RemoteCommand::On);
                 info!("Turned on lights!");
             }
         }
     });
+    if let Some(notifications) = config
+        .notifications
+        .clone()
+        .filter(|notifications| notifications.weekly_report)
+    {
+        scheduler.every(1.hour()).run(move || {
+            let url = notifications.url.clone();
+            async move {
+                let today = chrono::Local::now().date_naive();
+                if let Some(report) = report::weekly(&schedule, today) {
+                    info!("{}", report);
+                    report::save();
+                    tokio::task::spawn_blocking(move || notifications::send(&url, &report));
+                }
+            }
+        });
+    }
+    // The render loop logs commands in memory; they reach storage from here, away from it
+    scheduler.every(1.minute()).run(|| async {
+        tokio::task::spawn_blocking(history::flush_commands)
+            .await
+            .ok();
+    });
 
-    let mut hue_deg = 1.0;
-    loop {
-        scheduler.run_pending().await;
-
-        if !is_off.load(Ordering::Relaxed) {
-            hue_deg = (hue_deg + 1.0) % 360.0;
-            let hsv = Hsv::new(Deg(hue_deg), 1.0, 1.0);
-            let rgb = Rgb::from_color(&hsv);
-            let (r, g, b) = rgb_f32_to_u8_capped(rgb);
-            set_color(
-                (*cmd_char.lock().await).borrow(),
-                (*light.lock().await).borrow(),
-                (r, g, b),
-            )
-            .await;
+    // The dimming curve's current level, as f32 bits
+    let curve = config.brightness.curve.clone();
+    let dimming = Arc::new(AtomicU32::new(
+        curve.level(&schedule, chrono::Utc::now()).to_bits(),
+    ));
+    if !curve.steps.is_empty() {
+        let dimming = Arc::clone(&dimming);
+        scheduler.every(1.minutes()).run(move || {
+            let level = curve.level(&schedule, chrono::Utc::now());
+            if dimming.swap(level.to_bits(), Ordering::Relaxed) != level.to_bits() {
+                info!("Dimming curve is at {:.0}%", level * 100.0);
+            }
+            async {}
+        });
+    }
 
-            time::sleep(Duration::from_millis(CYCLE_TIME_MILLISECOND)).await;
-        } else {
-            time::sleep(Duration::from_secs(60)).await;
+    let on_battery = Arc::new(AtomicBool::new(false));
+    if config.battery.enabled {
+        let on_battery_clone = Arc::clone(&on_battery);
+        scheduler.every(1.minutes()).run(move || {
+            let on_battery_clone = on_battery_clone.clone();
+            async move {
+                let is_on_battery = tokio::task::spawn_blocking(host::is_on_battery)
+                    .await
+                    .unwrap_or(false);
+                if is_on_battery != on_battery_clone.swap(is_on_battery, Ordering::Relaxed) {
+                    if is_on_battery {
+                        warn!("Host is running on battery, dimming lights");
+                    } else {
+                        info!("Host is back on mains power");
+                    }
+                }
+            }
+        });
+    }
+
+    let is_overheating = Arc::new(AtomicBool::new(false));
+    let is_overheating_clone = Arc::clone(&is_overheating);
+    let temperature_limit = config.thermal.limit_celsius;
+    scheduler.every(30.seconds()).run(move || {
+        let is_overheating_clone = is_overheating_clone.clone();
+        async move {
+            let Some(temperature) = host::cpu_temperature() else {
+                return;
+            };
+            let overheating = temperature > temperature_limit;
+            if overheating != is_overheating_clone.swap(overheating, Ordering::Relaxed) {
+                if overheating {
+                    warn!(
+                        "CPU temperature is {:.1}°C, throttling animation",
+                        temperature
+                    );
+                } else {
+                    info!(
+                        "CPU temperature is {:.1}°C, resuming animation",
+                        temperature
+                    );
+                }
+            }
         }
+    });
+
+    // `kill -USR1` dumps the recent frames and commands without waiting for a crash
+    tokio::spawn(async {
+        let Ok(mut dump_requests) = signal(SignalKind::user_defined1()) else {
+            warn!("Cannot listen for SIGUSR1, debug dumps only happen on failures");
+            return;
+        };
+        while dump_requests.recv().await.is_some() {
+            history::dump();
+        }
+    });
+
+    // Sensor readings arrive as remote commands and are read by the temperature effect
+    let outdoor = OutdoorTemperature::new();
+    let audio = AudioLevels::new();
+    // Calendar themes stand in for the configured effect on their dates and switch at midnight
+    // in the schedule's timezone; remote changes last until the next switch
+    let base_config = config.clone();
+    let mut theme_date = zone.date(chrono::Utc::now().timestamp());
+    let (themed, mut theme) = themed_config(&base_config, theme_date);
+    config = themed;
+    if let Some(theme) = &theme {
+        info!("Using the {} theme", theme);
     }
-}
+    // How far into the animation the last run got, and its brightness
+    let mut position = Duration::ZERO;
+    let mut resumed_brightness = None;
+    if let Some(last) = resumed.filter(|last| last.is_from(theme_date)) {
+        let updated = scenes::apply(&last.show, &config);
+        match updated.validate() {
+            Ok(()) => {
+                info!("Resuming the show where the last run left off");
+                config = updated;
+                position = last.position;
+                resumed_brightness = Some(last.show.brightness);
+            }
+            Err(e) => warn!("Not resuming the last show: {}", e),
+        }
+    }
+    let (mut renderers, mut defaults) = build_renderers(&config, lights.len(), &outdoor, &audio);
+    info!(
+        "Showing the {} effect on {} light(s)",
+        config.effect.name(),
+        lights.len()
+    );
+    let watts_full_white: Vec<f32> = (0..lights.len())
+        .map(|i| config.power.watts_full_white(i))
+        .collect();
+    // The latest frame of each light, as lights blending frames do not all render every cycle
+    let mut colors = vec![(0, 0, 0); lights.len()];
+    let started = Instant::now();
+    let mut write_failures = 0;
+    let mut paused_until: Option<Instant> = None;
+    let mut reconnect_backoff = RECONNECT_INITIAL_BACKOFF;
+    let mut switched_on_at: Option<Instant> = None;
+    // The plan last sent as the daily summary, by its date
+    let mut summarized: Option<chrono::NaiveDate> = None;
+    // The phone's next alarm in Unix seconds, for the wake-up sunrise
+    let mut next_alarm: Option<i64> = None;
+    let mut scans = ScanCoordinator::new(config.scan.interval, config.scan.window, Instant::now());
 
-async fn get_light() -> Peripheral {
-    let manager = Manager::new().await.unwrap();
-    let central = manager
-        .adapters()
-        .await
-        .expect("Unable to fetch adapter list.")
+    // Remote integrations send commands here and watch the status
+    let (remote_tx, mut remote_commands) = mpsc::channel(16);
+    let mut brightness = resumed_brightness.unwrap_or(1.0);
+    let (status_tx, status_rx) = watch::channel(LightStatus {
+        on: !is_off.load(Ordering::Relaxed),
+        effect: config.effect,
+        color: config.solid.color,
+        brightness,
+        override_until: None,
+    });
+    if config.update.enabled {
+        let budget = FailureBudget::new("Update check", &config.supervisor);
+        tokio::spawn(update::run(config.update.clone(), budget));
+    }
+    let (public_tx, public_rx) = watch::channel(PublicStatus {
+        on: false,
+        color: (0, 0, 0),
+        next_change: None,
+    });
+    let live = LiveColors::new();
+    let undo_tx = remote_tx.clone();
+    let context = Context {
+        commands: remote_tx,
+        status: status_rx.clone(),
+        public: public_rx,
+        live: live.clone(),
+        schedule,
+        lights: lights.len(),
+        device: lights.device_information(),
+        connections: lights.progress(),
+    };
+    for integration in integrations::enabled(&config) {
+        let budget = FailureBudget::new(integration.name(), &config.supervisor);
+        tokio::spawn(integration.start(context.clone(), budget));
+    }
+    for trigger in triggers::enabled(&config) {
+        let budget = FailureBudget::new(trigger.name(), &config.supervisor);
+        let events = Events::new(
+            &trigger.name().to_ascii_lowercase(),
+            context.commands.clone(),
+        );
+        tokio::spawn(trigger.start(events, budget));
+    }
+    for (section, feature) in integrations::unavailable(&config) {
+        warn!(
+            "This build has no {} support, so [{}] is ignored; rebuild with --features {}",
+            feature, section, feature
+        );
+    }
+    // Commands taken off the channel while the lights were off, those rules sent, and the
+    // agenda's catch-up
+    let mut pending_commands: VecDeque<_> = caught_up
         .into_iter()
-        .next()
-        .expect("Unable to find adapters.");
-    info!("Found adapter: {:?}", central);
+        .map(|command| (Source::Agenda, command))
+        .collect();
+    // The show as last saved for the next run to resume, and when
+    let mut last_state: Option<Resume> = None;
+    let mut state_saved_at = Instant::now();
+    // When the signal strengths were last read and the device states saved
+    let mut devices_saved_at = Instant::now();
 
-    central.start_scan(ScanFilter::default()).await.ok();
-    info!("Starting scan for BLE devices");
-    time::sleep(Duration::from_secs(2)).await;
+    // What each light's effect showed last and the color written to it, for fading between
+    // effects and out at switch-off
+    let mut transitions: Vec<Option<Transition>> = vec![None; lights.len()];
+    let mut last_frames = vec![Rgb::new(0.0, 0.0, 0.0); lights.len()];
+    let mut last_sent = vec![(0, 0, 0); lights.len()];
+    // When each person was last welcomed home, and when the current welcome scene ends
+    let mut welcomed_at: HashMap<String, Instant> = HashMap::new();
+    let mut welcome_until: Option<Instant> = None;
+    // The show to go back to after a remote command sent with a TTL
+    let mut overrides = Overrides::default();
+    // A scene switching several lights, with the show and frames to go back to should its first
+    // frame not reach every one of them
+    let mut scene_switch: Option<(String, Show, Vec<Frame>)> = None;
 
-    let light = print_devices(&central)
-        .await
-        .expect("No Actuel lights found");
-    info!("Found lights: {:?}", light);
+    // Pinging from the render loop lets systemd restart the daemon when a write hangs
+    let watchdog_ping = notify::watchdog_interval().map(|interval| interval / 2);
+    let mut last_ping = Instant::now();
+    notify::ready();
+    update::confirm();
+
+    // Frames go out on a fixed grid whatever the writes take, so slow writes cost frames rather
+    // than stretching the frame period
+    let mut frame_period = config.animation.cycle_time;
+    let mut frame_ticks = pacing(frame_period);
+    let mut slow_frames_warned_at: Option<Instant> = None;
+
+    let turn_off_on_shutdown = config.shutdown.turn_off;
+    let render = async {
+        loop {
+            if watchdog_ping.is_some_and(|every| last_ping.elapsed() >= every) {
+                notify::watchdog();
+                last_ping = Instant::now();
+            }
+            scheduler.run_pending().await;
+
+            let today = zone.date(chrono::Utc::now().timestamp());
+            if today != theme_date {
+                theme_date = today;
+                let (themed, name) = themed_config(&base_config, today);
+                if name != theme {
+                    info!(
+                        "Switching to the {} theme",
+                        name.as_deref().unwrap_or("configured")
+                    );
+                    theme = name;
+                    // The new theme replaces whatever an override would go back to
+                    overrides.clear();
+                    config = themed;
+                    (renderers, defaults) =
+                        build_renderers(&config, lights.len(), &outdoor, &audio);
+                    transitions = fade_from(
+                        &last_frames,
+                        Instant::now(),
+                        config.transitions.color_change,
+                    );
+                }
+            }
 
-    light
+            if let Some(show) = overrides.expired(Instant::now()) {
+                info!("Temporary override ended, going back to the earlier show");
+                if let Some((off, held_off)) = show.power {
+                    is_off.store(off, Ordering::Relaxed);
+                    is_held_off.store(held_off, Ordering::Relaxed);
+                }
+                brightness = show.brightness;
+                config = show.config;
+                (renderers, defaults) = build_renderers(&config, lights.len(), &outdoor, &audio);
+                transitions = fade_from(
+                    &last_frames,
+                    Instant::now(),
+                    config.transitions.color_change,
+                );
+            }
+
+            let due = agenda::due(chrono::Utc::now().timestamp());
+            pending_commands.extend(due.into_iter().map(|command| (Source::Agenda, command)));
+            while let Ok(command) = remote_commands.try_recv() {
+                pending_commands.push_back(command);
+            }
+            power_first(&mut pending_commands);
+            if overrides.ends_at().is_none() {
+                agenda::override_ended();
+            }
+            while let Some((source, command)) = pending_commands
+                .pop_front()
+                .or_else(|| remote_commands.try_recv().ok())
+            {
+                if let Some(event) = Event::from_command(&command) {
+                    let status = light_status(
+                        is_off.load(Ordering::Relaxed),
+                        &config,
+                        brightness,
+                        &overrides,
+                    );
+                    let now = chrono::Utc::now().timestamp();
+                    for (rule, action) in rules.fire(&event, &status, now, Instant::now()) {
+                        info!("Rule {} fired on {}", rule, event);
+                        pending_commands.push_back((Source::Rule(rule), action));
+                    }
+                    match idle.observe(&event) {
+                        Some(true) => info!("Nobody benefits from the show, pausing it"),
+                        // From what the lights were left showing to where the show is now
+                        Some(false) => {
+                            info!("Resuming the show");
+                            transitions = fade_from(
+                                &last_frames,
+                                Instant::now(),
+                                config.transitions.color_change,
+                            );
+                        }
+                        None => {}
+                    }
+                }
+                // Sensors report every few minutes, which would flood the log
+                if let RemoteCommand::OutdoorTemperature(celsius) = command {
+                    debug!("Outdoor temperature is {:.1}°C", celsius);
+                    outdoor.set(celsius);
+                    continue;
+                }
+                if let RemoteCommand::NextAlarm(alarm) = command {
+                    match alarm.and_then(|at| zone.local(at)) {
+                        Some(at) => info!("Next alarm at {}", at.format("%a %H:%M")),
+                        None => info!("No alarm set"),
+                    }
+                    next_alarm = alarm;
+                    continue;
+                }
+                info!("Remote command from {}: {}", source, command);
+                history::log_command(source, &command);
+                let command = match command {
+                    RemoteCommand::Undo => {
+                        let shown = RemoteCommand::Effect(configured_effect);
+                        match history::undo(&history::commands(), shown) {
+                            Some(previous) => {
+                                info!("Going back to {}", previous);
+                                previous
+                            }
+                            None => {
+                                info!("Nothing to undo");
+                                continue;
+                            }
+                        }
+                    }
+                    command => command,
+                };
+                let command = match command {
+                    RemoteCommand::Temporary(command, ttl) => {
+                        // Power only goes back if the override switched it
+                        let power = matches!(*command, RemoteCommand::On | RemoteCommand::Off)
+                            .then(|| {
+                                (
+                                    is_off.load(Ordering::Relaxed),
+                                    is_held_off.load(Ordering::Relaxed),
+                                )
+                            });
+                        let prior = Show {
+                            config: config.clone(),
+                            brightness,
+                            power,
+                        };
+                        overrides.start(prior, Instant::now() + ttl);
+                        agenda::overriding(chrono::Utc::now().timestamp(), (*command).clone(), ttl);
+                        *command
+                    }
+                    command => {
+                        if command.can_expire() {
+                            overrides.clear();
+                            agenda::override_ended();
+                        }
+                        command
+                    }
+                };
+                match command {
+                    RemoteCommand::On => {
+                        is_held_off.store(false, Ordering::Relaxed);
+                        is_off.store(false, Ordering::Relaxed);
+                    }
+                    // Stays off until the next sunset, like startup_stay_off
+                    RemoteCommand::Off => {
+                        is_held_off.store(true, Ordering::Relaxed);
+                        is_off.store(true, Ordering::Relaxed);
+                    }
+                    RemoteCommand::Brightness(value) => brightness = value,
+                    RemoteCommand::OutdoorTemperature(_)
+                    | RemoteCommand::Temporary(..)
+                    | RemoteCommand::Event(_)
+                    | RemoteCommand::Undo
+                    | RemoteCommand::NextAlarm(_) => {}
+                    RemoteCommand::Arrived(name) => {
+                        let now = Instant::now();
+                        if !config.welcome.people.contains(&name) {
+                            warn!(
+                                "Ignoring the arrival of {:?}, who is not in welcome.people",
+                                name
+                            );
+                        } else if is_off.load(Ordering::Relaxed) {
+                            info!("{} came home while the lights are off", name);
+                        } else if welcomed_at
+                            .get(&name)
+                            .is_some_and(|at| now.duration_since(*at) < config.welcome.cooldown)
+                        {
+                            info!("Already welcomed {} home recently", name);
+                        } else {
+                            info!("Welcoming {} home", name);
+                            welcomed_at.insert(name, now);
+                            welcome_until = Some(now + config.welcome.duration);
+                            transitions =
+                                fade_from(&last_frames, now, config.transitions.color_change);
+                        }
+                    }
+                    RemoteCommand::SaveScene(name) => {
+                        match scenes::save(scenes::capture(&name, &config, brightness), &config) {
+                            Ok(()) => info!("Saved scene {}", name),
+                            Err(e) => warn!("Not saving scene {:?}: {}", name, e),
+                        }
+                    }
+                    RemoteCommand::Color(_)
+                    | RemoteCommand::Effect(_)
+                    | RemoteCommand::Scene(_)
+                    | RemoteCommand::Palette(_) => {
+                        let mut updated = config.clone();
+                        let mut scene_brightness = None;
+                        let switching_to = match &command {
+                            RemoteCommand::Scene(name) if lights.len() > 1 => Some(name.clone()),
+                            _ => None,
+                        };
+                        match command {
+                            RemoteCommand::Color(rgb) => {
+                                updated.effect = EffectKind::Solid;
+                                updated.solid.color = rgb;
+                            }
+                            RemoteCommand::Effect(effect) => updated.effect = effect,
+                            RemoteCommand::Scene(name) => {
+                                let Some(scene) = scenes::find(&name, &base_config) else {
+                                    warn!("Ignoring unknown scene {:?}", name);
+                                    continue;
+                                };
+                                updated = scenes::apply(&scene, &config);
+                                scene_brightness = Some(scene.brightness);
+                            }
+                            RemoteCommand::Palette(name) => {
+                                if !config.palettes.iter().any(|palette| palette.name == name) {
+                                    warn!("Ignoring unknown palette {:?}", name);
+                                    continue;
+                                }
+                                updated.effect = EffectKind::Palette;
+                                updated.palette.active = Some(name);
+                            }
+                            _ => {}
+                        }
+                        match updated.validate() {
+                            Ok(()) => {
+                                if let Some(name) = switching_to {
+                                    let prior = Show {
+                                        config: config.clone(),
+                                        brightness,
+                                        power: None,
+                                    };
+                                    scene_switch = Some((name, prior, last_frames.clone()));
+                                }
+                                config = updated;
+                                brightness = scene_brightness.unwrap_or(brightness);
+                                (renderers, defaults) =
+                                    build_renderers(&config, lights.len(), &outdoor, &audio);
+                                transitions = fade_from(
+                                    &last_frames,
+                                    Instant::now(),
+                                    config.transitions.color_change,
+                                );
+                            }
+                            Err(e) => warn!("Ignoring remote command: {}", e),
+                        }
+                    }
+                }
+            }
+            let status = light_status(
+                is_off.load(Ordering::Relaxed),
+                &config,
+                brightness,
+                &overrides,
+            );
+            if *status_tx.borrow() != status {
+                status_tx.send_replace(status);
+            }
+            metrics::record(
+                status.on,
+                config.brightness.level
+                    * f32::from_bits(dimming.load(Ordering::Relaxed))
+                    * brightness,
+            );
+            if devices_saved_at.elapsed() >= devices::SAMPLE_INTERVAL {
+                lights.sample_rssi().await;
+                report::sample(
+                    status.on && !idle.is_idle(),
+                    &devices::current(),
+                    chrono::Utc::now().timestamp(),
+                );
+                devices::save();
+                report::save();
+                devices_saved_at = Instant::now();
+            }
+            if config.resume.enabled {
+                // After a restart the show under an override comes back, not the override
+                let held_off = is_held_off.load(Ordering::Relaxed);
+                let (shown, shown_brightness, held_off) = match overrides.prior() {
+                    Some(show) => (
+                        &show.config,
+                        show.brightness,
+                        show.power.map_or(held_off, |(_, held_off)| held_off),
+                    ),
+                    None => (&config, brightness, held_off),
+                };
+                let state = Resume::capture(
+                    shown,
+                    shown_brightness,
+                    held_off,
+                    position + started.elapsed().mul_f32(config.animation.speed),
+                );
+                if last_state
+                    .as_ref()
+                    .is_none_or(|last| !last.same_show(&state))
+                    || state_saved_at.elapsed() >= config.resume.save_interval
+                {
+                    resume::save(&state);
+                    state_saved_at = Instant::now();
+                }
+                last_state = Some(state);
+            }
+            if config.status_page.is_some() {
+                let now = chrono::Utc::now();
+                let previous = *public_tx.borrow();
+                // The next switch only moves when the lights switch or once it has passed
+                let next_change = if previous.on != status.on
+                    || previous.next_change.is_none_or(|at| now.timestamp() >= at)
+                {
+                    schedule.next_change(now, status.on)
+                } else {
+                    previous.next_change
+                };
+                let public = PublicStatus {
+                    on: status.on,
+                    color: if status.on {
+                        color::rgb_f32_to_u8_capped(last_frames[0], Calibration::NONE)
+                    } else {
+                        (0, 0, 0)
+                    },
+                    next_change,
+                };
+                if previous != public {
+                    public_tx.send_replace(public);
+                }
+            }
+
+            // Our own reconnects also disconnect first, so only react if the link is really gone
+            if disconnected.swap(false, Ordering::Relaxed)
+                && paused_until.is_none()
+                && !lights.is_connected().await
+            {
+                info!("Lost connection to lights, reconnecting");
+                metrics::disconnected();
+                paused_until = Some(Instant::now());
+                reconnect_backoff = RECONNECT_INITIAL_BACKOFF;
+            }
+
+            let link = if paused_until.is_some() {
+                LinkQuality::Down
+            } else if write_failures > 0 {
+                LinkQuality::Degraded
+            } else {
+                LinkQuality::Healthy
+            };
+            if let Some(scanning) = scans.update(Instant::now(), link) {
+                if let Err(e) = lights.set_scanning(scanning).await {
+                    warn!("Failed to toggle BLE scanning: {}", e);
+                }
+            }
+
+            if let Some(until) = paused_until {
+                if Instant::now() < until {
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+                match lights.reconnect(|i| devices.is_shed(i)).await {
+                    Ok(()) => {
+                        paused_until = None;
+                        reconnect_backoff = RECONNECT_INITIAL_BACKOFF;
+                        // The lights may have been power-cycled while we were away
+                        switched_on_at = None;
+                        metrics::reconnected();
+                        info!("Reconnected to lights, resuming");
+                    }
+                    Err(e) => {
+                        info!(
+                            "Lights are still unavailable ({}), retrying in {}s",
+                            e,
+                            reconnect_backoff.as_secs()
+                        );
+                        paused_until = Some(Instant::now() + reconnect_backoff);
+                        // Never shortens a longer pause, e.g. the vendor app grace period
+                        reconnect_backoff = reconnect_backoff.max(
+                            (reconnect_backoff * 2).min(config.connection.reconnect_backoff_max),
+                        );
+                        continue;
+                    }
+                }
+            }
+
+            // The sunrise lights up the morning whatever the schedule says
+            let waking = next_alarm.and_then(|alarm| {
+                let now = chrono::Utc::now().timestamp_millis() as f64 / 1000.0;
+                wake::sunrise(&config.wake, alarm, now)
+            });
+
+            // The lights hold their last color and stay connected, without a write per frame
+            if idle.is_idle() && !is_off.load(Ordering::Relaxed) && waking.is_none() {
+                let wait = off_wait(watchdog_ping);
+                if let Ok(command) = time::timeout(wait, remote_commands.recv()).await {
+                    pending_commands.extend(command);
+                }
+                continue;
+            }
+
+            if !is_off.load(Ordering::Relaxed) || waking.is_some() {
+                let now = Instant::now();
+                if switched_on_at.is_none() {
+                    transitions = vec![
+                        Some(Transition::fade_in(now, config.transitions.fade_in));
+                        lights.len()
+                    ];
+                    let summary = config
+                        .notifications
+                        .as_ref()
+                        .filter(|notifications| notifications.daily_summary)
+                        .zip(plan::current())
+                        .filter(|(_, plan)| summarized != Some(plan.date) && waking.is_none());
+                    if let Some((notifications, plan)) = summary {
+                        summarized = Some(plan.date);
+                        let url = notifications.url.clone();
+                        let summary = plan.summary(config.effect.name(), zone);
+                        info!("{}", summary);
+                        tokio::task::spawn_blocking(move || notifications::send(&url, &summary));
+                    }
+                }
+                let switched_on_at = *switched_on_at.get_or_insert(now);
+                let (value, mut cycle_time) = if on_battery.load(Ordering::Relaxed) {
+                    (
+                        config.battery.brightness,
+                        config.animation.cycle_time * config.battery.cycle_slowdown,
+                    )
+                } else {
+                    (1.0, config.animation.cycle_time)
+                };
+                let value = value
+                    * config.brightness.level
+                    * f32::from_bits(dimming.load(Ordering::Relaxed));
+                if is_overheating.load(Ordering::Relaxed) {
+                    cycle_time *= config.thermal.cycle_slowdown;
+                }
+                if cycle_time != frame_period {
+                    frame_period = cycle_time;
+                    frame_ticks = pacing(frame_period);
+                }
+                let welcoming = welcome_until.is_some_and(|until| now < until);
+                if welcome_until.is_some() && !welcoming {
+                    welcome_until = None;
+                    transitions = fade_from(&last_frames, now, config.transitions.color_change);
+                }
+                for i in devices.returning(now) {
+                    if let Err(e) = lights.lights()[i].reconnect().await {
+                        devices.shed_again(i, now, e);
+                    }
+                }
+                let sequenced = live.colors();
+                let frame_started = Instant::now();
+                let mut rendered = vec![false; lights.len()];
+                for (i, (effect, keyframes)) in renderers.iter_mut().enumerate() {
+                    let elapsed = position
+                        + started.elapsed().mul_f32(config.animation.speed)
+                        + config.device.phase_offset * i as u32;
+                    let mut frame = effect.next_frame(elapsed);
+                    if let Some(sunrise) = waking {
+                        frame = sunrise;
+                    } else if welcoming {
+                        let (r, g, b) = config.welcome.color;
+                        frame = Rgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+                    } else if let Some(&(r, g, b)) = sequenced.as_ref().and_then(|s| s.get(i)) {
+                        frame = Rgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+                    }
+                    if let Some(transition) = transitions[i] {
+                        frame = transition.apply(frame, now);
+                        if transition.is_done(now) {
+                            transitions[i] = None;
+                        }
+                    }
+                    last_frames[i] = frame;
+                    if devices.is_shed(i) {
+                        continue;
+                    }
+                    let Some(rgb) = keyframes.push(frame, now) else {
+                        continue;
+                    };
+                    let mut rgb = color::rgb_f32_to_u8_capped(
+                        color::scale_rgb(rgb, defaults.brightness * value * brightness),
+                        config.calibration,
+                    );
+                    if let Some(plan) = plan::current() {
+                        rgb = plan.apply(rgb, chrono::Utc::now().timestamp());
+                    }
+                    colors[i] = color::apply_white_point(rgb, defaults.white_point);
+                    rendered[i] = true;
+                }
+                if !rendered.contains(&true) {
+                    next_frame(
+                        &mut frame_ticks,
+                        &mut remote_commands,
+                        &mut pending_commands,
+                    )
+                    .await;
+                    continue;
+                }
+                // The budget covers the whole installation, so every light is scaled by the same
+                // factor, and lights that did not render this cycle count with what they still show
+                let limited = color::limit_group_to_power_budget(
+                    &colors,
+                    &watts_full_white,
+                    config.power.budget_watts,
+                );
+                let frame: Vec<Option<(u8, u8, u8)>> = (0..lights.len())
+                    .map(|i| {
+                        rendered[i].then(|| {
+                            color::soft_start(
+                                limited[i],
+                                now.duration_since(switched_on_at),
+                                config.power.soft_start,
+                                config.power.soft_start_brightness,
+                            )
+                        })
+                    })
+                    .collect();
+                // The first frame of a scene goes to every light or none, so the display is
+                // never left half switched
+                let switching = scene_switch.take();
+                let retries = if switching.is_some() {
+                    SCENE_SWITCH_RETRIES
+                } else {
+                    0
+                };
+                let written = lights.write_frame(&frame, retries).await;
+                let missed = written
+                    .iter()
+                    .position(|written| matches!(written, Some(Err(_))));
+                let rolled_back = match (switching, missed) {
+                    (Some((name, prior, prior_frames)), Some(i)) => {
+                        warn!(
+                            "Scene {} did not reach {}, going back to the earlier show",
+                            name,
+                            lights.lights()[i].address()
+                        );
+                        let back: Vec<Option<(u8, u8, u8)>> = written
+                            .iter()
+                            .enumerate()
+                            .map(|(i, written)| {
+                                matches!(written, Some(Ok(()))).then_some(last_sent[i])
+                            })
+                            .collect();
+                        lights.write_frame(&back, 0).await;
+                        overrides.clear();
+                        config = prior.config;
+                        brightness = prior.brightness;
+                        (renderers, defaults) =
+                            build_renderers(&config, lights.len(), &outdoor, &audio);
+                        last_frames = prior_frames;
+                        transitions = fade_from(&last_frames, now, config.transitions.color_change);
+                        true
+                    }
+                    _ => false,
+                };
+                let mut failed = false;
+                for (i, (written, rgb)) in written.into_iter().zip(frame).enumerate() {
+                    let (Some(written), Some(rgb)) = (written, rgb) else {
+                        continue;
+                    };
+                    if !rolled_back {
+                        history::record_frame(i, rgb);
+                        last_sent[i] = rgb;
+                    }
+                    match written {
+                        Ok(()) => devices.succeeded(i),
+                        Err(e) => {
+                            // Only critical lights reconnect the group; the rest may be shed
+                            failed |= lights.is_critical(i);
+                            for shed in devices.failed(i, now, e) {
+                                lights.lights()[shed].disconnect().await.ok();
+                            }
+                        }
+                    }
+                }
+                mirror::publish(&last_sent);
+                let frame_time = frame_started.elapsed();
+                metrics::frame(frame_time);
+                if frame_time > frame_period
+                    && slow_frames_warned_at
+                        .is_none_or(|at| at.elapsed() >= SLOW_FRAME_WARNING_INTERVAL)
+                {
+                    warn!(
+                        "A frame took {}ms to write, longer than the {}ms frame period, so frames are being dropped",
+                        frame_time.as_millis(),
+                        frame_period.as_millis()
+                    );
+                    slow_frames_warned_at = Some(Instant::now());
+                }
+
+                if !failed {
+                    write_failures = 0;
+                } else {
+                    write_failures += 1;
+                    if write_failures >= config.connection.reconnect_after_failed_writes {
+                        warn!("{} consecutive writes failed, reconnecting", write_failures);
+                        history::dump();
+                        write_failures = 0;
+                        if !lights.is_connected().await {
+                            info!(
+                                "Lights dropped the connection, pausing for {}s in case another app took over",
+                                config.connection.vendor_app_grace_period.as_secs()
+                            );
+                            metrics::disconnected();
+                            paused_until =
+                                Some(Instant::now() + config.connection.vendor_app_grace_period);
+                            reconnect_backoff = config.connection.vendor_app_grace_period;
+                        } else {
+                            match lights.reconnect(|i| devices.is_shed(i)).await {
+                                Ok(()) => {
+                                    metrics::reconnected();
+                                    info!("Reconnected to lights");
+                                }
+                                Err(e) => warn!("Failed to reconnect to lights: {}", e),
+                            }
+                        }
+                    }
+                }
+
+                next_frame(
+                    &mut frame_ticks,
+                    &mut remote_commands,
+                    &mut pending_commands,
+                )
+                .await;
+            } else {
+                if switched_on_at.is_some() {
+                    info!("Turning off lights");
+                    if let Err(e) = lights
+                        .fade(
+                            &last_sent,
+                            (0, 0, 0),
+                            config.transitions.fade_out,
+                            fade_step(&config),
+                        )
+                        .await
+                    {
+                        warn!("Failed to fade out lights: {}", e);
+                    }
+                    match lights.turn_off().await {
+                        Ok(()) => info!("Turned off lights"),
+                        Err(e) => warn!("Failed to turn off lights: {}", e),
+                    }
+                    mirror::publish(&vec![(0, 0, 0); lights.len()]);
+                }
+                // Wakes up early for remote commands, e.g. to turn the lights on, and in time
+                // for the next watchdog ping at the top of the loop
+                let wait = off_wait(watchdog_ping);
+                if let Ok(command) = time::timeout(wait, remote_commands.recv()).await {
+                    pending_commands.extend(command);
+                }
+                switched_on_at = None;
+                // The lights come back on in the new show, so there is nothing to go back to
+                scene_switch = None;
+                for (_, keyframes) in &mut renderers {
+                    keyframes.reset();
+                }
+            }
+        }
+    };
+
+    let mut monitor =
+        soak_for.map(|duration| soak::Monitor::new(duration, schedule, status_rx.clone()));
+    let soak_over = async {
+        match &mut monitor {
+            Some(monitor) => monitor.run().await,
+            None => std::future::pending().await,
+        }
+    };
+    // The render loop never finishes on its own, so this only returns once a signal arrives or
+    // the soak test is over, or another instance takes the lights over
+    let mut takeover = None;
+    let received = tokio::select! {
+        _ = render => return Ok(()),
+        received = shutdown_signal() => received,
+        () = soak_over => "the end of the soak test",
+        requester = lock.takeover_requested(&undo_tx) => {
+            takeover = Some(requester);
+            "a takeover by another instance"
+        }
+    };
+    info!("Received {}, shutting down", received);
+    notify::stopping();
+    metrics::save();
+    history::flush_commands();
+    devices::save();
+    report::save();
+    if let Some(state) = &last_state {
+        resume::save(state);
+    }
+    // The instance taking over carries on from what the lights show
+    if turn_off_on_shutdown && takeover.is_none() {
+        match lights.turn_off().await {
+            Ok(()) => info!("Turned off lights"),
+            Err(e) => warn!("Failed to turn off lights: {}", e),
+        }
+    }
+    lights.disconnect().await.ok();
+    if let Some(requester) = takeover {
+        lock.release(requester).await;
+    }
+    log::logger().flush();
+    let Some(report) = monitor.map(|monitor| monitor.report()) else {
+        return Ok(());
+    };
+    print!("{}", report);
+    match report.failures().as_slice() {
+        [] => Ok(()),
+        failures => Err(Failure::Soak(failures.join(", "))),
+    }
+}
+
+// What the lights show, as integrations and rules see it
+fn light_status(off: bool, config: &Config, brightness: f32, overrides: &Overrides) -> LightStatus {
+    LightStatus {
+        on: !off,
+        effect: config.effect,
+        color: config.solid.color,
+        brightness,
+        override_until: overrides.ends_at(),
+    }
+}
+
+// Finds and connects the lights, backing off and retrying while the failure looks transient
+// until the startup timeout runs out
+async fn find_with_retry(config: &Config) -> Result<LightGroup, Failure> {
+    let deadline = Instant::now() + config.connection.startup_timeout;
+    let mut backoff = RECONNECT_INITIAL_BACKOFF;
+    loop {
+        let result = match LightGroup::find(&config.device, &config.connection).await {
+            Ok(lights) => lights.connect().await.map(|()| lights),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(lights) => return Ok(lights),
+            Err(e) if e.is_transient() && Instant::now() + backoff < deadline => {
+                warn!("{}, retrying in {}s", e, backoff.as_secs());
+                // Keeps a Type=notify unit from timing out while the lights are still away
+                notify::extend_startup(backoff + STARTUP_ATTEMPT_ALLOWANCE);
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(config.connection.reconnect_backoff_max);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Resolves with the signal's name once systemd (SIGTERM) or the terminal (SIGINT) asks the
+// daemon to stop
+async fn shutdown_signal() -> &'static str {
+    let (Ok(mut terminate), Ok(mut interrupt)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        warn!("Cannot listen for shutdown signals, the lights will keep their last color");
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    }
+}
+
+// How long to wait for commands while not rendering: at most a minute, and no later than the next
+// watchdog ping or agenda action
+fn off_wait(watchdog_ping: Option<Duration>) -> Duration {
+    let wait = notify::capped_wait(OFF_CHECK_INTERVAL, watchdog_ping);
+    agenda::until_next(chrono::Utc::now().timestamp()).map_or(wait, |next| wait.min(next))
 }
 
-async fn get_command_characteristics(light: &Peripheral) -> Characteristic {
-    let chars = light.characteristics();
-    let cmd_char = chars
+// Moves power commands ahead of the rest, keeping their order, so switching off never waits
+// behind a burst of color or brightness changes
+fn power_first(pending: &mut VecDeque<(Source, RemoteCommand)>) {
+    let (power, rest): (VecDeque<_>, VecDeque<_>) = pending
+        .drain(..)
+        .partition(|(_, command)| command.is_power());
+    *pending = power;
+    pending.extend(rest);
+}
+
+// Waits for the next frame's tick, collecting commands meanwhile; a power command ends the wait
+// early, so it is applied without sitting out the frame period
+async fn next_frame(
+    ticks: &mut time::Interval,
+    commands: &mut mpsc::Receiver<(Source, RemoteCommand)>,
+    pending: &mut VecDeque<(Source, RemoteCommand)>,
+) {
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => return,
+            Some(command) = commands.recv() => {
+                let power = command.1.is_power();
+                pending.push_back(command);
+                if power {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Fades each light from what it showed last into whatever comes next
+fn fade_from(last_frames: &[Frame], now: Instant, duration: Duration) -> Vec<Option<Transition>> {
+    last_frames
         .iter()
-        .find(|c| c.uuid == LIGHT_CHARACTERISTIC_UUID)
-        .cloned()
-        .expect("Unable to find characterics");
-    info!("Found characterics: {}", LIGHT_CHARACTERISTIC_UUID);
-    cmd_char
+        .map(|frame| Some(Transition::new(*frame, now, duration)))
+        .collect()
+}
+
+// Ticks every `period`, skipping the ticks a slow frame or a pause missed instead of bursting
+fn pacing(period: Duration) -> time::Interval {
+    let mut ticks = time::interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    ticks
 }
 
-async fn print_devices(central: &Adapter) -> Option<Peripheral> {
-    for p in central.peripherals().await.unwrap() {
-        if p.properties()
+// Fades write no faster than the animation or the lights' write limit
+fn fade_step(config: &Config) -> Duration {
+    match config.device.max_writes_per_second {
+        Some(rate) => config
+            .animation
+            .cycle_time
+            .max(Duration::from_secs_f32(1.0 / rate)),
+        None => config.animation.cycle_time,
+    }
+}
+
+// A color as the render loop would write it with this calibration
+fn calibrated((r, g, b): (u8, u8, u8), calibration: Calibration) -> (u8, u8, u8) {
+    let frame = Rgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    color::rgb_f32_to_u8_capped(frame, calibration)
+}
+
+// Shows each test color until Enter is pressed, for tuning [calibration] by eye
+async fn calibrate(lights: &LightGroup, calibration: Calibration) -> Result<(), Failure> {
+    println!(
+        "Calibrating with gamma {} and gains {:?}. Press Enter for the next color.",
+        calibration.gamma, calibration.gains
+    );
+    for (name, rgb, look_for) in CALIBRATION_STEPS {
+        println!("{:<12} {}", name, look_for);
+        lights.set_color(calibrated(rgb, calibration)).await?;
+        tokio::task::spawn_blocking(|| std::io::stdin().read_line(&mut String::new()))
             .await
-            .unwrap()
-            .unwrap()
-            .local_name
-            .iter()
-            .any(|name| name.contains("Light"))
-        {
-            return Some(p);
-        }
+            .map_err(|e| Failure::Usage(format!("cannot read from the terminal: {}", e)))?
+            .map_err(|e| Failure::Usage(format!("cannot read from the terminal: {}", e)))?;
     }
-    None
+    println!("Done; put the gamma and gains that looked right in [calibration]");
+    Ok(())
 }
 
-async fn set_color(cmd_char: &Characteristic, light: &Peripheral, (r, g, b): (u8, u8, u8)) {
-    let color_cmd = vec![MAGIC_NUMBER, 0x02, r, g, b];
-    light
-        .write(cmd_char, &color_cmd, WriteType::WithoutResponse)
-        .await
-        .ok();
+// Where the configured storage is, or the cache directory when the config does not load
+fn stored_state_config() -> StorageConfig {
+    Config::load().map_or_else(|_| Config::default().storage, |config| config.storage)
 }
 
-async fn turn_off_lights(cmd_char: &Characteristic, light: &Peripheral) {
-    let shut_off_cmd = vec![MAGIC_NUMBER, 0x01];
-    light
-        .write(cmd_char, &shut_off_cmd, WriteType::WithoutResponse)
-        .await
-        .ok();
+// A light's effect and the keyframes it writes through
+type Renderer = (Box<dyn Effect>, Keyframes);
+
+// Each light renders its own copy of the effect, so twinkles do not line up
+fn build_renderers(
+    config: &Config,
+    count: usize,
+    outdoor: &OutdoorTemperature,
+    audio: &AudioLevels,
+) -> (Vec<Renderer>, EffectDefaults) {
+    // The microphone stays closed until the audio effect first runs
+    if config.effect == EffectKind::Audio {
+        audio.start(&config.audio);
+    }
+    let (renderers, defaults): (Vec<_>, Vec<_>) = (0..count)
+        .map(|_| {
+            let (effect, defaults) = effects::from_config(config, outdoor, audio);
+            let keyframes = Keyframes::new(config.device.max_writes_per_second);
+            ((effect, keyframes), defaults)
+        })
+        .unzip();
+    (renderers, defaults[0])
+}
+
+// `base` with the theme for `date` applied, and the theme's name
+fn themed_config(base: &Config, date: chrono::NaiveDate) -> (Config, Option<String>) {
+    match themes::on(date, &base.themes) {
+        Some(theme) => (themes::apply(theme, base), Some(theme.name.clone())),
+        None => (base.clone(), None),
+    }
+}
+
+// Must be called from the task running the render loop. A panic there turns the lights off and
+// aborts; spawned tasks only end themselves, as tokio catches it.
+fn install_panic_guard(lights: Arc<LightGroup>) {
+    let runtime = tokio::runtime::Handle::current();
+    // The render loop is polled by the main future, which stays on the thread that started it
+    let render_thread = std::thread::current().id();
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic_info| {
+        // The effect's guard catches it and falls back to safe mode, unless the build aborts on
+        // panics, as the minimal profile does
+        if effects::is_guarded() && cfg!(panic = "unwind") {
+            error!("An effect panicked: {}", panic_info);
+            return;
+        }
+        if std::thread::current().id() != render_thread {
+            error!("A background task panicked: {}", panic_info);
+            default_hook(panic_info);
+            return;
+        }
+        error!("Daemon panicked: {}", panic_info);
+        history::dump();
+
+        // The panicking thread may be the one driving the runtime, so only wait a bounded time
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let runtime = runtime.clone();
+        let lights = Arc::clone(&lights);
+        std::thread::spawn(move || {
+            if runtime.block_on(lights.turn_off()).is_ok() {
+                info!("Turned off lights after panic");
+            }
+            done_tx.send(()).ok();
+        });
+        done_rx.recv_timeout(PANIC_SHUTDOWN_TIMEOUT).ok();
+
+        log::logger().flush();
+        default_hook(panic_info);
+        std::process::abort();
+    }));
+}
+
+// Outside the on window, and not yet into the early start the plan makes for a dull evening
+fn is_planned_off(schedule: &Schedule) -> bool {
+    let now = chrono::Utc::now();
+    let is_early = plan::current().is_some_and(|plan| plan.is_early(now.timestamp()));
+    !schedule.is_on(now) && !is_early
 }
 
-fn is_after_sunrise() -> bool {
-    let current_date = chrono::Utc::now();
-    let (sunrise, _) = get_sunrise_sunset(current_date);
+async fn make_daily_plan(schedule: &Schedule, date: chrono::NaiveDate) {
+    let location = schedule.location();
+    let forecast = tokio::task::spawn_blocking(move || plan::fetch_forecast(location))
+        .await
+        .ok()
+        .flatten();
+    plan::record_forecast(date, forecast);
+    let plan = DailyPlan::new(date, schedule.plan(date), forecast);
+    log_daily_plan(&plan, schedule.zone());
+    plan::set(plan);
+}
 
-    sunrise < current_date.timestamp()
+fn log_daily_plan(plan: &DailyPlan, zone: Zone) {
+    let weather = match plan.forecast {
+        Some(forecast) => format!(
+            "{:.0}% cloud, {:.1} mm, low of {:.0}°C",
+            forecast.cloud_cover, forecast.precipitation, forecast.temperature_min
+        ),
+        None => "no forecast".to_string(),
+    };
+    let curve = plan
+        .curve
+        .iter()
+        .map(|(from, level)| {
+            format!(
+                "{:.0}% from {}",
+                level * 100.0,
+                format_timestamp(zone, *from)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    info!(
+        "Plan for {} ({}): lights on at {} {}, off at {} {} (day length {}h {}m), {}, {} palette",
+        plan.date,
+        weather,
+        format_timestamp(zone, plan.on_at),
+        zone.label(),
+        format_timestamp(zone, plan.off_at),
+        zone.label(),
+        plan.day_length / 3600,
+        plan.day_length % 3600 / 60,
+        curve,
+        plan.palette.name()
+    );
 }
 
-fn is_before_sunset() -> bool {
-    let current_date = chrono::Utc::now();
-    let (_, sunset) = get_sunrise_sunset(current_date);
+// Asks the daemon for a guest pass, which only it can check, and prints the link to it
+#[cfg(feature = "http")]
+async fn print_guest_pass(config: &Config, minutes: Option<u64>) -> Result<(), Failure> {
+    let Some(api) = &config.http else {
+        return Err(Failure::ConfigInvalid(
+            "set http.listen to hand out guest passes".to_string(),
+        ));
+    };
+    if api.guest_scenes.is_empty() {
+        return Err(Failure::ConfigInvalid(
+            "set http.guest_scenes to the scenes guests may pick".to_string(),
+        ));
+    }
+    let valid_for = minutes.map_or(api.guest_duration, |minutes| {
+        Duration::from_secs(minutes * 60)
+    });
+    let token = instance::guest_pass(&config.device, valid_for).await?;
+    let hostname = host::hostname().unwrap_or_else(|| "localhost".to_string());
+    let url = http::guest_url(api, &hostname, &token);
+    let until = chrono::Local::now() + chrono::Duration::seconds(valid_for.as_secs() as i64);
+    println!(
+        "Guest pass until {}:\n{}",
+        until.format("%Y-%m-%d %H:%M"),
+        url
+    );
+    if let Some(code) = QrCode::encode(url.as_bytes()) {
+        print!("\n{}", code.to_terminal());
+    }
+    Ok(())
+}
 
-    current_date.timestamp() < sunset
+#[cfg(not(feature = "http"))]
+async fn print_guest_pass(_config: &Config, _minutes: Option<u64>) -> Result<(), Failure> {
+    Err(Failure::Usage(
+        "this build has no HTTP API, so it has no guest page".to_string(),
+    ))
 }
 
-fn get_sunrise_sunset(current_date: DateTime<Utc>) -> (i64, i64) {
-    let (sunrise, sunset) = sunrise::sunrise_sunset(
-        CURRENT_LOCATION.0,
-        CURRENT_LOCATION.1,
-        current_date.year(),
-        current_date.month(),
-        current_date.day(),
+fn print_schedule(config: &Config, from: chrono::NaiveDate, to: chrono::NaiveDate) {
+    let schedule = Schedule::from_config(config);
+    println!(
+        "Date        On     Off    Day     Effect  ({} times)",
+        schedule.zone().label()
     );
+    let mut date = Some(from);
+    while let Some(day) = date.filter(|day| *day <= to) {
+        let (on, off, day_length) = schedule.plan(day);
+        println!(
+            "{}  {}  {}  {:>2}h{:02}m  {}",
+            day,
+            format_timestamp(schedule.zone(), on),
+            format_timestamp(schedule.zone(), off),
+            day_length / 3600,
+            day_length % 3600 / 60,
+            match (
+                observances::on(day, &config.observances.categories),
+                themes::on(day, &config.themes),
+            ) {
+                (Some(observance), _) => observance.name.to_string(),
+                (None, Some(theme)) => format!("{} ({})", theme.effect.name(), theme.name),
+                (None, None) => config.effect.name().to_string(),
+            }
+        );
+        date = day.succ_opt();
+    }
+}
 
-    (sunrise, sunset)
+fn format_timestamp(zone: Zone, timestamp: i64) -> String {
+    zone.local(timestamp)
+        .map(|time| time.format("%H:%M").to_string())
+        .unwrap_or_else(|| "??:??".to_string())
 }
 
-fn rgb_f32_to_u8_capped(rgb: Rgb<f32>) -> (u8, u8, u8) {
-    (
-        (rgb.red() * 255.0) as u8,
-        (rgb.green() * 255.0) as u8,
-        (rgb.blue() * 255.0) as u8,
-    )
+// A Unix timestamp in local time, as `devices` prints it
+fn local_time(at: i64) -> String {
+    chrono::Local
+        .timestamp_opt(at, 0)
+        .single()
+        .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| "?".to_string())
 }
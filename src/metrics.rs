@@ -0,0 +1,296 @@
+// History for dashboards: a sample of the lights every minute for the last week, and daily
+// totals for the whole season. The daily totals survive restarts in the configured storage.
+// Alongside them, counters for the health of the BLE link since the daemon started.
+use crate::storage;
+use chrono::{Local, NaiveDate, NaiveDateTime};
+use log::warn;
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+// Builds without the `metrics` feature have nothing serving samples, so keep none
+#[cfg(feature = "metrics")]
+const MAX_SAMPLES: usize = 7 * 24 * 60;
+const SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// Frames per second are averaged over this long
+const FPS_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    // Unix timestamp
+    pub at: i64,
+    pub on: bool,
+    // Overall brightness between 0 and 1, zero while off
+    pub brightness: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Day {
+    // Local date
+    pub date: NaiveDate,
+    pub on_seconds: u64,
+    pub reconnects: u32,
+}
+
+/// The BLE link since the daemon started.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Link {
+    pub writes: u64,
+    pub failed_writes: u64,
+    pub reconnects: u64,
+    // None while disconnected
+    pub uptime: Option<Duration>,
+    pub frames_per_second: f32,
+    // Average time to work out a frame and write it to every light
+    pub frame_time: Duration,
+}
+
+struct Metrics {
+    samples: VecDeque<Sample>,
+    days: Vec<Day>,
+    last_sample: Option<Instant>,
+    last_save: Option<Instant>,
+    writes: u64,
+    failed_writes: u64,
+    reconnects: u64,
+    connected_since: Option<Instant>,
+    frames: u32,
+    frames_since: Option<Instant>,
+    frames_per_second: f32,
+    frame_time_total: Duration,
+    frame_time: Duration,
+}
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    samples: VecDeque::new(),
+    days: Vec::new(),
+    last_sample: None,
+    last_save: None,
+    writes: 0,
+    failed_writes: 0,
+    reconnects: 0,
+    connected_since: None,
+    frames: 0,
+    frames_since: None,
+    frames_per_second: 0.0,
+    frame_time_total: Duration::ZERO,
+    frame_time: Duration::ZERO,
+});
+
+// Where storage keeps the daily totals, also their name in a backup
+pub const KEY: &str = "daily";
+
+/// Picks up the daily totals saved by earlier runs.
+pub fn load() {
+    let days = match storage::current().days() {
+        Ok(days) => days,
+        Err(e) => {
+            warn!("Cannot load daily totals: {}", e);
+            return;
+        }
+    };
+    if let Ok(mut metrics) = METRICS.lock() {
+        metrics.days = days;
+    }
+}
+
+/// Writes the daily totals out, e.g. on shutdown.
+pub fn save() {
+    let days = {
+        let Ok(mut metrics) = METRICS.lock() else {
+            return;
+        };
+        metrics.last_save = Some(Instant::now());
+        metrics.days.clone()
+    };
+    if let Err(e) = storage::current().save_days(&days) {
+        warn!("Cannot save daily totals: {}", e);
+    }
+}
+
+/// Daily totals from their text form, one "date on_seconds reconnects" line per day.
+pub fn parse_days(contents: &str) -> Vec<Day> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some(Day {
+                date: NaiveDate::parse_from_str(fields.next()?, "%Y-%m-%d").ok()?,
+                on_seconds: fields.next()?.parse().ok()?,
+                reconnects: fields.next()?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+pub fn format_days(days: &[Day]) -> String {
+    days.iter()
+        .map(|day| format!("{} {} {}\n", day.date, day.on_seconds, day.reconnects))
+        .collect()
+}
+
+/// Records the state of the lights. Called from the render loop; keeps one sample a minute.
+pub fn record(on: bool, brightness: f32) {
+    let now = Instant::now();
+    let due_save = {
+        let Ok(mut metrics) = METRICS.lock() else {
+            return;
+        };
+        let since_last = metrics.last_sample.map(|last| now.duration_since(last));
+        if since_last.is_some_and(|since| since < SAMPLE_INTERVAL) {
+            return;
+        }
+        metrics.last_sample = Some(now);
+        if on {
+            today(&mut metrics).on_seconds += since_last.map_or(0, |since| since.as_secs());
+        }
+        keep_sample(&mut metrics, on, brightness);
+        metrics
+            .last_save
+            .is_none_or(|last| now.duration_since(last) >= SAVE_INTERVAL)
+    };
+    if due_save {
+        save();
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn keep_sample(metrics: &mut Metrics, on: bool, brightness: f32) {
+    if metrics.samples.len() == MAX_SAMPLES {
+        metrics.samples.pop_front();
+    }
+    metrics.samples.push_back(Sample {
+        at: chrono::Utc::now().timestamp(),
+        on,
+        brightness: if on { brightness } else { 0.0 },
+    });
+}
+
+#[cfg(not(feature = "metrics"))]
+fn keep_sample(_: &mut Metrics, _: bool, _: f32) {}
+
+pub fn reconnected() {
+    if let Ok(mut metrics) = METRICS.lock() {
+        today(&mut metrics).reconnects += 1;
+        metrics.reconnects += 1;
+        metrics.connected_since = Some(Instant::now());
+    }
+}
+
+pub fn connected() {
+    if let Ok(mut metrics) = METRICS.lock() {
+        metrics.connected_since = Some(Instant::now());
+    }
+}
+
+pub fn disconnected() {
+    if let Ok(mut metrics) = METRICS.lock() {
+        metrics.connected_since = None;
+    }
+}
+
+/// Counts one write to a light.
+pub fn wrote(ok: bool) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        metrics.writes += 1;
+        if !ok {
+            metrics.failed_writes += 1;
+        }
+    }
+}
+
+/// Counts one frame sent to the lights, which took `took` to work out and write.
+pub fn frame(took: Duration) {
+    let Ok(mut metrics) = METRICS.lock() else {
+        return;
+    };
+    let now = Instant::now();
+    metrics.frames += 1;
+    metrics.frame_time_total += took;
+    let since = *metrics.frames_since.get_or_insert(now);
+    let elapsed = now.duration_since(since);
+    if elapsed >= FPS_WINDOW {
+        metrics.frames_per_second = metrics.frames as f32 / elapsed.as_secs_f32();
+        metrics.frame_time = metrics.frame_time_total / metrics.frames;
+        metrics.frame_time_total = Duration::ZERO;
+        metrics.frames = 0;
+        metrics.frames_since = Some(now);
+    }
+}
+
+pub fn link() -> Option<Link> {
+    let metrics = METRICS.lock().ok()?;
+    // No frames for a while, e.g. during the day
+    let rendering = metrics
+        .frames_since
+        .is_some_and(|since| since.elapsed() < 2 * FPS_WINDOW);
+    Some(Link {
+        writes: metrics.writes,
+        failed_writes: metrics.failed_writes,
+        reconnects: metrics.reconnects,
+        uptime: metrics.connected_since.map(|since| since.elapsed()),
+        frames_per_second: if rendering {
+            metrics.frames_per_second
+        } else {
+            0.0
+        },
+        frame_time: metrics.frame_time,
+    })
+}
+
+fn today(metrics: &mut Metrics) -> &mut Day {
+    let date = Local::now().date_naive();
+    if metrics.days.last().is_none_or(|day| day.date != date) {
+        metrics.days.push(Day {
+            date,
+            on_seconds: 0,
+            reconnects: 0,
+        });
+    }
+    metrics.days.last_mut().expect("today was just added")
+}
+
+/// Samples between two Unix timestamps in milliseconds, as Grafana passes its time range.
+pub fn samples(from_ms: Option<i64>, to_ms: Option<i64>) -> Vec<Sample> {
+    let Ok(metrics) = METRICS.lock() else {
+        return Vec::new();
+    };
+    metrics
+        .samples
+        .iter()
+        .filter(|sample| in_range(sample.at, from_ms, to_ms))
+        .copied()
+        .collect()
+}
+
+/// Daily totals for days that start between two Unix timestamps in milliseconds.
+pub fn days(from_ms: Option<i64>, to_ms: Option<i64>) -> Vec<Day> {
+    let Ok(metrics) = METRICS.lock() else {
+        return Vec::new();
+    };
+    metrics
+        .days
+        .iter()
+        .filter(|day| in_range(midnight(day.date), from_ms, to_ms))
+        .copied()
+        .collect()
+}
+
+fn in_range(at: i64, from_ms: Option<i64>, to_ms: Option<i64>) -> bool {
+    from_ms.is_none_or(|from| at * 1000 >= from) && to_ms.is_none_or(|to| at * 1000 <= to)
+}
+
+fn midnight(date: NaiveDate) -> i64 {
+    date.and_hms_opt(0, 0, 0)
+        .map_or(0, |midnight| midnight.timestamp())
+}
+
+// RFC 3339 in UTC, which Grafana reads as a time field
+pub fn format_time(at: i64) -> String {
+    NaiveDateTime::from_timestamp_opt(at, 0)
+        .map(|time| time.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,215 @@
+// The colors the lights were last sent, for a browser mirroring them: GET /tree draws a virtual
+// tree and keeps it in step over a WebSocket on GET /frames, which gets a text message like
+// ["#ff8c28","#000000"] per light whenever the colors change, at most 20 times a second. Only
+// as much of RFC 6455 as that takes is here: the handshake, unmasked text frames from the
+// server, and noticing the browser closing the socket.
+use std::{sync::OnceLock, time::Duration};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::watch,
+    time,
+};
+
+// Gives the browser time to draw, and keeps a fast effect from flooding a slow link
+const FRAME_INTERVAL: Duration = Duration::from_millis(50);
+// Appended to the browser's key before hashing, from RFC 6455
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const FINAL_FRAGMENT: u8 = 0x80;
+
+static FRAMES: OnceLock<watch::Sender<Vec<(u8, u8, u8)>>> = OnceLock::new();
+
+fn frames() -> &'static watch::Sender<Vec<(u8, u8, u8)>> {
+    FRAMES.get_or_init(|| watch::channel(Vec::new()).0)
+}
+
+/// Passes the colors just sent to the lights on to whoever mirrors them.
+pub fn publish(colors: &[(u8, u8, u8)]) {
+    frames().send_if_modified(|shown| {
+        let changed = shown.as_slice() != colors;
+        if changed {
+            *shown = colors.to_vec();
+        }
+        changed
+    });
+}
+
+/// Completes the WebSocket handshake for the browser that sent `key` (its Sec-WebSocket-Key)
+/// and streams the colors to it until it goes away.
+pub async fn stream(stream: TcpStream, key: &str) -> io::Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let handshake = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    writer.write_all(handshake.as_bytes()).await?;
+    let mut frames = frames().subscribe();
+    let mut buffer = [0; 256];
+    loop {
+        let colors = colors_json(&frames.borrow_and_update());
+        writer.write_all(&text_frame(&colors)).await?;
+        time::sleep(FRAME_INTERVAL).await;
+        // Browsers send nothing but the closing handshake, so the first byte read is its header
+        loop {
+            tokio::select! {
+                _ = frames.changed() => break,
+                read = reader.read(&mut buffer) => {
+                    let read = read?;
+                    if read == 0 {
+                        return Ok(());
+                    }
+                    if buffer[0] & 0x0f == OPCODE_CLOSE {
+                        writer.write_all(&[FINAL_FRAGMENT | OPCODE_CLOSE, 0]).await?;
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn colors_json(colors: &[(u8, u8, u8)]) -> String {
+    let colors: Vec<String> = colors
+        .iter()
+        .map(|(r, g, b)| format!("\"#{:02x}{:02x}{:02x}\"", r, g, b))
+        .collect();
+    format!("[{}]", colors.join(","))
+}
+
+// Server frames go unmasked, with the length in 7, 16 or 64 bits
+fn text_frame(payload: &str) -> Vec<u8> {
+    let mut frame = vec![FINAL_FRAGMENT | OPCODE_TEXT];
+    let len = payload.len();
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        frame.push(126);
+        frame.extend((len as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend((len as u64).to_be_bytes());
+    }
+    frame.extend(payload.as_bytes());
+    frame
+}
+
+fn accept_key(key: &str) -> String {
+    base64(&sha1(
+        format!("{}{}", key.trim(), HANDSHAKE_GUID).as_bytes(),
+    ))
+}
+
+fn sha1(message: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend((message.len() as u64 * 8).to_be_bytes());
+    for block in padded.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+        for (h, added) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(added);
+        }
+    }
+    let mut digest = [0; 20];
+    for (bytes, h) in digest.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_handshake_answers_the_rfc_example() {
+        let hex: String = sha1(b"abc").iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn frames_carry_a_color_per_light() {
+        let colors = colors_json(&[(255, 140, 40), (0, 0, 0)]);
+        assert_eq!(colors, r##"["#ff8c28","#000000"]"##);
+        assert_eq!(text_frame(&colors)[..2], [0x81, colors.len() as u8]);
+        let long = "x".repeat(300);
+        assert_eq!(text_frame(&long)[..4], [0x81, 126, 1, 44]);
+    }
+
+    #[tokio::test]
+    async fn browsers_get_the_colors_until_they_close() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut browser = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        publish(&[(1, 2, 3)]);
+        let streaming = tokio::spawn(stream(server, "dGhlIHNhbXBsZSBub25jZQ=="));
+        let mut received = Vec::new();
+        let mut buffer = [0; 512];
+        while !received.ends_with(br##"["#010203"]"##) {
+            let read = browser.read(&mut buffer).await.unwrap();
+            received.extend(&buffer[..read]);
+        }
+        let received = String::from_utf8_lossy(&received);
+        assert!(received.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(received.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        // A masked close frame with no payload
+        browser
+            .write_all(&[FINAL_FRAGMENT | OPCODE_CLOSE, 0x80, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        streaming.await.unwrap().unwrap();
+    }
+}
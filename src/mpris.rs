@@ -0,0 +1,218 @@
+// A slow gradient from the album art of whatever plays on the desktop, found over MPRIS on the
+// session bus, so any player works without an account with a streaming service. Players are
+// asked every few seconds; while one is playing, the dominant colors of its artwork drift
+// across the lights in place of the effect, and the effect comes back when playback stops.
+// Artwork comes as a local file from most players, or is fetched with curl when a player only
+// has a URL for it.
+use crate::{
+    config::{Config, MprisConfig},
+    integrations::{self, Context, Integration},
+    photo,
+    remote::LiveColors,
+    supervisor::FailureBudget,
+};
+use dbus::{
+    arg::{PropMap, RefArg},
+    blocking::{stdintf::org_freedesktop_dbus::Properties, Connection},
+};
+use futures::future::BoxFuture;
+use log::{info, warn};
+use std::{
+    env,
+    path::PathBuf,
+    process::Command,
+    time::{Duration, Instant},
+};
+use tokio::time;
+
+const PLAYER_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const BUS_TIMEOUT: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+// Often enough for the drift to look smooth, well within LiveColors' timeout
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+const ART_COLORS: usize = 4;
+const CURL_TIMEOUT_SECS: &str = "10";
+
+struct AlbumArt(MprisConfig);
+
+impl Integration for AlbumArt {
+    fn name(&self) -> &'static str {
+        "MPRIS album art"
+    }
+
+    fn start(self: Box<Self>, context: Context, budget: FailureBudget) -> BoxFuture<'static, ()> {
+        let commands = context.commands.clone();
+        Box::pin(integrations::restarting(commands, budget, move || {
+            follow(self.0.clone(), context.lights, context.live.clone())
+        }))
+    }
+}
+
+pub fn integration(config: &Config) -> Option<Box<dyn Integration>> {
+    Some(Box::new(AlbumArt(config.mpris.clone()?)))
+}
+
+/// Shows the artwork of the playing media until the session bus goes away.
+pub async fn follow(config: MprisConfig, lights: usize, live: LiveColors) -> Result<(), String> {
+    let mut shown: Option<String> = None;
+    let mut colors = Vec::new();
+    let mut asked_at: Option<Instant> = None;
+    let started = Instant::now();
+    let mut frames = time::interval(FRAME_INTERVAL);
+    loop {
+        frames.tick().await;
+        if asked_at.is_none_or(|at| at.elapsed() >= POLL_INTERVAL) {
+            asked_at = Some(Instant::now());
+            let art = tokio::task::spawn_blocking(playing_art)
+                .await
+                .map_err(|e| e.to_string())??;
+            if art != shown {
+                colors = match art.clone() {
+                    Some(url) => {
+                        info!("Showing the colors of the album art at {}", url);
+                        tokio::task::spawn_blocking(move || art_colors(&url))
+                            .await
+                            .map_err(|e| e.to_string())?
+                    }
+                    None => Vec::new(),
+                };
+                if colors.is_empty() {
+                    live.set(None);
+                }
+                shown = art;
+            }
+        }
+        if !colors.is_empty() {
+            let phase = started.elapsed().as_secs_f32() / config.drift.as_secs_f32();
+            live.set(Some(gradient(&colors, lights, phase)));
+        }
+    }
+}
+
+// The art URL of the first player that is playing, if any
+fn playing_art() -> Result<Option<String>, String> {
+    let connection = Connection::new_session().map_err(|e| e.to_string())?;
+    let bus = connection.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", BUS_TIMEOUT);
+    let (names,): (Vec<String>,) = bus
+        .method_call("org.freedesktop.DBus", "ListNames", ())
+        .map_err(|e| e.to_string())?;
+    for name in names.iter().filter(|name| name.starts_with(PLAYER_PREFIX)) {
+        let player = connection.with_proxy(name, "/org/mpris/MediaPlayer2", BUS_TIMEOUT);
+        // Players that do not answer are skipped, like ones that stopped
+        let playing = player
+            .get::<String>(PLAYER_INTERFACE, "PlaybackStatus")
+            .is_ok_and(|status| status == "Playing");
+        if !playing {
+            continue;
+        }
+        let Ok(metadata) = player.get::<PropMap>(PLAYER_INTERFACE, "Metadata") else {
+            continue;
+        };
+        if let Some(url) = metadata.get("mpris:artUrl").and_then(|url| url.0.as_str()) {
+            return Ok(Some(url.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+// The artwork's dominant colors, or none when it cannot be had
+fn art_colors(url: &str) -> Vec<(u8, u8, u8)> {
+    let path = match url.strip_prefix("file://") {
+        Some(path) => PathBuf::from(percent_decoded(path)),
+        None => match fetch(url) {
+            Ok(path) => path,
+            Err(e) => {
+                warn!("Cannot fetch the album art at {}: {}", url, e);
+                return Vec::new();
+            }
+        },
+    };
+    match photo::dominant_colors(&path, ART_COLORS) {
+        Ok(colors) => colors.iter().map(|color| color.color).collect(),
+        Err(e) => {
+            warn!("Cannot read the album art at {}: {}", url, e);
+            Vec::new()
+        }
+    }
+}
+
+fn fetch(url: &str) -> Result<PathBuf, String> {
+    let path = env::temp_dir().join("christmas-lights-album-art");
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--max-time", CURL_TIMEOUT_SECS, "--output"])
+        .arg(&path)
+        .arg(url)
+        .output()
+        .map_err(|e| format!("cannot run curl: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(path)
+}
+
+// File URLs escape spaces and anything else outside plain ASCII
+fn percent_decoded(path: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = path.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        let escaped = (byte == b'%')
+            .then(|| after.get(..2))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &after[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = after;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// The colors around a loop, spread over the lights and moved along by `phase` turns
+fn gradient(colors: &[(u8, u8, u8)], lights: usize, phase: f32) -> Vec<(u8, u8, u8)> {
+    (0..lights)
+        .map(|light| {
+            let at = (light as f32 / lights as f32 + phase).fract() * colors.len() as f32;
+            let from = colors[at as usize % colors.len()];
+            let to = colors[(at as usize + 1) % colors.len()];
+            let mix = |from: u8, to: u8| {
+                (from as f32 + (to as f32 - from as f32) * at.fract()).round() as u8
+            };
+            (mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_urls_are_decoded() {
+        assert_eq!(
+            percent_decoded("/home/anna/Music/Last%20Christmas/cover%2Ejpg%"),
+            "/home/anna/Music/Last Christmas/cover.jpg%"
+        );
+    }
+
+    #[test]
+    fn the_colors_drift_around_the_lights() {
+        let colors = [(200, 0, 0), (0, 200, 0)];
+        assert_eq!(
+            gradient(&colors, 4, 0.0),
+            [(200, 0, 0), (100, 100, 0), (0, 200, 0), (100, 100, 0)]
+        );
+        assert_eq!(
+            gradient(&colors, 4, 0.25)[..3],
+            gradient(&colors, 4, 0.0)[1..]
+        );
+        assert_eq!(gradient(&colors, 1, 1.5), [(0, 200, 0)]);
+    }
+}
@@ -0,0 +1,965 @@
+// Minimal MQTT 3.1.1 client for Home Assistant: QoS 0 publish and subscribe, keep-alive and
+// a last will. The lights are announced as a light entity with Home Assistant's JSON schema:
+// commands such as {"state":"ON","brightness":128} arrive on <topic_prefix>/set, and the whole
+// state goes back as one retained JSON message on <topic_prefix>/state. `christmas-lights
+// schema` prints its JSON Schema. The state is echoed after every command, whether or not it
+// changed anything, so flows such as Node-RED's can read back what was applied.
+//
+// For simpler clients set also takes plain ON and OFF, and rgb/set, brightness/set and
+// effect/set take one plain value each, published back on the matching .../state topics.
+// Commands that change the lights may carry "ttl": <seconds>, or end in " ttl=<seconds>" when
+// plain, e.g. "255,0,0 ttl=600" on rgb/set, to last only that long before the lights go back.
+//
+// A message on <topic_prefix>/trigger/<name> reports the event mqtt/<name> to the rules file,
+// with the payload as its value. The state of a next_alarm sensor on alarm_topic schedules the
+// wake-up sunrise, see wake.rs.
+use crate::{
+    config::{Config, MqttConfig},
+    controller::DeviceInformation,
+    effects::EffectKind,
+    integrations::{Context, Integration},
+    metrics::format_time,
+    remote::{
+        json_string, number_field, object_field, string_field, LightStatus, RemoteCommand, Source,
+    },
+    supervisor::FailureBudget,
+    triggers::Event,
+    wake,
+};
+use futures::future::BoxFuture;
+use log::{info, warn};
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc, watch},
+    time,
+};
+
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xC0;
+// Larger packets are dropped rather than buffered, nothing we subscribe to comes close
+const MAX_PACKET_LENGTH: usize = 64 * 1024;
+// How long a command gets to reach the lights before its state echo goes out regardless
+const ECHO_DELAY: Duration = Duration::from_secs(1);
+const STATE_SCHEMA: &str = include_str!("mqtt_state.schema.json");
+
+struct Topics {
+    availability: String,
+    // The whole state as JSON; the ones below carry one plain value each
+    state: String,
+    command: String,
+    rgb_state: String,
+    rgb_command: String,
+    brightness_state: String,
+    brightness_command: String,
+    effect_state: String,
+    effect_command: String,
+    discovery: String,
+    // Home Assistant announces restarts here, after which discovery has to be sent again
+    ha_status: String,
+    // An outdoor sensor publishing plain °C readings, owned by some other device
+    temperature: Option<String>,
+    // Home Assistant's next_alarm sensor, forwarded from a phone
+    alarm: Option<String>,
+    // Presence detection elsewhere publishes the name of whoever came home
+    arrived: String,
+    // Names one of the configured palettes to draw colors from
+    palette_command: String,
+    // Events for the rules, one topic level below this per event name
+    trigger: String,
+}
+
+impl Topics {
+    fn new(config: &MqttConfig) -> Self {
+        let topic = |suffix: &str| format!("{}/{}", config.topic_prefix, suffix);
+        Topics {
+            availability: topic("availability"),
+            state: topic("state"),
+            command: topic("set"),
+            rgb_state: topic("rgb/state"),
+            rgb_command: topic("rgb/set"),
+            brightness_state: topic("brightness/state"),
+            brightness_command: topic("brightness/set"),
+            effect_state: topic("effect/state"),
+            effect_command: topic("effect/set"),
+            discovery: format!(
+                "{}/light/{}/config",
+                config.discovery_prefix, config.client_id
+            ),
+            ha_status: format!("{}/status", config.discovery_prefix),
+            temperature: config.temperature_topic.clone(),
+            alarm: config.alarm_topic.clone(),
+            arrived: topic("arrived"),
+            palette_command: topic("palette/set"),
+            trigger: topic("trigger/"),
+        }
+    }
+}
+
+struct Mqtt(MqttConfig);
+
+impl Integration for Mqtt {
+    fn name(&self) -> &'static str {
+        "MQTT"
+    }
+
+    // MQTT backs off on its own, so it does without integrations::restarting
+    fn start(self: Box<Self>, context: Context, budget: FailureBudget) -> BoxFuture<'static, ()> {
+        Box::pin(run(
+            self.0,
+            context.device,
+            context.commands,
+            context.status,
+            budget,
+        ))
+    }
+}
+
+pub fn integration(config: &Config) -> Option<Box<dyn Integration>> {
+    Some(Box::new(Mqtt(config.mqtt.clone()?)))
+}
+
+/// Keeps a connection to the broker, reconnecting with backoff, until `commands` is closed.
+/// A broker that keeps failing is left alone for the budget's cool-down.
+pub async fn run(
+    config: MqttConfig,
+    device: DeviceInformation,
+    commands: mpsc::Sender<(Source, RemoteCommand)>,
+    mut status: watch::Receiver<LightStatus>,
+    mut budget: FailureBudget,
+) {
+    let topics = Topics::new(&config);
+    let mut backoff = RECONNECT_INITIAL_BACKOFF;
+    while !commands.is_closed() {
+        budget.resume();
+        match session(&config, &topics, &device, &commands, &mut status).await {
+            Ok(()) => return,
+            Err(e) => {
+                let error = format!("connection to {}: {}", config.broker, e);
+                match budget.failed(error) {
+                    Some(cooldown) => {
+                        time::sleep(cooldown).await;
+                        backoff = RECONNECT_INITIAL_BACKOFF;
+                    }
+                    None => {
+                        time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Returns Ok only when the daemon is shutting down
+async fn session(
+    config: &MqttConfig,
+    topics: &Topics,
+    device: &DeviceInformation,
+    commands: &mpsc::Sender<(Source, RemoteCommand)>,
+    status: &mut watch::Receiver<LightStatus>,
+) -> io::Result<()> {
+    let stream = TcpStream::connect(&config.broker).await?;
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all(&connect_packet(config, topics)).await?;
+
+    let (incoming_tx, incoming) = mpsc::channel(16);
+    let reader = tokio::spawn(read_packets(reader, incoming_tx));
+    let result = serve(
+        config,
+        topics,
+        device,
+        commands,
+        status,
+        &mut writer,
+        incoming,
+    )
+    .await;
+    reader.abort();
+    result
+}
+
+async fn serve(
+    config: &MqttConfig,
+    topics: &Topics,
+    device: &DeviceInformation,
+    commands: &mpsc::Sender<(Source, RemoteCommand)>,
+    status: &mut watch::Receiver<LightStatus>,
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    mut incoming: mpsc::Receiver<(u8, Vec<u8>)>,
+) -> io::Result<()> {
+    // The first packet from the broker is always the CONNACK
+    match incoming.recv().await {
+        Some((CONNACK, body)) if body.get(1) == Some(&0) => {}
+        Some((CONNACK, body)) => {
+            return Err(protocol_error(format!(
+                "broker refused the connection with code {:?}",
+                body.get(1)
+            )))
+        }
+        _ => return Err(protocol_error("expected CONNACK")),
+    }
+    info!("Connected to MQTT broker {}", config.broker);
+
+    let mut filters = vec![
+        &topics.command,
+        &topics.rgb_command,
+        &topics.brightness_command,
+        &topics.effect_command,
+        &topics.ha_status,
+        &topics.arrived,
+        &topics.palette_command,
+    ];
+    filters.extend(&topics.temperature);
+    filters.extend(&topics.alarm);
+    let triggers = format!("{}+", topics.trigger);
+    filters.push(&triggers);
+    writer.write_all(&subscribe_packet(&filters)).await?;
+    writer
+        .write_all(&publish_packet(&topics.availability, b"online", true))
+        .await?;
+    // Copied out first, so the watch is not locked while the publish waits
+    let current = *status.borrow_and_update();
+    announce(writer, topics, config, device, current).await?;
+
+    let mut keep_alive = time::interval(config.keep_alive);
+    keep_alive.tick().await;
+    // When to echo the state for a command that has not changed it
+    let mut echo_at = None;
+    loop {
+        tokio::select! {
+            packet = incoming.recv() => {
+                let Some((header, body)) = packet else {
+                    return Err(protocol_error("broker closed the connection"));
+                };
+                if header & 0xF0 != PUBLISH {
+                    continue;
+                }
+                let Some((topic, payload)) = parse_publish(header, &body) else {
+                    continue;
+                };
+                if topic == topics.ha_status {
+                    if payload == b"online" {
+                        let current = *status.borrow();
+                        announce(writer, topics, config, device, current).await?;
+                    }
+                } else if let Some(parsed) = parse_command(topics, &topic, &payload) {
+                    for command in parsed {
+                        if commands.send((Source::Mqtt, command)).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    echo_at.get_or_insert(time::Instant::now() + ECHO_DELAY);
+                } else {
+                    warn!(
+                        "Ignoring MQTT command {:?} on {}",
+                        String::from_utf8_lossy(&payload),
+                        topic
+                    );
+                }
+            }
+            changed = status.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+                let current = *status.borrow_and_update();
+                publish_status(writer, topics, current).await?;
+                echo_at = None;
+            }
+            _ = time::sleep_until(echo_at.unwrap_or_else(time::Instant::now)), if echo_at.is_some() => {
+                echo_at = None;
+                let current = *status.borrow();
+                publish_status(writer, topics, current).await?;
+            }
+            _ = keep_alive.tick() => {
+                writer.write_all(&[PINGREQ, 0]).await?;
+            }
+        }
+    }
+}
+
+async fn announce(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    topics: &Topics,
+    config: &MqttConfig,
+    device: &DeviceInformation,
+    status: LightStatus,
+) -> io::Result<()> {
+    let discovery = discovery_config(topics, config, device);
+    writer
+        .write_all(&publish_packet(
+            &topics.discovery,
+            discovery.as_bytes(),
+            true,
+        ))
+        .await?;
+    publish_status(writer, topics, status).await
+}
+
+async fn publish_status(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    topics: &Topics,
+    status: LightStatus,
+) -> io::Result<()> {
+    let (r, g, b) = status.color;
+    let messages = [
+        (
+            &topics.state,
+            state_json(status, Instant::now(), chrono::Utc::now().timestamp()),
+        ),
+        (&topics.rgb_state, format!("{},{},{}", r, g, b)),
+        (
+            &topics.brightness_state,
+            ((status.brightness * 255.0).round() as u8).to_string(),
+        ),
+        (&topics.effect_state, status.effect.name().to_string()),
+    ];
+    for (topic, payload) in messages {
+        writer
+            .write_all(&publish_packet(topic, payload.as_bytes(), true))
+            .await?;
+    }
+    Ok(())
+}
+
+// What the daemon applied, as described by STATE_SCHEMA; `now` and `now_utc` place the end of
+// an override on the clock
+fn state_json(status: LightStatus, now: Instant, now_utc: i64) -> String {
+    let (r, g, b) = status.color;
+    let override_until = status.override_until.map_or("null".to_string(), |until| {
+        let left = until.saturating_duration_since(now).as_secs_f64().round() as i64;
+        format!(
+            "{{\"until\":{}}}",
+            json_string(&format_time(now_utc + left))
+        )
+    });
+    format!(
+        "{{\"state\":\"{}\",\"color_mode\":\"rgb\",\"color\":{{\"r\":{},\"g\":{},\"b\":{}}},\
+         \"brightness\":{},\"effect\":{},\"override\":{}}}",
+        if status.on { "ON" } else { "OFF" },
+        r,
+        g,
+        b,
+        (status.brightness * 255.0).round() as u8,
+        json_string(status.effect.name()),
+        override_until
+    )
+}
+
+/// The JSON Schema of the message on .../state.
+pub fn state_schema() -> String {
+    let effects: Vec<String> = EffectKind::ALL
+        .iter()
+        .map(|effect| json_string(effect.name()))
+        .collect();
+    STATE_SCHEMA.replace("$EFFECTS", &format!("[{}]", effects.join(", ")))
+}
+
+// The commands in a message, in order, or None when it is not one
+fn parse_command(topics: &Topics, topic: &str, payload: &[u8]) -> Option<Vec<RemoteCommand>> {
+    let payload = std::str::from_utf8(payload).ok()?.trim();
+    if topic == topics.command && payload.starts_with('{') {
+        return parse_json_command(payload);
+    }
+    let Some((payload, ttl)) = payload.rsplit_once(" ttl=") else {
+        return parse_payload(topics, topic, payload).map(|command| vec![command]);
+    };
+    let ttl = ttl.parse::<u64>().ok().filter(|seconds| *seconds > 0)?;
+    let command = parse_payload(topics, topic, payload.trim())?;
+    Some(vec![command.expiring_after(Duration::from_secs(ttl))?])
+}
+
+// Home Assistant's JSON light schema: any of state, color, brightness and effect, plus ttl
+fn parse_json_command(payload: &str) -> Option<Vec<RemoteCommand>> {
+    let mut commands = Vec::new();
+    match string_field(payload, "state") {
+        Some("ON") => commands.push(RemoteCommand::On),
+        Some("OFF") => commands.push(RemoteCommand::Off),
+        Some(_) => return None,
+        None => {}
+    }
+    if let Some(color) = object_field(payload, "color") {
+        let channel = |key| {
+            number_field(color, key)
+                .filter(|value| (0.0..=255.0).contains(value) && value.fract() == 0.0)
+        };
+        let rgb = (
+            channel("r")? as u8,
+            channel("g")? as u8,
+            channel("b")? as u8,
+        );
+        commands.push(RemoteCommand::Color(rgb));
+    }
+    if let Some(brightness) = number_field(payload, "brightness") {
+        if !(0.0..=255.0).contains(&brightness) {
+            return None;
+        }
+        commands.push(RemoteCommand::Brightness(brightness as f32 / 255.0));
+    }
+    if let Some(effect) = string_field(payload, "effect") {
+        commands.push(RemoteCommand::Effect(EffectKind::from_name(effect)?));
+    }
+    if commands.is_empty() {
+        return None;
+    }
+    let Some(ttl) = number_field(payload, "ttl") else {
+        return Some(commands);
+    };
+    let ttl = Duration::from_secs(Some(ttl).filter(|seconds| *seconds >= 1.0)? as u64);
+    commands
+        .into_iter()
+        .map(|command| command.expiring_after(ttl))
+        .collect()
+}
+
+fn parse_payload(topics: &Topics, topic: &str, payload: &str) -> Option<RemoteCommand> {
+    if topic == topics.command {
+        match payload {
+            "ON" => Some(RemoteCommand::On),
+            "OFF" => Some(RemoteCommand::Off),
+            _ => None,
+        }
+    } else if topic == topics.rgb_command {
+        let mut channels = payload.split(',').map(|c| c.trim().parse::<u8>());
+        let rgb = (
+            channels.next()?.ok()?,
+            channels.next()?.ok()?,
+            channels.next()?.ok()?,
+        );
+        channels
+            .next()
+            .is_none()
+            .then_some(RemoteCommand::Color(rgb))
+    } else if topic == topics.brightness_command {
+        let brightness = payload.parse::<u8>().ok()?;
+        Some(RemoteCommand::Brightness(brightness as f32 / 255.0))
+    } else if topic == topics.effect_command {
+        EffectKind::from_name(payload).map(RemoteCommand::Effect)
+    } else if topic == topics.arrived {
+        (!payload.is_empty()).then(|| RemoteCommand::Arrived(payload.to_string()))
+    } else if topic == topics.palette_command {
+        (!payload.is_empty()).then(|| RemoteCommand::Palette(payload.to_string()))
+    } else if let Some(name) = topic
+        .strip_prefix(&topics.trigger)
+        .filter(|name| !name.is_empty())
+    {
+        Some(RemoteCommand::Event(Event::new("mqtt", name, payload)))
+    } else if topics.temperature.as_deref() == Some(topic) {
+        let celsius = payload.parse::<f32>().ok()?;
+        celsius
+            .is_finite()
+            .then_some(RemoteCommand::OutdoorTemperature(celsius))
+    } else if topics.alarm.as_deref() == Some(topic) {
+        Some(RemoteCommand::NextAlarm(wake::parse_alarm(payload)))
+    } else {
+        None
+    }
+}
+
+/// Home Assistant YAML for an MQTT light matching the daemon's topics, for setups that do
+/// not use discovery.
+pub fn home_assistant_yaml(config: &MqttConfig) -> String {
+    let mut yaml = String::from("mqtt:\n  light:\n");
+    for (i, (key, value)) in entity_fields(&Topics::new(config), config)
+        .iter()
+        .enumerate()
+    {
+        let indent = if i == 0 { "    - " } else { "      " };
+        yaml.push_str(&format!("{}{}: {}\n", indent, key, value));
+    }
+    yaml
+}
+
+fn discovery_config(topics: &Topics, config: &MqttConfig, device: &DeviceInformation) -> String {
+    let mut fields = entity_fields(topics, config);
+    fields.push(("device", device_block(config, device)));
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| format!("\"{}\":{}", key, value))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+// Values are JSON, which doubles as YAML flow syntax
+fn entity_fields(topics: &Topics, config: &MqttConfig) -> Vec<(&'static str, String)> {
+    let effects = EffectKind::ALL
+        .map(|effect| json_string(effect.name()))
+        .join(",");
+    vec![
+        ("name", json_string(&config.name)),
+        ("unique_id", json_string(&config.client_id)),
+        ("schema", json_string("json")),
+        ("availability_topic", json_string(&topics.availability)),
+        ("command_topic", json_string(&topics.command)),
+        ("state_topic", json_string(&topics.state)),
+        ("brightness", "true".to_string()),
+        ("supported_color_modes", "[\"rgb\"]".to_string()),
+        ("effect", "true".to_string()),
+        ("effect_list", format!("[{}]", effects)),
+    ]
+}
+
+// Home Assistant shows the model and firmware on the device page; the light reports them on
+// connect, lights that do not are listed without
+fn device_block(config: &MqttConfig, device: &DeviceInformation) -> String {
+    let mut fields = vec![
+        (
+            "identifiers",
+            format!("[{}]", json_string(&config.client_id)),
+        ),
+        ("name", json_string(&config.name)),
+        (
+            "manufacturer",
+            json_string(device.manufacturer.as_deref().unwrap_or("Actuel")),
+        ),
+    ];
+    if let Some(model) = &device.model {
+        fields.push(("model", json_string(model)));
+    }
+    if let Some(firmware) = &device.firmware {
+        fields.push(("sw_version", json_string(firmware)));
+    }
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| format!("\"{}\":{}", key, value))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+// Forwards (first header byte, body) for each packet until the connection fails
+async fn read_packets(mut reader: impl AsyncRead + Unpin, incoming: mpsc::Sender<(u8, Vec<u8>)>) {
+    loop {
+        let Ok(header) = reader.read_u8().await else {
+            return;
+        };
+        // The remaining length takes at most four bytes, seven bits each
+        let mut length = None;
+        let mut value = 0;
+        for shift in (0..28).step_by(7) {
+            let Ok(byte) = reader.read_u8().await else {
+                return;
+            };
+            value |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                length = Some(value);
+                break;
+            }
+        }
+        let Some(length) = length else {
+            warn!("Dropping MQTT connection after a malformed packet length");
+            return;
+        };
+        if length > MAX_PACKET_LENGTH {
+            warn!("Dropping MQTT connection after a {} byte packet", length);
+            return;
+        }
+        let mut body = vec![0; length];
+        if reader.read_exact(&mut body).await.is_err()
+            || incoming.send((header, body)).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+fn parse_publish(header: u8, body: &[u8]) -> Option<(String, Vec<u8>)> {
+    let topic_length = u16::from_be_bytes([*body.first()?, *body.get(1)?]) as usize;
+    let topic = std::str::from_utf8(body.get(2..2 + topic_length)?).ok()?;
+    // QoS 1 and 2 messages carry a packet identifier; we only subscribe at QoS 0, so the
+    // broker never has to be acknowledged
+    let qos = (header >> 1) & 0x03;
+    let payload_start = 2 + topic_length + if qos > 0 { 2 } else { 0 };
+    Some((topic.to_string(), body.get(payload_start..)?.to_vec()))
+}
+
+fn connect_packet(config: &MqttConfig, topics: &Topics) -> Vec<u8> {
+    // Clean session, plus a retained QoS 0 will so Home Assistant marks the lights unavailable
+    let mut flags = 0x02 | 0x04 | 0x20;
+    let mut body = Vec::new();
+    put_string(&mut body, "MQTT");
+    body.push(4);
+    let flags_at = body.len();
+    body.push(0);
+    body.extend_from_slice(
+        &(config.keep_alive.as_secs().min(u16::MAX as u64) as u16).to_be_bytes(),
+    );
+    put_string(&mut body, &config.client_id);
+    put_string(&mut body, &topics.availability);
+    put_bytes(&mut body, b"offline");
+    if let Some(username) = &config.username {
+        flags |= 0x80;
+        put_string(&mut body, username);
+    }
+    if let Some(password) = &config.password {
+        flags |= 0x40;
+        put_string(&mut body, password);
+    }
+    body[flags_at] = flags;
+    packet(CONNECT, body)
+}
+
+fn subscribe_packet(filters: &[&String]) -> Vec<u8> {
+    let mut body = 1u16.to_be_bytes().to_vec();
+    for filter in filters {
+        put_string(&mut body, filter);
+        body.push(0);
+    }
+    packet(SUBSCRIBE, body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, topic);
+    body.extend_from_slice(payload);
+    packet(PUBLISH | retain as u8, body)
+}
+
+fn packet(header: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    packet
+}
+
+fn put_string(buffer: &mut Vec<u8>, value: &str) {
+    put_bytes(buffer, value.as_bytes());
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, value: &[u8]) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value);
+}
+
+fn protocol_error(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            broker: "broker:1883".to_string(),
+            client_id: "lights".to_string(),
+            username: Some("user".to_string()),
+            password: Some("pw".to_string()),
+            name: "Christmas lights".to_string(),
+            topic_prefix: "xmas".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+            keep_alive: Duration::from_secs(60),
+            temperature_topic: None,
+            alarm_topic: None,
+        }
+    }
+
+    // Everything read_packets passes on from `bytes`, until it stops
+    async fn decode(bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let (incoming_tx, mut incoming) = mpsc::channel(16);
+        read_packets(bytes, incoming_tx).await;
+        let mut packets = Vec::new();
+        while let Ok(packet) = incoming.try_recv() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[test]
+    fn remaining_lengths_are_encoded_as_varints() {
+        for (length, encoded) in [
+            (0, &[0x00][..]),
+            (127, &[0x7F]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xFF, 0x7F]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (2_097_151, &[0xFF, 0xFF, 0x7F]),
+            (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+        ] {
+            let packet = packet(PUBLISH, vec![0; length]);
+            assert_eq!(&packet[1..1 + encoded.len()], encoded, "length {}", length);
+            assert_eq!(packet.len(), 1 + encoded.len() + length);
+        }
+    }
+
+    #[test]
+    fn connect_carries_the_will_and_credentials() {
+        let packet = connect_packet(&config(), &Topics::new(&config()));
+        let mut expected = vec![CONNECT, 56];
+        expected.extend_from_slice(b"\x00\x04MQTT\x04");
+        // Username, password, retained will and clean session
+        expected.push(0x80 | 0x40 | 0x20 | 0x04 | 0x02);
+        expected.extend_from_slice(&[0, 60]);
+        expected.extend_from_slice(b"\x00\x06lights");
+        expected.extend_from_slice(b"\x00\x11xmas/availability");
+        expected.extend_from_slice(b"\x00\x07offline");
+        expected.extend_from_slice(b"\x00\x04user\x00\x02pw");
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn connect_leaves_out_missing_credentials() {
+        let config = MqttConfig {
+            username: None,
+            password: None,
+            ..config()
+        };
+        let packet = connect_packet(&config, &Topics::new(&config));
+        assert_eq!(packet[9], 0x20 | 0x04 | 0x02);
+        assert!(packet.ends_with(b"\x00\x07offline"));
+    }
+
+    #[tokio::test]
+    async fn publishes_decode_back_to_topic_and_payload() {
+        let mut bytes = publish_packet("xmas/rgb/set", b"255,0,0", false);
+        let payload = vec![b'x'; 300];
+        bytes.extend(publish_packet("xmas/state", &payload, true));
+        let packets = decode(&bytes).await;
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].0, PUBLISH);
+        assert_eq!(
+            parse_publish(packets[0].0, &packets[0].1),
+            Some(("xmas/rgb/set".to_string(), b"255,0,0".to_vec()))
+        );
+        assert_eq!(packets[1].0, PUBLISH | 1);
+        assert_eq!(
+            parse_publish(packets[1].0, &packets[1].1),
+            Some(("xmas/state".to_string(), payload))
+        );
+    }
+
+    #[test]
+    fn publishes_above_qos_0_skip_the_packet_identifier() {
+        let mut body = b"\x00\x06xmas/x".to_vec();
+        body.extend_from_slice(&[0x12, 0x34]);
+        body.extend_from_slice(b"ON");
+        assert_eq!(
+            parse_publish(PUBLISH | 0x02, &body),
+            Some(("xmas/x".to_string(), b"ON".to_vec()))
+        );
+        // A topic running past the end of the packet
+        assert_eq!(parse_publish(PUBLISH, b"\x00\x09xmas/x"), None);
+        assert_eq!(parse_publish(PUBLISH, b"\x00"), None);
+    }
+
+    #[tokio::test]
+    async fn malformed_and_oversized_packets_end_the_connection() {
+        // A fifth length byte is not allowed
+        assert!(decode(&[PUBLISH, 0x80, 0x80, 0x80, 0x80, 0x01])
+            .await
+            .is_empty());
+        let oversized = packet(PUBLISH, vec![0; MAX_PACKET_LENGTH + 1]);
+        assert!(decode(&oversized).await.is_empty());
+        // Cut off partway through the body
+        let mut truncated = publish_packet("xmas/set", b"ON", false);
+        truncated.pop();
+        assert!(decode(&truncated).await.is_empty());
+        // A CONNACK accepting the connection, then a ping response
+        assert_eq!(
+            decode(&[CONNACK, 2, 0, 0, 0xD0, 0]).await,
+            vec![(CONNACK, vec![0, 0]), (0xD0, vec![])]
+        );
+    }
+
+    #[test]
+    fn commands_are_read_from_their_topics() {
+        let topics = Topics::new(&config());
+        assert_eq!(
+            parse_command(&topics, "xmas/rgb/set", b"255, 0, 0"),
+            Some(vec![RemoteCommand::Color((255, 0, 0))])
+        );
+        assert!(matches!(
+            parse_command(&topics, "xmas/rgb/set", b"255,0,0 ttl=600").as_deref(),
+            Some([RemoteCommand::Temporary(_, ttl)]) if *ttl == Duration::from_secs(600)
+        ));
+        assert!(parse_command(&topics, "xmas/rgb/set", b"255,0,0 ttl=0").is_none());
+        assert!(parse_command(&topics, "xmas/rgb/set", b"255,0").is_none());
+        assert!(parse_command(&topics, "xmas/set", b"OFF").is_some());
+        assert!(parse_command(&topics, "xmas/unknown", b"OFF").is_none());
+        assert_eq!(
+            parse_command(&topics, "xmas/trigger/doorbell", b"pressed"),
+            Some(vec![RemoteCommand::Event(Event::new(
+                "mqtt", "doorbell", "pressed"
+            ))])
+        );
+        assert!(parse_command(&topics, "xmas/trigger/", b"pressed").is_none());
+
+        let topics = Topics::new(&MqttConfig {
+            alarm_topic: Some("phone/next_alarm".to_string()),
+            ..config()
+        });
+        assert_eq!(
+            parse_command(&topics, "phone/next_alarm", b"2024-12-20T05:30:00+00:00"),
+            Some(vec![RemoteCommand::NextAlarm(Some(1_734_672_600))])
+        );
+        assert_eq!(
+            parse_command(&topics, "phone/next_alarm", b"unavailable"),
+            Some(vec![RemoteCommand::NextAlarm(None)])
+        );
+    }
+
+    #[test]
+    fn json_commands_follow_home_assistant() {
+        let topics = Topics::new(&config());
+        let parse = |payload: &str| parse_command(&topics, "xmas/set", payload.as_bytes());
+        assert_eq!(
+            parse(r#"{"state":"ON","color":{"r":255,"g":0,"b":40},"brightness":128}"#),
+            Some(vec![
+                RemoteCommand::On,
+                RemoteCommand::Color((255, 0, 40)),
+                RemoteCommand::Brightness(128.0 / 255.0),
+            ])
+        );
+        assert_eq!(parse(r#"{"state":"OFF"}"#), Some(vec![RemoteCommand::Off]));
+        assert_eq!(
+            parse(r#"{"effect": "twinkle"}"#),
+            Some(vec![RemoteCommand::Effect(EffectKind::Twinkle)])
+        );
+        assert!(matches!(
+            parse(r#"{"color":{"r":0,"g":0,"b":255},"ttl":600}"#).as_deref(),
+            Some([RemoteCommand::Temporary(_, ttl)]) if *ttl == Duration::from_secs(600)
+        ));
+        assert_eq!(parse(r#"{"state":"DIM"}"#), None);
+        assert_eq!(parse(r#"{"color":{"r":256,"g":0,"b":0}}"#), None);
+        assert_eq!(parse(r#"{"effect":"disco"}"#), None);
+        assert_eq!(parse(r#"{"transition":2}"#), None);
+    }
+
+    #[test]
+    fn discovery_uses_the_json_schema() {
+        let discovery = discovery_config(
+            &Topics::new(&config()),
+            &config(),
+            &DeviceInformation::default(),
+        );
+        assert!(discovery.contains(r#""schema":"json""#));
+        assert!(discovery.contains(r#""command_topic":"xmas/set","state_topic":"xmas/state""#));
+        assert!(discovery.contains(r#""supported_color_modes":["rgb"]"#));
+        assert!(!discovery.contains("rgb_command_topic"));
+    }
+
+    #[tokio::test]
+    async fn a_command_that_changes_nothing_is_still_echoed() {
+        let config = config();
+        let topics = Topics::new(&config);
+        let (commands, mut received) = mpsc::channel(4);
+        let (_status_tx, mut status) = watch::channel(LightStatus {
+            on: true,
+            effect: EffectKind::Solid,
+            color: (255, 0, 0),
+            brightness: 1.0,
+            override_until: None,
+        });
+        let (incoming_tx, incoming) = mpsc::channel(4);
+        incoming_tx.send((CONNACK, vec![0, 0])).await.unwrap();
+        let set = publish_packet("xmas/set", br#"{"state":"ON"}"#, false);
+        incoming_tx
+            .send((PUBLISH, set[2..].to_vec()))
+            .await
+            .unwrap();
+        let (mut writer, published) = tokio::io::duplex(64 * 1024);
+        let (packets_tx, mut packets) = mpsc::channel(64);
+        tokio::spawn(read_packets(published, packets_tx));
+        let device = DeviceInformation::default();
+        let serving = serve(
+            &config,
+            &topics,
+            &device,
+            &commands,
+            &mut status,
+            &mut writer,
+            incoming,
+        );
+
+        // The announcement carries the state once, the echo a second time
+        let states = async {
+            assert_eq!(
+                received.recv().await,
+                Some((Source::Mqtt, RemoteCommand::On))
+            );
+            let mut states = 0;
+            while let Some((header, body)) = packets.recv().await {
+                let (topic, payload) = parse_publish(header, &body).unwrap_or_default();
+                if topic == "xmas/state" {
+                    assert!(payload.starts_with(br#"{"state":"ON""#));
+                    states += 1;
+                    if states == 2 {
+                        return;
+                    }
+                }
+            }
+        };
+        tokio::select! {
+            _ = serving => panic!("the session ended"),
+            echoed = time::timeout(ECHO_DELAY * 3, states) => assert!(echoed.is_ok(), "no echo"),
+        }
+    }
+
+    #[test]
+    fn the_device_block_carries_the_model_and_firmware() {
+        let device = DeviceInformation {
+            manufacturer: None,
+            model: Some("AL-100".to_string()),
+            firmware: Some("1.4.2".to_string()),
+        };
+
+        assert_eq!(
+            device_block(&config(), &device),
+            r#"{"identifiers":["lights"],"name":"Christmas lights","manufacturer":"Actuel","model":"AL-100","sw_version":"1.4.2"}"#
+        );
+        assert_eq!(
+            device_block(&config(), &DeviceInformation::default()),
+            r#"{"identifiers":["lights"],"name":"Christmas lights","manufacturer":"Actuel"}"#
+        );
+    }
+
+    #[test]
+    fn the_json_state_follows_the_schema() {
+        let now = Instant::now();
+        let mut status = LightStatus {
+            on: true,
+            effect: EffectKind::Solid,
+            color: (255, 140, 40),
+            brightness: 0.5,
+            override_until: None,
+        };
+        assert_eq!(
+            state_json(status, now, 0),
+            r#"{"state":"ON","color_mode":"rgb","color":{"r":255,"g":140,"b":40},"brightness":128,"effect":"solid","override":null}"#
+        );
+        status.on = false;
+        status.override_until = Some(now + Duration::from_secs(600));
+        assert!(state_json(status, now, 0).contains(r#""state":"OFF""#));
+        assert!(
+            state_json(status, now, 0).ends_with(r#""override":{"until":"1970-01-01T00:10:00Z"}}"#)
+        );
+    }
+
+    #[test]
+    fn the_schema_lists_every_effect() {
+        let schema = state_schema();
+        assert!(!schema.contains("$EFFECTS"));
+        for effect in EffectKind::ALL {
+            assert!(schema.contains(&json_string(effect.name())));
+        }
+    }
+}
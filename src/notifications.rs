@@ -0,0 +1,34 @@
+// Notifications for whoever looks after the lights: [notifications] url takes a POST with the
+// message as plain text, which ntfy topics, Gotify and most chat webhooks relaying to a phone
+// accept. Sent with curl, like the other outgoing requests; a notification that cannot be
+// delivered is logged and dropped.
+use log::warn;
+use std::process::Command;
+
+const CURL_TIMEOUT_SECS: &str = "10";
+
+/// Posts `message` to `url`, returning whether it was accepted.
+pub fn send(url: &str, message: &str) -> bool {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--max-time", CURL_TIMEOUT_SECS])
+        .args(["--header", "Content-Type: text/plain; charset=utf-8"])
+        .arg("--data-binary")
+        .arg(message)
+        .arg(url)
+        .output();
+    match output {
+        Ok(output) if output.status.success() => true,
+        Ok(output) => {
+            warn!(
+                "Cannot send a notification: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            false
+        }
+        Err(e) => {
+            warn!("Cannot run curl to send a notification: {}", e);
+            false
+        }
+    }
+}
@@ -0,0 +1,84 @@
+// sd_notify(3) without libsystemd: readiness and watchdog pings go to the datagram socket
+// systemd names in $NOTIFY_SOCKET. Outside a Type=notify unit every call does nothing.
+//
+//   [Service]
+//   Type=notify
+//   WatchdogSec=30
+use log::debug;
+use std::{
+    env,
+    os::{linux::net::SocketAddrExt, unix::net::SocketAddr, unix::net::UnixDatagram},
+    time::Duration,
+};
+
+/// The daemon is connected and running.
+pub fn ready() {
+    send("READY=1");
+}
+
+/// The daemon is shutting down.
+pub fn stopping() {
+    send("STOPPING=1");
+}
+
+/// Tells the watchdog the main loop is still making progress.
+pub fn watchdog() {
+    send("WATCHDOG=1");
+}
+
+/// A line for `systemctl status`, e.g. how far startup has got.
+pub fn status(text: &str) {
+    send(&format!("STATUS={}", text));
+}
+
+/// Asks systemd to wait this much longer for startup, e.g. while retrying discovery.
+pub fn extend_startup(by: Duration) {
+    send(&format!("EXTEND_TIMEOUT_USEC={}", by.as_micros()));
+}
+
+/// How often systemd expects a watchdog ping, if the unit has a watchdog for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    let for_us = env::var("WATCHDOG_PID")
+        .ok()
+        .is_none_or(|pid| pid.parse::<u32>().ok() == Some(std::process::id()));
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    for_us.then(|| Duration::from_micros(usec))
+}
+
+/// `wait`, cut short so a loop sleeping between pings still pings every `ping`.
+pub fn capped_wait(wait: Duration, ping: Option<Duration>) -> Duration {
+    ping.map_or(wait, |every| wait.min(every))
+}
+
+fn send(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.to_string_lossy();
+    // A leading @ names a socket in the abstract namespace
+    let address = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(&*path),
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        address.and_then(|address| socket.send_to_addr(state.as_bytes(), &address))
+    });
+    if let Err(e) = sent {
+        debug!("Cannot notify systemd of {}: {}", state, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_while_off_stay_within_the_watchdog() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(capped_wait(minute, None), minute);
+        // WatchdogSec=30 pings every 15 seconds
+        let ping = Duration::from_secs(15);
+        assert_eq!(capped_wait(minute, Some(ping)), ping);
+        assert_eq!(capped_wait(minute, Some(minute * 2)), minute);
+    }
+}
@@ -0,0 +1,80 @@
+// Awareness and charity days with a fixed date, and the color landmarks usually light up in
+// for them. Days whose date moves from year to year are left out.
+use chrono::{Datelike, NaiveDate};
+
+pub const CATEGORIES: [&str; 3] = ["health", "social", "environment"];
+
+/// An observance the lights can honor by showing its color for the day.
+pub struct Observance {
+    pub month: u32,
+    pub day: u32,
+    pub category: &'static str,
+    pub name: &'static str,
+    pub color: (u8, u8, u8),
+}
+
+const fn observance(
+    (month, day): (u32, u32),
+    category: &'static str,
+    name: &'static str,
+    color: (u8, u8, u8),
+) -> Observance {
+    Observance {
+        month,
+        day,
+        category,
+        name,
+        color,
+    }
+}
+
+const OBSERVANCES: &[Observance] = &[
+    observance((2, 4), "health", "World Cancer Day", (255, 140, 0)),
+    observance((3, 8), "social", "International Women's Day", (128, 0, 160)),
+    observance((3, 21), "health", "World Down Syndrome Day", (255, 210, 0)),
+    observance(
+        (3, 21),
+        "environment",
+        "International Day of Forests",
+        (0, 140, 40),
+    ),
+    observance((3, 22), "environment", "World Water Day", (0, 150, 255)),
+    observance((4, 2), "health", "World Autism Awareness Day", (0, 80, 255)),
+    observance((4, 22), "environment", "Earth Day", (0, 160, 0)),
+    observance((6, 5), "environment", "World Environment Day", (40, 200, 0)),
+    observance((6, 8), "environment", "World Oceans Day", (0, 100, 255)),
+    observance((6, 14), "health", "World Blood Donor Day", (200, 0, 0)),
+    observance(
+        (9, 10),
+        "health",
+        "World Suicide Prevention Day",
+        (255, 180, 0),
+    ),
+    observance((9, 21), "health", "World Alzheimer's Day", (120, 0, 200)),
+    observance((10, 10), "health", "World Mental Health Day", (0, 200, 80)),
+    observance((11, 14), "health", "World Diabetes Day", (0, 120, 255)),
+    observance((11, 17), "health", "World Prematurity Day", (160, 0, 255)),
+    observance(
+        (11, 25),
+        "social",
+        "International Day for the Elimination of Violence against Women",
+        (255, 120, 0),
+    ),
+    observance((12, 1), "health", "World AIDS Day", (255, 0, 0)),
+    observance(
+        (12, 3),
+        "social",
+        "International Day of Persons with Disabilities",
+        (128, 0, 200),
+    ),
+];
+
+/// The first observance on `date` in one of the enabled categories.
+pub fn on(date: NaiveDate, categories: &[String]) -> Option<&'static Observance> {
+    OBSERVANCES.iter().find(|observance| {
+        (observance.month, observance.day) == (date.month(), date.day())
+            && categories
+                .iter()
+                .any(|category| category == observance.category)
+    })
+}
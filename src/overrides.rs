@@ -0,0 +1,122 @@
+// Temporary overrides: a remote command sent with a TTL, say solid red for ten minutes, changes
+// the lights like any other, and the show from before it comes back by itself when the time is
+// up. Overrides sent while one is running stack: the time runs from the latest one and the
+// show comes back as it was before the first. A command without a TTL keeps whatever the lights
+// show then, ending the override early.
+use crate::config::Config;
+use std::time::Instant;
+
+/// What an override reverts to.
+#[derive(Clone, Debug)]
+pub struct Show {
+    pub config: Config,
+    // The brightness on top of brightness.level
+    pub brightness: f32,
+    // Whether the lights were off and held off, only when an override switched them, so a
+    // scheduled switch-off during a color override stands
+    pub power: Option<(bool, bool)>,
+}
+
+#[derive(Default)]
+pub struct Overrides {
+    active: Option<(Show, Instant)>,
+}
+
+impl Overrides {
+    /// Starts an override until `until`, or moves the end of the one running.
+    pub fn start(&mut self, prior: Show, until: Instant) {
+        self.active = Some(match self.active.take() {
+            Some((mut first, _)) => {
+                first.power = first.power.or(prior.power);
+                (first, until)
+            }
+            None => (prior, until),
+        });
+    }
+
+    /// Keeps the lights as they are now.
+    pub fn clear(&mut self) {
+        self.active = None;
+    }
+
+    /// The show to go back to once the override ran out at `now`.
+    pub fn expired(&mut self, now: Instant) -> Option<Show> {
+        if self.active.as_ref().is_some_and(|(_, until)| now >= *until) {
+            self.active.take().map(|(show, _)| show)
+        } else {
+            None
+        }
+    }
+
+    /// When the override running now ends.
+    pub fn ends_at(&self) -> Option<Instant> {
+        self.active.as_ref().map(|(_, until)| *until)
+    }
+
+    /// The show the override reverts to, while one runs.
+    pub fn prior(&self) -> Option<&Show> {
+        self.active.as_ref().map(|(show, _)| show)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::EffectKind;
+    use std::time::Duration;
+
+    fn show(effect: EffectKind, power: Option<(bool, bool)>) -> Show {
+        Show {
+            config: Config {
+                effect,
+                ..Config::default()
+            },
+            brightness: 0.8,
+            power,
+        }
+    }
+
+    #[test]
+    fn the_prior_show_comes_back_when_the_time_is_up() {
+        let now = Instant::now();
+        let mut overrides = Overrides::default();
+        overrides.start(
+            show(EffectKind::Rainbow, None),
+            now + Duration::from_secs(600),
+        );
+        assert!(overrides.expired(now + Duration::from_secs(599)).is_none());
+        let restored = overrides.expired(now + Duration::from_secs(600)).unwrap();
+        assert_eq!(restored.config.effect, EffectKind::Rainbow);
+        assert!(overrides.expired(now + Duration::from_secs(601)).is_none());
+    }
+
+    #[test]
+    fn stacked_overrides_revert_to_before_the_first() {
+        let now = Instant::now();
+        let mut overrides = Overrides::default();
+        overrides.start(
+            show(EffectKind::Rainbow, None),
+            now + Duration::from_secs(60),
+        );
+        overrides.start(
+            show(EffectKind::Solid, Some((true, true))),
+            now + Duration::from_secs(120),
+        );
+        assert!(overrides.expired(now + Duration::from_secs(60)).is_none());
+        assert_eq!(overrides.ends_at(), Some(now + Duration::from_secs(120)));
+        let restored = overrides.expired(now + Duration::from_secs(120)).unwrap();
+        assert_eq!(restored.config.effect, EffectKind::Rainbow);
+        // The power from before the override that switched it
+        assert_eq!(restored.power, Some((true, true)));
+    }
+
+    #[test]
+    fn a_lasting_command_ends_the_override() {
+        let now = Instant::now();
+        let mut overrides = Overrides::default();
+        overrides.start(show(EffectKind::Rainbow, None), now);
+        overrides.clear();
+        assert!(overrides.prior().is_none());
+        assert!(overrides.expired(now).is_none());
+    }
+}
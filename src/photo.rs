@@ -0,0 +1,217 @@
+// Colors picked from a photo, e.g. of the tree's ornaments or a holiday card, for
+// `palette from-photo`. ImageMagick decodes whatever format the photo is in and shrinks it to a
+// few thousand pixels, which k-means then groups into the photo's dominant colors, each
+// weighted by how much of the photo it covers. They come out as a [palettes] table and a
+// [gradient] to paste into the config.
+use crate::{config::PaletteColor, error::Failure};
+use std::{path::Path, process::Command};
+
+// Enough pixels to find the colors, few enough to group them at once
+const SIZE: &str = "64x64";
+const ITERATIONS: usize = 20;
+pub const MAX_COLORS: usize = 12;
+
+/// The `count` dominant colors of the photo at `path`, most of the photo first.
+pub fn dominant_colors(path: &Path, count: usize) -> Result<Vec<PaletteColor>, Failure> {
+    let output = Command::new("convert")
+        .arg(path)
+        .args(["-resize", SIZE, "-depth", "8", "ppm:-"])
+        .output()
+        .map_err(|e| {
+            Failure::Usage(format!(
+                "cannot run ImageMagick's convert to read the photo: {}",
+                e
+            ))
+        })?;
+    if !output.status.success() {
+        return Err(Failure::Usage(format!(
+            "cannot read {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let pixels = parse_ppm(&output.stdout)
+        .ok_or_else(|| Failure::Usage(format!("cannot read {}", path.display())))?;
+    Ok(cluster(&pixels, count))
+}
+
+// Binary PPM ("P6") with 8 bits per channel, as convert writes it
+fn parse_ppm(ppm: &[u8]) -> Option<Vec<(u8, u8, u8)>> {
+    let mut fields = Vec::new();
+    let mut at = 0;
+    // Magic, width, height and maximum value, each followed by one whitespace byte
+    while fields.len() < 4 {
+        while ppm.get(at)?.is_ascii_whitespace() {
+            at += 1;
+        }
+        let start = at;
+        while !ppm.get(at)?.is_ascii_whitespace() {
+            at += 1;
+        }
+        fields.push(std::str::from_utf8(&ppm[start..at]).ok()?);
+    }
+    let [magic, width, height, max] = fields[..] else {
+        return None;
+    };
+    if magic != "P6" || max != "255" {
+        return None;
+    }
+    let len = width.parse::<usize>().ok()? * height.parse::<usize>().ok()? * 3;
+    let pixels = ppm.get(at + 1..at + 1 + len)?;
+    Some(
+        pixels
+            .chunks(3)
+            .map(|pixel| (pixel[0], pixel[1], pixel[2]))
+            .collect(),
+    )
+}
+
+// K-means starting from the first pixel and then, one at a time, the pixel farthest from the
+// colors picked so far, so the same photo always gives the same palette
+fn cluster(pixels: &[(u8, u8, u8)], count: usize) -> Vec<PaletteColor> {
+    let Some(&first) = pixels.first() else {
+        return Vec::new();
+    };
+    let mut centers = vec![channels(first)];
+    while centers.len() < count {
+        let farthest = pixels
+            .iter()
+            .map(|&pixel| channels(pixel))
+            .max_by(|&a, &b| {
+                let a = distance(&centers[nearest(&centers, a)], a);
+                let b = distance(&centers[nearest(&centers, b)], b);
+                a.total_cmp(&b)
+            })
+            .expect("there are pixels");
+        if centers.contains(&farthest) {
+            // Fewer distinct colors than asked for
+            break;
+        }
+        centers.push(farthest);
+    }
+    let count = centers.len();
+    let mut members = vec![0; count];
+    for _ in 0..ITERATIONS {
+        let mut sums = vec![[0.0; 3]; count];
+        members = vec![0; count];
+        for &pixel in pixels {
+            let pixel = channels(pixel);
+            let nearest = nearest(&centers, pixel);
+            for (sum, channel) in sums[nearest].iter_mut().zip(pixel) {
+                *sum += channel;
+            }
+            members[nearest] += 1;
+        }
+        let moved: Vec<[f32; 3]> = sums
+            .iter()
+            .zip(&members)
+            .zip(&centers)
+            .map(|((sum, &members), center)| match members {
+                0 => *center,
+                members => sum.map(|sum| sum / members as f32),
+            })
+            .collect();
+        if moved == centers {
+            break;
+        }
+        centers = moved;
+    }
+    let mut colors: Vec<PaletteColor> = centers
+        .iter()
+        .zip(&members)
+        .filter(|(_, &members)| members > 0)
+        .map(|(center, &members)| PaletteColor {
+            color: (
+                center[0].round() as u8,
+                center[1].round() as u8,
+                center[2].round() as u8,
+            ),
+            weight: members as f32 / pixels.len() as f32,
+            dwell: None,
+        })
+        .collect();
+    colors.sort_by(|a, b| b.weight.total_cmp(&a.weight));
+    colors
+}
+
+fn channels((r, g, b): (u8, u8, u8)) -> [f32; 3] {
+    [r as f32, g as f32, b as f32]
+}
+
+fn distance(a: &[f32; 3], b: [f32; 3]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b).powi(2)).sum()
+}
+
+fn nearest(centers: &[[f32; 3]], pixel: [f32; 3]) -> usize {
+    (0..centers.len())
+        .min_by(|&a, &b| distance(&centers[a], pixel).total_cmp(&distance(&centers[b], pixel)))
+        .unwrap_or(0)
+}
+
+/// The colors as config to paste: a palette by `name` and a gradient through them.
+pub fn to_toml(name: &str, colors: &[PaletteColor]) -> String {
+    let hex = |(r, g, b): (u8, u8, u8)| format!("\"#{:02x}{:02x}{:02x}\"", r, g, b);
+    let bare = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    let key = if bare {
+        name.to_string()
+    } else {
+        format!("{:?}", name)
+    };
+    let mut toml = format!("[palettes.{}]\ncolors = [\n", key);
+    for color in colors {
+        toml.push_str(&format!(
+            "    {{ color = {}, weight = {:.2} }},\n",
+            hex(color.color),
+            color.weight
+        ));
+    }
+    let stops: Vec<String> = colors
+        .iter()
+        .enumerate()
+        .map(|(i, color)| {
+            let at = i as f32 / (colors.len() - 1).max(1) as f32;
+            format!("[{:.2}, {}]", at, hex(color.color))
+        })
+        .collect();
+    toml.push_str(&format!(
+        "]\n\n[gradient]\nstops = [{}]\n",
+        stops.join(", ")
+    ));
+    toml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_photo_comes_down_to_its_main_colors() {
+        let mut ppm = b"P6\n4 2\n255\n".to_vec();
+        let red = [200, 10, 10];
+        let green = [10, 120, 20];
+        for pixel in [red, red, red, green, red, green, red, [220, 0, 0]] {
+            ppm.extend(pixel);
+        }
+        let pixels = parse_ppm(&ppm).unwrap();
+        assert_eq!(pixels.len(), 8);
+        assert_eq!(parse_ppm(b"P3\n1 1\n255\n0 0 0"), None);
+
+        let colors = cluster(&pixels, 2);
+        assert_eq!(colors.len(), 2);
+        assert_eq!(colors[0].color, (203, 8, 8));
+        assert_eq!(colors[0].weight, 0.75);
+        assert_eq!(colors[1].color, (10, 120, 20));
+        assert_eq!(cluster(&[(1, 2, 3); 4], 3).len(), 1);
+        assert_eq!(
+            to_toml("ornaments", &colors),
+            "[palettes.ornaments]\ncolors = [\n    { color = \"#cb0808\", weight = 0.75 },\n    \
+             { color = \"#0a7814\", weight = 0.25 },\n]\n\n[gradient]\n\
+             stops = [[0.00, \"#cb0808\"], [1.00, \"#0a7814\"]]\n"
+        );
+        let pasted = crate::Config::from_toml(&to_toml("on the card", &colors)).unwrap();
+        assert_eq!(pasted.palettes[0].name, "on the card");
+        assert_eq!(pasted.gradient.stops.map(|stops| stops.len()), Some(2));
+    }
+}
@@ -0,0 +1,582 @@
+// The evening's lighting plan, worked out each morning from the day length and the weather
+// forecast: when the lights first come on, how bright they are through the night and which
+// colors they keep to. The forecast comes from Open-Meteo, queried with curl; without one the
+// plan follows the day length alone. Each day's forecast is kept in storage, so
+// `replay-decisions` can make the plan again later.
+use crate::{storage, timezone::Zone};
+use angular_units::Deg;
+use chrono::NaiveDate;
+use log::warn;
+use prisma::{FromColor, Hsv, Rgb};
+use std::{process::Command, sync::Mutex};
+
+// Where storage keeps the forecasts plans were made from
+pub const FORECASTS_KEY: &str = "forecasts";
+// A season and then some
+const MAX_RECORDED_FORECASTS: usize = 400;
+
+const FORECAST_URL: &str = "https://api.open-meteo.com/v1/forecast";
+const CURL_TIMEOUT_SECS: &str = "10";
+// Dull evenings get dark before sunset, so the lights come on this many minutes early
+const OVERCAST_LEAD_MINUTES: i64 = 30;
+const CLOUDY_LEAD_MINUTES: i64 = 15;
+const OVERCAST_CLOUD_COVER: f32 = 75.0;
+const CLOUDY_CLOUD_COVER: f32 = 40.0;
+// Millimetres over the day that count as a wet evening
+const WET_PRECIPITATION: f32 = 1.0;
+// The first third of the night is the busy part of the evening, after which the lights dim
+const EVENING_SHARE_OF_NIGHT: f64 = 1.0 / 3.0;
+const DULL_EVENING_BRIGHTNESS: f32 = 1.0;
+const CLEAR_EVENING_BRIGHTNESS: f32 = 0.85;
+const LATE_BRIGHTNESS: f32 = 0.4;
+
+static PLAN: Mutex<Option<DailyPlan>> = Mutex::new(None);
+
+/// The weather for the day the plan is made.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Forecast {
+    // Mean cloud cover in percent
+    pub cloud_cover: f32,
+    // Millimetres of rain and snow over the day
+    pub precipitation: f32,
+    // °C
+    pub temperature_min: f32,
+}
+
+impl Forecast {
+    fn is_overcast(&self) -> bool {
+        self.cloud_cover >= OVERCAST_CLOUD_COVER || self.precipitation >= WET_PRECIPITATION
+    }
+}
+
+/// Colors the night keeps to, picked from the weather.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Palette {
+    // Whatever the lights are showing
+    Show,
+    // Icy blues on frosty nights
+    Frost,
+    // Warm reds and ambers on wet nights
+    Ember,
+}
+
+impl Palette {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Palette::Show => "show",
+            Palette::Frost => "frost",
+            Palette::Ember => "ember",
+        }
+    }
+
+    // The hues the palette keeps to, in degrees
+    fn hues(&self) -> Option<(f32, f32)> {
+        match self {
+            Palette::Show => None,
+            Palette::Frost => Some((170.0, 250.0)),
+            Palette::Ember => Some((0.0, 45.0)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DailyPlan {
+    pub date: NaiveDate,
+    // UTC timestamps: when the lights come on, which is the scheduled time or earlier on dull
+    // evenings, and when they go off
+    pub on_at: i64,
+    pub scheduled_on_at: i64,
+    pub off_at: i64,
+    // Seconds
+    pub day_length: i64,
+    pub forecast: Option<Forecast>,
+    // Brightness from each UTC timestamp on, in order
+    pub curve: Vec<(i64, f32)>,
+    pub palette: Palette,
+}
+
+impl DailyPlan {
+    /// The plan for the evening of `date`, with the lights scheduled from `on` to `off`.
+    pub fn new(
+        date: NaiveDate,
+        (on, off, day_length): (i64, i64, i64),
+        forecast: Option<Forecast>,
+    ) -> Self {
+        let lead_minutes = match forecast {
+            Some(forecast) if forecast.is_overcast() => OVERCAST_LEAD_MINUTES,
+            Some(forecast) if forecast.cloud_cover >= CLOUDY_CLOUD_COVER => CLOUDY_LEAD_MINUTES,
+            _ => 0,
+        };
+        let on_at = (on - lead_minutes * 60).min(off);
+
+        let evening_brightness = match forecast {
+            Some(forecast) if !forecast.is_overcast() => CLEAR_EVENING_BRIGHTNESS,
+            _ => DULL_EVENING_BRIGHTNESS,
+        };
+        let late_at = on + ((off - on) as f64 * EVENING_SHARE_OF_NIGHT) as i64;
+        let curve = vec![(on_at, evening_brightness), (late_at, LATE_BRIGHTNESS)];
+
+        let palette = match forecast {
+            Some(forecast) if forecast.temperature_min <= 0.0 => Palette::Frost,
+            Some(forecast) if forecast.precipitation >= WET_PRECIPITATION => Palette::Ember,
+            _ => Palette::Show,
+        };
+
+        DailyPlan {
+            date,
+            on_at,
+            scheduled_on_at: on,
+            off_at: off,
+            day_length,
+            forecast,
+            curve,
+            palette,
+        }
+    }
+
+    /// Whether `now` is in the early start ahead of the scheduled on time.
+    pub fn is_early(&self, now: i64) -> bool {
+        self.on_at <= now && now < self.scheduled_on_at
+    }
+
+    /// The brightness the plan asks for at `now`, full outside the night it covers.
+    pub fn brightness_at(&self, now: i64) -> f32 {
+        if now < self.on_at || self.off_at <= now {
+            return 1.0;
+        }
+        self.curve
+            .iter()
+            .take_while(|(from, _)| *from <= now)
+            .last()
+            .map_or(1.0, |(_, level)| *level)
+    }
+
+    /// The plan as JSON, times as UTC Unix timestamps.
+    pub fn to_json(&self) -> String {
+        let forecast = match self.forecast {
+            Some(forecast) => format!(
+                "{{\"cloud_cover\":{},\"precipitation\":{},\"temperature_min\":{}}}",
+                forecast.cloud_cover, forecast.precipitation, forecast.temperature_min
+            ),
+            None => "null".to_string(),
+        };
+        let curve = self
+            .curve
+            .iter()
+            .map(|(from, level)| format!("{{\"from\":{},\"brightness\":{}}}", from, level))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"date\":\"{}\",\"on_at\":{},\"scheduled_on_at\":{},\"off_at\":{},\
+             \"day_length_secs\":{},\"forecast\":{},\"curve\":[{}],\"palette\":\"{}\"}}",
+            self.date,
+            self.on_at,
+            self.scheduled_on_at,
+            self.off_at,
+            self.day_length,
+            forecast,
+            curve,
+            self.palette.name()
+        )
+    }
+
+    /// The evening in a line for a notification, e.g. "Lights on at 15:58, plan: twinkle at
+    /// 85% until 19:40, then 40%, off at 23:30", with times on the zone's clocks.
+    pub fn summary(&self, effect: &str, zone: Zone) -> String {
+        let time = |timestamp| {
+            zone.local(timestamp)
+                .map(|time| time.format("%H:%M").to_string())
+                .unwrap_or_else(|| "??:??".to_string())
+        };
+        let steps: Vec<String> = self
+            .curve
+            .iter()
+            .enumerate()
+            .map(|(i, (_, level))| {
+                let step = format!(
+                    "{} {:.0}%",
+                    if i == 0 { "at" } else { "then" },
+                    level * 100.0
+                );
+                match self.curve.get(i + 1) {
+                    Some((until, _)) => format!("{} until {}", step, time(*until)),
+                    None => step,
+                }
+            })
+            .collect();
+        let colors = match self.palette {
+            Palette::Show => String::new(),
+            palette => format!(" in {} colors", palette.name()),
+        };
+        format!(
+            "Lights on at {}, plan: {}{} {}, off at {}",
+            time(self.on_at),
+            effect,
+            colors,
+            steps.join(", "),
+            time(self.off_at)
+        )
+    }
+
+    /// What the plan decided and why, as UTC timestamps with the decision and its reason, in
+    /// time order, with times in the reasons on the zone's clocks.
+    pub fn decisions(&self, zone: Zone) -> Vec<(i64, String, String)> {
+        let weather = match self.forecast {
+            Some(forecast) => format!(
+                "{:.0}% cloud, {:.1} mm, low of {:.0}°C",
+                forecast.cloud_cover, forecast.precipitation, forecast.temperature_min
+            ),
+            None => "no forecast".to_string(),
+        };
+        let lead_minutes = (self.scheduled_on_at - self.on_at) / 60;
+        let on = match lead_minutes {
+            0 => format!("the scheduled time ({})", weather),
+            lead => format!(
+                "{} minutes ahead of the scheduled {}, as the evening gets dark early ({})",
+                lead,
+                zone.local(self.scheduled_on_at).map_or_else(
+                    || "??:??".to_string(),
+                    |time| time.format("%H:%M").to_string()
+                ),
+                weather
+            ),
+        };
+        let mut decisions = vec![(self.on_at, "lights on".to_string(), on)];
+        for (i, (from, level)) in self.curve.iter().enumerate() {
+            let why = match (i, self.forecast) {
+                (0, Some(forecast)) if !forecast.is_overcast() => "a clear evening",
+                (0, Some(_)) => "a dull evening",
+                (0, None) => "no forecast to dim for",
+                _ => "the busy first third of the night is over",
+            };
+            decisions.push((
+                *from,
+                format!("brightness {:.0}%", level * 100.0),
+                why.to_string(),
+            ));
+        }
+        if let (Some(forecast), Palette::Frost | Palette::Ember) = (self.forecast, self.palette) {
+            let why = match self.palette {
+                Palette::Frost => format!("a low of {:.0}°C", forecast.temperature_min),
+                _ => format!("{:.1} mm of rain or snow", forecast.precipitation),
+            };
+            decisions.push((self.on_at, format!("{} colors", self.palette.name()), why));
+        }
+        decisions.push((
+            self.off_at,
+            "lights off".to_string(),
+            "the scheduled time".to_string(),
+        ));
+        decisions.sort_by_key(|(at, _, _)| *at);
+        decisions
+    }
+
+    /// The color dimmed to the plan's brightness at `now` and moved into its palette.
+    pub fn apply(&self, (r, g, b): (u8, u8, u8), now: i64) -> (u8, u8, u8) {
+        let level = self.brightness_at(now);
+        let rgb = Rgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+        let hsv: Hsv<f32, Deg<f32>> = Hsv::from_color(&rgb);
+        let hue = match self.palette.hues() {
+            // Squeezing the whole circle into the range keeps the show's movement
+            Some((from, to)) => Deg(from + hsv.hue().0 / 360.0 * (to - from)),
+            None => hsv.hue(),
+        };
+        let rgb = Rgb::from_color(&Hsv::new(hue, hsv.saturation(), hsv.value() * level));
+        (
+            (rgb.red() * 255.0).round() as u8,
+            (rgb.green() * 255.0).round() as u8,
+            (rgb.blue() * 255.0).round() as u8,
+        )
+    }
+}
+
+/// Makes `plan` the one the lights follow.
+pub fn set(plan: DailyPlan) {
+    *PLAN.lock().unwrap() = Some(plan);
+}
+
+pub fn current() -> Option<DailyPlan> {
+    PLAN.lock().unwrap().clone()
+}
+
+/// Keeps the forecast the plan for `date` was made from, None when there was none.
+pub fn record_forecast(date: NaiveDate, forecast: Option<Forecast>) {
+    let mut recorded: Vec<(NaiveDate, Option<Forecast>)> = recorded_forecasts()
+        .into_iter()
+        .filter(|(day, _)| *day != date)
+        .collect();
+    recorded.push((date, forecast));
+    let excess = recorded.len().saturating_sub(MAX_RECORDED_FORECASTS);
+    let formatted = format_recorded(&recorded[excess..]);
+    if let Err(e) = storage::current().write(FORECASTS_KEY, formatted.as_bytes()) {
+        warn!("Cannot save the forecast for {}: {}", date, e);
+    }
+}
+
+/// The forecast the plan for `date` was made from, or None when the day was not recorded.
+pub fn recorded_forecast(date: NaiveDate) -> Option<Option<Forecast>> {
+    recorded_forecasts()
+        .into_iter()
+        .find(|(day, _)| *day == date)
+        .map(|(_, forecast)| forecast)
+}
+
+// One "<date> <cloud cover> <precipitation> <low>" or "<date> none" line per day
+fn recorded_forecasts() -> Vec<(NaiveDate, Option<Forecast>)> {
+    match storage::current().read(FORECASTS_KEY) {
+        Ok(contents) => parse_recorded(&String::from_utf8_lossy(&contents.unwrap_or_default())),
+        Err(e) => {
+            warn!("Cannot read the recorded forecasts: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+fn format_recorded(recorded: &[(NaiveDate, Option<Forecast>)]) -> String {
+    recorded
+        .iter()
+        .map(|(day, forecast)| match forecast {
+            Some(forecast) => format!(
+                "{} {} {} {}\n",
+                day, forecast.cloud_cover, forecast.precipitation, forecast.temperature_min
+            ),
+            None => format!("{} none\n", day),
+        })
+        .collect()
+}
+
+fn parse_recorded(contents: &str) -> Vec<(NaiveDate, Option<Forecast>)> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let date = NaiveDate::parse_from_str(fields.next()?, "%Y-%m-%d").ok()?;
+            let cloud_cover = fields.next()?;
+            if cloud_cover == "none" {
+                return Some((date, None));
+            }
+            let forecast = Forecast {
+                cloud_cover: cloud_cover.parse().ok()?,
+                precipitation: fields.next()?.parse().ok()?,
+                temperature_min: fields.next()?.parse().ok()?,
+            };
+            Some((date, Some(forecast)))
+        })
+        .collect()
+}
+
+/// Today's weather at `(latitude, longitude)`, or None when Open-Meteo cannot be reached.
+pub fn fetch_forecast((latitude, longitude): (f64, f64)) -> Option<Forecast> {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", "--get"])
+        .args(["--max-time", CURL_TIMEOUT_SECS])
+        .arg("--data")
+        .arg(format!("latitude={}&longitude={}", latitude, longitude))
+        .args([
+            "--data",
+            "daily=cloud_cover_mean,precipitation_sum,temperature_2m_min",
+            "--data",
+            "timezone=UTC&forecast_days=1",
+            FORECAST_URL,
+        ])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            parse_forecast(&String::from_utf8_lossy(&output.stdout))
+        }
+        Ok(output) => {
+            warn!(
+                "Cannot fetch the weather forecast: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            warn!("Cannot run curl to fetch the weather forecast: {}", e);
+            None
+        }
+    }
+}
+
+// Each daily value is an array with one entry per forecast day
+fn parse_forecast(json: &str) -> Option<Forecast> {
+    let (_, daily) = json.split_once("\"daily\":")?;
+    Some(Forecast {
+        cloud_cover: first_value(daily, "cloud_cover_mean")?,
+        precipitation: first_value(daily, "precipitation_sum")?,
+        temperature_min: first_value(daily, "temperature_2m_min")?,
+    })
+}
+
+fn first_value(object: &str, key: &str) -> Option<f32> {
+    let (_, rest) = object.split_once(&format!("\"{}\"", key))?;
+    let values = rest
+        .trim_start()
+        .strip_prefix(':')?
+        .trim_start()
+        .strip_prefix('[')?;
+    let end = values.find([',', ']'])?;
+    values[..end].trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Trimmed from what Open-Meteo answers for Budapest
+    const FORECAST: &str = r#"{"latitude":47.5,"longitude":19.25,"daily_units":{"time":"iso8601",
+        "cloud_cover_mean":"%"},"daily":{"time":["2023-12-21"],"cloud_cover_mean":[87],
+        "precipitation_sum":[2.40],"temperature_2m_min":[-1.5]}}"#;
+
+    const ON: i64 = 1_703_174_400;
+    const OFF: i64 = ON + 15 * 3600;
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, 12, 21).unwrap()
+    }
+
+    fn forecast(cloud_cover: f32, precipitation: f32, temperature_min: f32) -> Option<Forecast> {
+        Some(Forecast {
+            cloud_cover,
+            precipitation,
+            temperature_min,
+        })
+    }
+
+    #[test]
+    fn the_forecast_is_read_from_the_daily_values() {
+        assert_eq!(parse_forecast(FORECAST), forecast(87.0, 2.4, -1.5));
+        assert_eq!(parse_forecast(r#"{"error":true}"#), None);
+    }
+
+    #[test]
+    fn dull_evenings_start_early() {
+        let clear = DailyPlan::new(date(), (ON, OFF, 9 * 3600), forecast(10.0, 0.0, 5.0));
+        let cloudy = DailyPlan::new(date(), (ON, OFF, 9 * 3600), forecast(50.0, 0.0, 5.0));
+        let wet = DailyPlan::new(date(), (ON, OFF, 9 * 3600), forecast(20.0, 3.0, 5.0));
+
+        assert_eq!(clear.on_at, ON);
+        assert_eq!(cloudy.on_at, ON - 15 * 60);
+        assert_eq!(wet.on_at, ON - 30 * 60);
+        assert!(wet.is_early(ON - 60));
+        assert!(!wet.is_early(ON));
+    }
+
+    #[test]
+    fn the_plan_follows_the_day_length_without_a_forecast() {
+        let plan = DailyPlan::new(date(), (ON, OFF, 9 * 3600), None);
+
+        assert_eq!(plan.on_at, ON);
+        assert_eq!(plan.palette, Palette::Show);
+        assert_eq!(plan.brightness_at(ON), 1.0);
+        // A 15 hour night dims after its first 5 hours
+        assert_eq!(plan.brightness_at(ON + 5 * 3600 - 1), 1.0);
+        assert_eq!(plan.brightness_at(ON + 5 * 3600), 0.4);
+        assert_eq!(plan.brightness_at(OFF), 1.0);
+    }
+
+    #[test]
+    fn clear_evenings_are_dimmer() {
+        let plan = DailyPlan::new(date(), (ON, OFF, 9 * 3600), forecast(0.0, 0.0, 5.0));
+        assert_eq!(plan.brightness_at(ON), 0.85);
+    }
+
+    #[test]
+    fn the_palette_follows_the_weather() {
+        let palette = |forecast| DailyPlan::new(date(), (ON, OFF, 9 * 3600), forecast).palette;
+
+        assert_eq!(palette(forecast(90.0, 4.0, -3.0)), Palette::Frost);
+        assert_eq!(palette(forecast(90.0, 4.0, 6.0)), Palette::Ember);
+        assert_eq!(palette(forecast(90.0, 0.0, 6.0)), Palette::Show);
+    }
+
+    #[test]
+    fn the_summary_reads_the_night_on_the_local_clocks() {
+        let plan = DailyPlan::new(
+            date(),
+            (ON, ON + 6 * 3600, 9 * 3600),
+            forecast(0.0, 0.0, -2.0),
+        );
+        assert_eq!(
+            plan.summary("twinkle", Zone::Utc),
+            "Lights on at 16:00, plan: twinkle in frost colors at 85% until 18:00, then 40%, \
+             off at 22:00"
+        );
+        let budapest = Zone::from_name("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        let plan = DailyPlan::new(date(), (ON, ON + 6 * 3600, 9 * 3600), None);
+        assert_eq!(
+            plan.summary("rainbow", budapest),
+            "Lights on at 17:00, plan: rainbow at 100% until 19:00, then 40%, off at 23:00"
+        );
+    }
+
+    #[test]
+    fn applying_the_plan_dims_and_recolors() {
+        let mut plan = DailyPlan::new(date(), (ON, OFF, 9 * 3600), None);
+        assert_eq!(plan.apply((255, 0, 0), ON), (255, 0, 0));
+        assert_eq!(plan.apply((200, 100, 0), OFF - 1), (80, 40, 0));
+
+        plan.palette = Palette::Frost;
+        // Red, at the start of the circle, becomes the start of the range
+        let (r, g, b) = plan.apply((255, 0, 0), ON);
+        assert_eq!((r, g, b), (0, 255, 212));
+    }
+
+    #[test]
+    fn recorded_forecasts_read_back() {
+        let recorded = [
+            (date(), forecast(87.0, 2.4, -1.5)),
+            (date().succ_opt().unwrap(), None),
+        ];
+        assert_eq!(parse_recorded(&format_recorded(&recorded)), recorded);
+        assert_eq!(parse_recorded("2023-12-21 87\nyesterday none"), []);
+    }
+
+    #[test]
+    fn the_decisions_come_with_their_reasons() {
+        let plan = DailyPlan::new(date(), (ON, OFF, 9 * 3600), forecast(87.0, 2.4, -1.5));
+        let decisions: Vec<(i64, String)> = plan
+            .decisions(Zone::Utc)
+            .into_iter()
+            .map(|(at, what, why)| (at, format!("{}: {}", what, why)))
+            .collect();
+        assert_eq!(
+            decisions,
+            [
+                (
+                    ON - 30 * 60,
+                    "lights on: 30 minutes ahead of the scheduled 16:00, as the evening gets \
+                     dark early (87% cloud, 2.4 mm, low of -2°C)"
+                        .to_string()
+                ),
+                (ON - 30 * 60, "brightness 100%: a dull evening".to_string()),
+                (ON - 30 * 60, "frost colors: a low of -2°C".to_string()),
+                (
+                    ON + 5 * 3600,
+                    "brightness 40%: the busy first third of the night is over".to_string()
+                ),
+                (OFF, "lights off: the scheduled time".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_plan_is_published_as_json() {
+        let plan = DailyPlan::new(date(), (ON, OFF, 9 * 3600), forecast(87.0, 2.5, -1.5));
+
+        assert_eq!(
+            plan.to_json(),
+            format!(
+                "{{\"date\":\"2023-12-21\",\"on_at\":{},\"scheduled_on_at\":{},\"off_at\":{},\
+                 \"day_length_secs\":32400,\"forecast\":{{\"cloud_cover\":87,\"precipitation\":2.5,\
+                 \"temperature_min\":-1.5}},\"curve\":[{{\"from\":{},\"brightness\":1}},\
+                 {{\"from\":{},\"brightness\":0.4}}],\"palette\":\"frost\"}}",
+                ON - 30 * 60,
+                ON,
+                OFF,
+                ON - 30 * 60,
+                ON + 5 * 3600
+            )
+        );
+    }
+}
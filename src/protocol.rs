@@ -0,0 +1,259 @@
+// Command encodings for the BLE light controllers we can drive. Each brand writes short
+// commands to a single characteristic; they differ in the bytes and in which one.
+use btleplug::api::bleuuid::uuid_from_u16;
+use uuid::Uuid;
+
+/// Encodes commands for one family of light controllers.
+pub trait LightProtocol: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The characteristic commands are written to.
+    fn characteristic_uuid(&self) -> Uuid;
+
+    fn color(&self, rgb: (u8, u8, u8)) -> Vec<u8>;
+
+    /// None when the controller has no power-on command and turns on with the next color.
+    fn power(&self, on: bool) -> Option<Vec<u8>>;
+
+    /// None when the controller has no brightness command and colors are scaled instead.
+    fn brightness(&self, _level: u8) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// None when the controller has no dedicated warm-white channel.
+    fn white(&self, _level: u8) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Which protocol to speak, from `device.protocol`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolKind {
+    // Picked per light from the services it advertises
+    Auto,
+    Actuel,
+    Triones,
+    MagicHome,
+}
+
+impl ProtocolKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ProtocolKind::Auto => "auto",
+            ProtocolKind::Actuel => "actuel",
+            ProtocolKind::Triones => "triones",
+            ProtocolKind::MagicHome => "magic_home",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            ProtocolKind::Auto,
+            ProtocolKind::Actuel,
+            ProtocolKind::Triones,
+            ProtocolKind::MagicHome,
+        ]
+        .into_iter()
+        .find(|kind| kind.name() == name)
+    }
+
+    /// Resolves Auto from the light's advertised service UUIDs, falling back to Actuel, which
+    /// does not advertise a distinctive service. `white_channel_opcode` is Actuel's, if set.
+    pub fn resolve(
+        self,
+        services: &[Uuid],
+        white_channel_opcode: Option<u8>,
+    ) -> Box<dyn LightProtocol> {
+        let kind = match self {
+            ProtocolKind::Auto if services.contains(&TRIONES_SERVICE_UUID) => ProtocolKind::Triones,
+            ProtocolKind::Auto if services.contains(&MAGIC_HOME_SERVICE_UUID) => {
+                ProtocolKind::MagicHome
+            }
+            ProtocolKind::Auto => ProtocolKind::Actuel,
+            kind => kind,
+        };
+        match kind {
+            ProtocolKind::Triones => Box::new(Triones),
+            ProtocolKind::MagicHome => Box::new(MagicHome),
+            _ => Box::new(Actuel {
+                white_channel_opcode,
+            }),
+        }
+    }
+
+    /// The protocol whose command characteristic is among the light's `characteristics`, once
+    /// connected. Brands with a characteristic of their own come before Actuel's 0x1001, which
+    /// other controllers may happen to have too; None when the light has none of them.
+    pub fn detect(characteristics: &[Uuid]) -> Option<ProtocolKind> {
+        [
+            ProtocolKind::Triones,
+            ProtocolKind::MagicHome,
+            ProtocolKind::Actuel,
+        ]
+        .into_iter()
+        .find(|kind| characteristics.contains(&kind.resolve(&[], None).characteristic_uuid()))
+    }
+}
+
+const ACTUEL_MAGIC_NUMBER: u8 = 0x3C;
+const TRIONES_SERVICE_UUID: Uuid = uuid_from_u16(0xFFD5);
+const MAGIC_HOME_SERVICE_UUID: Uuid = uuid_from_u16(0xFFE5);
+
+/// The Actuel strings this project started with.
+#[derive(Default)]
+pub struct Actuel {
+    // Opcode of the dedicated warm-white channel, for controllers that have one
+    pub white_channel_opcode: Option<u8>,
+}
+
+impl LightProtocol for Actuel {
+    fn name(&self) -> &'static str {
+        "Actuel"
+    }
+
+    fn characteristic_uuid(&self) -> Uuid {
+        uuid_from_u16(0x1001)
+    }
+
+    fn color(&self, (r, g, b): (u8, u8, u8)) -> Vec<u8> {
+        vec![ACTUEL_MAGIC_NUMBER, 0x02, r, g, b]
+    }
+
+    fn power(&self, on: bool) -> Option<Vec<u8>> {
+        (!on).then(|| vec![ACTUEL_MAGIC_NUMBER, 0x01])
+    }
+
+    fn white(&self, level: u8) -> Option<Vec<u8>> {
+        self.white_channel_opcode
+            .map(|opcode| vec![ACTUEL_MAGIC_NUMBER, opcode, level])
+    }
+}
+
+/// Triones / Happy Lighting strips, usually named "Triones-..." or "QHM-...".
+pub struct Triones;
+
+impl LightProtocol for Triones {
+    fn name(&self) -> &'static str {
+        "Triones"
+    }
+
+    fn characteristic_uuid(&self) -> Uuid {
+        uuid_from_u16(0xFFD9)
+    }
+
+    // 0xF0 selects the RGB channels rather than the white one
+    fn color(&self, (r, g, b): (u8, u8, u8)) -> Vec<u8> {
+        vec![0x56, r, g, b, 0x00, 0xF0, 0xAA]
+    }
+
+    fn power(&self, on: bool) -> Option<Vec<u8>> {
+        Some(vec![0xCC, if on { 0x23 } else { 0x24 }, 0x33])
+    }
+}
+
+/// Magic Home BLE strips, usually named "LEDBLE-...", which take the LEDnet commands with a
+/// trailing checksum.
+pub struct MagicHome;
+
+impl LightProtocol for MagicHome {
+    fn name(&self) -> &'static str {
+        "Magic Home"
+    }
+
+    fn characteristic_uuid(&self) -> Uuid {
+        uuid_from_u16(0xFFE9)
+    }
+
+    fn color(&self, (r, g, b): (u8, u8, u8)) -> Vec<u8> {
+        with_checksum(vec![0x31, r, g, b, 0x00, 0xF0, 0x0F])
+    }
+
+    fn power(&self, on: bool) -> Option<Vec<u8>> {
+        Some(with_checksum(vec![
+            0x71,
+            if on { 0x23 } else { 0x24 },
+            0x0F,
+        ]))
+    }
+}
+
+fn with_checksum(mut command: Vec<u8>) -> Vec<u8> {
+    let checksum = command
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    command.push(checksum);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triones_commands() {
+        assert_eq!(
+            Triones.color((1, 2, 3)),
+            vec![0x56, 1, 2, 3, 0x00, 0xF0, 0xAA]
+        );
+        assert_eq!(Triones.power(true), Some(vec![0xCC, 0x23, 0x33]));
+        assert_eq!(Triones.power(false), Some(vec![0xCC, 0x24, 0x33]));
+    }
+
+    #[test]
+    fn magic_home_commands_end_in_a_checksum() {
+        assert_eq!(MagicHome.power(true), Some(vec![0x71, 0x23, 0x0F, 0xA3]));
+        let color = MagicHome.color((0xFF, 0x00, 0x00));
+        let (body, checksum) = color.split_at(color.len() - 1);
+        assert_eq!(
+            checksum[0],
+            body.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        );
+    }
+
+    #[test]
+    fn the_white_channel_is_configured() {
+        assert!(ProtocolKind::Auto.resolve(&[], None).white(0x80).is_none());
+        assert_eq!(
+            ProtocolKind::Auto.resolve(&[], Some(0x05)).white(0x80),
+            Some(vec![ACTUEL_MAGIC_NUMBER, 0x05, 0x80])
+        );
+        // Only Actuel controllers take it
+        assert!(ProtocolKind::Triones
+            .resolve(&[], Some(0x05))
+            .white(0x80)
+            .is_none());
+    }
+
+    #[test]
+    fn auto_detects_from_advertised_services() {
+        let resolve = |services: &[Uuid]| ProtocolKind::Auto.resolve(services, None).name();
+        assert_eq!(resolve(&[TRIONES_SERVICE_UUID]), "Triones");
+        assert_eq!(resolve(&[MAGIC_HOME_SERVICE_UUID]), "Magic Home");
+        assert_eq!(resolve(&[]), "Actuel");
+        assert_eq!(
+            ProtocolKind::Triones
+                .resolve(&[MAGIC_HOME_SERVICE_UUID], None)
+                .name(),
+            "Triones"
+        );
+    }
+
+    #[test]
+    fn connected_lights_are_told_apart_by_their_characteristics() {
+        let actuel = uuid_from_u16(0x1001);
+        let triones = uuid_from_u16(0xFFD9);
+        assert_eq!(
+            ProtocolKind::detect(&[uuid_from_u16(0x2A29), actuel]),
+            Some(ProtocolKind::Actuel)
+        );
+        assert_eq!(
+            ProtocolKind::detect(&[actuel, triones]),
+            Some(ProtocolKind::Triones)
+        );
+        assert_eq!(
+            ProtocolKind::detect(&[uuid_from_u16(0xFFE9)]),
+            Some(ProtocolKind::MagicHome)
+        );
+        assert_eq!(ProtocolKind::detect(&[uuid_from_u16(0x2A29)]), None);
+    }
+}
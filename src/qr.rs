@@ -0,0 +1,508 @@
+// QR codes for short texts such as a guest pass URL, printed to the terminal for a phone to
+// scan. Only what that needs: byte mode, error correction level M and versions 1 to 9, which
+// hold up to 180 bytes. Follows ISO/IEC 18004, as laid out in Project Nayuki's QR generator.
+
+// Error correction codewords per block and the number of blocks, by version, for level M
+const ECC_PER_BLOCK: [usize; 10] = [0, 10, 16, 26, 18, 24, 16, 18, 22, 22];
+const BLOCKS: [usize; 10] = [0, 1, 1, 1, 2, 2, 4, 4, 4, 5];
+const MAX_VERSION: usize = 9;
+// Level M's two bits in the format information
+const LEVEL_M: u32 = 0;
+// Light modules around the code, which scanners need to find it
+const QUIET_ZONE: usize = 4;
+
+/// A QR code, as dark and light modules.
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+    // Finder, timing and alignment patterns and format bits, which data and masks leave alone
+    reserved: Vec<bool>,
+}
+
+impl QrCode {
+    /// The smallest code holding `data`, or None when it is too long.
+    pub fn encode(data: &[u8]) -> Option<QrCode> {
+        let version = (1..=MAX_VERSION).find(|&version| data.len() <= capacity(version))?;
+        let size = version * 4 + 17;
+        let mut code = QrCode {
+            size,
+            modules: vec![false; size * size],
+            reserved: vec![false; size * size],
+        };
+        code.draw_function_patterns(version);
+        code.draw_codewords(&interleave(&codewords(data, version), version));
+        let mask = (0..8)
+            .min_by_key(|&mask| {
+                code.apply_mask(mask);
+                code.draw_format_bits(mask);
+                let penalty = code.penalty();
+                code.apply_mask(mask);
+                penalty
+            })
+            .unwrap_or(0);
+        code.apply_mask(mask);
+        code.draw_format_bits(mask);
+        Some(code)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        x < self.size && y < self.size && self.modules[y * self.size + x]
+    }
+
+    /// The code in block characters, two rows of modules to a line, drawing the light modules
+    /// so that it scans on the usual dark terminal.
+    pub fn to_terminal(&self) -> String {
+        let span = self.size + 2 * QUIET_ZONE;
+        let light = |x: usize, y: usize| {
+            !(x.checked_sub(QUIET_ZONE))
+                .zip(y.checked_sub(QUIET_ZONE))
+                .is_some_and(|(x, y)| self.is_dark(x, y))
+        };
+        let mut text = String::new();
+        for y in (0..span).step_by(2) {
+            for x in 0..span {
+                text.push(match (light(x, y), y + 1 < span && light(x, y + 1)) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                });
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    fn set(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.reserved[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set(6, i, i % 2 == 0);
+            self.set(i, 6, i % 2 == 0);
+        }
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            self.draw_finder(x, y);
+        }
+        let positions = alignment_positions(version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // Those corners hold the finder patterns
+                if ![(0, 0), (0, last), (last, 0)].contains(&(i, j)) {
+                    self.draw_alignment(x, y);
+                }
+            }
+        }
+        // Reserved for now; the mask decides the bits
+        self.draw_format_bits(0);
+        self.draw_version(version);
+    }
+
+    // The 7x7 square in a corner, with its light border inside the code
+    fn draw_finder(&mut self, x: usize, y: usize) {
+        for dy in -4..=4_isize {
+            for dx in -4..=4_isize {
+                let (xx, yy) = (x as isize + dx, y as isize + dy);
+                if (0..self.size as isize).contains(&xx) && (0..self.size as isize).contains(&yy) {
+                    let distance = dx.abs().max(dy.abs());
+                    self.set(xx as usize, yy as usize, distance != 2 && distance != 4);
+                }
+            }
+        }
+    }
+
+    fn draw_alignment(&mut self, x: usize, y: usize) {
+        for dy in -2..=2_isize {
+            for dx in -2..=2_isize {
+                let dark = dx.abs().max(dy.abs()) != 1;
+                self.set((x as isize + dx) as usize, (y as isize + dy) as usize, dark);
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, mask: u32) {
+        let bits = format_bits(mask);
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+        let size = self.size;
+        // Around the top left finder
+        for i in 0..6 {
+            self.set(8, i, bit(i));
+        }
+        self.set(8, 7, bit(6));
+        self.set(8, 8, bit(7));
+        self.set(7, 8, bit(8));
+        for i in 9..15 {
+            self.set(14 - i, 8, bit(i));
+        }
+        // And again next to the other two
+        for i in 0..8 {
+            self.set(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set(8, size - 15 + i, bit(i));
+        }
+        self.set(8, size - 8, true);
+    }
+
+    fn draw_version(&mut self, version: usize) {
+        if version < 7 {
+            return;
+        }
+        let bits = version_bits(version);
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let (a, b) = (self.size - 11 + i % 3, i / 3);
+            self.set(a, b, dark);
+            self.set(b, a, dark);
+        }
+    }
+
+    // Two columns at a time from the right, zigzagging up and down, around the patterns
+    fn draw_codewords(&mut self, data: &[u8]) {
+        let size = self.size;
+        let mut bit = 0;
+        let mut right = size - 1;
+        loop {
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for x in [right, right - 1] {
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward {
+                        size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+                    if !self.reserved[y * size + x] && bit < data.len() * 8 {
+                        self.modules[y * size + x] = (data[bit / 8] >> (7 - bit % 8)) & 1 != 0;
+                        bit += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    // Applying a mask twice takes it off again
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let flip = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let i = y * self.size + x;
+                self.modules[i] ^= flip && !self.reserved[i];
+            }
+        }
+    }
+
+    // How hard the code is to scan, by the standard's four rules: long runs, 2x2 blocks,
+    // patterns that look like finders and an uneven share of dark modules
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+        for transposed in [false, true] {
+            for a in 0..size {
+                let line: Vec<bool> = (0..size)
+                    .map(|b| {
+                        let (x, y) = if transposed { (a, b) } else { (b, a) };
+                        self.is_dark(x, y)
+                    })
+                    .collect();
+                penalty += line_penalty(&line);
+            }
+        }
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let dark = self.is_dark(x, y);
+                if [(x + 1, y), (x, y + 1), (x + 1, y + 1)]
+                    .iter()
+                    .all(|&(x, y)| self.is_dark(x, y) == dark)
+                {
+                    penalty += 3;
+                }
+            }
+        }
+        let total = size * size;
+        let dark = self.modules.iter().filter(|&&dark| dark).count();
+        let k = (dark * 20)
+            .abs_diff(total * 10)
+            .div_ceil(total)
+            .saturating_sub(1);
+        penalty + k * 10
+    }
+}
+
+// Runs of five or more, and dark-light-dark-dark-dark-light-dark at 1:1:3:1:1 with four times
+// as much light on one side, where the quiet zone counts as plenty
+fn line_penalty(line: &[bool]) -> usize {
+    let mut runs: Vec<(bool, usize)> = Vec::new();
+    for &dark in line {
+        match runs.last_mut() {
+            Some((color, length)) if *color == dark => *length += 1,
+            _ => runs.push((dark, 1)),
+        }
+    }
+    let mut penalty: usize = runs
+        .iter()
+        .map(|&(_, length)| if length >= 5 { length - 2 } else { 0 })
+        .sum();
+    // Light runs at either end go on into the quiet zone
+    let light = |i: Option<usize>| match i {
+        Some(i) if i == 0 || i == runs.len() - 1 => runs[i].1 + line.len(),
+        Some(i) => runs[i].1,
+        None => line.len(),
+    };
+    for (i, window) in runs.windows(5).enumerate() {
+        let n = window[0].1;
+        let finder = window[0].0
+            && window[1].1 == n
+            && window[2].1 == 3 * n
+            && window[3].1 == n
+            && window[4].1 == n;
+        if !finder {
+            continue;
+        }
+        let before = light(i.checked_sub(1));
+        let after = light(Some(i + 5).filter(|&i| i < runs.len()));
+        penalty += 40
+            * (usize::from(before >= 4 * n && after >= n)
+                + usize::from(after >= 4 * n && before >= n));
+    }
+    penalty
+}
+
+// The modules left for codewords once the patterns are drawn
+fn data_modules(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        modules -= (25 * alignments - 10) * alignments - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+    modules
+}
+
+fn data_codewords(version: usize) -> usize {
+    data_modules(version) / 8 - ECC_PER_BLOCK[version] * BLOCKS[version]
+}
+
+// In bytes, after the 4 bit mode and 8 bit length
+fn capacity(version: usize) -> usize {
+    (data_codewords(version) * 8 - 12) / 8
+}
+
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let size = version * 4 + 17;
+    let step = (version * 8 + count * 3 + 5) / (count * 4 - 4) * 2;
+    let mut positions: Vec<usize> = (0..count - 1).map(|i| size - 7 - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+// Mode, length, the bytes, a terminator and padding up to the version's data codewords
+fn codewords(data: &[u8], version: usize) -> Vec<u8> {
+    let capacity = data_codewords(version);
+    let mut bits: Vec<bool> = Vec::with_capacity(capacity * 8);
+    let mut push = |value: usize, count: usize| {
+        bits.extend((0..count).rev().map(|i| (value >> i) & 1 != 0));
+    };
+    push(0b0100, 4);
+    push(data.len(), 8);
+    for &byte in data {
+        push(byte as usize, 8);
+    }
+    let terminator = (capacity * 8 - bits.len()).min(4);
+    bits.extend(std::iter::repeat_n(false, terminator));
+    while !bits.len().is_multiple_of(8) {
+        bits.push(false);
+    }
+    let mut bytes: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| {
+            byte.iter()
+                .fold(0, |value, &bit| value << 1 | u8::from(bit))
+        })
+        .collect();
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if bytes.len() == capacity {
+            break;
+        }
+        bytes.push(pad);
+    }
+    bytes
+}
+
+// Splits the data into blocks, adds each one's error correction and takes the blocks a
+// codeword at a time; later blocks may hold one data codeword more than the first ones
+fn interleave(data: &[u8], version: usize) -> Vec<u8> {
+    let blocks = BLOCKS[version];
+    let ecc_length = ECC_PER_BLOCK[version];
+    let raw = data_modules(version) / 8;
+    let short_blocks = blocks - raw % blocks;
+    let short_length = raw / blocks;
+    let divisor = reed_solomon_divisor(ecc_length);
+    let mut split = Vec::with_capacity(blocks);
+    let mut start = 0;
+    for i in 0..blocks {
+        let length = short_length - ecc_length + usize::from(i >= short_blocks);
+        let block = &data[start..start + length];
+        start += length;
+        let ecc = reed_solomon_remainder(block, &divisor);
+        split.push((block, ecc));
+    }
+    let mut result = Vec::with_capacity(raw);
+    for i in 0..short_length - ecc_length + 1 {
+        result.extend(split.iter().filter_map(|(block, _)| block.get(i)));
+    }
+    for i in 0..ecc_length {
+        result.extend(split.iter().map(|(_, ecc)| ecc[i]));
+    }
+    result
+}
+
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0; degree];
+    result[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    result
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (value, &coefficient) in result.iter_mut().zip(divisor) {
+            *value ^= gf_multiply(coefficient, factor);
+        }
+    }
+    result
+}
+
+// In GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y as u32 >> i) & 1) * x as u32;
+    }
+    z as u8
+}
+
+// The level and mask with their BCH code, masked so they are never all light
+fn format_bits(mask: u32) -> u32 {
+    let data = LEVEL_M << 3 | mask;
+    let mut remainder = data;
+    for _ in 0..10 {
+        remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+    }
+    (data << 10 | remainder) ^ 0x5412
+}
+
+fn version_bits(version: usize) -> u32 {
+    let version = version as u32;
+    let mut remainder = version;
+    for _ in 0..12 {
+        remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1F25);
+    }
+    version << 12 | remainder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_correction_matches_the_standard() {
+        // The worked example in ISO/IEC 18004: "01234567" as version 1-M
+        let data = [
+            0x10, 0x20, 0x0C, 0x56, 0x61, 0x80, 0xEC, 0x11, 0xEC, 0x11, 0xEC, 0x11, 0xEC, 0x11,
+            0xEC, 0x11,
+        ];
+        assert_eq!(
+            reed_solomon_remainder(&data, &reed_solomon_divisor(10)),
+            [0xA5, 0x24, 0xD4, 0xC1, 0xED, 0x36, 0xC7, 0x87, 0x2C, 0x55]
+        );
+        assert_eq!(format_bits(0), 0b101010000010010);
+        assert_eq!(version_bits(7), 0b000111110010010100);
+        assert_eq!(alignment_positions(7), [6, 22, 38]);
+    }
+
+    #[test]
+    fn the_patterns_leave_the_data_modules_free() {
+        for version in 1..=MAX_VERSION {
+            let size = version * 4 + 17;
+            let mut code = QrCode {
+                size,
+                modules: vec![false; size * size],
+                reserved: vec![false; size * size],
+            };
+            code.draw_function_patterns(version);
+            let free = code.reserved.iter().filter(|&&reserved| !reserved).count();
+            assert_eq!(free, data_modules(version), "version {}", version);
+        }
+    }
+
+    #[test]
+    fn versions_grow_with_the_data() {
+        assert_eq!(capacity(1), 14);
+        assert_eq!(capacity(5), 84);
+        assert_eq!(capacity(9), 180);
+        let url = b"http://christmas-tree.local:8080/guest?token=0123456789abcdef0123456789abcdef";
+        assert_eq!(QrCode::encode(url).unwrap().size(), 37);
+        assert!(QrCode::encode(&[b'x'; 181]).is_none());
+    }
+
+    #[test]
+    fn codes_have_their_finder_patterns() {
+        let code = QrCode::encode(b"hello").unwrap();
+        let size = code.size();
+        assert_eq!(size, 21);
+        for (x, y) in [(0, 0), (size - 7, 0), (0, size - 7)] {
+            assert!((0..7).all(|i| code.is_dark(x + i, y) && code.is_dark(x, y + i)));
+            assert!((1..6).all(|i| !code.is_dark(x + i, y + 1)));
+            assert!(code.is_dark(x + 3, y + 3));
+        }
+        let lines = code.to_terminal();
+        assert_eq!(lines.lines().count(), (size + 2 * QUIET_ZONE).div_ceil(2));
+        assert!(lines
+            .lines()
+            .all(|line| line.chars().count() == size + 2 * QUIET_ZONE));
+    }
+}
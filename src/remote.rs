@@ -0,0 +1,235 @@
+use crate::{effects::EffectKind, metrics::format_time, triggers::Event};
+use std::{
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+// Live colors count as lost after this long without an update, as E1.31 does for its data
+const LIVE_COLORS_TIMEOUT: Duration = Duration::from_millis(2500);
+
+/// A request from a remote control integration, applied by the render loop.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RemoteCommand {
+    On,
+    Off,
+    // Switches to the solid effect showing this color
+    Color((u8, u8, u8)),
+    // 0 to 1, on top of the effect's own brightness
+    Brightness(f32),
+    Effect(EffectKind),
+    // An outdoor temperature reading in °C, shown by the temperature effect
+    OutdoorTemperature(f32),
+    // Someone came home, by the name they have in welcome.people
+    Arrived(String),
+    // Shows a configured or saved scene by name
+    Scene(String),
+    // Saves what the lights show now as a scene by this name
+    SaveScene(String),
+    // Switches to the palette effect drawing from this palette
+    Palette(String),
+    // Any of the commands that change the lights, reverted once this long has passed
+    Temporary(Box<RemoteCommand>, Duration),
+    // Something a trigger saw, for the rules to act on
+    Event(Event),
+    // Goes back to the show or brightness before the last change, by the command log
+    Undo,
+    // The phone's next alarm in Unix seconds, None once it is cleared, for the wake-up sunrise
+    NextAlarm(Option<i64>),
+}
+
+impl RemoteCommand {
+    /// Whether the command changes the lights, so it can be sent as a temporary override.
+    pub fn can_expire(&self) -> bool {
+        matches!(
+            self,
+            RemoteCommand::On
+                | RemoteCommand::Off
+                | RemoteCommand::Color(_)
+                | RemoteCommand::Brightness(_)
+                | RemoteCommand::Effect(_)
+                | RemoteCommand::Scene(_)
+                | RemoteCommand::Palette(_)
+        )
+    }
+
+    /// Whether the command switches the lights on or off, which goes ahead of other commands
+    /// and does not wait for the next frame.
+    pub fn is_power(&self) -> bool {
+        match self {
+            RemoteCommand::On | RemoteCommand::Off => true,
+            RemoteCommand::Temporary(command, _) => command.is_power(),
+            _ => false,
+        }
+    }
+
+    /// The command lasting `ttl`, or None when it cannot expire.
+    pub fn expiring_after(self, ttl: Duration) -> Option<RemoteCommand> {
+        self.can_expire()
+            .then(|| RemoteCommand::Temporary(Box::new(self), ttl))
+    }
+}
+
+impl fmt::Display for RemoteCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteCommand::On => write!(f, "on"),
+            RemoteCommand::Off => write!(f, "off"),
+            RemoteCommand::Color((r, g, b)) => write!(f, "color #{:02x}{:02x}{:02x}", r, g, b),
+            RemoteCommand::Brightness(level) => write!(f, "brightness {}", level),
+            RemoteCommand::Effect(effect) => write!(f, "effect {}", effect.name()),
+            RemoteCommand::OutdoorTemperature(celsius) => write!(f, "temperature {}", celsius),
+            RemoteCommand::Arrived(name) => write!(f, "arrived {}", name),
+            RemoteCommand::Scene(name) => write!(f, "scene {}", name),
+            RemoteCommand::SaveScene(name) => write!(f, "scene save {}", name),
+            RemoteCommand::Palette(name) => write!(f, "palette {}", name),
+            RemoteCommand::Temporary(command, ttl) => {
+                write!(f, "{} for {}s", command, ttl.as_secs())
+            }
+            RemoteCommand::Event(event) => write!(f, "event {}", event),
+            RemoteCommand::Undo => write!(f, "undo"),
+            RemoteCommand::NextAlarm(Some(at)) => write!(f, "alarm {}", format_time(*at)),
+            RemoteCommand::NextAlarm(None) => write!(f, "alarm none"),
+        }
+    }
+}
+
+/// Who asked for a command, for the command log.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    Cli,
+    // The client's address, for requests without a named token
+    Http(IpAddr),
+    // The name of the token from http.tokens
+    HttpToken(String),
+    Mqtt,
+    Schedule,
+    // A trigger reporting an event, by its name
+    Trigger(String),
+    // A rule acting on an event, by the rule's name
+    Rule(String),
+    // Someone with a guest pass
+    Guest,
+    // An action planned ahead through POST /agenda
+    Agenda,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Cli => write!(f, "cli"),
+            Source::Http(address) => write!(f, "http {}", address),
+            Source::HttpToken(name) => write!(f, "http token {}", name),
+            Source::Mqtt => write!(f, "mqtt"),
+            Source::Schedule => write!(f, "schedule"),
+            Source::Trigger(name) => write!(f, "trigger {}", name),
+            Source::Rule(name) => write!(f, "rule {}", name),
+            Source::Guest => write!(f, "guest"),
+            Source::Agenda => write!(f, "agenda"),
+        }
+    }
+}
+
+/// What the lights are showing, as reported back to remote control integrations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LightStatus {
+    pub on: bool,
+    pub effect: EffectKind,
+    // The solid effect's color
+    pub color: (u8, u8, u8),
+    pub brightness: f32,
+    // When the temporary override running now ends
+    pub override_until: Option<Instant>,
+}
+
+/// What read-only displays such as the status page show.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PublicStatus {
+    pub on: bool,
+    // What the lights show right now, before brightness
+    pub color: (u8, u8, u8),
+    // UTC timestamp of the next scheduled switch
+    pub next_change: Option<i64>,
+}
+
+/// Colors streamed in from outside, one per light, which stand in for the effect while they
+/// keep coming.
+#[derive(Clone, Default)]
+pub struct LiveColors(Arc<Mutex<Option<Snapshot>>>);
+
+// The latest colors and when they arrived
+type Snapshot = (Instant, Vec<(u8, u8, u8)>);
+
+impl LiveColors {
+    pub fn new() -> Self {
+        LiveColors::default()
+    }
+
+    /// The colors to show, unless the sender has gone quiet.
+    pub fn colors(&self) -> Option<Vec<(u8, u8, u8)>> {
+        let latest = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        latest
+            .as_ref()
+            .filter(|(at, _)| at.elapsed() < LIVE_COLORS_TIMEOUT)
+            .map(|(_, colors)| colors.clone())
+    }
+
+    /// New colors, or None to hand the lights back to the effect right away.
+    pub fn set(&self, colors: Option<Vec<(u8, u8, u8)>>) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) =
+            colors.map(|colors| (Instant::now(), colors));
+    }
+}
+
+// Compared without stopping at the first difference, so the time taken gives nothing away
+pub fn same_token(sent: &str, token: &str) -> bool {
+    sent.len() == token.len()
+        && sent
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+// Quotes and escapes a string for JSON, which YAML also accepts
+pub fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// Readers for the flat JSON objects that come in, such as a geocoder's results. They find the
+// first value under `key` anywhere in `object`, nested or not, and do not unescape strings.
+fn field<'a>(object: &'a str, key: &str) -> Option<&'a str> {
+    let (_, rest) = object.split_once(&format!("\"{}\"", key))?;
+    Some(rest.trim_start().strip_prefix(':')?.trim_start())
+}
+
+pub fn number_field(object: &str, key: &str) -> Option<f64> {
+    let value = field(object, key)?;
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')))
+        .unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+pub fn string_field<'a>(object: &'a str, key: &str) -> Option<&'a str> {
+    let value = field(object, key)?.strip_prefix('"')?;
+    value.split('"').next()
+}
+
+/// The object under `key`, braces included, for objects that nest no further.
+pub fn object_field<'a>(object: &'a str, key: &str) -> Option<&'a str> {
+    let value = field(object, key)?;
+    let end = value.strip_prefix('{').and(value.find('}'))?;
+    Some(&value[..=end])
+}
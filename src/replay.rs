@@ -0,0 +1,136 @@
+// A day's decisions worked out again from what was recorded, for `replay-decisions`, to make
+// sense of what the lights did after the fact. The schedule gives the on and off times from the
+// sun or the clock, the plan is made again from the forecast kept the day it was made, and the
+// command log tells which triggers, rules and remotes changed the lights that evening.
+use crate::{
+    history::LoggedCommand,
+    plan::{DailyPlan, Forecast},
+    schedule::Schedule,
+    timezone::Zone,
+};
+use chrono::NaiveDate;
+
+/// Something decided through the day, and why.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decision {
+    // UTC timestamp
+    pub at: i64,
+    pub what: String,
+    pub why: String,
+}
+
+/// The decisions for the evening of `date`, in time order, from the forecast the plan was made
+/// with and the command log.
+pub fn replay(
+    schedule: &Schedule,
+    date: NaiveDate,
+    forecast: Option<Forecast>,
+    logged: &[LoggedCommand],
+) -> Vec<Decision> {
+    let (on, off, day_length) = schedule.plan(date);
+    let (on_reason, off_reason) = schedule.reasons(date);
+    let mut decisions = vec![
+        Decision {
+            at: on,
+            what: "scheduled on".to_string(),
+            why: on_reason,
+        },
+        Decision {
+            at: off,
+            what: "scheduled off".to_string(),
+            why: off_reason,
+        },
+    ];
+    let plan = DailyPlan::new(date, (on, off, day_length), forecast);
+    decisions.extend(
+        plan.decisions(schedule.zone())
+            .into_iter()
+            .map(|(at, what, why)| Decision { at, what, why }),
+    );
+    // The whole day, and on into the next morning until the lights go off
+    let zone = schedule.zone();
+    let from = zone.at(date, (0, 0));
+    let until = off.max(zone.at(date.succ_opt().unwrap_or(date), (0, 0)));
+    decisions.extend(
+        logged
+            .iter()
+            .filter(|logged| (from..until).contains(&logged.at.timestamp()))
+            .map(|logged| Decision {
+                at: logged.at.timestamp(),
+                what: logged.command.clone(),
+                why: format!("from {}", logged.source),
+            }),
+    );
+    // Stable, so the schedule comes before the plan it led to
+    decisions.sort_by_key(|decision| decision.at);
+    decisions
+}
+
+/// One line per decision, with its time on the zone's clocks.
+pub fn format(decisions: &[Decision], zone: Zone) -> String {
+    decisions
+        .iter()
+        .map(|decision| {
+            let time = zone.local(decision.at).map_or_else(
+                || "??? ??:??".to_string(),
+                |time| time.format("%a %H:%M").to_string(),
+            );
+            format!("{}  {:<24} {}\n", time, decision.what, decision.why)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{schedule::Trigger, sun::SunSchedule};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn the_evening_replays_in_order() {
+        let schedule = Schedule::new(
+            SunSchedule {
+                location: (47.4979, 19.0402),
+                fallback_sunrise_utc: (7, 0),
+                fallback_sunset_utc: (15, 0),
+            },
+            Trigger::At((17, 0)),
+            Trigger::At((23, 0)),
+            Zone::Utc,
+        );
+        let date = NaiveDate::from_ymd_opt(2024, 12, 20).unwrap();
+        let logged = |hour, source: &str, command: &str| LoggedCommand {
+            at: Utc.with_ymd_and_hms(2024, 12, 20, hour, 0, 0).unwrap(),
+            source: source.to_string(),
+            command: command.to_string(),
+        };
+        let forecast = Forecast {
+            cloud_cover: 10.0,
+            precipitation: 0.0,
+            temperature_min: 3.0,
+        };
+        let decisions = replay(
+            &schedule,
+            date,
+            Some(forecast),
+            &[
+                logged(16, "http 192.168.1.20", "color #800080"),
+                logged(17, "schedule", "on"),
+                logged(19, "rule doorbell", "effect strobe"),
+            ],
+        );
+        assert_eq!(
+            format(&decisions, Zone::Utc),
+            "Fri 16:00  color #800080            from http 192.168.1.20\n\
+             Fri 17:00  scheduled on             17:00 on the clock\n\
+             Fri 17:00  lights on                the scheduled time \
+             (10% cloud, 0.0 mm, low of 3°C)\n\
+             Fri 17:00  brightness 85%           a clear evening\n\
+             Fri 17:00  on                       from schedule\n\
+             Fri 19:00  brightness 40%           the busy first third of the night is over\n\
+             Fri 19:00  effect strobe            from rule doorbell\n\
+             Fri 23:00  scheduled off            23:00 on the clock\n\
+             Fri 23:00  lights off               the scheduled time\n"
+        );
+    }
+}
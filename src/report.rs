@@ -0,0 +1,270 @@
+// The weekly reliability report, for spotting Bluetooth conditions getting worse over the
+// season: per light, how often it reconnected, how long it took no writes while the lights were
+// meant to be on and its average signal strength, and how much of the scheduled time the
+// lights were on, from the daily totals in storage. Tallied through the week and saved with the
+// devices, so restarts do not lose it, then logged and sent as a notification and started
+// afresh.
+use crate::{
+    devices::{Device, SAMPLE_INTERVAL},
+    metrics,
+    schedule::Schedule,
+    storage,
+};
+use chrono::{Local, NaiveDate};
+use log::warn;
+use std::sync::Mutex;
+
+// Where storage keeps the week so far
+pub const KEY: &str = "report";
+const REPORT_AFTER_DAYS: i64 = 7;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tally {
+    pub address: String,
+    pub reconnects: u32,
+    // While the lights were meant to be on
+    pub down_seconds: u64,
+    // Sum of the readings, in dBm
+    pub rssi_total: i64,
+    pub rssi_readings: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Week {
+    // Local date the tally started on
+    pub since: NaiveDate,
+    pub lights: Vec<Tally>,
+}
+
+impl Week {
+    fn new(since: NaiveDate) -> Self {
+        Week {
+            since,
+            lights: Vec::new(),
+        }
+    }
+
+    fn light(&mut self, address: &str) -> &mut Tally {
+        let index = match self
+            .lights
+            .iter()
+            .position(|light| light.address == address)
+        {
+            Some(index) => index,
+            None => {
+                self.lights.push(Tally {
+                    address: address.to_string(),
+                    ..Tally::default()
+                });
+                self.lights.len() - 1
+            }
+        };
+        &mut self.lights[index]
+    }
+
+    // A light that took no write in the last interval counts as down for all of it
+    fn sample(&mut self, meant_on: bool, devices: &[Device], now: i64) {
+        let interval = SAMPLE_INTERVAL.as_secs();
+        for device in devices {
+            let light = self.light(&device.address);
+            let writing = device
+                .last_write_ok
+                .is_some_and(|wrote| now - wrote < interval as i64);
+            if meant_on && !writing {
+                light.down_seconds += interval;
+            }
+            if let Some(&rssi) = device.rssi.back() {
+                light.rssi_total += i64::from(rssi);
+                light.rssi_readings += 1;
+            }
+        }
+    }
+
+    /// The report, given the seconds the lights were on and were scheduled to be on.
+    pub fn summary(&self, until: NaiveDate, (on_seconds, scheduled_seconds): (u64, u64)) -> String {
+        let adherence = match scheduled_seconds {
+            0 => "nothing scheduled".to_string(),
+            scheduled => format!(
+                "on {} of {} scheduled ({:.0}%)",
+                hours_minutes(on_seconds),
+                hours_minutes(scheduled),
+                on_seconds as f64 / scheduled as f64 * 100.0
+            ),
+        };
+        let mut summary = format!("Lights {} to {}: {}", self.since, until, adherence);
+        for light in &self.lights {
+            let rssi = match light.rssi_readings {
+                0 => "no signal readings".to_string(),
+                readings => format!("{} dBm", light.rssi_total / i64::from(readings)),
+            };
+            summary.push_str(&format!(
+                "; {}: {} reconnects, down {}, {}",
+                light.address,
+                light.reconnects,
+                hours_minutes(light.down_seconds),
+                rssi
+            ));
+        }
+        summary
+    }
+}
+
+static WEEK: Mutex<Option<Week>> = Mutex::new(None);
+
+// Runs `update` on the week so far, starting one today when there is none
+fn update(update: impl FnOnce(&mut Week)) {
+    let Ok(mut week) = WEEK.lock() else {
+        return;
+    };
+    update(week.get_or_insert_with(|| Week::new(Local::now().date_naive())));
+}
+
+/// Counts one reconnect of the light with `address`.
+pub fn reconnected(address: &str) {
+    update(|week| week.light(address).reconnects += 1);
+}
+
+/// Takes note of each light's state, once every devices::SAMPLE_INTERVAL while the daemon runs.
+pub fn sample(meant_on: bool, devices: &[Device], now: i64) {
+    update(|week| week.sample(meant_on, devices, now));
+}
+
+/// The report once the week is up, starting the next; None before then.
+pub fn weekly(schedule: &Schedule, today: NaiveDate) -> Option<String> {
+    let week = {
+        let mut week = WEEK.lock().ok()?;
+        let due = week
+            .as_ref()
+            .is_some_and(|week| (today - week.since).num_days() >= REPORT_AFTER_DAYS);
+        if !due {
+            return None;
+        }
+        week.replace(Week::new(today))?
+    };
+    let on_seconds = metrics::days(None, None)
+        .iter()
+        .filter(|day| week.since <= day.date && day.date < today)
+        .map(|day| day.on_seconds)
+        .sum();
+    let scheduled_seconds = week
+        .since
+        .iter_days()
+        .take_while(|date| *date < today)
+        .map(|date| {
+            let (on, off, _) = schedule.plan(date);
+            (off - on).max(0) as u64
+        })
+        .sum();
+    let until = today.pred_opt().unwrap_or(today);
+    Some(week.summary(until, (on_seconds, scheduled_seconds)))
+}
+
+/// Picks up the week so far from earlier runs.
+pub fn load() {
+    let contents = match storage::current().read(KEY) {
+        Ok(contents) => contents,
+        Err(e) => {
+            warn!("Cannot read the weekly report: {}", e);
+            return;
+        }
+    };
+    if let Some(saved) = contents.and_then(|contents| parse(&String::from_utf8_lossy(&contents))) {
+        if let Ok(mut week) = WEEK.lock() {
+            *week = Some(saved);
+        }
+    }
+}
+
+pub fn save() {
+    let formatted = match WEEK.lock() {
+        Ok(week) => match week.as_ref() {
+            Some(week) => format(week),
+            None => return,
+        },
+        Err(_) => return,
+    };
+    if let Err(e) = storage::current().write(KEY, formatted.as_bytes()) {
+        warn!("Cannot save the weekly report: {}", e);
+    }
+}
+
+fn hours_minutes(seconds: u64) -> String {
+    format!("{}h {}m", seconds / 3600, seconds % 3600 / 60)
+}
+
+// A "since <date>" line, then one "address reconnects down_seconds rssi_total rssi_readings"
+// line per light
+fn format(week: &Week) -> String {
+    let mut formatted = format!("since {}\n", week.since);
+    for light in &week.lights {
+        formatted.push_str(&format!(
+            "{} {} {} {} {}\n",
+            light.address,
+            light.reconnects,
+            light.down_seconds,
+            light.rssi_total,
+            light.rssi_readings
+        ));
+    }
+    formatted
+}
+
+fn parse(contents: &str) -> Option<Week> {
+    let mut lines = contents.lines();
+    let since =
+        NaiveDate::parse_from_str(lines.next()?.strip_prefix("since ")?.trim(), "%Y-%m-%d").ok()?;
+    let lights = lines
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some(Tally {
+                address: fields.next()?.to_string(),
+                reconnects: fields.next()?.parse().ok()?,
+                down_seconds: fields.next()?.parse().ok()?,
+                rssi_total: fields.next()?.parse().ok()?,
+                rssi_readings: fields.next()?.parse().ok()?,
+            })
+        })
+        .collect();
+    Some(Week { since, lights })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 12, day).unwrap()
+    }
+
+    #[test]
+    fn the_week_reads_back_and_sums_up() {
+        let mut week = Week::new(date(1));
+        let devices = [
+            Device {
+                address: "A4:C1:38:12:34:56".to_string(),
+                last_write_ok: Some(1_000),
+                rssi: VecDeque::from([-80, -70]),
+                ..Device::default()
+            },
+            Device {
+                address: "A4:C1:38:65:43:21".to_string(),
+                last_write_ok: Some(500),
+                ..Device::default()
+            },
+        ];
+        week.sample(true, &devices, 1_030);
+        week.sample(false, &devices, 1_090);
+        week.sample(true, &devices, 1_090);
+        week.light("A4:C1:38:65:43:21").reconnects = 2;
+        assert_eq!(parse(&format(&week)), Some(week.clone()));
+        assert_eq!(parse("A4:C1:38:12:34:56 0 0 0 0"), None);
+
+        assert_eq!(
+            week.summary(date(7), (9 * 3600, 10 * 3600)),
+            "Lights 2024-12-01 to 2024-12-07: on 9h 0m of 10h 0m scheduled (90%); \
+             A4:C1:38:12:34:56: 0 reconnects, down 0h 1m, -70 dBm; \
+             A4:C1:38:65:43:21: 2 reconnects, down 0h 2m, no signal readings"
+        );
+    }
+}
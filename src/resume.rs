@@ -0,0 +1,169 @@
+// Picks the show up where it left off after a crash or reboot: what the lights were showing,
+// at what brightness and how far into the animation, and whether they were switched off by
+// hand. Like remote changes, the show only carries over within the same day, and a switch-off
+// by hand only until the schedule next switches the lights off.
+use crate::{
+    color,
+    config::{Config, SceneConfig},
+    effects::EffectKind,
+    scenes,
+    schedule::Schedule,
+    storage,
+};
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use log::warn;
+use std::time::Duration;
+
+// Where storage keeps the last state
+pub const KEY: &str = "resume";
+
+/// The state of the show as last saved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Resume {
+    // The effect, its color and speed, and the brightness on top of brightness.level
+    pub show: SceneConfig,
+    pub held_off: bool,
+    // Animation time into the effect
+    pub position: Duration,
+    pub saved_at: DateTime<Utc>,
+}
+
+impl Resume {
+    pub fn capture(config: &Config, brightness: f32, held_off: bool, position: Duration) -> Self {
+        Resume {
+            show: scenes::capture(KEY, config, brightness),
+            held_off,
+            position,
+            saved_at: Utc::now(),
+        }
+    }
+
+    /// Whether the show was saved on `today`, local time, so it still stands.
+    pub fn is_from(&self, today: NaiveDate) -> bool {
+        self.saved_at.with_timezone(&Local).date_naive() == today
+    }
+
+    /// Whether a switch-off by hand still holds at `now`, as no scheduled switch-off came since.
+    pub fn is_held_off(&self, schedule: &Schedule, now: DateTime<Utc>) -> bool {
+        self.held_off
+            && schedule
+                .next_change(self.saved_at, true)
+                .is_some_and(|off| off > now.timestamp())
+    }
+
+    /// Whether `other` shows the same as this, wherever the animation is.
+    pub fn same_show(&self, other: &Resume) -> bool {
+        self.show == other.show && self.held_off == other.held_off
+    }
+}
+
+/// The state saved by the last run, if any.
+pub fn load() -> Option<Resume> {
+    let contents = match storage::current().read(KEY) {
+        Ok(contents) => contents?,
+        Err(e) => {
+            warn!("Cannot read the last state: {}", e);
+            return None;
+        }
+    };
+    let resume = parse(&String::from_utf8_lossy(&contents));
+    if resume.is_none() {
+        warn!("Ignoring the last state, it is damaged");
+    }
+    resume
+}
+
+pub fn save(resume: &Resume) {
+    if let Err(e) = storage::current().write(KEY, format(resume).as_bytes()) {
+        warn!("Cannot save the current state: {}", e);
+    }
+}
+
+// One "key value" line each, like the daily totals
+fn format(resume: &Resume) -> String {
+    let color = resume
+        .show
+        .color
+        .map(|(r, g, b)| format!("color #{:02x}{:02x}{:02x}\n", r, g, b))
+        .unwrap_or_default();
+    format!(
+        "effect {}\n{}brightness {:?}\nspeed {:?}\nheld_off {}\nposition_ms {}\nsaved_at {}\n",
+        resume.show.effect.name(),
+        color,
+        resume.show.brightness,
+        resume.show.speed,
+        resume.held_off,
+        resume.position.as_millis(),
+        resume.saved_at.timestamp()
+    )
+}
+
+fn parse(contents: &str) -> Option<Resume> {
+    let field = |key: &str| {
+        contents
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix(' '))
+            .map(str::trim)
+    };
+    Some(Resume {
+        show: SceneConfig {
+            name: KEY.to_string(),
+            effect: EffectKind::from_name(field("effect")?)?,
+            color: match field("color") {
+                Some(hex) => Some(color::parse_hex(hex)?),
+                None => None,
+            },
+            brightness: field("brightness")?.parse().ok()?,
+            speed: field("speed")?.parse().ok()?,
+        },
+        held_off: field("held_off")?.parse().ok()?,
+        position: Duration::from_millis(field("position_ms")?.parse().ok()?),
+        saved_at: Utc
+            .timestamp_opt(field("saved_at")?.parse().ok()?, 0)
+            .single()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resume() -> Resume {
+        Resume {
+            show: SceneConfig {
+                name: KEY.to_string(),
+                effect: EffectKind::Solid,
+                color: Some((255, 140, 40)),
+                brightness: 0.6,
+                speed: 1.5,
+            },
+            held_off: true,
+            position: Duration::from_millis(93_250),
+            saved_at: Utc.timestamp_opt(1_733_000_000, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn saved_state_reads_back() {
+        assert_eq!(parse(&format(&resume())), Some(resume()));
+        let mut rainbow = resume();
+        rainbow.show.effect = EffectKind::Rainbow;
+        rainbow.show.color = None;
+        assert_eq!(parse(&format(&rainbow)), Some(rainbow));
+    }
+
+    #[test]
+    fn damaged_state_is_ignored() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse(&format(&resume()).replace("solid", "disco")), None);
+    }
+
+    #[test]
+    fn a_moved_animation_is_the_same_show() {
+        let mut later = resume();
+        later.position += Duration::from_secs(60);
+        assert!(resume().same_show(&later));
+        later.show.brightness = 0.2;
+        assert!(!resume().same_show(&later));
+    }
+}
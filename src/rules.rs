@@ -0,0 +1,414 @@
+// Rules from the rules file next to the config: which events from triggers.rs change the
+// lights, and under what conditions. E.g.
+//
+//   [doorbell]
+//   on = "gpio/doorbell"
+//   value = "1"
+//   between = ["sunset", "23:00"]
+//   during_override = false
+//   debounce_ms = 200
+//   cooldown_seconds = 60
+//   effect = "strobe"
+//   for_seconds = 30
+//
+// flashes the lights for half a minute when the doorbell button goes down in the evening,
+// unless someone set the lights by hand for a while, and at most once a minute however often
+// the button is pressed. The command dispatcher in the render loop hands each event here and
+// applies the commands that come back as it does any other, logged under the rule's name.
+// A color or brightness may also be worked out from the event's value, see template.rs.
+use crate::{
+    config::{RuleAction, RuleConfig, TimeOfDay},
+    remote::{LightStatus, RemoteCommand},
+    schedule::Schedule,
+    template,
+    triggers::Event,
+};
+use chrono::{TimeZone, Timelike, Utc};
+use log::warn;
+use std::time::Instant;
+
+pub struct Rules {
+    rules: Vec<Rule>,
+    // For the time windows, on its clocks and with its sun
+    schedule: Schedule,
+}
+
+// A rule and when it last saw its event and last acted
+struct Rule {
+    config: RuleConfig,
+    seen_at: Option<Instant>,
+    fired_at: Option<Instant>,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<RuleConfig>, schedule: Schedule) -> Self {
+        let rules = rules
+            .into_iter()
+            .map(|config| Rule {
+                config,
+                seen_at: None,
+                fired_at: None,
+            })
+            .collect();
+        Rules { rules, schedule }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The commands the rules send for `event`, in the file's order, each with the name of
+    /// its rule. The conditions look at `status`, what the lights show, and at `now_utc`, a UTC
+    /// timestamp; debouncing and cool-downs go by `now`.
+    pub fn fire(
+        &mut self,
+        event: &Event,
+        status: &LightStatus,
+        now_utc: i64,
+        now: Instant,
+    ) -> Vec<(String, RemoteCommand)> {
+        let schedule = self.schedule;
+        let minute = minute_of_day(schedule, now_utc);
+        let mut fired = Vec::new();
+        for rule in &mut self.rules {
+            let config = &rule.config;
+            if !listens_for(config, event)
+                || config
+                    .value
+                    .as_ref()
+                    .is_some_and(|value| *value != event.value)
+            {
+                continue;
+            }
+            let bounced = rule
+                .seen_at
+                .is_some_and(|at| now.duration_since(at) < config.debounce);
+            rule.seen_at = Some(now);
+            let cooling_down = rule
+                .fired_at
+                .is_some_and(|at| now.duration_since(at) < config.cooldown);
+            let holds = config.lights.is_none_or(|on| on == status.on)
+                && (config.effects.is_empty() || config.effects.contains(&status.effect))
+                && (config.during_override || status.override_until.is_none())
+                && config.between.is_none_or(|(from, to)| {
+                    within(
+                        minute,
+                        schedule_minute(schedule, from, now_utc),
+                        schedule_minute(schedule, to, now_utc),
+                    )
+                });
+            if bounced || cooling_down || !holds {
+                continue;
+            }
+            let Some(command) = command(config, &event.value) else {
+                warn!(
+                    "Rule {} cannot work out its template from {:?}",
+                    config.name, event.value
+                );
+                continue;
+            };
+            rule.fired_at = Some(now);
+            fired.push((config.name.clone(), command));
+        }
+        fired
+    }
+}
+
+// Minutes since midnight on the schedule's clocks
+fn minute_of_day(schedule: Schedule, timestamp: i64) -> u32 {
+    schedule
+        .zone()
+        .local(timestamp)
+        .map_or(0, |local| local.hour() * 60 + local.minute())
+}
+
+// When the clocks read `time` on the day of `now`, in minutes since midnight
+fn schedule_minute(schedule: Schedule, time: TimeOfDay, now: i64) -> u32 {
+    let sun = || {
+        let today = Utc.timestamp_opt(now, 0).single().unwrap_or_default();
+        schedule.sun().sunrise_sunset(today)
+    };
+    match time {
+        TimeOfDay::At((hour, minute)) => hour * 60 + minute,
+        TimeOfDay::Sunrise(offset) => minute_of_day(schedule, sun().0 + offset * 60),
+        TimeOfDay::Sunset(offset) => minute_of_day(schedule, sun().1 + offset * 60),
+    }
+}
+
+// The rule's command for an event carrying `value`, lasting as long as the rule says
+fn command(rule: &RuleConfig, value: &str) -> Option<RemoteCommand> {
+    let command = match &rule.action {
+        RuleAction::Command(command) => command.clone(),
+        RuleAction::Color(template) => match template.render(value)? {
+            template::Value::Color(rgb) => RemoteCommand::Color(rgb),
+            template::Value::Number(_) => return None,
+        },
+        RuleAction::Brightness(template) => match template.render(value)? {
+            template::Value::Number(level) => {
+                RemoteCommand::Brightness(level.clamp(0.0, 1.0) as f32)
+            }
+            template::Value::Color(_) => return None,
+        },
+    };
+    match rule.duration {
+        Some(duration) => command.expiring_after(duration),
+        None => Some(command),
+    }
+}
+
+fn listens_for(rule: &RuleConfig, event: &Event) -> bool {
+    match rule.on.split_once('/') {
+        Some((trigger, name)) => trigger == event.trigger && name == event.name,
+        None => rule.on == event.trigger,
+    }
+}
+
+// Whether the minute of the day is in the window, which runs over midnight when it ends
+// before it starts
+fn within(minute: u32, from: u32, to: u32) -> bool {
+    if from <= to {
+        (from..=to).contains(&minute)
+    } else {
+        minute >= from || minute <= to
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config, effects::EffectKind, schedule::Trigger, sun::SunSchedule, timezone::Zone};
+    use std::time::Duration;
+
+    const RULES: &str = r##"
+        [doorbell]
+        on = "gpio/doorbell"
+        value = "1"
+        between = ["16:00", "01:00"]
+        lights = "on"
+        effect = "strobe"
+        for_seconds = 30
+
+        [cold]
+        on = "weather"
+        color = "#0000ff"
+    "##;
+
+    fn rules(contents: &str) -> Rules {
+        let sun = SunSchedule {
+            location: (47.5, 19.0),
+            fallback_sunrise_utc: (7, 0),
+            fallback_sunset_utc: (15, 0),
+        };
+        let schedule = Schedule::new(
+            sun,
+            Trigger::Sun { offset_minutes: 0 },
+            Trigger::At((23, 0)),
+            Zone::Utc,
+        );
+        Rules::new(config::rules_from_toml(contents).unwrap(), schedule)
+    }
+
+    fn at(hour: u32, minute: u32) -> i64 {
+        Utc.with_ymd_and_hms(2024, 12, 1, hour, minute, 0)
+            .unwrap()
+            .timestamp()
+    }
+
+    fn status(on: bool) -> LightStatus {
+        LightStatus {
+            on,
+            effect: EffectKind::Rainbow,
+            color: (255, 0, 0),
+            brightness: 1.0,
+            override_until: None,
+        }
+    }
+
+    #[test]
+    fn rules_are_read_with_their_action() {
+        let rules = config::rules_from_toml(RULES).unwrap();
+        assert_eq!(rules.len(), 2);
+        let doorbell = rules.iter().find(|rule| rule.name == "doorbell").unwrap();
+        assert_eq!(
+            doorbell.between,
+            Some((TimeOfDay::At((16, 0)), TimeOfDay::At((1, 0))))
+        );
+        assert_eq!(doorbell.lights, Some(true));
+        assert_eq!(
+            doorbell.action,
+            RuleAction::Command(RemoteCommand::Effect(EffectKind::Strobe))
+        );
+        assert_eq!(doorbell.duration, Some(Duration::from_secs(30)));
+        let rules = config::rules_from_toml(
+            "[a]\non = \"gpio\"\nbetween = [\"sunset+30\", \"sunrise\"]\npower = \"off\"",
+        )
+        .unwrap();
+        assert_eq!(
+            rules[0].between,
+            Some((TimeOfDay::Sunset(30), TimeOfDay::Sunrise(0)))
+        );
+    }
+
+    #[test]
+    fn rules_without_exactly_one_action_are_rejected() {
+        for rule in [
+            "[a]\non = \"gpio\"",
+            "[a]\non = \"gpio\"\ncolor = \"#ff0000\"\neffect = \"solid\"",
+            "[a]\ncolor = \"#ff0000\"",
+            "[a]\non = \"gpio\"\ncolor = \"#ff0000\"\ncooldown = 5",
+            "[a]\non = \"gpio\"\ncolor = \"#ff0000\"\nbetween = [\"25:00\", \"sunset\"]",
+            "[a]\non = \"gpio\"\ncolor = \"#ff0000\"\nbetween = [\"sunset30\", \"sunrise\"]",
+            "[a]\non = \"mqtt\"\ncolor = \"{{ value * 2 }}\"",
+            "[a]\non = \"mqtt\"\nbrightness = \"{{ rgb(value, 0, 0) }}\"",
+            "[a]\non = \"mqtt\"\nbrightness = \"{{ value + }}\"",
+        ] {
+            assert!(config::rules_from_toml(rule).is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn events_fire_the_rules_that_listen_for_them() {
+        let mut rules = rules(RULES);
+        let now = Instant::now();
+        let doorbell = Event::new("gpio", "doorbell", "1");
+        assert_eq!(
+            rules.fire(&doorbell, &status(true), at(18, 0), now),
+            [(
+                "doorbell".to_string(),
+                RemoteCommand::Temporary(
+                    Box::new(RemoteCommand::Effect(EffectKind::Strobe)),
+                    Duration::from_secs(30)
+                )
+            )]
+        );
+        let gate = Event::new("gpio", "gate", "1");
+        assert_eq!(rules.fire(&gate, &status(true), at(18, 0), now), []);
+        let cold = Event::new("weather", "temperature", "-3");
+        assert_eq!(
+            rules.fire(&cold, &status(false), at(12, 0), now),
+            [("cold".to_string(), RemoteCommand::Color((0, 0, 255)))]
+        );
+    }
+
+    #[test]
+    fn conditions_hold_rules_back() {
+        let mut rules = rules(RULES);
+        let now = Instant::now();
+        let pressed = Event::new("gpio", "doorbell", "1");
+        let released = Event::new("gpio", "doorbell", "0");
+        assert_eq!(rules.fire(&released, &status(true), at(18, 0), now), []);
+        assert_eq!(rules.fire(&pressed, &status(false), at(18, 0), now), []);
+        // The window runs over midnight
+        assert_eq!(rules.fire(&pressed, &status(true), at(0, 30), now).len(), 1);
+        assert_eq!(rules.fire(&pressed, &status(true), at(12, 0), now), []);
+    }
+
+    #[test]
+    fn the_effect_and_overrides_are_conditions_too() {
+        let mut rules = rules(
+            "[a]\non = \"gpio\"\neffects = [\"twinkle\"]\nduring_override = false\ncolor = \"#ff0000\"",
+        );
+        let event = Event::new("gpio", "button", "1");
+        let now = Instant::now();
+        let mut twinkling = status(true);
+        assert_eq!(rules.fire(&event, &twinkling, at(18, 0), now), []);
+        twinkling.effect = EffectKind::Twinkle;
+        twinkling.override_until = Some(now + Duration::from_secs(60));
+        assert_eq!(rules.fire(&event, &twinkling, at(18, 0), now), []);
+        twinkling.override_until = None;
+        assert_eq!(rules.fire(&event, &twinkling, at(18, 0), now).len(), 1);
+    }
+
+    #[test]
+    fn windows_follow_the_sun() {
+        let mut rules =
+            rules("[a]\non = \"gpio\"\nbetween = [\"sunset\", \"sunset+60\"]\npower = \"on\"");
+        let event = Event::new("gpio", "button", "1");
+        let now = Instant::now();
+        // Sunset in Budapest on December 1st is at about 14:55 UTC
+        assert_eq!(rules.fire(&event, &status(false), at(14, 0), now), []);
+        assert_eq!(rules.fire(&event, &status(false), at(15, 30), now).len(), 1);
+        assert_eq!(
+            rules.fire(
+                &event,
+                &status(false),
+                at(16, 30),
+                now + Duration::from_secs(1)
+            ),
+            []
+        );
+    }
+
+    #[test]
+    fn templates_work_colors_and_brightness_out_from_the_event() {
+        let mut rules = rules(
+            r##"
+            [outside]
+            on = "weather/temperature"
+            color = "{{ gradient(value, -10, 30, #0000ff, #ff0000) }}"
+            for_seconds = 60
+
+            [light]
+            on = "mqtt/lux"
+            brightness = "{{ 1 - value.lux / 1000 }}"
+            "##,
+        );
+        let now = Instant::now();
+        let cold = Event::new("weather", "temperature", "-15");
+        assert_eq!(
+            rules.fire(&cold, &status(true), at(18, 0), now),
+            [(
+                "outside".to_string(),
+                RemoteCommand::Temporary(
+                    Box::new(RemoteCommand::Color((0, 0, 255))),
+                    Duration::from_secs(60)
+                )
+            )]
+        );
+        let dark = Event::new("mqtt", "lux", r#"{"lux": 250}"#);
+        assert_eq!(
+            rules.fire(&dark, &status(true), at(18, 0), now),
+            [("light".to_string(), RemoteCommand::Brightness(0.75))]
+        );
+        // A payload without the number skips the rule, and brightness stays between 0 and 1
+        let broken = Event::new("mqtt", "lux", "unavailable");
+        assert_eq!(rules.fire(&broken, &status(true), at(18, 0), now), []);
+        let glare = Event::new("mqtt", "lux", r#"{"lux": 5000}"#);
+        assert_eq!(
+            rules.fire(&glare, &status(true), at(18, 0), now),
+            [("light".to_string(), RemoteCommand::Brightness(0.0))]
+        );
+    }
+
+    #[test]
+    fn bounces_and_repeats_within_the_cool_down_are_ignored() {
+        let mut rules = rules(
+            "[a]\non = \"gpio\"\ndebounce_ms = 200\ncooldown_seconds = 60\ncolor = \"#ff0000\"",
+        );
+        let event = Event::new("gpio", "button", "1");
+        let start = Instant::now();
+        let fire = |rules: &mut Rules, after: u64| {
+            rules
+                .fire(
+                    &event,
+                    &status(true),
+                    at(18, 0),
+                    start + Duration::from_millis(after),
+                )
+                .len()
+        };
+        assert_eq!(fire(&mut rules, 0), 1);
+        // A bounce, then a second press while the rule cools down
+        assert_eq!(fire(&mut rules, 50), 0);
+        assert_eq!(fire(&mut rules, 5_000), 0);
+        assert_eq!(fire(&mut rules, 61_000), 1);
+        // Bounces keep the debounce window open
+        assert_eq!(fire(&mut rules, 122_000), 1);
+        assert_eq!(fire(&mut rules, 122_150), 0);
+        assert_eq!(fire(&mut rules, 122_300), 0);
+    }
+}
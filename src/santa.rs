@@ -0,0 +1,50 @@
+// A stand-in for the Santa trackers: Santa sets off from the North Pole and follows local
+// midnight westwards around the globe at the home latitude, starting at the date line at
+// 12:00 UTC on December 24th and finishing there at 12:00 UTC on the 25th.
+use chrono::{DateTime, Datelike, Timelike, Utc};
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+/// The farthest Santa can be, half way around the world.
+pub const MAX_DISTANCE_KM: f64 = EARTH_RADIUS_KM * std::f64::consts::PI;
+const NORTH_POLE: (f64, f64) = (90.0, 0.0);
+const DEPARTURE_HOUR: f64 = 12.0;
+const FLIGHT_HOURS: f64 = 24.0;
+
+/// Santa's latitude and longitude, or None outside the tracking window from midnight UTC on
+/// December 24th until he is done.
+pub fn position(home: (f64, f64), now: DateTime<Utc>) -> Option<(f64, f64)> {
+    let hours = hours_since_christmas_eve(now)?;
+    if hours < DEPARTURE_HOUR {
+        return Some(NORTH_POLE);
+    }
+    let flown = (hours - DEPARTURE_HOUR) / FLIGHT_HOURS;
+    if flown >= 1.0 {
+        return None;
+    }
+    // The date line is the first place to reach midnight, 15 degrees further west every hour
+    let longitude = 180.0 - flown * 360.0;
+    Some((home.0, longitude))
+}
+
+/// How far Santa is from home in kilometres, while he is being tracked.
+pub fn distance_km(home: (f64, f64), now: DateTime<Utc>) -> Option<f64> {
+    position(home, now).map(|santa| great_circle_km(home, santa))
+}
+
+fn hours_since_christmas_eve(now: DateTime<Utc>) -> Option<f64> {
+    let hours = now.hour() as f64 + now.minute() as f64 / 60.0 + now.second() as f64 / 3600.0;
+    match (now.month(), now.day()) {
+        (12, 24) => Some(hours),
+        (12, 25) => Some(24.0 + hours),
+        _ => None,
+    }
+}
+
+// Haversine distance between two latitude/longitude pairs in degrees
+fn great_circle_km((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
@@ -0,0 +1,51 @@
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkQuality {
+    // Not connected, so there is no stream to protect and scanning helps rediscover the light
+    Down,
+    // Writes are failing; scanning would only make it worse
+    Degraded,
+    Healthy,
+}
+
+// Decides when the adapter may scan, since scanning on cheap adapters steals airtime from
+// the connection. While the link is healthy it scans for `window` once every `interval`.
+pub struct ScanCoordinator {
+    interval: Duration,
+    window: Duration,
+    next_window_at: Instant,
+    scanning: bool,
+}
+
+impl ScanCoordinator {
+    // The adapter is left scanning after discovery, so start out assuming it is
+    pub fn new(interval: Duration, window: Duration, now: Instant) -> Self {
+        ScanCoordinator {
+            interval,
+            window,
+            next_window_at: now + interval,
+            scanning: true,
+        }
+    }
+
+    // Returns whether to start (Some(true)) or stop (Some(false)) scanning, if that changes
+    pub fn update(&mut self, now: Instant, link: LinkQuality) -> Option<bool> {
+        let wanted = match link {
+            LinkQuality::Down => true,
+            LinkQuality::Degraded => false,
+            LinkQuality::Healthy if self.interval.is_zero() || self.window.is_zero() => false,
+            LinkQuality::Healthy => {
+                if now >= self.next_window_at + self.window {
+                    self.next_window_at = now + self.interval;
+                }
+                now >= self.next_window_at
+            }
+        };
+        if wanted == self.scanning {
+            return None;
+        }
+        self.scanning = wanted;
+        Some(wanted)
+    }
+}
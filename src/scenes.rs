@@ -0,0 +1,185 @@
+// Named scenes: an effect with its color, brightness and speed to switch to by hand. Scenes
+// come from the config or are saved at runtime into storage, where a saved scene replaces a
+// configured one of the same name.
+use crate::{
+    config::{self, Config, SceneConfig},
+    effects::EffectKind,
+    error::Failure,
+    storage,
+};
+use log::warn;
+
+// Where storage keeps saved scenes, also their name in a backup
+pub const KEY: &str = "scenes.toml";
+
+/// Scenes saved at runtime.
+pub fn saved() -> Vec<SceneConfig> {
+    let contents = match storage::current().read(KEY) {
+        Ok(Some(contents)) => contents,
+        Ok(None) => return Vec::new(),
+        Err(e) => {
+            warn!("Cannot read saved scenes: {}", e);
+            return Vec::new();
+        }
+    };
+    config::scenes_from_toml(&String::from_utf8_lossy(&contents)).unwrap_or_else(|e| {
+        warn!("Ignoring saved scenes: {}", e);
+        Vec::new()
+    })
+}
+
+/// Every scene, configured and saved, ordered by name.
+pub fn all(config: &Config) -> Vec<SceneConfig> {
+    let saved = saved();
+    let mut scenes: Vec<SceneConfig> = config
+        .scenes
+        .iter()
+        .filter(|scene| !saved.iter().any(|other| other.name == scene.name))
+        .cloned()
+        .collect();
+    scenes.extend(saved);
+    scenes.sort_by(|a, b| a.name.cmp(&b.name));
+    scenes
+}
+
+pub fn find(name: &str, config: &Config) -> Option<SceneConfig> {
+    all(config).into_iter().find(|scene| scene.name == name)
+}
+
+/// What the lights show under `config` at `brightness`, as a scene called `name`.
+pub fn capture(name: &str, config: &Config, brightness: f32) -> SceneConfig {
+    let color = match config.effect {
+        EffectKind::Solid => Some(config.solid.color),
+        EffectKind::Breathing => Some(config.breathing.color),
+        EffectKind::Twinkle => Some(config.twinkle.color),
+        EffectKind::Strobe => Some(config.strobe.color),
+        _ => None,
+    };
+    SceneConfig {
+        name: name.to_string(),
+        effect: config.effect,
+        color,
+        brightness,
+        speed: config.animation.speed,
+    }
+}
+
+/// `config` with the scene's effect, color and speed in place of the configured ones. The
+/// scene's brightness is left to the caller, as it applies on top of brightness.level.
+pub fn apply(scene: &SceneConfig, config: &Config) -> Config {
+    let mut applied = config.clone();
+    applied.effect = scene.effect;
+    applied.animation.speed = scene.speed;
+    if let Some(color) = scene.color {
+        match scene.effect {
+            EffectKind::Solid => applied.solid.color = color,
+            EffectKind::Breathing => applied.breathing.color = color,
+            EffectKind::Twinkle => applied.twinkle.color = color,
+            EffectKind::Strobe => applied.strobe.color = color,
+            _ => {}
+        }
+    }
+    applied
+}
+
+/// Checks that a scene can be saved and shown under `config`.
+pub fn check(scene: &SceneConfig, config: &Config) -> Result<(), Failure> {
+    let invalid = |reason: &str| Failure::ConfigInvalid(reason.to_string());
+    // Saved scenes become table names, so only bare TOML keys will do
+    if scene.name.is_empty()
+        || !scene
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(invalid(
+            "scene names may only hold letters, digits, - and _",
+        ));
+    }
+    if !(0.0..=1.0).contains(&scene.brightness) {
+        return Err(invalid("scene brightness must be between 0 and 1"));
+    }
+    if scene.speed <= 0.0 {
+        return Err(invalid("scene speed must be positive"));
+    }
+    let mut applied = apply(scene, config);
+    applied.themes.clear();
+    applied.scenes.clear();
+    applied.validate()
+}
+
+/// Saves a scene for later runs, replacing any saved scene of the same name.
+pub fn save(scene: SceneConfig, config: &Config) -> Result<(), Failure> {
+    check(&scene, config)?;
+    let mut scenes = saved();
+    scenes.retain(|saved| saved.name != scene.name);
+    scenes.push(scene);
+    storage::current()
+        .write(KEY, to_toml(&scenes).as_bytes())
+        .map_err(Failure::Scene)
+}
+
+fn to_toml(scenes: &[SceneConfig]) -> String {
+    scenes
+        .iter()
+        .map(|scene| {
+            let color = scene
+                .color
+                .map(|(r, g, b)| format!("color = \"#{:02x}{:02x}{:02x}\"\n", r, g, b))
+                .unwrap_or_default();
+            format!(
+                "[scenes.{}]\neffect = \"{}\"\n{}brightness = {:?}\nspeed = {:?}\n",
+                scene.name,
+                scene.effect.name(),
+                color,
+                scene.brightness,
+                scene.speed
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cozy() -> SceneConfig {
+        SceneConfig {
+            name: "cozy".to_string(),
+            effect: EffectKind::Solid,
+            color: Some((255, 140, 40)),
+            brightness: 0.4,
+            speed: 1.0,
+        }
+    }
+
+    #[test]
+    fn saved_scenes_read_back() {
+        let party = SceneConfig {
+            name: "party".to_string(),
+            effect: EffectKind::Rainbow,
+            color: None,
+            brightness: 1.0,
+            speed: 3.0,
+        };
+        let scenes = vec![cozy(), party];
+        assert_eq!(config::scenes_from_toml(&to_toml(&scenes)).unwrap(), scenes);
+    }
+
+    #[test]
+    fn a_captured_scene_applies_back() {
+        let config = apply(&cozy(), &Config::default());
+        assert_eq!(config.effect, EffectKind::Solid);
+        assert_eq!(config.solid.color, (255, 140, 40));
+        assert_eq!(capture("cozy", &config, 0.4), cozy());
+    }
+
+    #[test]
+    fn names_must_be_bare_keys() {
+        let mut scene = cozy();
+        assert!(check(&scene, &Config::default()).is_ok());
+        scene.name = "by the fire".to_string();
+        assert!(check(&scene, &Config::default()).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use crate::{config::Config, sun::SunSchedule, timezone::Zone};
+use chrono::{DateTime, NaiveDate, Utc};
+
+/// One end of the nightly on window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    // Minutes after sunset when turning on or after sunrise when turning off, negative for before
+    Sun { offset_minutes: i64 },
+    // A fixed time of day in the schedule's timezone, ignoring the sun
+    At((u32, u32)),
+}
+
+/// When the lights are on: from the evening's on trigger until the following off trigger.
+#[derive(Clone, Copy, Debug)]
+pub struct Schedule {
+    sun: SunSchedule,
+    on: Trigger,
+    off: Trigger,
+    // What fixed times and dates are in
+    zone: Zone,
+}
+
+impl Schedule {
+    pub fn new(sun: SunSchedule, on: Trigger, off: Trigger, zone: Zone) -> Self {
+        Schedule { sun, on, off, zone }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Schedule::new(
+            SunSchedule {
+                location: config.location,
+                fallback_sunrise_utc: config.schedule.fallback_sunrise_utc,
+                fallback_sunset_utc: config.schedule.fallback_sunset_utc,
+            },
+            config.schedule.on,
+            config.schedule.off,
+            config.schedule.timezone,
+        )
+    }
+
+    pub fn sun(&self) -> SunSchedule {
+        self.sun
+    }
+
+    pub fn zone(&self) -> Zone {
+        self.zone
+    }
+
+    /// The on and off times for the evening of `date`, a date in the schedule's timezone, as UTC
+    /// timestamps, along with that day's length in seconds.
+    pub fn plan(&self, date: NaiveDate) -> (i64, i64, i64) {
+        let (sunrise, sunset) = self.sun.sunrise_sunset(noon(date));
+        let on = match self.on {
+            Trigger::Sun { offset_minutes } => sunset + offset_minutes * 60,
+            Trigger::At(time) => self.zone.at(date, time),
+        };
+        let off = match self.off {
+            Trigger::Sun { offset_minutes } => {
+                let next_day = date.succ_opt().unwrap_or(date);
+                let (next_sunrise, _) = self.sun.sunrise_sunset(noon(next_day));
+                next_sunrise + offset_minutes * 60
+            }
+            // The first time the clock reads `time` after turning on
+            Trigger::At(time) => {
+                let off = self.zone.at(date, time);
+                if off > on {
+                    off
+                } else {
+                    self.zone.at(date.succ_opt().unwrap_or(date), time)
+                }
+            }
+        };
+        // Offsets that cross each other leave the lights off for the night
+        (on, off.max(on), sunset - sunrise)
+    }
+
+    /// Why the lights go on and off when they do on the evening of `date`, e.g. "15 minutes
+    /// after sunset at 16:02" and "23:30 on the clock", with times on the zone's clocks.
+    pub fn reasons(&self, date: NaiveDate) -> (String, String) {
+        let (_, sunset) = self.sun.sunrise_sunset(noon(date));
+        let (sunrise, _) = self
+            .sun
+            .sunrise_sunset(noon(date.succ_opt().unwrap_or(date)));
+        let reason = |trigger, sun: &str, sun_at: i64| match trigger {
+            Trigger::Sun { offset_minutes } => {
+                let time = self.zone.local(sun_at).map_or_else(
+                    || "??:??".to_string(),
+                    |time| time.format("%H:%M").to_string(),
+                );
+                match offset_minutes {
+                    0 => format!("{} at {}", sun, time),
+                    offset if offset > 0 => {
+                        format!("{} minutes after {} at {}", offset, sun, time)
+                    }
+                    offset => format!("{} minutes before {} at {}", -offset, sun, time),
+                }
+            }
+            Trigger::At((hour, minute)) => format!("{:02}:{:02} on the clock", hour, minute),
+        };
+        (
+            reason(self.on, "sunset", sunset),
+            reason(self.off, "sunrise", sunrise),
+        )
+    }
+
+    /// The latitude and longitude the sun times are computed for.
+    pub fn location(&self) -> (f64, f64) {
+        self.sun.location
+    }
+
+    pub fn is_on(&self, now: DateTime<Utc>) -> bool {
+        self.switched_on_at(now).is_some()
+    }
+
+    /// When the current on window opened, as a UTC timestamp, or None while the lights are due
+    /// to be off.
+    pub fn switched_on_at(&self, now: DateTime<Utc>) -> Option<i64> {
+        let now = now.timestamp();
+        let today = self.zone.date(now);
+        // Last night's window may still be open in the early morning
+        [today.pred_opt(), Some(today)]
+            .into_iter()
+            .flatten()
+            .map(|date| self.plan(date))
+            .find(|(on, off, _)| *on <= now && now < *off)
+            .map(|(on, _, _)| on)
+    }
+
+    /// When the lights are next due to switch off if `on`, or on otherwise, as a UTC timestamp.
+    pub fn next_change(&self, now: DateTime<Utc>, on: bool) -> Option<i64> {
+        let now = now.timestamp();
+        let today = self.zone.date(now);
+        [today.pred_opt(), Some(today), today.succ_opt()]
+            .into_iter()
+            .flatten()
+            .map(|date| self.plan(date))
+            // An empty window never switches anything
+            .filter(|(switch_on, switch_off, _)| switch_on < switch_off)
+            .map(|(switch_on, switch_off, _)| if on { switch_off } else { switch_on })
+            .filter(|change| *change > now)
+            .min()
+    }
+
+    /// The on window the lights are in at `now`, or else the next one, as UTC timestamps.
+    pub fn window(&self, now: DateTime<Utc>) -> Option<(i64, i64)> {
+        let now = now.timestamp();
+        let today = self.zone.date(now);
+        [today.pred_opt(), Some(today), today.succ_opt()]
+            .into_iter()
+            .flatten()
+            .map(|date| self.plan(date))
+            .find(|(on, off, _)| on < off && now < *off)
+            .map(|(on, off, _)| (on, off))
+    }
+}
+
+/// Brightness steps through the night, e.g. 40% from 22:00. Each step holds until the next one
+/// and the lights start every evening at full brightness.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DimmingCurve {
+    // Times of day in the schedule's timezone and the brightness from then on, between 0 and 1
+    pub steps: Vec<((u32, u32), f32)>,
+}
+
+impl DimmingCurve {
+    pub fn level(&self, schedule: &Schedule, now: DateTime<Utc>) -> f32 {
+        let Some(on) = schedule.switched_on_at(now) else {
+            return 1.0;
+        };
+        let zone = schedule.zone;
+        let on_date = zone.date(on);
+        let now = now.timestamp();
+        self.steps
+            .iter()
+            .filter_map(|&(time, level)| {
+                // The first time the clock reads `time` after turning on
+                let starts = zone.at(on_date, time);
+                let starts = if starts < on {
+                    zone.at(on_date.succ_opt().unwrap_or(on_date), time)
+                } else {
+                    starts
+                };
+                (starts <= now).then_some((starts, level))
+            })
+            .max_by_key(|(starts, _)| *starts)
+            .map_or(1.0, |(_, level)| level)
+    }
+}
+
+fn noon(date: NaiveDate) -> DateTime<Utc> {
+    let noon = date.and_hms_opt(12, 0, 0).expect("noon is a valid time");
+    DateTime::from_utc(noon, Utc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const BUDAPEST: (f64, f64) = (47.4979, 19.0402);
+
+    fn schedule(on: Trigger, off: Trigger) -> Schedule {
+        let sun = SunSchedule {
+            location: BUDAPEST,
+            fallback_sunrise_utc: (7, 0),
+            fallback_sunset_utc: (15, 0),
+        };
+        Schedule::new(sun, on, off, Zone::Utc)
+    }
+
+    fn at_utc(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 12, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn on_from_sunset_to_sunrise() {
+        let schedule = schedule(
+            Trigger::Sun { offset_minutes: 0 },
+            Trigger::Sun { offset_minutes: 0 },
+        );
+        assert!(!schedule.is_on(at_utc(1, 12, 0)));
+        assert!(schedule.is_on(at_utc(1, 20, 0)));
+        // Still last night's window
+        assert!(schedule.is_on(at_utc(2, 3, 0)));
+        assert!(!schedule.is_on(at_utc(2, 9, 0)));
+    }
+
+    #[test]
+    fn sun_offsets_move_the_window() {
+        // Sunset in Budapest is around 14:53 UTC in early December
+        let early = schedule(
+            Trigger::Sun {
+                offset_minutes: -60,
+            },
+            Trigger::Sun { offset_minutes: 0 },
+        );
+        let exact = schedule(
+            Trigger::Sun { offset_minutes: 0 },
+            Trigger::Sun { offset_minutes: 0 },
+        );
+        assert!(early.is_on(at_utc(1, 14, 30)));
+        assert!(!exact.is_on(at_utc(1, 14, 30)));
+    }
+
+    #[test]
+    fn fixed_off_time_ends_the_evening() {
+        let schedule = schedule(Trigger::Sun { offset_minutes: 0 }, Trigger::At((23, 30)));
+        assert!(schedule.is_on(at_utc(1, 23, 0)));
+        assert!(!schedule.is_on(at_utc(1, 23, 45)));
+        assert!(!schedule.is_on(at_utc(2, 3, 0)));
+    }
+
+    #[test]
+    fn fixed_times_can_span_midnight() {
+        let schedule = schedule(Trigger::At((20, 0)), Trigger::At((2, 0)));
+        assert!(!schedule.is_on(at_utc(1, 19, 0)));
+        assert!(schedule.is_on(at_utc(1, 21, 0)));
+        assert!(schedule.is_on(at_utc(2, 1, 0)));
+        assert!(!schedule.is_on(at_utc(2, 3, 0)));
+    }
+
+    #[test]
+    fn next_change_is_the_coming_switch() {
+        let schedule = schedule(Trigger::At((20, 0)), Trigger::At((2, 0)));
+        let at = |day, hour| Some(at_utc(day, hour, 0).timestamp());
+        assert_eq!(schedule.next_change(at_utc(1, 12, 0), false), at(1, 20));
+        assert_eq!(schedule.next_change(at_utc(1, 21, 0), true), at(2, 2));
+        // Held off for the night, so the next evening
+        assert_eq!(schedule.next_change(at_utc(1, 21, 0), false), at(2, 20));
+    }
+
+    #[test]
+    fn the_reasons_name_the_trigger() {
+        let schedule = schedule(
+            Trigger::Sun {
+                offset_minutes: -15,
+            },
+            Trigger::At((23, 30)),
+        );
+        let (on, off) = schedule.reasons(at_utc(1, 0, 0).date_naive());
+        assert!(on.starts_with("15 minutes before sunset at 14:"), "{}", on);
+        assert_eq!(off, "23:30 on the clock");
+    }
+
+    #[test]
+    fn the_window_is_the_current_or_next_one() {
+        let schedule = schedule(Trigger::At((20, 0)), Trigger::At((2, 0)));
+        let at = |day, hour| at_utc(day, hour, 0).timestamp();
+        assert_eq!(
+            schedule.window(at_utc(1, 12, 0)),
+            Some((at(1, 20), at(2, 2)))
+        );
+        assert_eq!(
+            schedule.window(at_utc(2, 1, 0)),
+            Some((at(1, 20), at(2, 2)))
+        );
+        assert_eq!(
+            schedule.window(at_utc(2, 2, 0)),
+            Some((at(2, 20), at(3, 2)))
+        );
+    }
+
+    #[test]
+    fn fixed_times_follow_summer_time() {
+        let budapest = Zone::from_name("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        let schedule = Schedule::new(
+            schedule(Trigger::Sun { offset_minutes: 0 }, Trigger::At((23, 0))).sun,
+            Trigger::Sun { offset_minutes: 0 },
+            Trigger::At((23, 0)),
+            budapest,
+        );
+        let at = |day, hour, minute| {
+            Utc.with_ymd_and_hms(2024, 10, day, hour, minute, 0)
+                .unwrap()
+        };
+        // 23:00 is 21:00 UTC in summer time and 22:00 UTC after the clocks go back
+        assert!(!schedule.is_on(at(26, 21, 30)));
+        assert!(schedule.is_on(at(27, 21, 30)));
+        assert!(!schedule.is_on(at(27, 22, 30)));
+    }
+
+    #[test]
+    fn dimming_steps_hold_until_the_next_evening() {
+        let schedule = schedule(Trigger::At((17, 0)), Trigger::At((6, 0)));
+        let curve = DimmingCurve {
+            steps: vec![((22, 0), 0.4), ((1, 0), 0.2)],
+        };
+        assert_eq!(curve.level(&schedule, at_utc(1, 21, 0)), 1.0);
+        assert_eq!(curve.level(&schedule, at_utc(1, 22, 30)), 0.4);
+        assert_eq!(curve.level(&schedule, at_utc(2, 2, 0)), 0.2);
+        assert_eq!(curve.level(&schedule, at_utc(2, 18, 0)), 1.0);
+        // Off hours
+        assert_eq!(curve.level(&schedule, at_utc(2, 12, 0)), 1.0);
+    }
+}
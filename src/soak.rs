@@ -0,0 +1,255 @@
+// Soak test: runs the daemon for hours while watching for what would only show after weeks
+// unattended, i.e. growing memory or open files, a render loop that stops sending frames while
+// the lights should be on, and switches the schedule promised but the lights never made.
+// Nobody should drive the lights meanwhile, as a remote command or startup_stay_off looks
+// just like a missed switch.
+use crate::{metrics, remote::LightStatus, schedule::Schedule};
+use std::{
+    fmt, fs,
+    time::{Duration, Instant},
+};
+use tokio::{sync::watch, time};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+// Startup allocations settle before memory is measured against the end
+const WARMUP: Duration = Duration::from_secs(10 * 60);
+// The scheduler checks every two minutes, so give it a little longer to switch
+const SWITCH_GRACE: Duration = Duration::from_secs(5 * 60);
+// Consecutive samples without frames while on before it counts as a stall
+const STALL_SAMPLES: u32 = 2;
+const MAX_MEMORY_GROWTH_KB: u64 = 4 * 1024;
+const MAX_MEMORY_GROWTH_RATIO: f64 = 0.25;
+const MAX_OPEN_FILES_GROWTH: usize = 16;
+const MAX_WRITE_FAILURE_RATIO: f64 = 0.05;
+
+/// Watches a running daemon and collects what goes wrong.
+pub struct Monitor {
+    duration: Duration,
+    schedule: Schedule,
+    status: watch::Receiver<LightStatus>,
+    started: Instant,
+    report: Report,
+    samples_without_frames: u32,
+    // Since when the lights disagree with the schedule, and whether that was counted yet
+    mismatch_since: Option<(Instant, bool)>,
+}
+
+/// What a soak test found.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    pub hours: f64,
+    pub writes: u64,
+    pub failed_writes: u64,
+    pub reconnects: u64,
+    pub stalls: u32,
+    pub missed_switches: u32,
+    // Resident memory in kB after the warmup, at its peak and at the end
+    pub memory_kb: Option<(u64, u64, u64)>,
+    // Open file descriptors after the warmup and at the end
+    pub open_files: Option<(usize, usize)>,
+}
+
+impl Monitor {
+    pub fn new(
+        duration: Duration,
+        schedule: Schedule,
+        status: watch::Receiver<LightStatus>,
+    ) -> Self {
+        Monitor {
+            duration,
+            schedule,
+            status,
+            started: Instant::now(),
+            report: Report::default(),
+            samples_without_frames: 0,
+            mismatch_since: None,
+        }
+    }
+
+    /// Samples until the soak test is over.
+    pub async fn run(&mut self) {
+        while self.started.elapsed() < self.duration {
+            time::sleep(SAMPLE_INTERVAL.min(self.duration)).await;
+            self.sample();
+        }
+    }
+
+    /// The findings so far, also for a test cut short.
+    pub fn report(&self) -> Report {
+        Report {
+            hours: self.started.elapsed().as_secs_f64() / 3600.0,
+            ..self.report.clone()
+        }
+    }
+
+    fn sample(&mut self) {
+        let now = Instant::now();
+        let on = self.status.borrow().on;
+        if let Some(link) = metrics::link() {
+            self.report.writes = link.writes;
+            self.report.failed_writes = link.failed_writes;
+            self.report.reconnects = link.reconnects;
+            if on && link.frames_per_second == 0.0 {
+                self.samples_without_frames += 1;
+                if self.samples_without_frames == STALL_SAMPLES {
+                    self.report.stalls += 1;
+                }
+            } else {
+                self.samples_without_frames = 0;
+            }
+        }
+
+        if self.schedule.is_on(chrono::Utc::now()) == on {
+            self.mismatch_since = None;
+        } else {
+            let (since, counted) = self.mismatch_since.get_or_insert((now, false));
+            if !*counted && now.duration_since(*since) >= SWITCH_GRACE {
+                *counted = true;
+                self.report.missed_switches += 1;
+            }
+        }
+
+        if self.started.elapsed() < WARMUP.min(self.duration / 10) {
+            return;
+        }
+        if let Some(rss) = fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| resident_kb(&status))
+        {
+            let (first, peak, _) = self.report.memory_kb.unwrap_or((rss, rss, rss));
+            self.report.memory_kb = Some((first, peak.max(rss), rss));
+        }
+        if let Ok(files) = fs::read_dir("/proc/self/fd") {
+            let open = files.count();
+            let (first, _) = self.report.open_files.unwrap_or((open, open));
+            self.report.open_files = Some((first, open));
+        }
+    }
+}
+
+impl Report {
+    /// Why the soak test failed, empty if it passed.
+    pub fn failures(&self) -> Vec<String> {
+        let mut failures = Vec::new();
+        if self.stalls > 0 {
+            failures.push(format!("the render loop stalled {} times", self.stalls));
+        }
+        if self.missed_switches > 0 {
+            failures.push(format!(
+                "the lights missed {} scheduled switches",
+                self.missed_switches
+            ));
+        }
+        if self.writes > 0
+            && self.failed_writes as f64 / self.writes as f64 > MAX_WRITE_FAILURE_RATIO
+        {
+            failures.push(format!(
+                "{} of {} writes failed",
+                self.failed_writes, self.writes
+            ));
+        }
+        if let Some((first, _, last)) = self.memory_kb {
+            let growth = last.saturating_sub(first);
+            if growth > MAX_MEMORY_GROWTH_KB
+                && growth as f64 > first as f64 * MAX_MEMORY_GROWTH_RATIO
+            {
+                failures.push(format!("memory grew from {} kB to {} kB", first, last));
+            }
+        }
+        if let Some((first, last)) = self.open_files {
+            if last > first + MAX_OPEN_FILES_GROWTH {
+                failures.push(format!("open files grew from {} to {}", first, last));
+            }
+        }
+        failures
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failures = self.failures();
+        writeln!(
+            f,
+            "Soak test over {:.1} hours: {}",
+            self.hours,
+            if failures.is_empty() {
+                "passed"
+            } else {
+                "FAILED"
+            }
+        )?;
+        writeln!(
+            f,
+            "  BLE writes       {}, {} failed",
+            self.writes, self.failed_writes
+        )?;
+        writeln!(f, "  Reconnects       {}", self.reconnects)?;
+        writeln!(f, "  Stalls           {}", self.stalls)?;
+        writeln!(f, "  Missed switches  {}", self.missed_switches)?;
+        if let Some((first, peak, last)) = self.memory_kb {
+            writeln!(
+                f,
+                "  Memory           {} kB after warmup, {} kB peak, {} kB at the end",
+                first, peak, last
+            )?;
+        }
+        if let Some((first, last)) = self.open_files {
+            writeln!(
+                f,
+                "  Open files       {} after warmup, {} at the end",
+                first, last
+            )?;
+        }
+        for failure in failures {
+            writeln!(f, "  - {}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+// VmRSS from /proc/self/status
+fn resident_kb(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resident_memory_is_read_from_proc_status() {
+        let status = "Name:\tchristmas-lights\nVmPeak:\t  20480 kB\nVmRSS:\t    8192 kB\n";
+        assert_eq!(resident_kb(status), Some(8192));
+        assert_eq!(resident_kb("Name:\tchristmas-lights\n"), None);
+    }
+
+    #[test]
+    fn a_quiet_run_passes() {
+        let report = Report {
+            hours: 24.0,
+            writes: 10_000,
+            failed_writes: 12,
+            memory_kb: Some((8_000, 9_000, 8_500)),
+            open_files: Some((12, 13)),
+            ..Report::default()
+        };
+        assert!(report.failures().is_empty());
+    }
+
+    #[test]
+    fn leaks_and_stalls_fail() {
+        let report = Report {
+            stalls: 1,
+            memory_kb: Some((8_000, 40_000, 40_000)),
+            open_files: Some((12, 200)),
+            ..Report::default()
+        };
+        assert_eq!(report.failures().len(), 3);
+    }
+}
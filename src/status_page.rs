@@ -0,0 +1,208 @@
+// Read-only status page on its own port, for embedding in a dashboard without handing out
+// control of the lights:
+//
+//   GET  /              HTML page with a swatch of the current color, refreshing itself
+//   GET  /status.json   {"on":true,"color":"#ff8c28","next_change":"2024-12-01T06:12:00Z"}
+//
+// Each client address gets a fixed number of requests per minute.
+use crate::{
+    config::{Config, StatusPageConfig},
+    http::{self, Response},
+    integrations::{self, Context, Integration},
+    remote::PublicStatus,
+    supervisor::FailureBudget,
+};
+use futures::future::BoxFuture;
+use log::{debug, info};
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    time,
+};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+const REFRESH_SECONDS: u32 = 30;
+
+// Requests per client address in the current window
+type RateLimits = Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>;
+
+struct StatusPage(StatusPageConfig);
+
+impl Integration for StatusPage {
+    fn name(&self) -> &'static str {
+        "Status page"
+    }
+
+    fn start(self: Box<Self>, context: Context, budget: FailureBudget) -> BoxFuture<'static, ()> {
+        let commands = context.commands.clone();
+        Box::pin(integrations::restarting(commands, budget, move || {
+            serve(self.0.clone(), context.public.clone())
+        }))
+    }
+}
+
+pub fn integration(config: &Config) -> Option<Box<dyn Integration>> {
+    Some(Box::new(StatusPage(config.status_page.clone()?)))
+}
+
+/// Accepts requests until the listener fails.
+pub async fn serve(
+    config: StatusPageConfig,
+    status: watch::Receiver<PublicStatus>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(&config.listen).await?;
+    info!("Status page listening on {}", config.listen);
+    let limits = RateLimits::default();
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let allowed = allow(&limits, peer.ip(), config.requests_per_minute);
+        let status = *status.borrow();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, allowed, status).await {
+                debug!("Status page request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+fn allow(limits: &RateLimits, client: IpAddr, per_minute: u32) -> bool {
+    let Ok(mut limits) = limits.lock() else {
+        return false;
+    };
+    let now = Instant::now();
+    limits.retain(|_, (started, _)| now.duration_since(*started) < RATE_WINDOW);
+    let (_, count) = limits.entry(client).or_insert((now, 0));
+    *count += 1;
+    *count <= per_minute
+}
+
+async fn handle(mut stream: TcpStream, allowed: bool, status: PublicStatus) -> io::Result<()> {
+    let response = match time::timeout(http::REQUEST_TIMEOUT, http::read_request(&mut stream)).await
+    {
+        _ if !allowed => Response::new("429 Too Many Requests", "too many requests"),
+        Ok(Ok(Ok(request))) => route(&request.method, &request.path, status),
+        Ok(Ok(Err(rejected))) => rejected,
+        Ok(Err(e)) => return Err(e),
+        Err(_) => Response::new("408 Request Timeout", "request timed out"),
+    };
+    http::write_response(&mut stream, response).await
+}
+
+fn route(method: &str, path: &str, status: PublicStatus) -> Response {
+    match (method, path) {
+        ("GET", "/") => Response::new("200 OK", page(status)),
+        ("GET", "/status.json") => Response::new("200 OK", status_json(status)),
+        (_, "/" | "/status.json") => Response::new("405 Method Not Allowed", "method not allowed"),
+        _ => Response::new("404 Not Found", "not found"),
+    }
+}
+
+fn status_json(status: PublicStatus) -> String {
+    let (r, g, b) = status.color;
+    let next_change = status
+        .next_change
+        .and_then(|timestamp| chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0))
+        .map_or("null".to_string(), |time| {
+            time.format("\"%Y-%m-%dT%H:%M:%SZ\"").to_string()
+        });
+    format!(
+        "{{\"on\":{},\"color\":\"#{:02x}{:02x}{:02x}\",\"next_change\":{}}}",
+        status.on, r, g, b, next_change
+    )
+}
+
+fn page(status: PublicStatus) -> String {
+    let (r, g, b) = if status.on { status.color } else { (0, 0, 0) };
+    let next_change = status
+        .next_change
+        .and_then(|timestamp| chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0))
+        .map(|time| {
+            format!(
+                "<p>Switching {} at {} UTC</p>",
+                if status.on { "off" } else { "on" },
+                time.format("%Y-%m-%d %H:%M")
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"{}\"><title>Christmas lights</title></head>\
+         <body style=\"font-family: sans-serif; text-align: center\">\
+         <div style=\"width: 8em; height: 8em; margin: 1em auto; border-radius: 50%; \
+         background: #{:02x}{:02x}{:02x}\"></div>\
+         <h1>The lights are {}</h1>{}</body></html>\n",
+        REFRESH_SECONDS,
+        r,
+        g,
+        b,
+        if status.on { "on" } else { "off" },
+        next_change
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const STATUS: PublicStatus = PublicStatus {
+        on: true,
+        color: (255, 140, 40),
+        next_change: None,
+    };
+
+    // Sends `request` to a page answering one connection and returns the status line
+    async fn answer(request: Vec<u8>, allowed: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle(stream, allowed, STATUS).await
+        });
+        let mut client = TcpStream::connect(address).await.unwrap();
+        // The page may answer and close before the whole request is written
+        client.write_all(&request).await.ok();
+        let mut response = Vec::new();
+        let mut chunk = [0; 1024];
+        while let Ok(read @ 1..) = client.read(&mut chunk).await {
+            response.extend_from_slice(&chunk[..read]);
+        }
+        server.await.unwrap().ok();
+        let response = String::from_utf8_lossy(&response);
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn the_status_is_served() {
+        let request = b"GET /status.json HTTP/1.1\r\n\r\n".to_vec();
+        assert_eq!(answer(request, true).await, "HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn oversized_headers_are_refused() {
+        let request = format!(
+            "GET / HTTP/1.1\r\nCookie: {}\r\n\r\n",
+            "a".repeat(16 * 1024)
+        );
+        assert_eq!(
+            answer(request.into_bytes(), true).await,
+            "HTTP/1.1 413 Payload Too Large"
+        );
+    }
+
+    #[tokio::test]
+    async fn clients_over_the_limit_are_turned_away() {
+        let request = b"GET / HTTP/1.1\r\n\r\n".to_vec();
+        assert_eq!(
+            answer(request, false).await,
+            "HTTP/1.1 429 Too Many Requests"
+        );
+    }
+}
@@ -0,0 +1,341 @@
+// Where the daemon keeps what it learns between runs: the light addresses, saved scenes and the
+// daily totals. Plain files in the cache directory suit an SD card best; sled and SQLite are
+// optional builds, and SQLite keeps the daily totals as a table that can be queried directly.
+use crate::{
+    cache,
+    config::StorageConfig,
+    error::Failure,
+    metrics::{self, Day},
+};
+use std::{
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// Documents stored under a name, the file name they would have in the cache directory.
+pub trait Storage: Send + Sync {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, String>;
+
+    fn write(&self, key: &str, contents: &[u8]) -> Result<(), String>;
+
+    /// The daily totals, oldest first.
+    fn days(&self) -> Result<Vec<Day>, String> {
+        Ok(self
+            .read(metrics::KEY)?
+            .map(|contents| metrics::parse_days(&String::from_utf8_lossy(&contents)))
+            .unwrap_or_default())
+    }
+
+    /// Replaces the daily totals.
+    fn save_days(&self, days: &[Day]) -> Result<(), String> {
+        self.write(metrics::KEY, metrics::format_days(days).as_bytes())
+    }
+}
+
+/// Which storage to use, from `storage.backend`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageBackend {
+    File,
+    Sled,
+    Sqlite,
+}
+
+impl StorageBackend {
+    pub fn name(self) -> &'static str {
+        match self {
+            StorageBackend::File => "file",
+            StorageBackend::Sled => "sled",
+            StorageBackend::Sqlite => "sqlite",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            StorageBackend::File,
+            StorageBackend::Sled,
+            StorageBackend::Sqlite,
+        ]
+        .into_iter()
+        .find(|backend| backend.name() == name)
+    }
+}
+
+static SELECTED: Mutex<Option<Arc<dyn Storage>>> = Mutex::new(None);
+
+/// Opens the configured storage.
+pub fn open(config: &StorageConfig) -> Result<Arc<dyn Storage>, Failure> {
+    let path = |default: &str| {
+        config
+            .path
+            .clone()
+            .or_else(|| Some(cache::dir()?.join(default)))
+            .ok_or_else(|| Failure::Storage("no place to keep state, set HOME".to_string()))
+    };
+    match config.backend {
+        StorageBackend::File => Ok(Arc::new(FileStorage::new(
+            config.path.clone().or_else(cache::dir),
+        ))),
+        StorageBackend::Sled => open_sled(path("state.sled")?),
+        StorageBackend::Sqlite => open_sqlite(path("state.sqlite")?),
+    }
+}
+
+/// Opens the configured storage for the rest of the run.
+pub fn select(config: &StorageConfig) -> Result<(), Failure> {
+    let storage = open(config)?;
+    if let Ok(mut selected) = SELECTED.lock() {
+        *selected = Some(storage);
+    }
+    Ok(())
+}
+
+/// The storage picked with `select`, or files in the cache directory before that.
+pub fn current() -> Arc<dyn Storage> {
+    SELECTED
+        .lock()
+        .ok()
+        .and_then(|selected| selected.clone())
+        .unwrap_or_else(|| Arc::new(FileStorage::new(cache::dir())))
+}
+
+/// One file per key in a directory.
+pub struct FileStorage {
+    // None without a home directory, when nothing is kept
+    dir: Option<PathBuf>,
+}
+
+impl FileStorage {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        FileStorage { dir }
+    }
+}
+
+impl Storage for FileStorage {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let Some(dir) = &self.dir else {
+            return Ok(None);
+        };
+        match fs::read(dir.join(key)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn write(&self, key: &str, contents: &[u8]) -> Result<(), String> {
+        let dir = self
+            .dir
+            .as_ref()
+            .ok_or("no place to keep state, set HOME")?;
+        fs::create_dir_all(dir)
+            .and_then(|()| fs::write(dir.join(key), contents))
+            .map_err(|e| format!("cannot write {}: {}", dir.join(key).display(), e))
+    }
+}
+
+#[cfg(feature = "sled")]
+fn open_sled(path: PathBuf) -> Result<Arc<dyn Storage>, Failure> {
+    // sled locks the database, so a second process, e.g. `scene save` next to the daemon, fails
+    // here rather than corrupting it
+    let db = sled::open(&path)
+        .map_err(|e| Failure::Storage(format!("cannot open {}: {}", path.display(), e)))?;
+    Ok(Arc::new(SledStorage(db)))
+}
+
+#[cfg(not(feature = "sled"))]
+fn open_sled(_path: PathBuf) -> Result<Arc<dyn Storage>, Failure> {
+    Err(Failure::Storage(
+        "this build has no sled support, rebuild with --features sled".to_string(),
+    ))
+}
+
+#[cfg(feature = "sled")]
+struct SledStorage(sled::Db);
+
+#[cfg(feature = "sled")]
+impl Storage for SledStorage {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        self.0
+            .get(key)
+            .map(|contents| contents.map(|contents| contents.to_vec()))
+            .map_err(|e| e.to_string())
+    }
+
+    fn write(&self, key: &str, contents: &[u8]) -> Result<(), String> {
+        self.0
+            .insert(key, contents)
+            .and_then(|_| self.0.flush())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(path: PathBuf) -> Result<Arc<dyn Storage>, Failure> {
+    let failed =
+        |e: rusqlite::Error| Failure::Storage(format!("cannot open {}: {}", path.display(), e));
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| Failure::Storage(format!("cannot create {}: {}", parent.display(), e)))?;
+    }
+    let connection = rusqlite::Connection::open(&path).map_err(failed)?;
+    connection
+        .execute_batch(
+            "CREATE TABLE IF NOT EXISTS documents (
+                 key TEXT PRIMARY KEY,
+                 contents BLOB NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS days (
+                 date TEXT PRIMARY KEY,
+                 on_seconds INTEGER NOT NULL,
+                 reconnects INTEGER NOT NULL
+             );",
+        )
+        .map_err(failed)?;
+    Ok(Arc::new(SqliteStorage(Mutex::new(connection))))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(_path: PathBuf) -> Result<Arc<dyn Storage>, Failure> {
+    Err(Failure::Storage(
+        "this build has no SQLite support, rebuild with --features sqlite".to_string(),
+    ))
+}
+
+// A connection cannot be shared between threads, hence the lock
+#[cfg(feature = "sqlite")]
+struct SqliteStorage(Mutex<rusqlite::Connection>);
+
+#[cfg(feature = "sqlite")]
+impl Storage for SqliteStorage {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        use rusqlite::OptionalExtension;
+
+        let connection = self.0.lock().map_err(|e| e.to_string())?;
+        connection
+            .query_row(
+                "SELECT contents FROM documents WHERE key = ?1",
+                [key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    fn write(&self, key: &str, contents: &[u8]) -> Result<(), String> {
+        let connection = self.0.lock().map_err(|e| e.to_string())?;
+        connection
+            .execute(
+                "INSERT INTO documents (key, contents) VALUES (?1, ?2)
+                 ON CONFLICT (key) DO UPDATE SET contents = excluded.contents",
+                rusqlite::params![key, contents],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn days(&self) -> Result<Vec<Day>, String> {
+        let connection = self.0.lock().map_err(|e| e.to_string())?;
+        let mut query = connection
+            .prepare("SELECT date, on_seconds, reconnects FROM days ORDER BY date")
+            .map_err(|e| e.to_string())?;
+        let rows = query
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })
+            .map_err(|e| e.to_string())?;
+        let mut days = Vec::new();
+        for row in rows {
+            let (date, on_seconds, reconnects) = row.map_err(|e| e.to_string())?;
+            // Rows edited by hand into something else are skipped, like lines in the file
+            if let Ok(date) = chrono::NaiveDate::parse_from_str(&date, "%Y-%m-%d") {
+                days.push(Day {
+                    date,
+                    on_seconds: on_seconds.max(0) as u64,
+                    reconnects: reconnects.max(0) as u32,
+                });
+            }
+        }
+        Ok(days)
+    }
+
+    fn save_days(&self, days: &[Day]) -> Result<(), String> {
+        let mut connection = self.0.lock().map_err(|e| e.to_string())?;
+        let transaction = connection.transaction().map_err(|e| e.to_string())?;
+        transaction
+            .execute("DELETE FROM days", [])
+            .map_err(|e| e.to_string())?;
+        for day in days {
+            transaction
+                .execute(
+                    "INSERT INTO days (date, on_seconds, reconnects) VALUES (?1, ?2, ?3)",
+                    rusqlite::params![
+                        day.date.format("%Y-%m-%d").to_string(),
+                        day.on_seconds as i64,
+                        day.reconnects
+                    ],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        transaction.commit().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch_dir;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn files_read_back() {
+        let dir = scratch_dir("storage-files");
+        let storage = FileStorage::new(Some(dir.clone()));
+        assert_eq!(storage.read("addresses").unwrap(), None);
+        storage.write("addresses", b"A4:C1:38:12:34:56\n").unwrap();
+        assert_eq!(
+            storage.read("addresses").unwrap().as_deref(),
+            Some(&b"A4:C1:38:12:34:56\n"[..])
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn days_are_kept_as_a_document() {
+        let dir = scratch_dir("storage-days");
+        let storage = FileStorage::new(Some(dir.clone()));
+        let days = vec![Day {
+            date: NaiveDate::from_ymd_opt(2024, 12, 24).unwrap(),
+            on_seconds: 18_000,
+            reconnects: 2,
+        }];
+        storage.save_days(&days).unwrap();
+        assert_eq!(storage.days().unwrap(), days);
+        assert_eq!(
+            fs::read_to_string(dir.join(metrics::KEY)).unwrap(),
+            "2024-12-24 18000 2\n"
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn nothing_is_kept_without_a_home() {
+        let storage = FileStorage::new(None);
+        assert_eq!(storage.read("scenes.toml").unwrap(), None);
+        assert!(storage.write("scenes.toml", b"").is_err());
+    }
+
+    #[test]
+    fn backends_are_named() {
+        assert_eq!(
+            StorageBackend::from_name("sqlite"),
+            Some(StorageBackend::Sqlite)
+        );
+        assert_eq!(StorageBackend::from_name("postgres"), None);
+    }
+}
@@ -0,0 +1,110 @@
+use chrono::{DateTime, Datelike, Duration, Utc};
+
+#[derive(Clone, Copy, Debug)]
+pub struct SunSchedule {
+    pub location: (f64, f64),
+    // Used on days when the sun never rises or never sets at this location
+    pub fallback_sunrise_utc: (u32, u32),
+    pub fallback_sunset_utc: (u32, u32),
+}
+
+impl SunSchedule {
+    pub fn sunrise_sunset(&self, current_date: DateTime<Utc>) -> (i64, i64) {
+        let (latitude, longitude) = self.location;
+        let (sunrise, sunset) = sunrise::sunrise_sunset(
+            latitude,
+            longitude,
+            current_date.year(),
+            current_date.month(),
+            current_date.day(),
+        );
+
+        // The sunrise crate returns (0, 0) when the sun never rises or never sets
+        if sunrise == 0 && sunset == 0 {
+            return (
+                fixed_time_on(current_date, self.fallback_sunrise_utc),
+                fixed_time_on(current_date, self.fallback_sunset_utc),
+            );
+        }
+
+        (sunrise, sunset)
+    }
+
+    /// The first sunset after `now`, as a UTC timestamp.
+    pub fn next_sunset(&self, now: DateTime<Utc>) -> i64 {
+        let (_, sunset) = self.sunrise_sunset(now);
+        if sunset > now.timestamp() {
+            return sunset;
+        }
+        let (_, sunset) = self.sunrise_sunset(now + Duration::days(1));
+        sunset
+    }
+
+    pub fn is_daytime(&self, current_date: DateTime<Utc>) -> bool {
+        let (sunrise, sunset) = self.sunrise_sunset(current_date);
+        sunrise < current_date.timestamp() && current_date.timestamp() < sunset
+    }
+}
+
+fn fixed_time_on(current_date: DateTime<Utc>, (hour, minute): (u32, u32)) -> i64 {
+    current_date
+        .date_naive()
+        .and_hms_opt(hour, minute, 0)
+        .expect("Invalid fallback time")
+        .timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const TROMSO: (f64, f64) = (69.6492, 18.9553);
+    const BUDAPEST: (f64, f64) = (47.552922, 19.254477);
+
+    fn schedule(location: (f64, f64)) -> SunSchedule {
+        SunSchedule {
+            location,
+            fallback_sunrise_utc: (7, 0),
+            fallback_sunset_utc: (15, 0),
+        }
+    }
+
+    #[test]
+    fn polar_night_falls_back_to_the_fixed_times() {
+        let midwinter = Utc.with_ymd_and_hms(2023, 12, 21, 12, 0, 0).unwrap();
+        let (sunrise, sunset) = schedule(TROMSO).sunrise_sunset(midwinter);
+
+        assert_eq!(
+            sunrise,
+            Utc.with_ymd_and_hms(2023, 12, 21, 7, 0, 0)
+                .unwrap()
+                .timestamp()
+        );
+        assert_eq!(
+            sunset,
+            Utc.with_ymd_and_hms(2023, 12, 21, 15, 0, 0)
+                .unwrap()
+                .timestamp()
+        );
+    }
+
+    #[test]
+    fn polar_day_falls_back_to_the_fixed_times() {
+        let midsummer = Utc.with_ymd_and_hms(2023, 6, 21, 12, 0, 0).unwrap();
+        let (sunrise, sunset) = schedule(TROMSO).sunrise_sunset(midsummer);
+
+        assert_eq!(sunrise, fixed_time_on(midsummer, (7, 0)));
+        assert_eq!(sunset, fixed_time_on(midsummer, (15, 0)));
+    }
+
+    #[test]
+    fn ordinary_days_use_the_real_sun_times() {
+        let midwinter = Utc.with_ymd_and_hms(2023, 12, 21, 12, 0, 0).unwrap();
+        let (sunrise, sunset) = schedule(BUDAPEST).sunrise_sunset(midwinter);
+
+        assert_ne!(sunrise, fixed_time_on(midwinter, (7, 0)));
+        assert!(schedule(BUDAPEST).is_daytime(midwinter));
+        assert!(sunrise < midwinter.timestamp() && midwinter.timestamp() < sunset);
+    }
+}
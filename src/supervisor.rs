@@ -0,0 +1,255 @@
+// Keeps optional subsystems such as the MQTT connection from flooding the log or spinning
+// when they keep failing. The light loop only asks here which best-effort lights to shed; it
+// has its own reconnect handling.
+use crate::config::{DevicesConfig, SupervisorConfig};
+use log::{info, warn};
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+/// Counts one subsystem's recent failures. Within the budget failures are retried quietly;
+/// past it the subsystem is disabled for a cool-down with a single warning.
+pub struct FailureBudget {
+    name: &'static str,
+    max_failures: usize,
+    window: Duration,
+    cooldown: Duration,
+    failures: VecDeque<Instant>,
+    cooling_down: bool,
+}
+
+impl FailureBudget {
+    pub fn new(name: &'static str, config: &SupervisorConfig) -> Self {
+        FailureBudget {
+            name,
+            max_failures: config.max_failures,
+            window: config.window,
+            cooldown: config.cooldown,
+            failures: VecDeque::new(),
+            cooling_down: false,
+        }
+    }
+
+    /// Records a failure. Returns the cool-down to sit out, or None to retry as usual.
+    pub fn failed(&mut self, error: impl Display) -> Option<Duration> {
+        let now = Instant::now();
+        while self
+            .failures
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > self.window)
+        {
+            self.failures.pop_front();
+        }
+        self.failures.push_back(now);
+        if self.failures.len() < self.max_failures {
+            info!("{} failed ({}), retrying", self.name, error);
+            return None;
+        }
+
+        warn!(
+            "{} failed {} times within {} minutes, disabling it for {} minutes (last error: {})",
+            self.name,
+            self.failures.len(),
+            self.window.as_secs() / 60,
+            self.cooldown.as_secs() / 60,
+            error
+        );
+        self.failures.clear();
+        self.cooling_down = true;
+        Some(self.cooldown)
+    }
+
+    /// Call before each attempt; announces the end of a cool-down once.
+    pub fn resume(&mut self) {
+        if self.cooling_down {
+            self.cooling_down = false;
+            info!("Re-enabling {} after its cool-down", self.name);
+        }
+    }
+}
+
+/// Sheds best-effort lights so the critical ones keep the adapter. A best-effort light whose
+/// writes fail shed_after_failed_writes times in a row is dropped for the cool-down, and so is
+/// every best-effort light while a critical one fails that often. Only critical lights are
+/// warned about.
+pub struct DeviceSupervisor {
+    lights: Vec<Supervised>,
+    shed_after: u32,
+    cooldown: Duration,
+}
+
+struct Supervised {
+    name: String,
+    critical: bool,
+    // Failed writes since the last one that went through
+    failures: u32,
+    shed_until: Option<Instant>,
+}
+
+impl DeviceSupervisor {
+    /// Supervises the lights by name, each marked critical or not.
+    pub fn new(lights: impl IntoIterator<Item = (String, bool)>, config: &DevicesConfig) -> Self {
+        DeviceSupervisor {
+            lights: lights
+                .into_iter()
+                .map(|(name, critical)| Supervised {
+                    name,
+                    critical,
+                    failures: 0,
+                    shed_until: None,
+                })
+                .collect(),
+            shed_after: config.shed_after_failed_writes,
+            cooldown: config.cooldown,
+        }
+    }
+
+    /// Whether light `i` is shed, and should be left alone.
+    pub fn is_shed(&self, i: usize) -> bool {
+        self.lights
+            .get(i)
+            .is_some_and(|light| light.shed_until.is_some())
+    }
+
+    /// The shed lights whose cool-down is over at `now`, to connect again. They count as back
+    /// until they fail again.
+    pub fn returning(&mut self, now: Instant) -> Vec<usize> {
+        let mut returning = Vec::new();
+        for (i, light) in self.lights.iter_mut().enumerate() {
+            if light.shed_until.is_some_and(|until| now >= until) {
+                light.shed_until = None;
+                info!("Bringing back best-effort light {}", light.name);
+                returning.push(i);
+            }
+        }
+        returning
+    }
+
+    /// Sheds light `i` for another cool-down from `now`, when it did not come back.
+    pub fn shed_again(&mut self, i: usize, now: Instant, error: impl Display) {
+        if let Some(light) = self.lights.get_mut(i) {
+            info!(
+                "Best-effort light {} is still unavailable ({})",
+                light.name, error
+            );
+            light.shed_until = Some(now + self.cooldown);
+        }
+    }
+
+    /// Records that a write to light `i` went through, which ends its run of failures.
+    pub fn succeeded(&mut self, i: usize) {
+        if let Some(light) = self.lights.get_mut(i) {
+            light.failures = 0;
+        }
+    }
+
+    /// Records a failed write to light `i` at `now`. Returns the lights shed because of it, to
+    /// disconnect.
+    pub fn failed(&mut self, i: usize, now: Instant, error: impl Display) -> Vec<usize> {
+        let shed_after = self.shed_after;
+        let Some(light) = self.lights.get_mut(i) else {
+            return Vec::new();
+        };
+        light.failures += 1;
+        if light.failures < shed_after {
+            return Vec::new();
+        }
+        light.failures = 0;
+
+        let until = now + self.cooldown;
+        let minutes = self.cooldown.as_secs() / 60;
+        if !light.critical {
+            info!(
+                "Best-effort light {} keeps failing ({}), dropping it for {} minutes",
+                light.name, error, minutes
+            );
+            light.shed_until = Some(until);
+            return vec![i];
+        }
+        warn!(
+            "Critical light {} failed {} writes in a row (last error: {})",
+            light.name, shed_after, error
+        );
+        let shed: Vec<usize> = self
+            .lights
+            .iter()
+            .enumerate()
+            .filter(|(_, light)| !light.critical && light.shed_until.is_none())
+            .map(|(i, _)| i)
+            .collect();
+        if !shed.is_empty() {
+            info!(
+                "Dropping {} best-effort light(s) for {} minutes to leave the adapter to the \
+                 critical ones",
+                shed.len(),
+                minutes
+            );
+        }
+        for &i in &shed {
+            self.lights[i].shed_until = Some(until);
+        }
+        shed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supervisor(critical: &[bool]) -> DeviceSupervisor {
+        DeviceSupervisor::new(
+            critical
+                .iter()
+                .enumerate()
+                .map(|(i, &critical)| (format!("light {}", i), critical)),
+            &DevicesConfig {
+                shed_after_failed_writes: 3,
+                cooldown: Duration::from_secs(600),
+            },
+        )
+    }
+
+    #[test]
+    fn a_failing_best_effort_light_is_shed_alone() {
+        let now = Instant::now();
+        let mut devices = supervisor(&[true, false, false]);
+        assert!(devices.failed(1, now, "timeout").is_empty());
+        assert!(devices.failed(1, now, "timeout").is_empty());
+        assert_eq!(devices.failed(1, now, "timeout"), vec![1]);
+        assert!(devices.is_shed(1));
+        assert!(!devices.is_shed(0) && !devices.is_shed(2));
+        assert!(devices.returning(now + Duration::from_secs(599)).is_empty());
+        assert_eq!(devices.returning(now + Duration::from_secs(600)), vec![1]);
+        assert!(!devices.is_shed(1));
+    }
+
+    #[test]
+    fn a_failing_critical_light_sheds_the_best_effort_ones() {
+        let now = Instant::now();
+        let mut devices = supervisor(&[true, false, true, false]);
+        devices.failed(0, now, "timeout");
+        devices.failed(0, now, "timeout");
+        assert_eq!(devices.failed(0, now, "timeout"), vec![1, 3]);
+        assert!(!devices.is_shed(0) && !devices.is_shed(2));
+        // Already shed, so nothing more to disconnect
+        devices.failed(2, now, "timeout");
+        devices.failed(2, now, "timeout");
+        assert!(devices.failed(2, now, "timeout").is_empty());
+    }
+
+    #[test]
+    fn transient_failures_shed_nothing() {
+        let now = Instant::now();
+        let mut devices = supervisor(&[true, false]);
+        for _ in 0..10 {
+            for i in 0..2 {
+                assert!(devices.failed(i, now, "timeout").is_empty());
+                assert!(devices.failed(i, now, "timeout").is_empty());
+                devices.succeeded(i);
+            }
+        }
+        assert!(!devices.is_shed(0) && !devices.is_shed(1));
+    }
+}
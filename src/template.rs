@@ -0,0 +1,429 @@
+// Small expressions that a rule computes its color or brightness with from the event that set
+// it off, so that a reading can drive the lights without an effect of its own. E.g.
+//
+//   {{ gradient(value, -10, 30, #0000ff, #ff0000) }}
+//
+// goes from blue at -10 to red at 30 degrees, and `{{ clamp(value.lux / 1000, 0.1, 1) }}` takes
+// a brightness from the lux field of a JSON payload. `value` is the event's value as a number
+// and `value.<field>`, or `value.<object>.<field>`, a number in it. There are numbers, #rrggbb
+// colors, + - * / and parentheses, and the functions gradient(x, low, high, colors...),
+// clamp(x, low, high) and rgb(red, green, blue) on a 0 to 255 scale. Types are checked when
+// the rules are read; an event without the value a template needs only skips its rule.
+use crate::{color, remote};
+
+// Templates are written between these, e.g. color = "{{ ... }}"
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Color((u8, u8, u8)),
+}
+
+/// A parsed template, e.g. from `{{ gradient(value, 0, 30, #0000ff, #ff0000) }}`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    expression: Expression,
+    color: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expression {
+    Number(f64),
+    Color((u8, u8, u8)),
+    // The event's value, or the field at this path in it
+    Value(Vec<String>),
+    Negate(Box<Expression>),
+    Binary(char, Box<Expression>, Box<Expression>),
+    Gradient(Vec<Expression>),
+    Clamp(Vec<Expression>),
+    Rgb(Vec<Expression>),
+}
+
+impl Template {
+    /// Whether the string is a template at all, i.e. written between {{ and }}.
+    pub fn is_template(text: &str) -> bool {
+        text.trim_start().starts_with(OPEN)
+    }
+
+    pub fn parse(text: &str) -> Result<Template, String> {
+        let inner = text
+            .trim()
+            .strip_prefix(OPEN)
+            .and_then(|rest| rest.strip_suffix(CLOSE))
+            .ok_or_else(|| format!("a template must be written between {} and {}", OPEN, CLOSE))?;
+        let mut parser = Parser {
+            tokens: tokenize(inner)?,
+            next: 0,
+        };
+        let (expression, color) = parser.sum()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {}", token));
+        }
+        Ok(Template { expression, color })
+    }
+
+    /// Whether the template computes a color rather than a number.
+    pub fn is_color(&self) -> bool {
+        self.color
+    }
+
+    /// The template's value for an event carrying `value`; None when that holds no number
+    /// where the template looks for one, or the arithmetic fails, e.g. dividing by zero.
+    pub fn render(&self, value: &str) -> Option<Value> {
+        if self.color {
+            evaluate_color(&self.expression, value).map(Value::Color)
+        } else {
+            evaluate(&self.expression, value).map(Value::Number)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Color((u8, u8, u8)),
+    Name(String),
+    Symbol(char),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(number) => write!(f, "{}", number),
+            Token::Color((r, g, b)) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "{:?}", symbol),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(first) = rest.chars().next() {
+        let end = |done: fn(char) -> bool| rest.find(done).unwrap_or(rest.len());
+        let length = match first {
+            '#' => {
+                let length = 1 + rest[1..]
+                    .find(|c: char| !c.is_ascii_hexdigit())
+                    .unwrap_or(rest.len() - 1);
+                let hex = &rest[..length];
+                let rgb = color::parse_hex(hex)
+                    .ok_or_else(|| format!("{} is not a color like #ff0000", hex))?;
+                tokens.push(Token::Color(rgb));
+                length
+            }
+            '0'..='9' | '.' => {
+                let length = end(|c| !(c.is_ascii_digit() || c == '.'));
+                let number = &rest[..length];
+                tokens.push(Token::Number(
+                    number
+                        .parse()
+                        .map_err(|_| format!("{} is not a number", number))?,
+                ));
+                length
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let length = end(|c| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'));
+                tokens.push(Token::Name(rest[..length].to_string()));
+                length
+            }
+            '+' | '-' | '*' | '/' | '(' | ')' | ',' => {
+                tokens.push(Token::Symbol(first));
+                1
+            }
+            other => return Err(format!("unexpected {:?}", other)),
+        };
+        rest = rest[length..].trim_start();
+    }
+    Ok(tokens)
+}
+
+// Recursive descent over the tokens, one function per precedence level. Each returns the
+// expression and whether it is a color.
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn take(&mut self) -> Result<Token, String> {
+        let token = self
+            .peek()
+            .cloned()
+            .ok_or_else(|| "the template ends too early".to_string())?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        match self.take()? {
+            Token::Symbol(found) if found == symbol => Ok(()),
+            other => Err(format!("expected {:?}, found {}", symbol, other)),
+        }
+    }
+
+    fn sum(&mut self) -> Result<(Expression, bool), String> {
+        self.binary(&['+', '-'], Parser::product)
+    }
+
+    fn product(&mut self) -> Result<(Expression, bool), String> {
+        self.binary(&['*', '/'], Parser::unary)
+    }
+
+    fn binary(
+        &mut self,
+        symbols: &[char],
+        operand: fn(&mut Parser) -> Result<(Expression, bool), String>,
+    ) -> Result<(Expression, bool), String> {
+        let (mut left, color) = operand(self)?;
+        while let Some(&Token::Symbol(symbol)) = self.peek() {
+            if !symbols.contains(&symbol) {
+                break;
+            }
+            self.next += 1;
+            let (right, right_color) = operand(self)?;
+            if color || right_color {
+                return Err(format!("{:?} only works on numbers, not colors", symbol));
+            }
+            left = Expression::Binary(symbol, Box::new(left), Box::new(right));
+        }
+        Ok((left, color))
+    }
+
+    fn unary(&mut self) -> Result<(Expression, bool), String> {
+        if self.peek() == Some(&Token::Symbol('-')) {
+            self.next += 1;
+            let (operand, color) = self.unary()?;
+            if color {
+                return Err("a color cannot be negative".to_string());
+            }
+            return Ok((Expression::Negate(Box::new(operand)), false));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<(Expression, bool), String> {
+        match self.take()? {
+            Token::Number(number) => Ok((Expression::Number(number), false)),
+            Token::Color(rgb) => Ok((Expression::Color(rgb), true)),
+            Token::Symbol('(') => {
+                let inner = self.sum()?;
+                self.expect(')')?;
+                Ok(inner)
+            }
+            Token::Name(name) if self.peek() == Some(&Token::Symbol('(')) => self.call(&name),
+            Token::Name(name) => {
+                let mut path = name.split('.');
+                if path.next() != Some("value") {
+                    return Err(format!("{} is not value or value.<field>", name));
+                }
+                let fields: Vec<String> = path.map(str::to_string).collect();
+                if fields.iter().any(String::is_empty) {
+                    return Err(format!("{} has an empty field name", name));
+                }
+                Ok((Expression::Value(fields), false))
+            }
+            other => Err(format!("unexpected {}", other)),
+        }
+    }
+
+    // The arguments' types are fixed for each function, apart from gradient's colors
+    fn call(&mut self, name: &str) -> Result<(Expression, bool), String> {
+        self.expect('(')?;
+        let mut arguments = Vec::new();
+        loop {
+            arguments.push(self.sum()?);
+            match self.take()? {
+                Token::Symbol(',') => {}
+                Token::Symbol(')') => break,
+                other => return Err(format!("expected ',' or ')', found {}", other)),
+            }
+        }
+        let colors = |count: usize| arguments.iter().skip(count).all(|(_, color)| *color);
+        let numbers = |count: usize| arguments.iter().take(count).all(|(_, color)| !*color);
+        let (valid, usage) = match name {
+            "gradient" => (
+                arguments.len() >= 5 && numbers(3) && colors(3),
+                "gradient(x, low, high, color, color, ...)",
+            ),
+            "clamp" => (
+                arguments.len() == 3 && numbers(3),
+                "clamp(x, low, high) on numbers",
+            ),
+            "rgb" => (
+                arguments.len() == 3 && numbers(3),
+                "rgb(red, green, blue) on numbers",
+            ),
+            other => return Err(format!("{} is not one of gradient, clamp and rgb", other)),
+        };
+        if !valid {
+            return Err(format!("{} is used as {}", name, usage));
+        }
+        let arguments = arguments
+            .into_iter()
+            .map(|(argument, _)| argument)
+            .collect();
+        Ok(match name {
+            "gradient" => (Expression::Gradient(arguments), true),
+            "clamp" => (Expression::Clamp(arguments), false),
+            _ => (Expression::Rgb(arguments), true),
+        })
+    }
+}
+
+// The parser has checked the types, so these only meet expressions of the kind they expect
+fn evaluate(expression: &Expression, value: &str) -> Option<f64> {
+    let number = match expression {
+        Expression::Number(number) => *number,
+        Expression::Value(path) => field(value, path)?,
+        Expression::Negate(operand) => -evaluate(operand, value)?,
+        Expression::Binary(symbol, left, right) => {
+            let (left, right) = (evaluate(left, value)?, evaluate(right, value)?);
+            match symbol {
+                '+' => left + right,
+                '-' => left - right,
+                '*' => left * right,
+                _ => left / right,
+            }
+        }
+        Expression::Clamp(arguments) => {
+            let [x, low, high] = numbers(arguments, value)?;
+            x.max(low).min(high)
+        }
+        Expression::Color(_) | Expression::Gradient(_) | Expression::Rgb(_) => return None,
+    };
+    number.is_finite().then_some(number)
+}
+
+fn evaluate_color(expression: &Expression, value: &str) -> Option<(u8, u8, u8)> {
+    match expression {
+        Expression::Color(rgb) => Some(*rgb),
+        Expression::Rgb(arguments) => {
+            let [r, g, b] = numbers(arguments, value)?;
+            let channel = |c: f64| c.clamp(0.0, 255.0).round() as u8;
+            Some((channel(r), channel(g), channel(b)))
+        }
+        Expression::Gradient(arguments) => {
+            let [x, low, high] = numbers(&arguments[..3], value)?;
+            let colors = arguments[3..]
+                .iter()
+                .map(|color| evaluate_color(color, value))
+                .collect::<Option<Vec<_>>>()?;
+            let last = (colors.len() - 1) as f32;
+            let stops: Vec<color::GradientStop> = colors
+                .into_iter()
+                .enumerate()
+                .map(|(i, rgb)| (i as f32 / last, rgb))
+                .collect();
+            let position = if high == low {
+                0.0
+            } else {
+                ((x - low) / (high - low)).clamp(0.0, 1.0)
+            };
+            let rgb = color::gradient_color(&stops, position as f32, false);
+            let channel = |c: f32| (c * 255.0).round() as u8;
+            Some((
+                channel(rgb.red()),
+                channel(rgb.green()),
+                channel(rgb.blue()),
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn numbers<const N: usize>(arguments: &[Expression], value: &str) -> Option<[f64; N]> {
+    let numbers = arguments
+        .iter()
+        .map(|argument| evaluate(argument, value))
+        .collect::<Option<Vec<_>>>()?;
+    numbers.try_into().ok()
+}
+
+// The value itself as a number, or a field of it as a JSON object, e.g. ["sensor", "lux"]
+fn field(value: &str, path: &[String]) -> Option<f64> {
+    let Some((last, objects)) = path.split_last() else {
+        return value.trim().parse().ok();
+    };
+    let mut object = value;
+    for key in objects {
+        object = remote::object_field(object, key)?;
+    }
+    remote::number_field(object, last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, value: &str) -> Option<Value> {
+        Template::parse(template).unwrap().render(value)
+    }
+
+    #[test]
+    fn arithmetic_follows_precedence() {
+        assert_eq!(
+            render("{{ -(value + 2) * 3 - 4 / 2 }}", "1"),
+            Some(Value::Number(-11.0))
+        );
+        assert_eq!(
+            render("{{ clamp(value / 100, 0, 1) }}", "250"),
+            Some(Value::Number(1.0))
+        );
+        assert_eq!(render("{{ value / 0 }}", "1"), None);
+        assert_eq!(render("{{ value }}", "on"), None);
+    }
+
+    #[test]
+    fn gradients_map_readings_to_colors() {
+        let template = "{{ gradient(value, -10, 30, #0000ff, #ff0000) }}";
+        assert!(Template::parse(template).unwrap().is_color());
+        assert_eq!(render(template, "-20"), Some(Value::Color((0, 0, 255))));
+        assert_eq!(render(template, "10"), Some(Value::Color((128, 0, 128))));
+        assert_eq!(render(template, "30"), Some(Value::Color((255, 0, 0))));
+        assert_eq!(
+            render("{{ rgb(value * 255, 0, 300) }}", "0.5"),
+            Some(Value::Color((128, 0, 255)))
+        );
+    }
+
+    #[test]
+    fn fields_come_from_json_payloads() {
+        let payload = r#"{"sensor": {"lux": 500}, "battery": 80}"#;
+        assert_eq!(
+            render("{{ value.sensor.lux / 1000 }}", payload),
+            Some(Value::Number(0.5))
+        );
+        assert_eq!(
+            render("{{ value.battery }}", payload),
+            Some(Value::Number(80.0))
+        );
+        assert_eq!(render("{{ value.humidity }}", payload), None);
+    }
+
+    #[test]
+    fn mistakes_are_caught_when_parsing() {
+        for template in [
+            "gradient(value, 0, 1, #000000, #ffffff)",
+            "{{ value + }}",
+            "{{ (value }}",
+            "{{ #ff0000 * 2 }}",
+            "{{ gradient(value, 0, 1, #000000) }}",
+            "{{ gradient(value, 0, 1, 2, #ffffff) }}",
+            "{{ rgb(#ff0000, 0, 0) }}",
+            "{{ temperature }}",
+            "{{ sqrt(value) }}",
+            "{{ #ff00 }}",
+            "{{ value % 2 }}",
+        ] {
+            assert!(Template::parse(template).is_err(), "{}", template);
+        }
+    }
+}
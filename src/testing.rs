@@ -0,0 +1,11 @@
+// Helpers shared by the unit tests
+use std::{fs, path::PathBuf};
+
+/// A fresh directory path under the system temp directory for the test called `name`, unique
+/// to this run. Nothing is created; whatever an earlier run left there is removed.
+pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("christmas-lights-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    dir
+}
@@ -0,0 +1,183 @@
+// Calendar themes: for the dates of a theme its effect and colors stand in for the configured
+// ones, so the lights go red and green for December or orange and purple for Halloween week
+use crate::{
+    config::{Config, NamedPalette, PaletteColor, ThemeConfig},
+    effects::EffectKind,
+};
+use chrono::{Datelike, NaiveDate};
+
+/// The theme for `date`. Where ranges overlap the shortest one wins, so a single flag day
+/// stands out from the month around it.
+pub fn on(date: NaiveDate, themes: &[ThemeConfig]) -> Option<&ThemeConfig> {
+    let day = (date.month(), date.day());
+    themes
+        .iter()
+        .filter(|theme| covers(theme, day))
+        .min_by_key(|theme| length(theme))
+}
+
+/// `config` with the theme's effect and colors in place of the configured ones.
+pub fn apply(theme: &ThemeConfig, config: &Config) -> Config {
+    let mut themed = config.clone();
+    themed.effect = theme.effect;
+    let Some(colors) = theme.colors.clone().filter(|colors| !colors.is_empty()) else {
+        return themed;
+    };
+    match theme.effect {
+        EffectKind::Gradient => {
+            let last = (colors.len() - 1).max(1) as f32;
+            themed.gradient.stops = Some(
+                colors
+                    .iter()
+                    .enumerate()
+                    .map(|(i, color)| (i as f32 / last, *color))
+                    .collect(),
+            );
+            themed.hold.palette = None;
+        }
+        EffectKind::Hold => {
+            themed.hold.palette = Some(colors);
+            themed.gradient.stops = None;
+        }
+        EffectKind::CandyCane => themed.candy_cane.colors = colors,
+        EffectKind::Breathing => themed.breathing.color = colors[0],
+        // The first color is the base, and the others sparkle over it
+        EffectKind::Twinkle => {
+            themed.twinkle.color = colors[0];
+            if colors.len() > 1 {
+                activate_palette(&mut themed, &theme.name, &colors[1..]);
+                themed.twinkle.palette_sparkles = true;
+            }
+        }
+        EffectKind::Solid => themed.solid.color = colors[0],
+        EffectKind::Strobe => themed.strobe.color = colors[0],
+        EffectKind::Palette => activate_palette(&mut themed, &theme.name, &colors),
+        EffectKind::Rainbow | EffectKind::Temperature | EffectKind::Audio => {}
+    }
+    themed
+}
+
+// Equal weights, under the theme's name so runtime switches can find it again
+fn activate_palette(config: &mut Config, name: &str, colors: &[(u8, u8, u8)]) {
+    config.palettes.retain(|palette| palette.name != name);
+    config.palettes.push(NamedPalette {
+        name: name.to_string(),
+        colors: colors
+            .iter()
+            .map(|&color| PaletteColor {
+                color,
+                weight: 1.0,
+                dwell: None,
+            })
+            .collect(),
+    });
+    config.palette.active = Some(name.to_string());
+}
+
+// Ranges that end before they start run over New Year
+fn covers(theme: &ThemeConfig, day: (u32, u32)) -> bool {
+    if theme.from <= theme.to {
+        theme.from <= day && day <= theme.to
+    } else {
+        theme.from <= day || day <= theme.to
+    }
+}
+
+// Days after the first one, counted in a leap year
+fn length(theme: &ThemeConfig) -> u32 {
+    let ordinal = |(month, day): (u32, u32)| {
+        NaiveDate::from_ymd_opt(2000, month, day).map_or(0, |date| date.ordinal())
+    };
+    (ordinal(theme.to) + 366 - ordinal(theme.from)) % 366
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn theme(name: &str, from: (u32, u32), to: (u32, u32)) -> ThemeConfig {
+        ThemeConfig {
+            name: name.to_string(),
+            from,
+            to,
+            effect: EffectKind::Hold,
+            colors: Some(vec![(255, 0, 0), (0, 160, 0)]),
+        }
+    }
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, month, day).unwrap()
+    }
+
+    #[test]
+    fn the_shortest_matching_theme_wins() {
+        let themes = [
+            theme("december", (12, 1), (12, 31)),
+            theme("christmas eve", (12, 24), (12, 24)),
+        ];
+        let name = |day| on(date(12, day), &themes).map(|theme| theme.name.as_str());
+        assert_eq!(name(23), Some("december"));
+        assert_eq!(name(24), Some("christmas eve"));
+        assert!(on(date(11, 30), &themes).is_none());
+    }
+
+    #[test]
+    fn ranges_can_run_over_new_year() {
+        let themes = [theme("winter", (12, 20), (1, 6))];
+        assert!(on(date(12, 31), &themes).is_some());
+        assert!(on(date(1, 6), &themes).is_some());
+        assert!(on(date(1, 7), &themes).is_none());
+    }
+
+    #[test]
+    fn colors_go_where_the_effect_reads_them() {
+        let mut halloween = theme("halloween", (10, 25), (10, 31));
+        halloween.effect = EffectKind::Gradient;
+        let themed = apply(&halloween, &Config::default());
+        assert_eq!(themed.effect, EffectKind::Gradient);
+        assert_eq!(
+            themed.gradient.stops,
+            Some(vec![(0.0, (255, 0, 0)), (1.0, (0, 160, 0))])
+        );
+        assert!(themed.validate().is_ok());
+    }
+
+    #[test]
+    fn palette_themes_bring_their_own_palette() {
+        let mut december = theme("december", (12, 1), (12, 31));
+        december.effect = EffectKind::Palette;
+        let themed = apply(&december, &Config::default());
+        assert_eq!(themed.palette.active.as_deref(), Some("december"));
+        assert_eq!(
+            themed
+                .active_palette()
+                .map(|colors| colors.iter().map(|c| c.color).collect::<Vec<_>>()),
+            Some(vec![(255, 0, 0), (0, 160, 0)])
+        );
+        assert!(themed.validate().is_ok());
+    }
+
+    #[test]
+    fn twinkle_themes_sparkle_in_their_other_colors() {
+        let mut december = theme("december", (12, 1), (12, 31));
+        december.colors = Some(vec![(255, 140, 40), (255, 0, 0), (0, 160, 0)]);
+        december.effect = EffectKind::Twinkle;
+        let themed = apply(&december, &Config::default());
+        assert_eq!(themed.twinkle.color, (255, 140, 40));
+        assert!(themed.twinkle.palette_sparkles);
+        assert_eq!(
+            themed
+                .active_palette()
+                .map(|colors| colors.iter().map(|c| c.color).collect::<Vec<_>>()),
+            Some(vec![(255, 0, 0), (0, 160, 0)])
+        );
+        assert!(themed.validate().is_ok());
+
+        december.colors = Some(vec![(255, 140, 40)]);
+        assert!(
+            !apply(&december, &Config::default())
+                .twinkle
+                .palette_sparkles
+        );
+    }
+}
@@ -0,0 +1,388 @@
+// The timezone the schedule's fixed times are in. Besides UTC and the host's own timezone,
+// any zone in the system's tz database works: the rule for its current offsets and summer
+// time is the POSIX TZ string at the end of the zone file, so no copy of the database needs
+// to be built in. The rule is applied to every year, which is right for anything the schedule
+// looks at.
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, TimeZone};
+use std::{fs, path::PathBuf};
+
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+// Summer time is an hour ahead unless the rule says otherwise
+const DEFAULT_DST_SHIFT: i32 = 60 * 60;
+// Summer time starts and ends at 02:00 unless the rule says otherwise
+const DEFAULT_TRANSITION_TIME: i32 = 2 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Zone {
+    Utc,
+    // Whatever the host is set to
+    Local,
+    Rule(Rule),
+}
+
+/// A POSIX TZ rule, e.g. CET-1CEST,M3.5.0,M10.5.0/3.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rule {
+    // Seconds east of UTC
+    standard: i32,
+    dst: Option<Dst>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Dst {
+    offset: i32,
+    start: Transition,
+    end: Transition,
+}
+
+// A day of the year and a local time on it, in seconds, that may lie outside 0 to 24 hours
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Transition {
+    day: Day,
+    time: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Day {
+    // Jn: 1 to 365, never counting 29 February
+    Julian(u16),
+    // n: 0 to 365, counting 29 February
+    Ordinal(u16),
+    // Mm.w.d: weekday d (0 is Sunday) of week w (5 is the last) of month m
+    Weekday { month: u32, week: u32, weekday: u32 },
+}
+
+impl Zone {
+    /// "UTC", "local" for the host's timezone, a tz database name such as "Europe/Budapest",
+    /// or a POSIX TZ rule.
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "UTC" | "utc" => return Ok(Zone::Utc),
+            "local" => return Ok(Zone::Local),
+            _ => {}
+        }
+        if let Some(rule) = Rule::parse(name) {
+            return Ok(Zone::Rule(rule));
+        }
+        if name.split('/').any(|part| part.is_empty() || part == "..") {
+            return Err(format!("{:?} is not a timezone", name));
+        }
+        let dir = std::env::var_os("TZDIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(ZONEINFO_DIR));
+        let path = dir.join(name);
+        let contents = fs::read(&path)
+            .map_err(|e| format!("cannot read timezone {} at {}: {}", name, path.display(), e))?;
+        footer(&contents)
+            .and_then(Rule::parse)
+            .map(Zone::Rule)
+            .ok_or_else(|| format!("{} has no rule for its current offsets", path.display()))
+    }
+
+    /// "UTC", or "local" for times in any other zone.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Zone::Utc => "UTC",
+            Zone::Local | Zone::Rule(_) => "local",
+        }
+    }
+
+    /// The date and time on the zone's clocks at a UTC timestamp.
+    pub fn local(&self, timestamp: i64) -> Option<NaiveDateTime> {
+        match self {
+            Zone::Utc => NaiveDateTime::from_timestamp_opt(timestamp, 0),
+            Zone::Local => Local
+                .timestamp_opt(timestamp, 0)
+                .single()
+                .map(|time| time.naive_local()),
+            Zone::Rule(rule) => {
+                NaiveDateTime::from_timestamp_opt(timestamp + i64::from(rule.offset(timestamp)), 0)
+            }
+        }
+    }
+
+    /// The date on the zone's clocks at a UTC timestamp.
+    pub fn date(&self, timestamp: i64) -> NaiveDate {
+        self.local(timestamp)
+            .or_else(|| NaiveDateTime::from_timestamp_opt(timestamp, 0))
+            .expect("Timestamp out of range")
+            .date()
+    }
+
+    /// When the zone's clocks read `time` on `date`, as a UTC timestamp. A time skipped when
+    /// summer time starts comes that much later, and a time repeated when it ends comes the
+    /// first time round.
+    pub fn at(&self, date: NaiveDate, (hour, minute): (u32, u32)) -> i64 {
+        let local = date
+            .and_hms_opt(hour, minute, 0)
+            .expect("Invalid schedule time");
+        match self {
+            Zone::Utc => local.timestamp(),
+            Zone::Local => {
+                let found = Local.from_local_datetime(&local).earliest().or_else(|| {
+                    let later = local + chrono::Duration::hours(1);
+                    Local.from_local_datetime(&later).earliest()
+                });
+                found.map_or(local.timestamp(), |time| time.timestamp())
+            }
+            Zone::Rule(rule) => rule.at(local),
+        }
+    }
+}
+
+impl Rule {
+    /// Parses a POSIX TZ string, or None if it is not one.
+    pub fn parse(rule: &str) -> Option<Self> {
+        let mut rest = abbreviation(rule)?;
+        let (standard, after) = offset(rest)?;
+        rest = after;
+        let standard = -standard;
+        if rest.is_empty() {
+            return Some(Rule {
+                standard,
+                dst: None,
+            });
+        }
+        rest = abbreviation(rest)?;
+        let dst_offset = match offset(rest) {
+            Some((dst, after)) => {
+                rest = after;
+                -dst
+            }
+            None => standard + DEFAULT_DST_SHIFT,
+        };
+        // Without dates there is no telling when summer time starts, so insist on them
+        let (start, end) = rest.strip_prefix(',')?.split_once(',')?;
+        Some(Rule {
+            standard,
+            dst: Some(Dst {
+                offset: dst_offset,
+                start: Transition::parse(start)?,
+                end: Transition::parse(end)?,
+            }),
+        })
+    }
+
+    /// Seconds east of UTC at a UTC timestamp.
+    pub fn offset(&self, timestamp: i64) -> i32 {
+        let Some(dst) = self.dst else {
+            return self.standard;
+        };
+        let year = NaiveDateTime::from_timestamp_opt(timestamp + i64::from(self.standard), 0)
+            .map_or(1970, |time| time.year());
+        // Summer time starts by standard time and ends by summer time
+        let start = dst.start.local_seconds(year) - i64::from(self.standard);
+        let end = dst.end.local_seconds(year) - i64::from(dst.offset);
+        let in_dst = if start < end {
+            start <= timestamp && timestamp < end
+        } else {
+            // Southern hemisphere: summer time spans the new year
+            !(end <= timestamp && timestamp < start)
+        };
+        if in_dst {
+            dst.offset
+        } else {
+            self.standard
+        }
+    }
+
+    fn at(&self, local: NaiveDateTime) -> i64 {
+        let local = local.timestamp();
+        let offsets = [Some(self.standard), self.dst.map(|dst| dst.offset)];
+        offsets
+            .into_iter()
+            .flatten()
+            .map(|offset| local - i64::from(offset))
+            .filter(|&utc| local - i64::from(self.offset(utc)) == utc)
+            .min()
+            // Skipped by summer time: by standard time that is just after the clocks went on
+            .unwrap_or(local - i64::from(self.standard))
+    }
+}
+
+impl Transition {
+    // date[/time]
+    fn parse(transition: &str) -> Option<Self> {
+        let (day, time) = match transition.split_once('/') {
+            Some((day, time)) => (day, signed_seconds(time)?),
+            None => (transition, DEFAULT_TRANSITION_TIME),
+        };
+        let day = if let Some(day) = day.strip_prefix('J') {
+            Day::Julian(day.parse().ok().filter(|day| (1..=365).contains(day))?)
+        } else if let Some(day) = day.strip_prefix('M') {
+            let mut fields = day.splitn(3, '.').map(|field| field.parse::<u32>().ok());
+            let (month, week, weekday) = (fields.next()??, fields.next()??, fields.next()??);
+            if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+                return None;
+            }
+            Day::Weekday {
+                month,
+                week,
+                weekday,
+            }
+        } else {
+            Day::Ordinal(day.parse().ok().filter(|day| *day <= 365)?)
+        };
+        Some(Transition { day, time })
+    }
+
+    // Seconds from the Unix epoch to the transition, on clocks that read UTC
+    fn local_seconds(&self, year: i32) -> i64 {
+        let new_year = NaiveDate::from_ymd_opt(year, 1, 1).expect("Year out of range");
+        let date = match self.day {
+            Day::Julian(day) => {
+                let leap = NaiveDate::from_ymd_opt(year, 2, 29).is_some();
+                let day = i64::from(day) - 1 + i64::from(leap && day >= 60);
+                new_year + chrono::Duration::days(day)
+            }
+            Day::Ordinal(day) => new_year + chrono::Duration::days(i64::from(day)),
+            Day::Weekday {
+                month,
+                week,
+                weekday,
+            } => {
+                let first = NaiveDate::from_ymd_opt(year, month, 1).unwrap_or(new_year);
+                let first_weekday = first.weekday().num_days_from_sunday();
+                let mut day = 1 + (weekday + 7 - first_weekday) % 7 + (week - 1) * 7;
+                // Week 5 means the last one, which may be the fourth
+                while NaiveDate::from_ymd_opt(year, month, day).is_none() {
+                    day -= 7;
+                }
+                first.with_day(day).unwrap_or(first)
+            }
+        };
+        date.and_hms_opt(0, 0, 0)
+            .expect("midnight is a valid time")
+            .timestamp()
+            + i64::from(self.time)
+    }
+}
+
+// Skips a zone abbreviation, either letters or anything in angle brackets like <+0330>
+fn abbreviation(rule: &str) -> Option<&str> {
+    let rest = if let Some(quoted) = rule.strip_prefix('<') {
+        quoted.split_once('>')?.1
+    } else {
+        let end = rule
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rule.len());
+        (end >= 3).then_some(&rule[end..])?
+    };
+    Some(rest)
+}
+
+// An offset like -1 or 5:30, west of UTC as POSIX has it, and what follows
+fn offset(rule: &str) -> Option<(i32, &str)> {
+    let end = rule
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '+' | '-' | ':')))
+        .unwrap_or(rule.len());
+    Some((signed_seconds(&rule[..end])?, &rule[end..]))
+}
+
+// [+|-]hh[:mm[:ss]] in seconds
+fn signed_seconds(time: &str) -> Option<i32> {
+    let (sign, time) = match time.strip_prefix('-') {
+        Some(time) => (-1, time),
+        None => (1, time.strip_prefix('+').unwrap_or(time)),
+    };
+    let mut seconds = 0;
+    for (i, field) in time.split(':').enumerate() {
+        let value: i32 = field.parse().ok()?;
+        if i > 2 || field.is_empty() || (i > 0 && value >= 60) || value > 167 {
+            return None;
+        }
+        seconds += value * [3600, 60, 1][i];
+    }
+    Some(sign * seconds)
+}
+
+// The POSIX TZ string a version 2 or later zone file ends with, between two newlines
+fn footer(contents: &[u8]) -> Option<&str> {
+    if !contents.starts_with(b"TZif") || contents.get(4).is_some_and(|version| *version == 0) {
+        return None;
+    }
+    let contents = contents.strip_suffix(b"\n")?;
+    let start = contents.iter().rposition(|byte| *byte == b'\n')? + 1;
+    std::str::from_utf8(&contents[start..])
+        .ok()
+        .filter(|footer| !footer.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    const BUDAPEST: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+    const SYDNEY: &str = "AEST-10AEDT,M10.1.0,M4.1.0/3";
+
+    fn utc(month: u32, day: u32, hour: u32, minute: u32) -> i64 {
+        Utc.with_ymd_and_hms(2024, month, day, hour, minute, 0)
+            .unwrap()
+            .timestamp()
+    }
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, month, day).unwrap()
+    }
+
+    #[test]
+    fn rules_parse() {
+        let rule = Rule::parse(BUDAPEST).unwrap();
+        assert_eq!(rule.standard, 3600);
+        assert_eq!(rule.dst.unwrap().offset, 7200);
+        assert_eq!(rule.dst.unwrap().end.time, 3 * 3600);
+        assert_eq!(
+            Rule::parse("<+0530>-5:30").unwrap().standard,
+            5 * 3600 + 1800
+        );
+        assert_eq!(Rule::parse("EST5").unwrap().standard, -5 * 3600);
+        assert_eq!(Rule::parse("Europe/Budapest"), None);
+        // Summer time without dates
+        assert_eq!(Rule::parse("CET-1CEST"), None);
+    }
+
+    #[test]
+    fn summer_time_follows_the_rule() {
+        let budapest = Rule::parse(BUDAPEST).unwrap();
+        // The clocks go forward at 01:00 UTC on the last Sunday of March
+        assert_eq!(budapest.offset(utc(3, 31, 0, 59)), 3600);
+        assert_eq!(budapest.offset(utc(3, 31, 1, 0)), 7200);
+        // and back at 01:00 UTC on the last Sunday of October
+        assert_eq!(budapest.offset(utc(10, 27, 0, 59)), 7200);
+        assert_eq!(budapest.offset(utc(10, 27, 1, 0)), 3600);
+
+        let sydney = Rule::parse(SYDNEY).unwrap();
+        assert_eq!(sydney.offset(utc(1, 15, 12, 0)), 11 * 3600);
+        assert_eq!(sydney.offset(utc(7, 15, 12, 0)), 10 * 3600);
+    }
+
+    #[test]
+    fn local_times_stay_put_across_summer_time() {
+        let budapest = Zone::Rule(Rule::parse(BUDAPEST).unwrap());
+        assert_eq!(budapest.at(date(3, 30), (23, 0)), utc(3, 30, 22, 0));
+        assert_eq!(budapest.at(date(3, 31), (23, 0)), utc(3, 31, 21, 0));
+        assert_eq!(
+            budapest.date(utc(12, 31, 23, 30)),
+            NaiveDate::from_ymd_opt(2025, 1, 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn skipped_and_repeated_times_resolve() {
+        let budapest = Zone::Rule(Rule::parse(BUDAPEST).unwrap());
+        // 02:30 never happens on 31 March; it comes as 03:30
+        assert_eq!(budapest.at(date(3, 31), (2, 30)), utc(3, 31, 1, 30));
+        // 02:30 happens twice on 27 October; the first is still summer time
+        assert_eq!(budapest.at(date(10, 27), (2, 30)), utc(10, 27, 0, 30));
+    }
+
+    #[test]
+    fn zone_files_end_with_a_rule() {
+        let mut contents = b"TZif2\0\0\0binary\n\x01data\n".to_vec();
+        contents.extend_from_slice(format!("\n{}\n", BUDAPEST).as_bytes());
+        assert_eq!(footer(&contents), Some(BUDAPEST));
+        assert_eq!(footer(b"TZif\0\0\0\0data"), None);
+        assert_eq!(footer(b"not a zone file\n"), None);
+    }
+}
@@ -0,0 +1,80 @@
+// Fades between colors in HSV space, so a fade from red to green passes through yellow rather
+// than a muddy brown, and a fade from black only ramps the brightness
+use crate::effects::Frame;
+use angular_units::Deg;
+use prisma::{FromColor, Hsv, Rgb};
+use std::time::{Duration, Instant};
+
+/// A fade from one color into whatever the effect shows, started at a given instant.
+#[derive(Clone, Copy, Debug)]
+pub struct Transition {
+    from: Frame,
+    started: Instant,
+    duration: Duration,
+}
+
+impl Transition {
+    pub fn new(from: Frame, started: Instant, duration: Duration) -> Self {
+        Transition {
+            from,
+            started,
+            duration,
+        }
+    }
+
+    /// Fades in from black, for switching the lights on.
+    pub fn fade_in(started: Instant, duration: Duration) -> Self {
+        Transition::new(Rgb::new(0.0, 0.0, 0.0), started, duration)
+    }
+
+    pub fn is_done(&self, now: Instant) -> bool {
+        now.duration_since(self.started) >= self.duration
+    }
+
+    pub fn apply(&self, to: Frame, now: Instant) -> Frame {
+        if self.is_done(now) {
+            return to;
+        }
+        let t = now.duration_since(self.started).as_secs_f32() / self.duration.as_secs_f32();
+        interpolate(self.from, to, t)
+    }
+}
+
+/// Blends `from` into `to` as `t` goes from 0 to 1, taking the short way round the color wheel.
+pub fn interpolate(from: Frame, to: Frame, t: f32) -> Frame {
+    let t = t.clamp(0.0, 1.0);
+    let from: Hsv<f32, Deg<f32>> = Hsv::from_color(&from);
+    let to: Hsv<f32, Deg<f32>> = Hsv::from_color(&to);
+
+    // Black and greys have no hue of their own, so they borrow the other color's
+    let (from_hue, to_hue) = match (from.saturation() > 0.0, to.saturation() > 0.0) {
+        (false, true) => (to.hue().0, to.hue().0),
+        (true, false) => (from.hue().0, from.hue().0),
+        _ => (from.hue().0, to.hue().0),
+    };
+    let (from_saturation, to_saturation) = match (from.value() > 0.0, to.value() > 0.0) {
+        (false, true) => (to.saturation(), to.saturation()),
+        (true, false) => (from.saturation(), from.saturation()),
+        _ => (from.saturation(), to.saturation()),
+    };
+    let hue_step = (to_hue - from_hue + 180.0).rem_euclid(360.0) - 180.0;
+    let lerp = |a: f32, b: f32| a + (b - a) * t;
+
+    Rgb::from_color(&Hsv::new(
+        Deg((from_hue + hue_step * t).rem_euclid(360.0)),
+        lerp(from_saturation, to_saturation),
+        lerp(from.value(), to.value()),
+    ))
+}
+
+/// The u8 color `t` of the way from `from` to `to`.
+pub fn interpolate_rgb(from: (u8, u8, u8), to: (u8, u8, u8), t: f32) -> (u8, u8, u8) {
+    let frame =
+        |(r, g, b): (u8, u8, u8)| Rgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    let blended = interpolate(frame(from), frame(to), t);
+    (
+        (blended.red() * 255.0).round() as u8,
+        (blended.green() * 255.0).round() as u8,
+        (blended.blue() * 255.0).round() as u8,
+    )
+}
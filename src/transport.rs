@@ -0,0 +1,136 @@
+// The byte pipe to a light, kept behind a trait so command handling can be exercised
+// without a Bluetooth adapter
+use crate::{error::Failure, history, metrics};
+use btleplug::{
+    api::{Characteristic, Peripheral as _, WriteType},
+    platform::Peripheral,
+};
+use futures::future::BoxFuture;
+use std::time::Duration;
+use tokio::time;
+
+// btleplug does not expose the negotiated MTU, so assume the default ATT payload size
+pub const MAX_WRITE_LENGTH: usize = 20;
+const WRITE_CHUNK_DELAY: Duration = Duration::from_millis(5);
+
+/// Delivers raw command bytes to a light.
+pub trait LightTransport: Send + Sync {
+    /// Writes one chunk of at most `MAX_WRITE_LENGTH` bytes.
+    fn write<'a>(&'a self, chunk: &'a [u8]) -> BoxFuture<'a, Result<(), Failure>>;
+}
+
+/// Writes to the command characteristic of a connected peripheral.
+pub struct BleTransport {
+    peripheral: Peripheral,
+    characteristic: Characteristic,
+}
+
+impl BleTransport {
+    pub fn new(peripheral: Peripheral, characteristic: Characteristic) -> Self {
+        BleTransport {
+            peripheral,
+            characteristic,
+        }
+    }
+}
+
+impl LightTransport for BleTransport {
+    fn write<'a>(&'a self, chunk: &'a [u8]) -> BoxFuture<'a, Result<(), Failure>> {
+        Box::pin(async move {
+            self.peripheral
+                .write(&self.characteristic, chunk, WriteType::WithoutResponse)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Sends a command in chunks the link can carry, stopping at the first failed chunk, and
+/// records it in the command history.
+pub async fn send(transport: &dyn LightTransport, command: Vec<u8>) -> Result<(), Failure> {
+    let mut result = Ok(());
+    for (i, chunk) in command.chunks(MAX_WRITE_LENGTH).enumerate() {
+        if i > 0 {
+            time::sleep(WRITE_CHUNK_DELAY).await;
+        }
+        result = transport.write(chunk).await;
+        metrics::wrote(result.is_ok());
+        if result.is_err() {
+            break;
+        }
+    }
+    history::record(command, result.is_ok());
+    result
+}
+
+/// Records every chunk instead of sending it, optionally failing from a given write on.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockTransport {
+    pub written: std::sync::Mutex<Vec<Vec<u8>>>,
+    pub fail_from: Option<usize>,
+}
+
+#[cfg(test)]
+impl LightTransport for MockTransport {
+    fn write<'a>(&'a self, chunk: &'a [u8]) -> BoxFuture<'a, Result<(), Failure>> {
+        let mut written = self.written.lock().unwrap();
+        let result = if self.fail_from.is_some_and(|from| written.len() >= from) {
+            Err(Failure::DeviceNotFound("mock light"))
+        } else {
+            written.push(chunk.to_vec());
+            Ok(())
+        };
+        Box::pin(async move { result })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Actuel, LightProtocol};
+
+    async fn sent(command: Vec<u8>) -> Vec<Vec<u8>> {
+        let transport = MockTransport::default();
+        send(&transport, command).await.unwrap();
+        transport.written.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn actuel_set_color_writes_one_color_command() {
+        assert_eq!(
+            sent(Actuel::default().color((0x12, 0x34, 0x56))).await,
+            vec![vec![0x3C, 0x02, 0x12, 0x34, 0x56]]
+        );
+    }
+
+    #[tokio::test]
+    async fn actuel_turn_off_writes_the_shut_off_command() {
+        let off = Actuel::default()
+            .power(false)
+            .expect("Actuel lights have an off command");
+        assert_eq!(sent(off).await, vec![vec![0x3C, 0x01]]);
+        assert!(Actuel::default().power(true).is_none());
+    }
+
+    #[tokio::test]
+    async fn long_commands_are_split_into_chunks() {
+        let command: Vec<u8> = (0..45).collect();
+        let chunks = sent(command.clone()).await;
+        assert_eq!(
+            chunks.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![MAX_WRITE_LENGTH, MAX_WRITE_LENGTH, 5]
+        );
+        assert_eq!(chunks.concat(), command);
+    }
+
+    #[tokio::test]
+    async fn a_failed_chunk_stops_the_command() {
+        let transport = MockTransport {
+            fail_from: Some(1),
+            ..MockTransport::default()
+        };
+        assert!(send(&transport, vec![0; 45]).await.is_err());
+        assert_eq!(transport.written.into_inner().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,218 @@
+// Things that happen outside and can set off a rule: a button on a GPIO pin, a webhook, a
+// message on an MQTT trigger topic, someone coming home or a new weather reading. Each source
+// reports events through the command channel, and the render loop matches them against the
+// rules file (see rules.rs). A new source only implements Trigger and registers in built().
+// MQTT and the HTTP API, which are integrations already, send RemoteCommand::Event themselves,
+// and the presence and weather commands count as events as they are.
+use crate::{
+    config::{Config, GpioConfig},
+    remote::{RemoteCommand, Source},
+    supervisor::FailureBudget,
+};
+use futures::future::BoxFuture;
+use log::info;
+use std::{fmt, fs, io, time::Duration};
+use tokio::{sync::mpsc, time};
+
+// Pause before watching the pins again after a failure, unless the budget asks for longer
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+// udev takes a moment to hand over the files of a newly exported pin
+const EXPORT_DELAY: Duration = Duration::from_millis(100);
+
+/// Something that happened, e.g. `gpio/doorbell` with the value 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    // The source, such as gpio, webhook, mqtt, presence or weather
+    pub trigger: String,
+    // What happened there, e.g. the pin's or the topic's name
+    pub name: String,
+    // The payload, such as a sensor reading; empty when there is none
+    pub value: String,
+}
+
+impl Event {
+    pub fn new(trigger: &str, name: &str, value: &str) -> Self {
+        Event {
+            trigger: trigger.to_string(),
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    /// The event a command stands for, for commands that the rules can react to as well.
+    pub fn from_command(command: &RemoteCommand) -> Option<Event> {
+        match command {
+            RemoteCommand::Event(event) => Some(event.clone()),
+            RemoteCommand::Arrived(name) => Some(Event::new("presence", name, "")),
+            RemoteCommand::OutdoorTemperature(celsius) => {
+                Some(Event::new("weather", "temperature", &celsius.to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.trigger, self.name)?;
+        if !self.value.is_empty() {
+            write!(f, " {}", self.value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hands a trigger's events to the render loop.
+#[derive(Clone)]
+pub struct Events {
+    trigger: String,
+    commands: mpsc::Sender<(Source, RemoteCommand)>,
+}
+
+impl Events {
+    pub fn new(trigger: &str, commands: mpsc::Sender<(Source, RemoteCommand)>) -> Self {
+        Events {
+            trigger: trigger.to_string(),
+            commands,
+        }
+    }
+
+    /// Reports an event; false once the daemon is shutting down.
+    pub async fn send(&self, name: &str, value: &str) -> bool {
+        let event = Event::new(&self.trigger, name, value);
+        self.commands
+            .send((
+                Source::Trigger(self.trigger.clone()),
+                RemoteCommand::Event(event),
+            ))
+            .await
+            .is_ok()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.commands.is_closed()
+    }
+}
+
+/// A source of events enabled in the config, ready to start.
+pub trait Trigger: Send {
+    /// For the log and its failure budget; its events go by the name in lower case.
+    fn name(&self) -> &'static str;
+
+    /// Reports events until the daemon shuts down, restarting itself when it fails.
+    fn start(self: Box<Self>, events: Events, budget: FailureBudget) -> BoxFuture<'static, ()>;
+}
+
+type Register = fn(&Config) -> Option<Box<dyn Trigger>>;
+
+fn built() -> Vec<Register> {
+    vec![gpio]
+}
+
+/// The triggers the config enables.
+pub fn enabled(config: &Config) -> Vec<Box<dyn Trigger>> {
+    built()
+        .into_iter()
+        .filter_map(|register| register(config))
+        .collect()
+}
+
+// Buttons and sensors on the host's GPIO pins, read through sysfs. Each change of a pin's
+// value is an event named after the pin, carrying the new value.
+struct Gpio(GpioConfig);
+
+fn gpio(config: &Config) -> Option<Box<dyn Trigger>> {
+    Some(Box::new(Gpio(config.gpio.clone()?)))
+}
+
+impl Trigger for Gpio {
+    fn name(&self) -> &'static str {
+        "GPIO"
+    }
+
+    fn start(self: Box<Self>, events: Events, mut budget: FailureBudget) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            while !events.is_closed() {
+                budget.resume();
+                if let Err(e) = watch_pins(&self.0, &events).await {
+                    let pause = budget.failed(e).unwrap_or(RESTART_DELAY);
+                    time::sleep(pause).await;
+                }
+            }
+        })
+    }
+}
+
+// Returns Ok only when the daemon is shutting down
+async fn watch_pins(config: &GpioConfig, events: &Events) -> io::Result<()> {
+    let mut exported = false;
+    for (_, pin) in &config.pins {
+        exported |= export(*pin)?;
+    }
+    if exported {
+        time::sleep(EXPORT_DELAY).await;
+    }
+    for (_, pin) in &config.pins {
+        fs::write(format!("/sys/class/gpio/gpio{}/direction", pin), "in")?;
+    }
+    info!("Watching {} GPIO pin(s)", config.pins.len());
+    let mut values: Vec<Option<String>> = vec![None; config.pins.len()];
+    let mut ticks = time::interval(config.poll);
+    loop {
+        ticks.tick().await;
+        for ((name, pin), last) in config.pins.iter().zip(&mut values) {
+            // sysfs reads never block, so they can stay on the runtime's threads
+            let value = fs::read_to_string(format!("/sys/class/gpio/gpio{}/value", pin))?;
+            let value = value.trim();
+            // The first reading only sets where the pin starts
+            if last.as_deref().is_some_and(|last| last != value) && !events.send(name, value).await
+            {
+                return Ok(());
+            }
+            *last = Some(value.to_string());
+        }
+    }
+}
+
+// Exports the pin unless something did already; true when it had to
+fn export(pin: u32) -> io::Result<bool> {
+    if fs::metadata(format!("/sys/class/gpio/gpio{}", pin)).is_ok() {
+        return Ok(false);
+    }
+    fs::write("/sys/class/gpio/export", pin.to_string())?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presence_and_weather_commands_are_events_too() {
+        assert_eq!(
+            Event::from_command(&RemoteCommand::Arrived("anna".to_string())),
+            Some(Event::new("presence", "anna", ""))
+        );
+        assert_eq!(
+            Event::from_command(&RemoteCommand::OutdoorTemperature(-2.5)),
+            Some(Event::new("weather", "temperature", "-2.5"))
+        );
+        assert_eq!(Event::from_command(&RemoteCommand::On), None);
+    }
+
+    #[tokio::test]
+    async fn events_arrive_as_commands_from_their_trigger() {
+        let (commands, mut received) = mpsc::channel(1);
+        let events = Events::new("gpio", commands);
+        assert!(events.send("doorbell", "1").await);
+        assert_eq!(
+            received.recv().await,
+            Some((
+                Source::Trigger("gpio".to_string()),
+                RemoteCommand::Event(Event::new("gpio", "doorbell", "1"))
+            ))
+        );
+        drop(received);
+        assert!(!events.send("doorbell", "0").await);
+    }
+}
@@ -0,0 +1,365 @@
+// Opt-in check for newer releases. The feed is fetched with curl, which brings its own TLS,
+// and installing swaps the binary in place while keeping the previous one for a rollback.
+// Downloads must match the SHA-256 the release publishes, either as <asset>.sha256 or in a
+// SHA256SUMS asset. A new binary that keeps failing before it connects to the lights is
+// rolled back by itself.
+use crate::{config::UpdateConfig, error::Failure, supervisor::FailureBudget};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    ffi::OsStr,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+};
+use tokio::{task, time};
+
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+const CURL_TIMEOUT_SECS: &str = "120";
+// Starts a new binary gets to report ready before the previous one is put back
+const MAX_UNCONFIRMED_STARTS: u32 = 3;
+
+/// A release newer than the running binary.
+#[derive(Clone, Debug)]
+pub struct Release {
+    pub version: String,
+    // The asset built for this machine's architecture, if the release has one
+    pub download_url: Option<String>,
+    // Every asset, to find the published checksum among
+    pub assets: Vec<String>,
+}
+
+// The newer release found by the last check, reported in the status output
+static AVAILABLE: Mutex<Option<Release>> = Mutex::new(None);
+
+pub fn available() -> Option<String> {
+    let available = AVAILABLE.lock().ok()?;
+    available.as_ref().map(|release| release.version.clone())
+}
+
+/// Checks the feed every interval, logging each new version once and installing it when
+/// `install` is set.
+pub async fn run(config: UpdateConfig, mut budget: FailureBudget) {
+    let mut handled = None;
+    loop {
+        budget.resume();
+        if let Err(e) = check_once(&config, &mut handled).await {
+            budget.failed(e);
+        }
+        time::sleep(config.check_interval).await;
+    }
+}
+
+async fn check_once(config: &UpdateConfig, handled: &mut Option<String>) -> Result<(), Failure> {
+    let check_config = config.clone();
+    let Some(release) = blocking(move || check(&check_config)).await? else {
+        return Ok(());
+    };
+    if handled.as_ref() == Some(&release.version) {
+        return Ok(());
+    }
+    warn!(
+        "christmas-lights {} is available, running {}",
+        release.version, CURRENT_VERSION
+    );
+    let version = release.version.clone();
+    if config.install {
+        blocking(move || install(&release)).await?;
+    }
+    *handled = Some(version);
+    Ok(())
+}
+
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, Failure> + Send + 'static,
+) -> Result<T, Failure> {
+    task::spawn_blocking(f)
+        .await
+        .map_err(|e| Failure::Update(e.to_string()))?
+}
+
+/// Queries the release feed, returning the latest release when it is newer than this binary.
+pub fn check(config: &UpdateConfig) -> Result<Option<Release>, Failure> {
+    let feed = curl(&[
+        OsStr::new("--header"),
+        OsStr::new("Accept: application/vnd.github+json"),
+        OsStr::new(&config.feed),
+    ])?;
+    let feed = String::from_utf8_lossy(&feed);
+    let tag = json_strings(&feed, "tag_name")
+        .into_iter()
+        .next()
+        .ok_or_else(|| Failure::Update(format!("no release found at {}", config.feed)))?;
+    let version = tag.trim_start_matches('v').to_string();
+    let assets = json_strings(&feed, "browser_download_url");
+    let release = (parse_version(&version) > parse_version(CURRENT_VERSION)).then(|| Release {
+        version,
+        download_url: assets
+            .iter()
+            .find(|url| url.contains(env::consts::ARCH) && !is_checksum(url))
+            .cloned(),
+        assets,
+    });
+    if let Ok(mut available) = AVAILABLE.lock() {
+        available.clone_from(&release);
+    }
+    Ok(release)
+}
+
+/// Downloads the release next to the running binary and swaps it in once its checksum matches
+/// and it proves it runs here. The new version takes over on the next start.
+pub fn install(release: &Release) -> Result<(), Failure> {
+    let url = release.download_url.as_deref().ok_or_else(|| {
+        Failure::Update(format!(
+            "release {} has no binary for {}",
+            release.version,
+            env::consts::ARCH
+        ))
+    })?;
+    let expected = published_checksum(release, url)?;
+    let exe = current_exe()?;
+    let download = exe.with_extension("new");
+    curl(&[
+        OsStr::new("--output"),
+        download.as_os_str(),
+        OsStr::new(url),
+    ])?;
+    let contents = fs::read(&download)
+        .map_err(|e| Failure::Update(format!("cannot read the download: {}", e)))?;
+    let actual = hex(&Sha256::digest(&contents));
+    if actual != expected {
+        fs::remove_file(&download).ok();
+        return Err(Failure::Update(format!(
+            "the downloaded {} has SHA-256 {}, but the release publishes {}",
+            release.version, actual, expected
+        )));
+    }
+    fs::set_permissions(&download, fs::Permissions::from_mode(0o755))
+        .map_err(|e| Failure::Update(format!("cannot make the download executable: {}", e)))?;
+
+    // A truncated download or one built for another target fails here rather than at boot
+    let reported = Command::new(&download)
+        .arg("version")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    if reported.as_deref() != Some(release.version.as_str()) {
+        fs::remove_file(&download).ok();
+        return Err(Failure::Update(format!(
+            "the downloaded {} does not run on this machine",
+            release.version
+        )));
+    }
+
+    swap(&exe, &download)?;
+    fs::write(exe.with_extension("pending"), "0")
+        .map_err(|e| Failure::Update(format!("cannot mark the install as pending: {}", e)))?;
+    info!(
+        "Installed christmas-lights {}, it takes over on the next start and {} comes back if \
+         it fails to start {} times; `christmas-lights update --rollback` restores it too",
+        release.version, CURRENT_VERSION, MAX_UNCONFIRMED_STARTS
+    );
+    Ok(())
+}
+
+/// Counts a start of the daemon while an install is unconfirmed, putting the previous binary
+/// back once the new one has failed to report ready too often.
+pub fn started() -> Result<(), Failure> {
+    let Ok(pending) = current_exe().map(|exe| exe.with_extension("pending")) else {
+        return Ok(());
+    };
+    let Ok(starts) = fs::read_to_string(&pending) else {
+        return Ok(());
+    };
+    let starts = starts.trim().parse::<u32>().unwrap_or(0) + 1;
+    if starts <= MAX_UNCONFIRMED_STARTS {
+        fs::write(&pending, starts.to_string())
+            .map_err(|e| Failure::Update(format!("cannot count the start: {}", e)))?;
+        return Ok(());
+    }
+    rollback()?;
+    Err(Failure::Update(format!(
+        "christmas-lights {} did not get going in {} starts, so the previous version is back \
+         and runs on the next start",
+        CURRENT_VERSION, MAX_UNCONFIRMED_STARTS
+    )))
+}
+
+/// The daemon is connected and running, so the installed binary stays.
+pub fn confirm() {
+    let Ok(pending) = current_exe().map(|exe| exe.with_extension("pending")) else {
+        return;
+    };
+    if fs::remove_file(pending).is_ok() {
+        info!(
+            "christmas-lights {} started fine, keeping it",
+            CURRENT_VERSION
+        );
+    }
+}
+
+/// Puts the binary replaced by the last install back. Rolling back twice undoes the rollback.
+pub fn rollback() -> Result<(), Failure> {
+    let exe = current_exe()?;
+    let previous = exe.with_extension("previous");
+    if !previous.exists() {
+        return Err(Failure::Update(format!(
+            "no previous version at {}",
+            previous.display()
+        )));
+    }
+    fs::remove_file(exe.with_extension("pending")).ok();
+    swap(&exe, &previous)
+}
+
+// Moves `replacement` over `exe` and keeps the old binary as .previous, putting it back if the
+// move fails
+fn swap(exe: &Path, replacement: &Path) -> Result<(), Failure> {
+    let backup = exe.with_extension("backup");
+    let failed = |e: std::io::Error| Failure::Update(format!("cannot replace the binary: {}", e));
+    fs::rename(exe, &backup).map_err(failed)?;
+    if let Err(e) = fs::rename(replacement, exe) {
+        fs::rename(&backup, exe).ok();
+        return Err(failed(e));
+    }
+    fs::rename(&backup, exe.with_extension("previous")).map_err(failed)
+}
+
+// The SHA-256 the release publishes for the asset at `url`
+fn published_checksum(release: &Release, url: &str) -> Result<String, Failure> {
+    let name = url.rsplit('/').next().unwrap_or(url);
+    let sidecar = format!("{}.sha256", url);
+    let checksum = if release.assets.contains(&sidecar) {
+        let sums = curl(&[OsStr::new(&sidecar)])?;
+        checksum_for(&String::from_utf8_lossy(&sums), None)
+    } else if let Some(sums_url) = release
+        .assets
+        .iter()
+        .find(|asset| asset.ends_with("/SHA256SUMS"))
+    {
+        let sums = curl(&[OsStr::new(sums_url)])?;
+        checksum_for(&String::from_utf8_lossy(&sums), Some(name))
+    } else {
+        None
+    };
+    checksum.ok_or_else(|| {
+        Failure::Update(format!(
+            "release {} publishes no SHA-256 for {}",
+            release.version, name
+        ))
+    })
+}
+
+fn is_checksum(url: &str) -> bool {
+    url.ends_with(".sha256") || url.ends_with("/SHA256SUMS")
+}
+
+// The hash from sha256sum output: the line for `name`, or the first line without one
+fn checksum_for(sums: &str, name: Option<&str>) -> Option<String> {
+    sums.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let hash = fields.next()?;
+        // sha256sum marks files read in binary mode with a *
+        let file = fields.next().map(|file| file.trim_start_matches('*'));
+        let wanted = name.is_none_or(|name| file == Some(name));
+        (wanted && hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+            .then(|| hash.to_ascii_lowercase())
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn current_exe() -> Result<PathBuf, Failure> {
+    env::current_exe()
+        .map_err(|e| Failure::Update(format!("cannot find the running binary: {}", e)))
+}
+
+fn curl(args: &[&OsStr]) -> Result<Vec<u8>, Failure> {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--max-time", CURL_TIMEOUT_SECS])
+        .args(args)
+        .output()
+        .map_err(|e| Failure::Update(format!("cannot run curl: {}", e)))?;
+    if !output.status.success() {
+        return Err(Failure::Update(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(output.stdout)
+}
+
+// Every string value stored under `key`, wherever it appears. Enough for the release feed,
+// whose tags and URLs need no escapes beyond \" and \/.
+fn json_strings(json: &str, key: &str) -> Vec<String> {
+    let quoted_key = format!("\"{}\"", key);
+    json.match_indices(&quoted_key)
+        .filter_map(|(at, _)| {
+            let rest = json[at + quoted_key.len()..]
+                .trim_start()
+                .strip_prefix(':')?
+                .trim_start()
+                .strip_prefix('"')?;
+            let mut value = String::new();
+            let mut chars = rest.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => return Some(value),
+                    '\\' => value.push(chars.next()?),
+                    c => value.push(c),
+                }
+            }
+            None
+        })
+        .collect()
+}
+
+// "1.10.2" as [1, 10, 2], so versions compare numerically
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn checksums_are_read_from_sha256sum_output() {
+        assert_eq!(checksum_for(HASH, None).as_deref(), Some(HASH));
+        let sums = format!(
+            "{}  christmas-lights-aarch64\n{} *christmas-lights-x86_64\n",
+            "0".repeat(64),
+            HASH.to_uppercase()
+        );
+        assert_eq!(
+            checksum_for(&sums, Some("christmas-lights-x86_64")).as_deref(),
+            Some(HASH)
+        );
+        assert_eq!(checksum_for(&sums, Some("christmas-lights-armv7")), None);
+        assert_eq!(checksum_for("not a hash", None), None);
+    }
+
+    #[test]
+    fn downloads_are_hashed_as_sha256() {
+        assert_eq!(hex(&Sha256::digest(b"test")), HASH);
+    }
+
+    #[test]
+    fn checksum_assets_are_not_mistaken_for_the_binary() {
+        assert!(is_checksum(
+            "https://example.com/v1.1.0/christmas-lights-x86_64.sha256"
+        ));
+        assert!(is_checksum("https://example.com/v1.1.0/SHA256SUMS"));
+        assert!(!is_checksum(
+            "https://example.com/v1.1.0/christmas-lights-x86_64"
+        ));
+    }
+}
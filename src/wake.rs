@@ -0,0 +1,71 @@
+// The wake-up light: a sunrise on the lights that ends at the phone's next alarm. Home
+// Assistant's next_alarm sensor, forwarded to mqtt.alarm_topic, reports the alarm as an
+// RFC 3339 time, and a new time moves the sunrise along with it. The lights come on for it even
+// outside the schedule, go from a dim deep red through orange to warm white over [wake]
+// sunrise_minutes, stay lit for stay_minutes after the alarm and then follow the schedule again.
+use crate::{config::WakeConfig, effects::Frame};
+use chrono::DateTime;
+use prisma::Rgb;
+
+// The sky's color as the sunrise goes on, from 0 at its start to 1 at the alarm
+const SKY: [(f32, (f32, f32, f32)); 3] = [
+    (0.0, (1.0, 0.1, 0.0)),
+    (0.6, (1.0, 0.45, 0.08)),
+    (1.0, (1.0, 0.8, 0.55)),
+];
+
+/// The alarm in Unix seconds from the sensor's state, or None while no alarm is set.
+pub fn parse_alarm(state: &str) -> Option<i64> {
+    // The sensor reads "unavailable" or "unknown" without an alarm
+    DateTime::parse_from_rfc3339(state.trim())
+        .ok()
+        .map(|at| at.timestamp())
+}
+
+/// What the lights show at `now` for the alarm at `alarm`, both in Unix seconds, or None
+/// before the sunrise and once the stay after the alarm is over.
+pub fn sunrise(config: &WakeConfig, alarm: i64, now: f64) -> Option<Frame> {
+    let start = alarm as f64 - config.sunrise.as_secs_f64();
+    if now < start || now >= alarm as f64 + config.stay.as_secs_f64() {
+        return None;
+    }
+    let t = ((now - start) / config.sunrise.as_secs_f64()).min(1.0) as f32;
+    let (from, to) = SKY
+        .windows(2)
+        .map(|stops| (stops[0], stops[1]))
+        .find(|(_, to)| t <= to.0)
+        .unwrap_or((SKY[1], SKY[2]));
+    let mix = (t - from.0) / (to.0 - from.0);
+    let channel = |from: f32, to: f32| (from * (1.0 - mix) + to * mix) * t * t;
+    Some(Rgb::new(
+        channel(from.1 .0, to.1 .0),
+        channel(from.1 .1, to.1 .1),
+        channel(from.1 .2, to.1 .2),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn the_sunrise_ends_in_warm_white_at_the_alarm() {
+        let alarm = parse_alarm("2024-12-20T06:30:00+01:00").unwrap();
+        assert_eq!(alarm, 1_734_672_600);
+        assert_eq!(parse_alarm("unavailable"), None);
+
+        let config = WakeConfig {
+            sunrise: Duration::from_secs(30 * 60),
+            stay: Duration::from_secs(15 * 60),
+        };
+        let at = |minutes: f64| sunrise(&config, alarm, alarm as f64 + minutes * 60.0);
+        assert_eq!(at(-31.0), None);
+        assert_eq!(at(-30.0), Some(Rgb::new(0.0, 0.0, 0.0)));
+        let early = at(-24.0).unwrap();
+        assert!(early.red() < 0.05 && early.green() < early.red() / 4.0);
+        assert_eq!(at(0.0), Some(Rgb::new(1.0, 0.8, 0.55)));
+        assert_eq!(at(10.0), at(0.0));
+        assert_eq!(at(15.0), None);
+    }
+}